@@ -0,0 +1,32 @@
+//! any input parses or is an error pointing into it, never a panic
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/parser.rs"]
+mod parser;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(input) = std::str::from_utf8(data) else { return };
+    // only variables of one letter are set
+    let env = |name: &str| (name.len() == 1).then(|| format!("<{name}>"));
+    for parsed in [parser::parse(input), parser::parse_expanding(input, &env)] {
+        check(input, parsed);
+    }
+});
+
+fn check(input: &str, parsed: Result<Vec<parser::Stage>, parser::ParseError>) {
+    match parsed {
+        Ok(stages) => {
+            for stage in stages {
+                assert!(!stage.words.is_empty());
+                assert!(stage.span.end <= input.len());
+            }
+        },
+        Err(e) => {
+            assert!(input.is_char_boundary(e.at));
+            e.show(input);
+        },
+    }
+}
@@ -0,0 +1,26 @@
+//! words single-quoted into a pipeline parse back to the same words
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/parser.rs"]
+mod parser;
+
+fuzz_target!(|stages: Vec<Vec<String>>| {
+    let stages: Vec<Vec<String>> = stages.into_iter().filter(|words| !words.is_empty()).collect();
+    if stages.is_empty() {
+        return;
+    }
+    let input = stages.iter()
+        .map(|words| words.iter().map(|w| format!("'{}'", w.replace('\'', "'\\''"))).collect::<Vec<_>>().join(" "))
+        .map(|words| format!("-- {words}"))
+        .collect::<Vec<_>>()
+        .join(" | ");
+    let parsed: Vec<Vec<String>> = parser::parse(&input)
+        .unwrap()
+        .into_iter()
+        .map(|stage| stage.words.into_iter().map(|w| w.value).collect())
+        .collect();
+    assert_eq!(parsed, stages);
+});
@@ -0,0 +1,230 @@
+//! helpers for integration-testing pipelines run by plumber
+//!
+//! a `Scratch` is a state dir of its own under the system temp dir, handed to plumber as
+//! `$PLUMBER_ROOT`, so tests neither see nor disturb the pipelines running on the machine and
+//! can run in parallel. `Scratch::run` runs a pipeline to completion with `plumber exec`,
+//! capturing what the last stage writes, and reads back the run summary and stderr log of
+//! every stage:
+//! ```no_run
+//! let scratch = plumber_test::Scratch::new().unwrap();
+//! let run = scratch.run("words", "printf 'a b' | tr ' ' '\\n' | wc -l").unwrap();
+//! run.assert_success().assert_stage_exit("tr", 0).assert_stdout("2\n");
+//! ```
+//! the plumber binary is `$PLUMBER_BIN`, or `plumber` on `PATH`; tests in the crate that
+//! builds plumber use `Scratch::with_binary(env!("CARGO_BIN_EXE_plumber"))`.
+
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde_json::Value;
+
+/// a state dir of its own, removed when dropped
+pub struct Scratch {
+    root: PathBuf,
+    plumber: PathBuf,
+}
+
+impl Scratch {
+    /// runs `$PLUMBER_BIN`, or `plumber` from `PATH`
+    pub fn new() -> io::Result<Scratch> {
+        Scratch::with_binary(env::var_os("PLUMBER_BIN").map_or_else(|| PathBuf::from("plumber"), PathBuf::from))
+    }
+
+    pub fn with_binary(plumber: impl Into<PathBuf>) -> io::Result<Scratch> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let root = env::temp_dir().join(format!(
+            "plumber-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst),
+        ));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root)?;
+        Ok(Scratch { root, plumber: plumber.into() })
+    }
+
+    /// what plumber gets as `$PLUMBER_ROOT`
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// the metadata dir of pipeline `name`
+    pub fn metadata_dir(&self, name: &str) -> PathBuf {
+        self.root.join("lib").join(name)
+    }
+
+    /// the dir the stderr logs of pipeline `name` are written to
+    pub fn logging_dir(&self, name: &str) -> PathBuf {
+        self.root.join("log").join(name)
+    }
+
+    /// runs `pipeline` as `name` to completion, with nothing on its stdin
+    pub fn run(&self, name: &str, pipeline: &str) -> io::Result<Run> {
+        self.run_with(name, pipeline, &[], b"")
+    }
+
+    /// runs `pipeline` as `name` to completion with `exec` options such as `--pipefail`,
+    /// feeding `stdin` to its first stage
+    pub fn run_with(&self, name: &str, pipeline: &str, options: &[&str], stdin: &[u8]) -> io::Result<Run> {
+        let mut child = Command::new(&self.plumber)
+            .arg("exec")
+            .arg("-n")
+            .arg(name)
+            .args(options)
+            .arg("--")
+            .arg(pipeline)
+            .env("PLUMBER_ROOT", &self.root)
+            // would move the metadata and logs out of the scratch dir
+            .env_remove("PLUMBER_STATE_DIR")
+            .env_remove("PLUMBER_LOG_DIR")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let mut input = child.stdin.take().unwrap();
+        let stdin = stdin.to_vec();
+        // written on a thread of its own, so a pipeline that doesn't read can't block the test
+        let feeding = std::thread::spawn(move || input.write_all(&stdin));
+        let output = child.wait_with_output()?;
+        let _ = feeding.join();
+
+        let summary = fs::read_to_string(self.metadata_dir(name).join(".summary")).unwrap_or_default();
+        let mut stages = stage_results(&summary);
+        for stage in &mut stages {
+            stage.stderr = fs::read_to_string(self.logging_dir(name).join(format!("{}.stderr.log", stage.stage))).unwrap_or_default();
+        }
+        Ok(Run {
+            name: name.to_owned(),
+            status: output.status,
+            stdout: output.stdout,
+            plumber_stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            stages,
+        })
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+/// how one stage of a run ended
+#[derive(Debug, Clone, PartialEq)]
+pub struct StageResult {
+    /// the name its stderr log is named after, such as `jq` or `script`
+    pub stage: String,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    /// what it wrote to stderr, every run of the pipeline appends to it
+    pub stderr: String,
+}
+
+impl StageResult {
+    pub fn succeeded(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+}
+
+/// the stages of a `.summary`, in pipeline order
+fn stage_results(summary: &str) -> Vec<StageResult> {
+    let Ok(summary) = serde_json::from_str::<Value>(summary) else { return Vec::new() };
+    let Some(Value::Array(stages)) = summary.get("stages") else { return Vec::new() };
+    let number = |stage: &Value, key: &str| stage.get(key).and_then(Value::as_i64).map(|n| n as i32);
+    stages.iter()
+        .map(|stage| StageResult {
+            stage: match stage.get("stage") {
+                Some(Value::String(s)) => s.clone(),
+                _ => String::new(),
+            },
+            exit_code: number(stage, "exit_code"),
+            signal: number(stage, "signal"),
+            stderr: String::new(),
+        })
+        .collect()
+}
+
+/// a finished run of a pipeline
+#[derive(Debug)]
+pub struct Run {
+    pub name: String,
+    /// of plumber, see `--pipefail`
+    pub status: ExitStatus,
+    /// what the last stage wrote
+    pub stdout: Vec<u8>,
+    /// plumber's own log
+    pub plumber_stderr: String,
+    pub stages: Vec<StageResult>,
+}
+
+impl Run {
+    pub fn stdout_str(&self) -> String {
+        String::from_utf8_lossy(&self.stdout).into_owned()
+    }
+
+    /// the first stage named `stage`, panicking with the stages there are if there's none
+    pub fn stage(&self, stage: &str) -> &StageResult {
+        self.stages.iter().find(|s| s.stage == stage).unwrap_or_else(|| {
+            let stages: Vec<&str> = self.stages.iter().map(|s| s.stage.as_str()).collect();
+            panic!("{}: no stage '{stage}', the stages are {stages:?}", self.name)
+        })
+    }
+
+    fn context(&self) -> String {
+        format!("plumber exited with {}, stages {:?}\n{}", self.status, self.stages, self.plumber_stderr)
+    }
+
+    pub fn assert_success(&self) -> &Self {
+        assert!(self.status.success(), "{}: expected the run to succeed, {}", self.name, self.context());
+        self
+    }
+
+    pub fn assert_failure(&self) -> &Self {
+        assert!(!self.status.success(), "{}: expected the run to fail, {}", self.name, self.context());
+        self
+    }
+
+    pub fn assert_stage_exit(&self, stage: &str, code: i32) -> &Self {
+        let result = self.stage(stage);
+        assert_eq!(result.exit_code, Some(code), "{}: exit code of {stage}, {}", self.name, self.context());
+        self
+    }
+
+    pub fn assert_stdout(&self, expected: &str) -> &Self {
+        assert_eq!(self.stdout_str(), expected, "{}: stdout of the last stage", self.name);
+        self
+    }
+
+    pub fn assert_stderr_contains(&self, stage: &str, expected: &str) -> &Self {
+        let stderr = &self.stage(stage).stderr;
+        assert!(stderr.contains(expected), "{}: expected stderr of {stage} to contain '{expected}', it is:\n{stderr}", self.name);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_stage_results() {
+        let summary = r#"{"schema":1,"stages":[{"stage":"cat","pid":1,"exit_code":0},{"stage":"jq","pid":2,"signal":9}]}"#;
+        let stages = stage_results(summary);
+        assert_eq!(stages.len(), 2);
+        assert!(stages[0].succeeded());
+        assert_eq!((stages[1].exit_code, stages[1].signal), (None, Some(9)));
+        assert_eq!(stage_results("not json"), Vec::new());
+    }
+
+    #[test]
+    fn scratch_dirs_are_separate_and_removed() {
+        let (a, b) = (Scratch::with_binary("plumber").unwrap(), Scratch::with_binary("plumber").unwrap());
+        assert_ne!(a.root(), b.root());
+        let root = a.root().to_owned();
+        drop(a);
+        assert!(!root.exists());
+    }
+}
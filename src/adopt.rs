@@ -0,0 +1,129 @@
+//! adopting plumber files dropped into a directory `plumber run --watch` supervises
+//!
+//! the directory is watched with inotify. a `.plumb` or `.toml` file written or moved into it is
+//! started, or reloaded once its stages drained if it's already running, and one deleted or moved
+//! out of it is stopped, so deploying a pipeline is copying its file over. files are only picked up
+//! once closed after writing, and dotfiles are left alone so editors' swap files aren't started.
+
+use std::ffi::{CString, OsStr};
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::definition;
+
+/// what happened to a plumber file in the watched directory
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    Written(PathBuf),
+    Removed(PathBuf),
+}
+
+/// a directory being watched for plumber files
+pub struct Watch {
+    inotify: File,
+    dir: PathBuf,
+}
+
+/// whether `path` is named like a pipeline definition
+pub fn is_definition(path: &Path) -> bool {
+    path.extension().is_some_and(definition::is_plumber_extension)
+        && !path.file_name().is_some_and(|name| name.as_bytes().starts_with(b"."))
+}
+
+impl Watch {
+    pub fn new(dir: &Path) -> io::Result<Watch> {
+        let inotify = match unsafe { libc::inotify_init1(libc::IN_CLOEXEC) } {
+            fd if fd < 0 => return Err(io::Error::last_os_error()),
+            fd => File::from(unsafe { OwnedFd::from_raw_fd(fd) }),
+        };
+        let path = CString::new(dir.as_os_str().as_bytes())?;
+        let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_DELETE | libc::IN_MOVED_FROM | libc::IN_ONLYDIR;
+        if unsafe { libc::inotify_add_watch(inotify.as_raw_fd(), path.as_ptr(), mask) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Watch { inotify, dir: dir.to_owned() })
+    }
+
+    /// changes to plumber files in the directory, none if there were none within `timeout`
+    pub fn changes(&mut self, timeout: Duration) -> io::Result<Vec<Change>> {
+        let mut poll = libc::pollfd { fd: self.inotify.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        let millis = timeout.as_millis().min(i32::MAX as u128) as i32;
+        match unsafe { libc::poll(&mut poll, 1, millis) } {
+            0 => return Ok(Vec::new()),
+            n if n < 0 => {
+                let e = io::Error::last_os_error();
+                return if e.kind() == io::ErrorKind::Interrupted { Ok(Vec::new()) } else { Err(e) };
+            },
+            _ => {},
+        }
+        // room for a good many events, each at most a header and a file name
+        let mut buf = vec![0; 64 * (mem::size_of::<libc::inotify_event>() + libc::NAME_MAX as usize + 1)];
+        let read = self.inotify.read(&mut buf)?;
+        Ok(self.parse(&buf[..read]))
+    }
+
+    fn parse(&self, mut events: &[u8]) -> Vec<Change> {
+        let header = mem::size_of::<libc::inotify_event>();
+        let mut changes = Vec::new();
+        while events.len() >= header {
+            let event: libc::inotify_event = unsafe { std::ptr::read_unaligned(events.as_ptr().cast()) };
+            let end = (header + event.len as usize).min(events.len());
+            let name = &events[header..end];
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+            events = &events[end..];
+            if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                log::warn!("{}: missed changes to plumber files, restart plumber to pick them up", self.dir.display());
+                continue;
+            }
+            let path = self.dir.join(OsStr::from_bytes(name));
+            if name.is_empty() || !is_definition(&path) {
+                continue;
+            }
+            if event.mask & (libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO) != 0 {
+                changes.push(Change::Written(path));
+            } else if event.mask & (libc::IN_DELETE | libc::IN_MOVED_FROM) != 0 {
+                changes.push(Change::Removed(path));
+            }
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn sees_plumber_files_come_and_go() {
+        let dir = std::env::temp_dir().join(format!("plumber-adopt-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut watch = Watch::new(&dir).unwrap();
+
+        fs::write(dir.join("etl.plumb"), "cat | wc -l").unwrap();
+        fs::write(dir.join(".etl.plumb.swp"), "").unwrap();
+        fs::write(dir.join("notes.txt"), "").unwrap();
+        fs::write(dir.join("draft"), "sort").unwrap();
+        fs::rename(dir.join("draft"), dir.join("sort.toml")).unwrap();
+        fs::remove_file(dir.join("etl.plumb")).unwrap();
+
+        let mut changes = Vec::new();
+        while changes.len() < 3 {
+            let more = watch.changes(Duration::from_secs(5)).unwrap();
+            assert!(!more.is_empty(), "{changes:?}");
+            changes.extend(more);
+        }
+        assert_eq!(changes, [
+            Change::Written(dir.join("etl.plumb")),
+            Change::Written(dir.join("sort.toml")),
+            Change::Removed(dir.join("etl.plumb")),
+        ]);
+        assert!(watch.changes(Duration::ZERO).unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
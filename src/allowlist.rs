@@ -0,0 +1,88 @@
+//! signed allowlist of approved pipeline definitions
+//!
+//! the allowlist uses the `sha256sum` format, one `<hex digest>  <name>` line per approved
+//! definition, so it can be created with `sha256sum *.plumb > approved`. it must be signed
+//! with `ssh-keygen -Y sign -n plumber-allowlist -f <key> approved`, which writes
+//! `approved.sig`, and is only trusted if that signature verifies against a signer in an
+//! ssh `allowed_signers` file.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{sha256, signature};
+
+/// ssh signature namespace, so signatures made for other purposes can't be replayed here
+const NAMESPACE: &str = "plumber-allowlist";
+
+#[derive(Debug)]
+pub enum AllowlistError {
+    Unreadable(PathBuf),
+    BadSignature(String),
+    Malformed(usize),
+}
+
+impl std::fmt::Display for AllowlistError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AllowlistError::Unreadable(path) => write!(f, "unable to read {}", path.display()),
+            AllowlistError::BadSignature(reason) => write!(f, "allowlist signature rejected: {reason}"),
+            AllowlistError::Malformed(line) => write!(f, "allowlist line {line} is not '<sha256>  <name>'"),
+        }
+    }
+}
+
+pub struct Allowlist {
+    digests: Vec<String>,
+    pub signer: String,
+}
+
+impl Allowlist {
+    /// reads the allowlist and checks its `.sig` against `allowed_signers`
+    pub fn load(path: &Path, allowed_signers: &Path) -> Result<Self, AllowlistError> {
+        let content = fs::read(path).map_err(|_| AllowlistError::Unreadable(path.to_owned()))?;
+        let mut signature = path.as_os_str().to_owned();
+        signature.push(".sig");
+        let signer = signature::verify(NAMESPACE, &content, Path::new(&signature), allowed_signers)
+            .map_err(AllowlistError::BadSignature)?;
+
+        let digests = parse(&String::from_utf8_lossy(&content))?;
+        Ok(Allowlist { digests, signer })
+    }
+
+    pub fn allows(&self, definition: &[u8]) -> bool {
+        self.digests.contains(&sha256::hex_digest(definition))
+    }
+}
+
+fn parse(content: &str) -> Result<Vec<String>, AllowlistError> {
+    let mut digests = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let digest = line.split_whitespace().next().unwrap_or_default().to_ascii_lowercase();
+        if digest.len() != 64 || !digest.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(AllowlistError::Malformed(number + 1));
+        }
+        digests.push(digest);
+    }
+    Ok(digests)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha256sum_format() {
+        let digest = sha256::hex_digest(b"cat | wc\n");
+        let allowlist = Allowlist {
+            digests: parse(&format!("# approved 2024-01-01\n{digest}  etl.plumb\n")).unwrap(),
+            signer: String::new(),
+        };
+        assert!(allowlist.allows(b"cat | wc\n"));
+        assert!(!allowlist.allows(b"cat | wc -l\n"));
+        assert!(matches!(parse("abc  x.plumb"), Err(AllowlistError::Malformed(1))));
+    }
+}
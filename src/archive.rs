@@ -0,0 +1,406 @@
+//! runs kept after they end, and `plumber archive` to bundle one up
+//!
+//! every run of a pipeline leaves `runs/<id>/` in its metadata dir, with its summary, the
+//! pipeline it ran, plumber's own command line and where the run's lines are in the stderr
+//! logs. the id is the time the run started in utc, e.g. `20261016T101502.337Z`, a
+//! millisecond later for every run that started in the same one, so ids never collide and sort
+//! in the order runs started. the last 50 runs are kept.
+//!
+//! `plumber archive <name> [--run <id>]` bundles a run, the latest by default, with the lines
+//! it logged into `<name>-<id>.tar.zst`, or `.tar.gz` or `.tar` with `--format`, for
+//! attaching to a ticket. secrets are marked with `--redact <regex>`, when running the
+//! pipeline or when archiving it; matches, or the first group of a regex that has one, are
+//! replaced with `[redacted]` in everything archived. logs truncated by a later start of the
+//! pipeline are left out.
+
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::os::unix::ffi::OsStringExt;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, SystemTime};
+
+use regex::{Captures, Regex};
+
+use serde_json::{json, Value};
+use crate::summary::RunSummary;
+
+pub const RUNS: &str = "runs";
+const KEPT_RUNS: usize = 50;
+const REDACTED: &str = "[redacted]";
+/// of a kept run's `run.json`, bumped along with changes an older reader would trip over
+pub const SCHEMA: u32 = 1;
+
+/// id of the run started at `started`, ids sort in the order runs started
+pub fn run_id(started: SystemTime) -> String {
+    humantime::format_rfc3339_millis(started).to_string().replace(['-', ':'], "")
+}
+
+/// creates the dir in `runs` of the run started at `started` and returns its id, the first
+/// millisecond from `started` on no other run took
+pub fn claim_run_id(runs: &Path, started: SystemTime) -> io::Result<String> {
+    fs::create_dir_all(runs)?;
+    let mut at = started;
+    loop {
+        let id = run_id(at);
+        // create_dir fails on a dir another run created, even one of another plumber
+        match fs::create_dir(runs.join(&id)) {
+            Ok(()) => return Ok(id),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => at += Duration::from_millis(1),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// where the lines a stage logged during a run are in its stderr log
+pub struct LogSpan {
+    pub path: PathBuf,
+    pub from: u64,
+    pub to: u64,
+}
+
+/// what's kept of a finished run
+pub struct Run<'a> {
+    pub id: String,
+    pub summary: &'a RunSummary,
+    pub pipeline: &'a str,
+    /// plumber's command line
+    pub command: Vec<String>,
+    pub logs: Vec<LogSpan>,
+    pub redact: &'a [Regex],
+}
+
+impl Run<'_> {
+    /// keeps the run in `runs`, then removes the oldest runs past the last 50
+    pub fn keep(&self, runs: &Path) -> io::Result<()> {
+        let dir = runs.join(&self.id);
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("summary.json"), self.summary.to_json().to_string())?;
+        fs::write(dir.join("pipeline"), self.pipeline)?;
+        let logs: Vec<Value> = self.logs.iter()
+            .map(|log| json!({ "path": log.path.display().to_string(), "from": log.from, "to": log.to }))
+            .collect();
+        let redact: Vec<&str> = self.redact.iter().map(Regex::as_str).collect();
+        let run = json!({ "command": self.command, "logs": logs, "redact": redact });
+        fs::write(dir.join("run.json"), run.to_string())?;
+
+        let kept = ids(runs)?;
+        for id in &kept[..kept.len().saturating_sub(KEPT_RUNS)] {
+            fs::remove_dir_all(runs.join(id))?;
+        }
+        Ok(())
+    }
+}
+
+/// ids of the kept runs, oldest first
+fn ids(runs: &Path) -> io::Result<Vec<String>> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(runs)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            ids.extend(entry.file_name().to_str().map(str::to_owned));
+        }
+    }
+    ids.sort();
+    Ok(ids)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Format {
+    #[value(name = "tar.zst")]
+    TarZst,
+    #[value(name = "tar.gz")]
+    TarGz,
+    Tar,
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::TarZst => "tar.zst",
+            Format::TarGz => "tar.gz",
+            Format::Tar => "tar",
+        }
+    }
+
+    fn compression(self) -> Option<&'static str> {
+        match self {
+            Format::TarZst => Some("--zstd"),
+            Format::TarGz => Some("--gzip"),
+            Format::Tar => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    NoRuns,
+    UnknownRun(String),
+    Io(io::Error),
+    Tar(String),
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchiveError::NoRuns => write!(f, "no finished runs"),
+            ArchiveError::UnknownRun(id) => write!(f, "no run '{id}'"),
+            ArchiveError::Io(e) => write!(f, "{e}"),
+            ArchiveError::Tar(reason) => write!(f, "tar failed: {reason}"),
+        }
+    }
+}
+
+impl From<io::Error> for ArchiveError {
+    fn from(e: io::Error) -> Self {
+        ArchiveError::Io(e)
+    }
+}
+
+/// what to bundle up and how
+pub struct Archive<'a> {
+    pub name: &'a str,
+    /// the latest run when `None`
+    pub run: Option<&'a str>,
+    pub format: Format,
+    /// `<name>-<id>.<format>` in the current dir when `None`
+    pub output: Option<&'a Path>,
+    /// secrets to redact besides those marked when the pipeline ran
+    pub redact: &'a [Regex],
+}
+
+/// removed on drop, so nothing unredacted is left behind
+struct Staging(PathBuf);
+
+impl Staging {
+    /// a fresh dir only we may write to, so nobody can put a link where the archive is staged
+    fn new() -> io::Result<Self> {
+        let template = std::env::temp_dir().join("plumber-archive-XXXXXX");
+        let mut template = std::ffi::CString::new(template.into_os_string().into_vec())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+            .into_bytes_with_nul();
+        // created with mode 0700
+        if unsafe { libc::mkdtemp(template.as_mut_ptr().cast()) }.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        template.pop();
+        Ok(Staging(PathBuf::from(OsString::from_vec(template))))
+    }
+}
+
+/// `file_name` unless another log was archived under it already, then numbered, `2-jq.stderr.log`
+fn archived_name(file_name: &OsStr, taken: &mut HashSet<OsString>) -> OsString {
+    let mut name = file_name.to_owned();
+    for n in 2.. {
+        if taken.insert(name.clone()) {
+            break;
+        }
+        name = OsString::from(format!("{n}-"));
+        name.push(file_name);
+    }
+    name
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+impl Archive<'_> {
+    /// bundles the run kept in `runs`, returns where the archive was written
+    pub fn write(&self, runs: &Path) -> Result<PathBuf, ArchiveError> {
+        let ids = ids(runs).unwrap_or_default();
+        let id = match self.run {
+            Some(id) if ids.iter().any(|kept| kept == id) => id,
+            Some(id) => return Err(ArchiveError::UnknownRun(id.to_owned())),
+            None => ids.last().ok_or(ArchiveError::NoRuns)?,
+        };
+        let kept = runs.join(id);
+        let run: Value = serde_json::from_str(&fs::read_to_string(kept.join("run.json"))?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("run.json: {e}")))?;
+        let strings = |key| match run.get(key) {
+            Some(Value::Array(values)) => values.iter().filter_map(Value::as_str).map(str::to_owned).collect(),
+            _ => Vec::<String>::new(),
+        };
+        let mut redact: Vec<Regex> = strings("redact").iter()
+            .filter_map(|pattern| Regex::new(pattern)
+                .inspect_err(|e| log::warn!("{}: not redacting '{pattern}': {e}", self.name))
+                .ok())
+            .collect();
+        redact.extend(self.redact.iter().cloned());
+
+        let top = format!("{}-{id}", self.name);
+        let staging = Staging::new()?;
+        let root = staging.0.join(&top);
+        fs::create_dir_all(root.join("logs"))?;
+        for file in ["summary.json", "pipeline"] {
+            fs::write(root.join(file), redacted(&fs::read_to_string(kept.join(file))?, &redact))?;
+        }
+        let command = strings("command").iter().map(|arg| shlex::quote(arg).into_owned()).collect::<Vec<_>>();
+        fs::write(root.join("command"), redacted(&(command.join(" ") + "\n"), &redact))?;
+        if let Some(Value::Array(logs)) = run.get("logs") {
+            // logs of the same name in different dirs, such as a stage's and a `--stdout` file
+            let mut taken = HashSet::new();
+            for log in logs {
+                let (Some(path), Some(from), Some(to)) =
+                    (log["path"].as_str(), log["from"].as_u64(), log["to"].as_u64()) else { continue };
+                let path = Path::new(path);
+                let Some(lines) = read_span(path, from, to)? else {
+                    log::warn!("{}: {} was truncated since run {id}, leaving it out", self.name, path.display());
+                    continue;
+                };
+                let Some(file_name) = path.file_name() else { continue };
+                fs::write(root.join("logs").join(archived_name(file_name, &mut taken)), redacted(&lines, &redact))?;
+            }
+        }
+
+        let output = match self.output {
+            Some(output) => output.to_owned(),
+            None => PathBuf::from(format!("{top}.{}", self.format.extension())),
+        };
+        // tar runs in the staging dir
+        let output = std::env::current_dir()?.join(output);
+        let tar = Command::new("tar")
+            .arg("--create")
+            .args(self.format.compression())
+            .arg("--file")
+            .arg(&output)
+            .arg("--directory")
+            .arg(&staging.0)
+            .arg(&top)
+            .output()
+            .map_err(|e| ArchiveError::Tar(e.to_string()))?;
+        if !tar.status.success() {
+            return Err(ArchiveError::Tar(String::from_utf8_lossy(&tar.stderr).trim().to_owned()));
+        }
+        Ok(output)
+    }
+}
+
+/// bytes `from..to` of a log, `None` if it no longer has them
+fn read_span(path: &Path, from: u64, to: u64) -> io::Result<Option<String>> {
+    let mut log = match fs::File::open(path) {
+        Ok(log) => log,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if log.metadata()?.len() < to || to < from {
+        return Ok(None);
+    }
+    log.seek(SeekFrom::Start(from))?;
+    let mut lines = Vec::with_capacity((to - from) as usize);
+    log.take(to - from).read_to_end(&mut lines)?;
+    Ok(Some(String::from_utf8_lossy(&lines).into_owned()))
+}
+
+/// `text` with every match of `patterns` replaced, or only their first group if they have one
+fn redacted(text: &str, patterns: &[Regex]) -> String {
+    let mut text = text.to_owned();
+    for pattern in patterns {
+        text = pattern.replace_all(&text, |caps: &Captures| {
+            let whole = caps.get(0).unwrap();
+            match caps.get(1) {
+                Some(secret) => format!(
+                    "{}{REDACTED}{}",
+                    &text[whole.start()..secret.start()],
+                    &text[secret.end()..whole.end()],
+                ),
+                None => REDACTED.to_string(),
+            }
+        }).into_owned();
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::summary::StageRun;
+
+    #[test]
+    fn redacts_secrets() {
+        let patterns = [Regex::new("token=(\\w+)").unwrap(), Regex::new("hunter2").unwrap()];
+        assert_eq!(
+            redacted("curl -d token=abc123 -u me:hunter2", &patterns),
+            "curl -d token=[redacted] -u me:[redacted]",
+        );
+        assert_eq!(run_id(SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_500)), "20231114T221320.500Z");
+    }
+
+    #[test]
+    fn logs_of_the_same_name_are_numbered() {
+        let mut taken = HashSet::new();
+        let names: Vec<_> = ["jq.stderr.log", "jq.stderr.log", "jq.stderr.log", "cat.stderr.log"].into_iter()
+            .map(|name| archived_name(OsStr::new(name), &mut taken))
+            .collect();
+        assert_eq!(names, ["jq.stderr.log", "2-jq.stderr.log", "3-jq.stderr.log", "cat.stderr.log"]);
+
+        let staging = Staging::new().unwrap();
+        let mode = std::os::unix::fs::PermissionsExt::mode(&fs::metadata(&staging.0).unwrap().permissions());
+        assert_eq!(mode & 0o777, 0o700);
+        assert!(staging.0.file_name().unwrap().to_string_lossy().starts_with("plumber-archive-"));
+    }
+
+    #[test]
+    fn run_ids_never_collide() {
+        let runs = std::env::temp_dir().join(format!("plumber-run-ids-test-{}", std::process::id())).join(RUNS);
+        let started = SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_500);
+        let ids: Vec<String> = (0..3).map(|_| claim_run_id(&runs, started).unwrap()).collect();
+        assert_eq!(ids, ["20231114T221320.500Z", "20231114T221320.501Z", "20231114T221320.502Z"]);
+        fs::remove_dir_all(runs.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn archives_kept_runs() {
+        let dir = std::env::temp_dir().join(format!("plumber-archive-test-{}", std::process::id()));
+        let runs = dir.join(RUNS);
+        fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("curl.stderr.log");
+        fs::write(&log, "earlier run\nauth with token=abc123\n").unwrap();
+
+        let started = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let summary = RunSummary {
+            started,
+            ended: started,
+            stages: vec![StageRun { stage: "curl".to_string(), pid: Some(7), status: None, usage: None, core: None, respawned: false, warned: false, fallback: None }],
+            outputs: Vec::new(),
+            revision: None,
+            over_budget: false,
+        };
+        let redact = [Regex::new("token=(\\w+)").unwrap()];
+        for id in ["a", "b"] {
+            let run = Run {
+                id: id.to_string(),
+                summary: &summary,
+                pipeline: "curl -H 'Authorization: Bearer xyz' http://api/",
+                command: vec!["plumber".to_string(), "run".to_string()],
+                logs: vec![LogSpan { path: log.clone(), from: 12, to: 35 }],
+                redact: &redact,
+            };
+            run.keep(&runs).unwrap();
+        }
+
+        let output = dir.join("out.tar");
+        let archive = Archive {
+            name: "api",
+            run: None,
+            format: Format::Tar,
+            output: Some(&output),
+            redact: &[Regex::new("Bearer (\\w+)").unwrap()],
+        };
+        assert_eq!(archive.write(&runs).unwrap(), output);
+        let listed = Command::new("tar").arg("-tf").arg(&output).output().unwrap();
+        assert!(String::from_utf8_lossy(&listed.stdout).contains("api-b/logs/curl.stderr.log"));
+        let logged = Command::new("tar").arg("-xOf").arg(&output).arg("api-b/logs/curl.stderr.log").output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&logged.stdout), "auth with token=[redacted]\n");
+        let pipeline = Command::new("tar").arg("-xOf").arg(&output).arg("api-b/pipeline").output().unwrap();
+        assert!(String::from_utf8_lossy(&pipeline.stdout).contains("Bearer [redacted]"));
+
+        assert!(matches!(Archive { run: Some("c"), ..archive }.write(&runs), Err(ArchiveError::UnknownRun(_))));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,136 @@
+//! append-only audit trail of control operations
+//!
+//! every start, stop and forwarded signal is written as one json line to `audit.log` in the
+//! state root, separate from the debug log, recording who asked for it and when. a command
+//! sent over a supervisor's socket is recorded as the uid and pid of the process that sent it,
+//! as the kernel reports them, with `"via":"socket"` rather than `"via":"cli"`.
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use serde_json::json;
+use crate::pipeline;
+
+/// format of the lines of the audit log
+pub const SCHEMA: u32 = 1;
+
+/// the user behind a control operation
+pub struct Actor {
+    uid: u32,
+    user: String,
+    /// original user when running under sudo
+    sudo_user: Option<String>,
+    pid: u32,
+    /// `cli`, or `socket` for a command sent over a supervisor's socket
+    via: &'static str,
+}
+
+impl Actor {
+    fn current() -> Self {
+        let uid = fs::metadata("/proc/self").map(|m| m.uid()).unwrap_or(u32::MAX);
+        Actor {
+            uid,
+            user: user_name(uid).unwrap_or_else(|| uid.to_string()),
+            sudo_user: std::env::var("SUDO_USER").ok(),
+            pid: std::process::id(),
+            via: "cli",
+        }
+    }
+
+    /// the process at the other end of a supervisor's socket, as `SO_PEERCRED` has it
+    pub fn peer(uid: u32, pid: u32) -> Self {
+        Actor {
+            uid,
+            user: user_name(uid).unwrap_or_else(|| uid.to_string()),
+            sudo_user: None,
+            pid,
+            via: "socket",
+        }
+    }
+}
+
+fn user_name(uid: u32) -> Option<String> {
+    let passwd = fs::read_to_string("/etc/passwd").ok()?;
+    passwd.lines()
+        .map(|l| l.split(':').collect::<Vec<_>>())
+        .find(|f| f.len() > 2 && f[2].parse() == Ok(uid))
+        .map(|f| f[0].to_owned())
+}
+
+fn entry(action: &str, pipeline: &str, detail: &str, actor: &Actor, now: SystemTime) -> String {
+    let mut entry = json!({
+        "ts": humantime::format_rfc3339_millis(now).to_string(),
+        "action": action,
+        "pipeline": pipeline,
+        "uid": actor.uid,
+        "user": actor.user,
+    });
+    if let Some(sudo_user) = &actor.sudo_user {
+        entry["sudo_user"] = json!(sudo_user);
+    }
+    entry["pid"] = json!(actor.pid);
+    entry["via"] = json!(actor.via);
+    if !detail.is_empty() {
+        entry["detail"] = json!(detail);
+    }
+    entry.to_string()
+}
+
+/// `audit.log` under the state root, `/tmp/plumber` unless moved, see `pipeline::state_root`
+pub fn path() -> PathBuf {
+    pipeline::state_root().join("audit.log")
+}
+
+/// appends an entry, e.g. `record("stop", "etl", "")`. failures are logged, not fatal
+pub fn record(action: &str, pipeline: &str, detail: &str) {
+    record_by(action, pipeline, detail, &Actor::current());
+}
+
+/// appends an entry for what `actor` rather than this plumber asked for
+pub fn record_by(action: &str, pipeline: &str, detail: &str, actor: &Actor) {
+    let line = entry(action, pipeline, detail, actor, SystemTime::now());
+    let path = path();
+    let _ = fs::create_dir_all(path.parent().unwrap());
+    let written = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        // a single write keeps concurrent entries from interleaving
+        .and_then(|mut f| f.write_all(format!("{line}\n").as_bytes()));
+    if let Err(e) = written {
+        log::error!("unable to write audit log {}: {e}", path.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    #[test]
+    fn entry_format() {
+        let actor = Actor { uid: 1000, user: "ops".to_string(), sudo_user: Some("alice".to_string()), pid: 42, via: "cli" };
+        let line = entry("stop", "etl", "", &actor, SystemTime::UNIX_EPOCH);
+        let value = serde_json::from_str::<Value>(&line).unwrap();
+        assert_eq!(value.get("ts"), Some(&Value::String("1970-01-01T00:00:00.000Z".to_string())));
+        assert_eq!(value.get("action"), Some(&Value::String("stop".to_string())));
+        assert_eq!(value.get("uid"), Some(&Value::from(1000)));
+        assert_eq!(value.get("sudo_user"), Some(&Value::String("alice".to_string())));
+        assert_eq!(value.get("detail"), None);
+
+        let line = entry("signal", "etl", "HUP", &Actor::peer(0, 7), SystemTime::UNIX_EPOCH);
+        let value = serde_json::from_str::<Value>(&line).unwrap();
+        assert_eq!(value.get("user"), Some(&Value::String("root".to_string())));
+        assert_eq!(value.get("pid"), Some(&Value::from(7)));
+        assert_eq!(value.get("via"), Some(&Value::String("socket".to_string())));
+    }
+
+    #[test]
+    fn root_has_a_name() {
+        assert_eq!(user_name(0).as_deref(), Some("root"));
+    }
+}
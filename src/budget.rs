@@ -0,0 +1,56 @@
+//! a cap on the data a run may move between its stages, `--max-run-bytes`
+//!
+//! with a budget every link is relayed through plumber, as with `--instrument`, and every byte
+//! passed on counts against it. once a run moved more than it may, the relays stop passing data
+//! on and the supervisor ends every stage with SIGTERM, so an upstream bug producing unbounded
+//! output can't fill the disks downstream. the run then fails with exit code 3 whatever its
+//! stages exited with, and its summary says `over_budget`. a pipeline of one stage has no
+//! links, so nothing counts against its budget.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// what a run over its budget exits with
+pub const EXIT_CODE: i32 = 3;
+
+pub struct Budget {
+    max: u64,
+    moved: AtomicU64,
+}
+
+impl Budget {
+    pub fn new(max: u64) -> Self {
+        Budget { max, moved: AtomicU64::new(0) }
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// counts `bytes` against the budget, false once the run is over it
+    pub fn spend(&self, bytes: u64) -> bool {
+        self.moved.fetch_add(bytes, Ordering::Relaxed) + bytes <= self.max
+    }
+
+    pub fn exceeded(&self) -> bool {
+        self.moved.load(Ordering::Relaxed) > self.max
+    }
+
+    /// for the next run
+    pub fn reset(&self) {
+        self.moved.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spent_across_links() {
+        let budget = Budget::new(10);
+        assert!(budget.spend(4) && budget.spend(6) && !budget.exceeded());
+        assert!(!budget.spend(1) && budget.exceeded());
+        budget.reset();
+        assert!(!budget.exceeded());
+    }
+}
@@ -0,0 +1,538 @@
+//! capture of stage stderr through plumber
+//!
+//! by default stages write straight into their stderr logs. with `--max-log-lines` or
+//! `--collapse-repeats` a stage's stderr is a pipe read on the reactor instead. with
+//! `--max-log-lines <n>` lines beyond `n` per second are dropped. the next line that gets
+//! through, or the end of the stage's output, is preceded by a `[plumber] <count> lines
+//! suppressed` marker, so a flood of errors can't fill the disk but still shows up in the log.
+//!
+//! with `--collapse-repeats` a run of identical lines is logged once, followed by
+//! `[plumber] last message repeated <count> times` when a different line comes along or the
+//! stage's output ends, like syslog does. repeats don't count against `--max-log-lines`.
+//!
+//! `--max-log-size [STAGE=]SIZE` caps the size of a stage's stderr log, and of the file the last
+//! stage's stdout goes to with `--stdout log` or `append:<path>`. once a log would grow past it,
+//! `--on-log-full` says what happens:
+//! - `truncate` keeps the log at the whole lines that fit, marked by
+//!   `[plumber] log reached --max-log-size of <size> bytes`, and drops the rest
+//! - `rotate` moves what the log holds to `<log>.1` and starts it over. `--max-log-files <n>`
+//!   keeps `n` rotated logs, moving older ones up to `<log>.2` and on, and `--compress-logs`
+//!   gzips them with the `gzip` binary as they're rotated, to `<log>.1.gz` and on
+//! - `fail` truncates the log and ends the stage with SIGTERM, failing the run
+//!
+//! `--timestamp-logs` prefixes every line captured, markers included, with the utc time it was
+//! read at and the stage that wrote it, as in `2024-05-01T03:12:45.120Z jq: connection reset`,
+//! so a log shows when a stage went quiet.
+//!
+//! a structured definition may set `max_log_size`, `on_log_full`, `max_log_files` and
+//! `compress_logs` for its pipeline, which apply unless the flags say otherwise.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::reactor::{self, Finished, Handler, Wait};
+use crate::stages;
+
+/// longest line kept whole, longer ones are split
+const MAX_LINE: usize = 64 * 1024;
+/// reads per poll, so a flooding stage can't keep the reactor from other handlers
+const READS_PER_POLL: usize = 16;
+
+/// so a missing gzip binary is warned about once
+static NO_GZIP: AtomicBool = AtomicBool::new(false);
+
+/// bytes captured and time spent filtering them, by every stage of every pipeline
+static CAPTURED: AtomicU64 = AtomicU64::new(0);
+static BUSY_NS: AtomicU64 = AtomicU64::new(0);
+
+/// what capturing stderr cost plumber so far: bytes captured and time spent on them
+pub fn overhead() -> (u64, Duration) {
+    (CAPTURED.load(Ordering::Relaxed), Duration::from_nanos(BUSY_NS.load(Ordering::Relaxed)))
+}
+
+/// one `--max-log-size [STAGE=]SIZE` flag
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogSize {
+    stage: Option<String>,
+    bytes: u64,
+}
+
+impl std::str::FromStr for LogSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (stage, size) = match s.split_once('=') {
+            Some((stage, size)) => (Some(stage.to_owned()), size),
+            None => (None, s),
+        };
+        let bytes = stages::parse_size(size).map_err(|e| e.to_string())?;
+        Ok(LogSize { stage, bytes })
+    }
+}
+
+/// what a log does once it reached `--max-log-size`
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum LogFull {
+    /// keep what fit and drop the rest
+    #[default]
+    Truncate,
+    /// move the log to `<log>.1` and start it over
+    Rotate,
+    /// drop the rest and end the stage
+    Fail,
+}
+
+/// how captured lines are filtered on their way to the log
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LogFilter {
+    /// lines per second
+    pub max_lines: Option<u32>,
+    pub collapse_repeats: bool,
+    pub max_size: Vec<LogSize>,
+    pub on_full: Option<LogFull>,
+    /// rotated logs kept, 1 unless set
+    pub max_files: Option<u32>,
+    /// gzip rotated logs
+    pub compress: bool,
+    /// prefix lines with the time and the stage
+    pub timestamps: bool,
+}
+
+impl LogFilter {
+    /// whether the stderr of any stage has to pass through plumber at all
+    pub fn enabled(&self) -> bool {
+        self.max_lines.is_some() || self.collapse_repeats || self.timestamps || !self.max_size.is_empty()
+    }
+
+    /// whether the stderr of `stage` has to pass through plumber
+    pub fn captures(&self, stage: &str) -> bool {
+        self.max_lines.is_some() || self.collapse_repeats || self.timestamps || self.max_size(stage).is_some()
+    }
+
+    /// largest log of `stage`; a size for the stage wins over one for all
+    pub fn max_size(&self, stage: &str) -> Option<u64> {
+        self.max_size.iter()
+            .rev()
+            .find(|s| s.stage.as_deref() == Some(stage))
+            .or_else(|| self.max_size.iter().rev().find(|s| s.stage.is_none()))
+            .map(|s| s.bytes)
+    }
+
+    /// the `--max-log-size` of `stage`'s log at `path`, if it has one. `pid` is the stage's,
+    /// 0 until it's spawned
+    pub fn size_limit(&self, stage: &str, path: &Path, pid: &Arc<AtomicU32>) -> Option<SizeLimit> {
+        self.max_size(stage).map(|max| SizeLimit {
+            max,
+            on_full: self.on_full.unwrap_or_default(),
+            max_files: self.max_files.unwrap_or(1).max(1),
+            compress: self.compress,
+            path: path.to_owned(),
+            pid: pid.clone(),
+            full: false,
+            dropped: 0,
+        })
+    }
+}
+
+/// `--max-log-size` of one log
+pub struct SizeLimit {
+    max: u64,
+    on_full: LogFull,
+    max_files: u32,
+    compress: bool,
+    path: PathBuf,
+    pid: Arc<AtomicU32>,
+    full: bool,
+    /// bytes dropped since the log got full
+    dropped: u64,
+}
+
+impl SizeLimit {
+    /// writes `out` to `log`, if it fits
+    fn write(&mut self, log: &mut File, mut out: &[u8], stage: &str) -> io::Result<()> {
+        if self.full {
+            self.dropped += out.len() as u64;
+            return Ok(());
+        }
+        // other stages may log to the same file, its size is what counts
+        let size = log.metadata()?.len();
+        if size + out.len() as u64 <= self.max {
+            return log.write_all(out);
+        }
+        if self.on_full == LogFull::Rotate {
+            self.rotate(log)?;
+            // more than the log can hold at once keeps its end
+            if out.len() as u64 > self.max {
+                out = &out[out.len() - self.max as usize..];
+            }
+            log::info!("{stage}: log reached --max-log-size of {} bytes, rotated it", self.max);
+            return log.write_all(out);
+        }
+        // whole lines, as far as they fit
+        let room = self.max.saturating_sub(size).min(out.len() as u64) as usize;
+        let fits = out[..room].iter().rposition(|b| *b == b'\n').map_or(0, |end| end + 1);
+        log.write_all(&out[..fits])?;
+        self.full = true;
+        self.dropped = (out.len() - fits) as u64;
+        log.write_all(format!("[plumber] log reached --max-log-size of {} bytes\n", self.max).as_bytes())?;
+        match self.on_full {
+            LogFull::Fail => {
+                log::error!("{stage}: log reached --max-log-size of {} bytes, failing the run", self.max);
+                let pid = self.pid.load(Ordering::Relaxed);
+                // the stage leads its own process group
+                if pid != 0 {
+                    unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGTERM) };
+                }
+            },
+            _ => log::warn!("{stage}: log reached --max-log-size of {} bytes, dropping the rest", self.max),
+        }
+        Ok(())
+    }
+
+    /// moves what `log` holds to `<log>.1`, and the logs rotated before it one further
+    fn rotate(&self, log: &mut File) -> io::Result<()> {
+        let gz = if self.compress { ".gz" } else { "" };
+        for n in (1..self.max_files).rev() {
+            match fs::rename(rotated(&self.path, n, gz), rotated(&self.path, n + 1, gz)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {},
+            }
+        }
+        let newest = rotated(&self.path, 1, "");
+        fs::copy(&self.path, &newest)?;
+        log.set_len(0)?;
+        log.seek(SeekFrom::Start(0))?;
+        if self.compress {
+            match Command::new("gzip").arg("-f").arg(&newest).status() {
+                Ok(status) if status.success() => {},
+                Ok(status) => log::warn!("unable to compress {}: gzip {status}", newest.display()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    if !NO_GZIP.swap(true, Ordering::Relaxed) {
+                        log::warn!("no gzip binary to compress rotated logs with, keeping them uncompressed");
+                    }
+                },
+                Err(e) => log::warn!("unable to compress {}: {e}", newest.display()),
+            }
+        }
+        Ok(())
+    }
+
+    /// the stage's output ended: notes how much was dropped
+    fn finish(&mut self, log: &mut File) -> io::Result<()> {
+        match self.dropped {
+            0 => Ok(()),
+            dropped => log.write_all(format!("[plumber] {dropped} bytes dropped\n").as_bytes()),
+        }
+    }
+}
+
+/// where the `n`th newest rotated log is kept, `gz` ".gz" if it's compressed
+fn rotated(path: &Path, n: u32, gz: &str) -> PathBuf {
+    let mut rotated = OsString::from(path);
+    rotated.push(format!(".{n}{gz}"));
+    PathBuf::from(rotated)
+}
+
+/// the last line logged and how often it came again since
+#[derive(Default)]
+struct Repeats {
+    last: Vec<u8>,
+    count: u64,
+}
+
+impl Repeats {
+    /// whether `line` is new; writes the marker for the run it ends
+    fn is_new(&mut self, line: &[u8], out: &mut Vec<u8>) -> bool {
+        if line == self.last {
+            self.count += 1;
+            return false;
+        }
+        self.flush(out);
+        self.last.clear();
+        self.last.extend_from_slice(line);
+        true
+    }
+
+    fn flush(&mut self, out: &mut Vec<u8>) {
+        if self.count > 0 {
+            out.extend_from_slice(format!("[plumber] last message repeated {} times\n", self.count).as_bytes());
+            self.count = 0;
+        }
+    }
+}
+
+/// lines let through within the current second
+struct RateLimit {
+    max: u32,
+    second: Instant,
+    passed: u32,
+    suppressed: u64,
+}
+
+impl RateLimit {
+    fn new(max: u32, now: Instant) -> Self {
+        RateLimit { max, second: now, passed: 0, suppressed: 0 }
+    }
+
+    /// whether the line may be logged; writes the marker for what the last second dropped
+    fn allow(&mut self, now: Instant, out: &mut Vec<u8>) -> bool {
+        if now.duration_since(self.second) >= Duration::from_secs(1) {
+            self.flush(out);
+            self.second = now;
+            self.passed = 0;
+        }
+        if self.passed >= self.max {
+            self.suppressed += 1;
+            return false;
+        }
+        self.passed += 1;
+        true
+    }
+
+    fn flush(&mut self, out: &mut Vec<u8>) {
+        if self.suppressed > 0 {
+            out.extend_from_slice(format!("[plumber] {} lines suppressed\n", self.suppressed).as_bytes());
+            self.suppressed = 0;
+        }
+    }
+}
+
+/// filter state for one stage
+struct Lines {
+    repeats: Option<Repeats>,
+    limit: Option<RateLimit>,
+    /// start of a line whose end hasn't been read yet
+    partial: Vec<u8>,
+}
+
+impl Lines {
+    fn new(filter: &LogFilter, now: Instant) -> Self {
+        Lines {
+            repeats: filter.collapse_repeats.then(Repeats::default),
+            limit: filter.max_lines.map(|max| RateLimit::new(max, now)),
+            partial: Vec::new(),
+        }
+    }
+
+    /// filters the complete lines in `data` into `out`
+    fn push(&mut self, mut data: &[u8], now: Instant, out: &mut Vec<u8>) {
+        while let Some(end) = data.iter().position(|b| *b == b'\n') {
+            match self.partial.is_empty() {
+                true => self.line(&data[..=end], now, out),
+                false => {
+                    let mut line = std::mem::take(&mut self.partial);
+                    line.extend_from_slice(&data[..=end]);
+                    self.line(&line, now, out);
+                },
+            }
+            data = &data[end + 1..];
+        }
+        self.partial.extend_from_slice(data);
+        if self.partial.len() >= MAX_LINE {
+            let mut line = std::mem::take(&mut self.partial);
+            line.push(b'\n');
+            self.line(&line, now, out);
+        }
+    }
+
+    fn line(&mut self, line: &[u8], now: Instant, out: &mut Vec<u8>) {
+        if self.repeats.as_mut().is_some_and(|repeats| !repeats.is_new(line, out)) {
+            return;
+        }
+        if self.limit.as_mut().is_some_and(|limit| !limit.allow(now, out)) {
+            return;
+        }
+        out.extend_from_slice(line);
+    }
+
+    /// the stage closed stderr: logs what's left
+    fn finish(&mut self, now: Instant, out: &mut Vec<u8>) {
+        if !self.partial.is_empty() {
+            let mut line = std::mem::take(&mut self.partial);
+            line.push(b'\n');
+            self.line(&line, now, out);
+        }
+        if let Some(repeats) = &mut self.repeats {
+            repeats.flush(out);
+        }
+        if let Some(limit) = &mut self.limit {
+            limit.flush(out);
+        }
+    }
+}
+
+/// `out`, complete lines, with every line prefixed by `time` and `stage`
+fn stamped(out: &[u8], time: SystemTime, stage: &str) -> Vec<u8> {
+    let prefix = format!("{} {stage}: ", humantime::format_rfc3339_millis(time));
+    let mut stamped = Vec::with_capacity(out.len() + prefix.len() * 4);
+    for line in out.split_inclusive(|b| *b == b'\n') {
+        stamped.extend_from_slice(prefix.as_bytes());
+        stamped.extend_from_slice(line);
+    }
+    stamped
+}
+
+struct Capture {
+    stderr: File,
+    log: File,
+    lines: Lines,
+    limit: Option<SizeLimit>,
+    stage: String,
+    /// the stage name lines are prefixed with, with `--timestamp-logs`
+    prefix: Option<String>,
+}
+
+impl Handler for Capture {
+    fn poll(&mut self) -> Wait {
+        let started = Instant::now();
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut out = Vec::new();
+        let mut wait = Wait::Readable(self.stderr.as_raw_fd());
+        for _ in 0..READS_PER_POLL {
+            match self.stderr.read(&mut buf) {
+                Ok(0) => {
+                    self.lines.finish(Instant::now(), &mut out);
+                    wait = Wait::Done;
+                    break;
+                },
+                Ok(n) => {
+                    CAPTURED.fetch_add(n as u64, Ordering::Relaxed);
+                    self.lines.push(&buf[..n], Instant::now(), &mut out);
+                },
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    log::error!("{}: unable to read stderr: {e}", self.stage);
+                    wait = Wait::Done;
+                    break;
+                },
+            }
+        }
+        if let Some(stage) = self.prefix.as_deref().filter(|_| !out.is_empty()) {
+            out = stamped(&out, SystemTime::now(), stage);
+        }
+        let written = match &mut self.limit {
+            Some(limit) => limit.write(&mut self.log, &out, &self.stage)
+                .and_then(|_| if matches!(wait, Wait::Done) { limit.finish(&mut self.log) } else { Ok(()) }),
+            None => self.log.write_all(&out),
+        };
+        if let Err(e) = written {
+            log::error!("{}: unable to write log: {e}", self.stage);
+        }
+        BUSY_NS.fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        wait
+    }
+}
+
+/// logs what the stage writes to the read end of its stderr pipe into `log`, or stdout for
+/// the last stage's
+pub fn capture(stderr: OwnedFd, log: File, filter: &LogFilter, limit: Option<SizeLimit>, pipeline: &str, stage: &str) -> Finished {
+    unsafe { libc::fcntl(stderr.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) };
+    reactor::register(Box::new(Capture {
+        stderr: File::from(stderr),
+        log,
+        lines: Lines::new(filter, Instant::now()),
+        limit,
+        stage: format!("{pipeline}: {stage}"),
+        prefix: filter.timestamps.then(|| stage.to_owned()),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filtered(filter: &LogFilter, chunks: &[(&str, u64)]) -> String {
+        let start = Instant::now();
+        let mut lines = Lines::new(filter, start);
+        let mut out = Vec::new();
+        for (chunk, millis) in chunks {
+            lines.push(chunk.as_bytes(), start + Duration::from_millis(*millis), &mut out);
+        }
+        lines.finish(start + Duration::from_secs(10), &mut out);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn lines_over_the_limit_are_suppressed() {
+        let filter = LogFilter { max_lines: Some(2), ..LogFilter::default() };
+        let out = filtered(&filter, &[("a\nb\nc\nd", 0), ("\ne\n", 500), ("f\n", 1200), ("g\nh\ni\n", 1300)]);
+        assert_eq!(out, "a\nb\n[plumber] 3 lines suppressed\nf\ng\n[plumber] 2 lines suppressed\n");
+    }
+
+    #[test]
+    fn repeats_are_collapsed() {
+        let filter = LogFilter { max_lines: Some(2), collapse_repeats: true, ..LogFilter::default() };
+        let out = filtered(&filter, &[("retry\nretry\nret", 0), ("ry\nfailed\nretry\nok\n", 0)]);
+        assert_eq!(out, "retry\n[plumber] last message repeated 2 times\nfailed\n[plumber] 2 lines suppressed\n");
+        assert_eq!(filtered(&filter, &[("a\na\n", 0)]), "a\n[plumber] last message repeated 1 times\n");
+    }
+
+    #[test]
+    fn partial_lines_are_joined() {
+        let filter = LogFilter::default();
+        assert_eq!(filtered(&filter, &[("par", 0), ("tial\nno newline", 0)]), "partial\nno newline\n");
+    }
+
+    #[test]
+    fn lines_are_stamped() {
+        let filter = LogFilter { collapse_repeats: true, timestamps: true, ..LogFilter::default() };
+        assert!(filter.captures("jq"));
+        let out = filtered(&filter, &[("retry\nretry\nfailed\n", 0)]);
+        let time = SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_120);
+        assert_eq!(String::from_utf8(stamped(out.as_bytes(), time, "jq")).unwrap(), "\
+2023-11-14T22:13:20.120Z jq: retry
+2023-11-14T22:13:20.120Z jq: [plumber] last message repeated 1 times
+2023-11-14T22:13:20.120Z jq: failed
+");
+    }
+
+    #[test]
+    fn logs_are_kept_to_their_size() {
+        let dir = std::env::temp_dir().join(format!("plumber-capture-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("jq.stderr.log");
+        let filter = LogFilter { max_size: vec!["16".parse().unwrap(), "wc=1K".parse().unwrap()], ..LogFilter::default() };
+        assert_eq!(filter.max_size("wc"), Some(1024));
+        assert!(filter.captures("jq") && !LogFilter::default().captures("jq"));
+        assert!("jq=lots".parse::<LogSize>().is_err());
+
+        let pid = Arc::default();
+        let mut log = File::create(&path).unwrap();
+        let mut limit = filter.size_limit("jq", &path, &pid).unwrap();
+        for out in ["one\ntwo\n", "three\nfour\n", "five\n"] {
+            limit.write(&mut log, out.as_bytes(), "jq").unwrap();
+        }
+        limit.finish(&mut log).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "one\ntwo\nthree\n[plumber] log reached --max-log-size of 16 bytes\n[plumber] 10 bytes dropped\n");
+
+        let filter = LogFilter { on_full: Some(LogFull::Rotate), ..filter };
+        let mut log = File::create(&path).unwrap();
+        let mut limit = filter.size_limit("jq", &path, &pid).unwrap();
+        for out in ["one\ntwo\n", "three\nfour\n", "five\n"] {
+            limit.write(&mut log, out.as_bytes(), "jq").unwrap();
+        }
+        assert_eq!(fs::read_to_string(rotated(&path, 1, "")).unwrap(), "one\ntwo\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "three\nfour\nfive\n");
+
+        let filter = LogFilter { max_files: Some(2), ..filter };
+        let mut log = File::create(&path).unwrap();
+        let mut limit = filter.size_limit("jq", &path, &pid).unwrap();
+        for out in ["one\ntwo\n", "three\nfour\n", "five\nsix\nseven\n", "eight\n"] {
+            limit.write(&mut log, out.as_bytes(), "jq").unwrap();
+        }
+        // the oldest is dropped
+        assert_eq!(fs::read_to_string(rotated(&path, 2, "")).unwrap(), "three\nfour\n");
+        assert_eq!(fs::read_to_string(rotated(&path, 1, "")).unwrap(), "five\nsix\nseven\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "eight\n");
+        assert!(!rotated(&path, 3, "").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
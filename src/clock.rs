@@ -0,0 +1,100 @@
+//! time as the scheduling and restart logic of a pipeline sees it
+//!
+//! a pipeline reads the time, sleeps and bounds its waits through a `Clock`, the system's
+//! unless another is swapped in. tests use a `SimulatedClock`, which only moves when slept on
+//! or advanced, so restart backoffs, crash-loop windows, respawn delays and readiness timeouts
+//! can be checked deterministically, without waiting them out.
+
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+pub trait Clock: Send + Sync {
+    /// monotonic time, for delays and deadlines
+    fn now(&self) -> Instant;
+    /// wall-clock time, for what's recorded or outlives plumber
+    fn system_now(&self) -> SystemTime;
+    fn sleep(&self, duration: Duration);
+    /// how long to really block on something that ends a wait of `duration` early, like a
+    /// stage exiting. a simulated clock skips ahead instead and doesn't block
+    fn wait_at_most(&self, duration: Duration) -> Duration;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+
+    fn wait_at_most(&self, duration: Duration) -> Duration {
+        duration
+    }
+}
+
+/// starts at `at` and moves only when slept on or advanced
+#[cfg(test)]
+pub struct SimulatedClock {
+    start: Instant,
+    start_system: SystemTime,
+    elapsed: std::sync::Mutex<Duration>,
+}
+
+#[cfg(test)]
+impl SimulatedClock {
+    pub fn new(at: SystemTime) -> Self {
+        SimulatedClock { start: Instant::now(), start_system: at, elapsed: std::sync::Mutex::new(Duration::ZERO) }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+
+    /// how far the clock moved since it was made
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Clock for SimulatedClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        self.start_system + self.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+
+    fn wait_at_most(&self, duration: Duration) -> Duration {
+        self.advance(duration);
+        Duration::ZERO
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn simulated_time_moves_when_slept_on() {
+        let at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = SimulatedClock::new(at);
+        let started = clock.now();
+        clock.sleep(Duration::from_secs(3600));
+        assert_eq!(clock.wait_at_most(Duration::from_secs(5)), Duration::ZERO);
+        assert_eq!(clock.now() - started, Duration::from_secs(3605));
+        assert_eq!(clock.system_now(), at + Duration::from_secs(3605));
+    }
+}
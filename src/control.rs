@@ -0,0 +1,340 @@
+//! cooperative stops, `--cooperative <stage>`
+//!
+//! a stage that buffers, batches or holds a transaction open loses what it holds when it's
+//! killed. plumber gives a cooperative stage a unix socket in `$PLUMBER_CONTROL` to connect to
+//! and talk a line protocol on. the stage writes:
+//! - `hello` to be asked to stop instead of being signalled
+//! - `ready` once it's warmed up
+//! - `progress <text>` whenever there's something to report
+//!
+//! the last `ready` or progress of every stage is kept in `.progress` in the metadata dir.
+//! plumber writes `stop` to every stage that said hello once the pipeline is stopped. the stage
+//! flushes what it holds and exits, the stages after it see EOF. the first stage is still
+//! sent SIGTERM unless it said hello, and a stage that hasn't exited `--stop-grace` after it
+//! was asked gets SIGTERM after all.
+//!
+//! `plumber control` is the client for scripts, see `Client` for stages in rust.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::Shutdown;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::reactor::{self, Handler, Wait};
+
+/// where a cooperative stage finds its socket
+pub const ENV: &str = "PLUMBER_CONTROL";
+/// in the metadata dir while a stage said hello, so `plumber stop` leaves the stop to its supervisor
+pub const MARKER: &str = ".cooperative";
+const PROGRESS: &str = ".progress";
+/// what `plumber stop` writes to `.stop` when it leaves the stop to the supervisor
+pub const STOP: &str = "cooperative\n";
+
+/// what every endpoint of a pipeline shares
+struct Shared {
+    pipeline: String,
+    metadata_dir: PathBuf,
+    /// last report of every stage that made one, by index
+    progress: Mutex<BTreeMap<usize, (String, String)>>,
+    endpoints: Mutex<Vec<Arc<Endpoint>>>,
+}
+
+/// the socket of one stage and the connections to it
+struct Endpoint {
+    index: usize,
+    stage: String,
+    path: PathBuf,
+    /// connections by id, and whether they said hello
+    connections: Mutex<HashMap<u64, (UnixStream, bool)>>,
+    next_id: AtomicU64,
+    closing: AtomicBool,
+}
+
+impl Endpoint {
+    fn cooperating(&self) -> bool {
+        self.connections.lock().unwrap().values().any(|(_, hello)| *hello)
+    }
+}
+
+/// the sockets of a pipeline's cooperative stages, open for as long as it's supervised
+pub struct Control {
+    shared: Arc<Shared>,
+}
+
+impl Control {
+    /// opens a socket for each of `stages`, given by index and name
+    pub fn open(pipeline: &str, metadata_dir: &Path, stages: &[(usize, &str)]) -> Control {
+        let shared = Arc::new(Shared {
+            pipeline: pipeline.to_owned(),
+            metadata_dir: metadata_dir.to_owned(),
+            progress: Mutex::new(BTreeMap::new()),
+            endpoints: Mutex::new(Vec::new()),
+        });
+        // what an earlier run reported no longer applies
+        let _ = fs::remove_file(metadata_dir.join(PROGRESS));
+        let _ = fs::remove_file(metadata_dir.join(MARKER));
+        for &(index, stage) in stages {
+            // stages may share a name
+            let path = metadata_dir.join(format!("{stage}.{index}.control"));
+            let _ = fs::remove_file(&path);
+            let listener = match UnixListener::bind(&path) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    log::warn!("{pipeline}: unable to open control socket of {stage}, it will be signalled: {e}");
+                    continue;
+                },
+            };
+            let endpoint = Arc::new(Endpoint {
+                index,
+                stage: stage.to_owned(),
+                path,
+                connections: Mutex::new(HashMap::new()),
+                next_id: AtomicU64::new(0),
+                closing: AtomicBool::new(false),
+            });
+            shared.endpoints.lock().unwrap().push(endpoint.clone());
+            let (listening, shared) = (endpoint.clone(), shared.clone());
+            if let Err(e) = reactor::listen(listener, move |stream| accept(stream, &listening, &shared)) {
+                log::warn!("{pipeline}: unable to listen on control socket of {stage}, it will be signalled: {e}");
+            }
+        }
+        Control { shared }
+    }
+
+    fn endpoints(&self) -> Vec<Arc<Endpoint>> {
+        self.shared.endpoints.lock().unwrap().clone()
+    }
+
+    /// socket of stage `index`, if it's cooperative
+    pub fn path(&self, index: usize) -> Option<PathBuf> {
+        self.endpoints().into_iter().find(|e| e.index == index).map(|e| e.path.clone())
+    }
+
+    /// writes `stop` to stage `index`, false if it didn't say hello
+    pub fn ask_to_stop(&self, index: usize) -> bool {
+        let mut asked = false;
+        for endpoint in self.endpoints().iter().filter(|e| e.index == index) {
+            for (stream, hello) in endpoint.connections.lock().unwrap().values_mut() {
+                if *hello && stream.write_all(b"stop\n").is_ok() {
+                    asked = true;
+                }
+            }
+        }
+        asked
+    }
+}
+
+impl Drop for Control {
+    fn drop(&mut self) {
+        for endpoint in self.endpoints() {
+            endpoint.closing.store(true, Ordering::SeqCst);
+            for (stream, _) in endpoint.connections.lock().unwrap().values() {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            // wakes the listener
+            let _ = UnixStream::connect(&endpoint.path);
+            let _ = fs::remove_file(&endpoint.path);
+        }
+        let _ = fs::remove_file(self.shared.metadata_dir.join(MARKER));
+    }
+}
+
+/// takes a stage's connection, false once the endpoint is closing or failed
+fn accept(stream: io::Result<UnixStream>, endpoint: &Arc<Endpoint>, shared: &Arc<Shared>) -> bool {
+    if endpoint.closing.load(Ordering::SeqCst) {
+        return false;
+    }
+    let stream = match stream {
+        Ok(stream) => stream,
+        Err(e) => {
+            log::warn!("{}: control socket of {} failed: {e}", shared.pipeline, endpoint.stage);
+            return false;
+        },
+    };
+    let Ok(writer) = stream.try_clone() else { return true };
+    let id = endpoint.next_id.fetch_add(1, Ordering::SeqCst);
+    endpoint.connections.lock().unwrap().insert(id, (writer, false));
+    reactor::register(Box::new(Connection {
+        stream,
+        id,
+        endpoint: endpoint.clone(),
+        shared: shared.clone(),
+        partial: Vec::new(),
+    }));
+    true
+}
+
+/// what a stage sends on its connection, read on the reactor
+struct Connection {
+    stream: UnixStream,
+    id: u64,
+    endpoint: Arc<Endpoint>,
+    shared: Arc<Shared>,
+    /// the start of a line not yet read in full
+    partial: Vec<u8>,
+}
+
+impl Handler for Connection {
+    fn poll(&mut self) -> Wait {
+        let (lines, open) = reactor::read_lines(&mut self.stream, &mut self.partial);
+        for line in lines {
+            received(line.trim(), self.id, &self.endpoint, &self.shared);
+        }
+        if open {
+            return Wait::Readable(self.stream.as_raw_fd());
+        }
+        let hello = self.endpoint.connections.lock().unwrap().remove(&self.id).is_some_and(|(_, hello)| hello);
+        if hello {
+            mark(&self.shared);
+        }
+        Wait::Done
+    }
+}
+
+fn received(line: &str, id: u64, endpoint: &Endpoint, shared: &Shared) {
+    let (pipeline, stage) = (&shared.pipeline, &endpoint.stage);
+    let (word, rest) = line.split_once(' ').unwrap_or((line, ""));
+    match word {
+        "hello" => {
+            if let Some((_, hello)) = endpoint.connections.lock().unwrap().get_mut(&id) {
+                *hello = true;
+            }
+            log::debug!("{pipeline}: {stage} will be asked to stop");
+            mark(shared);
+        },
+        "ready" => {
+            log::info!("{pipeline}: {stage} reports it's ready");
+            report(shared, endpoint, "ready");
+        },
+        "progress" => report(shared, endpoint, rest.trim()),
+        "" => {},
+        _ => log::warn!("{pipeline}: {stage} sent '{line}' on its control socket, expected hello, ready or progress"),
+    }
+}
+
+/// leaves `MARKER` while a stage said hello
+fn mark(shared: &Shared) {
+    let marker = shared.metadata_dir.join(MARKER);
+    let cooperating = shared.endpoints.lock().unwrap().iter().any(|e| e.cooperating());
+    let _ = match cooperating {
+        true => fs::write(marker, ""),
+        false => fs::remove_file(marker),
+    };
+}
+
+fn report(shared: &Shared, endpoint: &Endpoint, text: &str) {
+    let mut progress = shared.progress.lock().unwrap();
+    progress.insert(endpoint.index, (endpoint.stage.clone(), text.to_owned()));
+    let lines: String = progress.values().map(|(stage, text)| format!("{stage} {text}\n")).collect();
+    if let Err(e) = fs::write(shared.metadata_dir.join(PROGRESS), lines) {
+        log::warn!("{}: unable to record progress: {e}", shared.pipeline);
+    }
+}
+
+/// whether a stage said hello to the supervisor of pipeline `metadata_dir`
+pub fn cooperating(metadata_dir: &Path) -> bool {
+    metadata_dir.join(MARKER).exists()
+}
+
+/// the last report of every stage of a pipeline, in pipeline order
+pub fn progress(metadata_dir: &Path) -> Vec<(String, String)> {
+    fs::read_to_string(metadata_dir.join(PROGRESS))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .map(|(stage, text)| (stage.to_owned(), text.to_owned()))
+        .collect()
+}
+
+/// what `plumber control` does for a script
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Action {
+    /// report the stage ready
+    Ready,
+    /// report the words after it as the stage's progress
+    Progress,
+    /// say hello and wait to be asked to stop, exiting 0 when asked and 1 if plumber went away
+    WaitStop,
+}
+
+/// a cooperative stage's end of the protocol
+pub struct Client {
+    stream: UnixStream,
+}
+
+impl Client {
+    /// connects to `$PLUMBER_CONTROL`, `None` outside of a cooperative stage
+    pub fn connect() -> Option<io::Result<Client>> {
+        let path = std::env::var_os(ENV)?;
+        Some(UnixStream::connect(path).map(|stream| Client { stream }))
+    }
+
+    fn send(&mut self, line: &str) -> io::Result<()> {
+        self.stream.write_all(format!("{line}\n").as_bytes())
+    }
+
+    pub fn ready(&mut self) -> io::Result<()> {
+        self.send("ready")
+    }
+
+    pub fn progress(&mut self, text: &str) -> io::Result<()> {
+        // one line per report
+        self.send(&format!("progress {}", text.replace('\n', " ")))
+    }
+
+    /// says hello and waits to be asked to stop, false if plumber went away first
+    pub fn wait_for_stop(&mut self) -> io::Result<bool> {
+        self.send("hello")?;
+        let reader = BufReader::new(self.stream.try_clone()?);
+        for line in reader.lines() {
+            if line?.trim() == "stop" {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn until(condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn asks_stages_that_said_hello_to_stop() {
+        let dir = std::env::temp_dir().join(format!("plumber-control-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let control = Control::open("etl", &dir, &[(0, "tail"), (2, "loader")]);
+        assert_eq!(control.path(1), None);
+
+        let mut client = Client { stream: UnixStream::connect(control.path(2).unwrap()).unwrap() };
+        client.progress("42 rows\nloaded").unwrap();
+        until(|| progress(&dir) == [("loader".to_string(), "42 rows loaded".to_string())]);
+        assert!(!control.ask_to_stop(2));
+
+        let waiting = thread::spawn(move || client.wait_for_stop().unwrap());
+        until(|| cooperating(&dir));
+        assert!(!control.ask_to_stop(0));
+        assert!(control.ask_to_stop(2));
+        assert!(waiting.join().unwrap());
+        until(|| !cooperating(&dir));
+
+        drop(control);
+        assert!(!dir.join("loader.2.control").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
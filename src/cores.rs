@@ -0,0 +1,218 @@
+//! collection of stage core dumps, `--core-dumps`
+//!
+//! stages are spawned with an unlimited core size limit, or `--max-core-space` when given.
+//! `kernel.core_pattern` is system-wide, so plumber leaves it alone and instead picks up the
+//! dump of a stage killed with a core wherever the pattern put it, moving it to
+//! `cores/<stage>.<pid>.core` in the pipeline's metadata dir, as the run summary records. with
+//! `--max-core-space` the oldest dumps there are removed once together they take up more
+//! than that.
+//!
+//! a pattern piping dumps to a handler, like systemd-coredump or apport, leaves nothing to
+//! collect; `coredumpctl` has them instead.
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use regex::Regex;
+
+const CORE_PATTERN: &str = "/proc/sys/kernel/core_pattern";
+const CORE_USES_PID: &str = "/proc/sys/kernel/core_uses_pid";
+
+#[derive(Debug, Clone, Default)]
+pub struct CoreDumps {
+    /// bytes all collected dumps of a pipeline may take up
+    pub max_space: Option<u64>,
+}
+
+impl CoreDumps {
+    /// lets the stage dump core, at most as big as all dumps may be
+    pub fn allow(&self, command: &mut Command) {
+        let limit = libc::rlimit {
+            rlim_cur: self.max_space.unwrap_or(libc::RLIM_INFINITY),
+            rlim_max: self.max_space.unwrap_or(libc::RLIM_INFINITY),
+        };
+        unsafe {
+            command.pre_exec(move || {
+                // a hard limit below what's asked for can't be raised, dump what it allows
+                if libc::setrlimit(libc::RLIMIT_CORE, &limit) < 0 {
+                    let mut current = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+                    libc::getrlimit(libc::RLIMIT_CORE, &mut current);
+                    current.rlim_cur = current.rlim_max;
+                    libc::setrlimit(libc::RLIMIT_CORE, &current);
+                }
+                Ok(())
+            });
+        }
+    }
+
+    /// moves the core dumped by a stage into `dir`, then makes room. `None` if it can't be found
+    pub fn collect(&self, dumped: &Dumped, dir: &Path) -> io::Result<Option<PathBuf>> {
+        let pattern = fs::read_to_string(CORE_PATTERN)?;
+        let uses_pid = fs::read_to_string(CORE_USES_PID).is_ok_and(|u| u.trim() != "0");
+        let Some((from_dir, name)) = locate(pattern.trim_end_matches('\n'), uses_pid, dumped) else {
+            return Ok(None);
+        };
+        let from_dir = std::env::current_dir()?.join(from_dir);
+        let Some(core) = newest_match(&from_dir, &name)? else { return Ok(None) };
+
+        fs::create_dir_all(dir)?;
+        let to = dir.join(format!("{}.{}.core", dumped.stage, dumped.pid));
+        if let Err(e) = fs::rename(&core, &to) {
+            if e.raw_os_error() != Some(libc::EXDEV) {
+                return Err(e);
+            }
+            fs::copy(&core, &to)?;
+            fs::remove_file(&core)?;
+        }
+        if let Some(max_space) = self.max_space {
+            make_room(dir, max_space)?;
+        }
+        Ok(Some(to))
+    }
+}
+
+/// handler dumps are piped to, if `kernel.core_pattern` pipes them
+pub fn handler() -> Option<String> {
+    let pattern = fs::read_to_string(CORE_PATTERN).ok()?;
+    pattern.strip_prefix('|').map(|handler| handler.trim().to_owned())
+}
+
+/// a stage killed with a core
+pub struct Dumped<'a> {
+    pub stage: &'a str,
+    pub pid: u32,
+    pub program: &'a Path,
+    pub signal: i32,
+}
+
+/// dir the kernel wrote the dump to and a regex for its file name. specifiers that can't be
+/// known afterwards, like the time of the dump, match anything
+fn locate(pattern: &str, uses_pid: bool, dumped: &Dumped) -> Option<(PathBuf, Regex)> {
+    if pattern.starts_with('|') {
+        return None;
+    }
+    // the kernel names dumps after the executable, at most 15 bytes of it
+    let comm: String = dumped.program.file_name()?.to_string_lossy().chars().take(15).collect();
+    // with `regex` literals are escaped and unknown specifiers match anything, without it
+    // there must be none
+    let expand = |part: &str, regex: bool| {
+        let literal = |s: &str| match regex {
+            true => regex::escape(s),
+            false => s.to_owned(),
+        };
+        let mut expanded = String::new();
+        let mut chars = part.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                expanded.push_str(&literal(&c.to_string()));
+                continue;
+            }
+            match chars.next() {
+                Some('%') => expanded.push_str(&literal("%")),
+                Some('p') => expanded.push_str(&dumped.pid.to_string()),
+                Some('s') => expanded.push_str(&dumped.signal.to_string()),
+                Some('e') => expanded.push_str(&literal(&comm)),
+                Some('E') => expanded.push_str(&literal(&dumped.program.to_string_lossy().replace('/', "!"))),
+                Some(_) if regex => expanded.push_str("[^/]*"),
+                Some(_) => return None,
+                None => {},
+            }
+        }
+        Some(expanded)
+    };
+
+    let (dir, name) = match pattern.rsplit_once('/') {
+        Some(("", name)) => ("/", name),
+        Some((dir, name)) => (dir, name),
+        None => (".", pattern),
+    };
+    // a dir that depends on what can't be known isn't worth searching for
+    let dir = expand(dir, false)?;
+    let mut name = expand(name, true)?;
+    if uses_pid && !pattern.contains("%p") {
+        name.push_str(&format!("\\.{}", dumped.pid));
+    }
+    Some((PathBuf::from(dir), Regex::new(&format!("^{name}$")).ok()?))
+}
+
+fn newest_match(dir: &Path, name: &Regex) -> io::Result<Option<PathBuf>> {
+    let mut newest: Option<(i64, PathBuf)> = None;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_name().to_str().is_some_and(|n| name.is_match(n)) {
+            continue;
+        }
+        let mtime = entry.metadata()?.mtime();
+        if newest.as_ref().is_none_or(|(newest, _)| mtime >= *newest) {
+            newest = Some((mtime, entry.path()));
+        }
+    }
+    Ok(newest.map(|(_, path)| path))
+}
+
+/// removes the oldest dumps in `dir` until they fit in `max_space`
+fn make_room(dir: &Path, max_space: u64) -> io::Result<()> {
+    let mut cores = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        cores.push((metadata.mtime(), metadata.len(), entry.path()));
+    }
+    cores.sort();
+    let mut used: u64 = cores.iter().map(|(_, size, _)| size).sum();
+    for (_, size, core) in cores {
+        if used <= max_space {
+            break;
+        }
+        log::info!("removing core dump {} to stay within {max_space} bytes", core.display());
+        fs::remove_file(&core)?;
+        used -= size;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dumped() -> Dumped<'static> {
+        Dumped { stage: "jq", pid: 4242, program: Path::new("/usr/local/bin/a-rather-long-name"), signal: 11 }
+    }
+
+    #[test]
+    fn locates_dumps() {
+        let (dir, name) = locate("core", true, &dumped()).unwrap();
+        assert_eq!(dir, Path::new("."));
+        assert!(name.is_match("core.4242"));
+        assert!(!name.is_match("core.4243"));
+
+        let (dir, name) = locate("/var/crash/%e.%p.%t.core", true, &dumped()).unwrap();
+        assert_eq!(dir, Path::new("/var/crash"));
+        assert!(name.is_match("a-rather-long-n.4242.1700000000.core"));
+
+        let (_, name) = locate("/%E-%s", false, &dumped()).unwrap();
+        assert!(name.is_match("!usr!local!bin!a-rather-long-name-11"));
+
+        assert!(locate("/var/crash/%u/core", false, &dumped()).is_none());
+        assert!(locate("|/usr/lib/systemd/systemd-coredump %P", false, &dumped()).is_none());
+    }
+
+    #[test]
+    fn oldest_dumps_make_room() {
+        let dir = std::env::temp_dir().join(format!("plumber-cores-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (core, mtime) in [("a.core", 1), ("b.core", 2), ("c.core", 3)] {
+            fs::write(dir.join(core), [0u8; 100]).unwrap();
+            let times = fs::FileTimes::new().set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(mtime));
+            fs::File::options().write(true).open(dir.join(core)).unwrap().set_times(times).unwrap();
+        }
+        make_room(&dir, 250).unwrap();
+        assert!(!dir.join("a.core").exists());
+        assert!(dir.join("b.core").exists() && dir.join("c.core").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,368 @@
+//! plumber files in toml or yaml, for stages with options of their own
+//!
+//! a file that parses as toml or yaml with at least one `stage` is a structured definition,
+//! anything else is a pipeline as a shell would write it:
+//! ```toml
+//! name = "etl"          # instead of the file's name
+//! restart = "on-failure"
+//! timeout = "1h"        # a run still going after this long is drained
+//! max_log_size = "100M" # and `on_log_full`, `max_log_files` and `compress_logs`, see `capture`
+//! windows = ["22:00-06:00"] # and `blackouts` and `outside_window`, see `window`
+//!
+//! [[stage]]
+//! cmd = "tail"
+//! args = ["-F", "/var/log/app.log"]
+//!
+//! [[stage]]
+//! cmd = "jq"
+//! args = ["-c", "select(.level == \"error\")"]
+//! env = { TZ = "UTC" }
+//! cwd = "/srv/app"
+//! stderr_log = "/var/log/plumber/jq.log"
+//! ```
+//! or, in yaml:
+//! ```yaml
+//! name: etl
+//! restart: on-failure
+//! stage:
+//!   - cmd: tail
+//!     args: [-F, /var/log/app.log]
+//!   - cmd: jq
+//!     args: [-c, 'select(.level == "error")']
+//!     env: { TZ: UTC }
+//! ```
+//! numbers and booleans in `args` and `env` are taken as the strings they're written as.
+
+use std::ffi::OsStr;
+use std::fmt::Display;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use indexmap::IndexMap;
+use serde::de::{DeserializeOwned, Deserializer, Error as _, IgnoredAny};
+use serde::Deserialize;
+
+use crate::capture::{LogFilter, LogFull, LogSize};
+use crate::parser::{Chain, ParseError, Problem};
+use crate::pipeline::{PipelineCommand, Restart};
+use crate::window::{Outside, Schedule, Window};
+
+/// whether a file of extension `ext` is a plumber file, a pipeline or a structured definition
+pub fn is_plumber_extension(ext: &OsStr) -> bool {
+    ["plumb", "toml", "yaml", "yml"].iter().any(|plumber| ext.eq_ignore_ascii_case(plumber))
+}
+
+/// a string, or a number or boolean taken as one
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged, expecting = "a string")]
+pub enum Scalar {
+    String(String),
+    Integer(i64),
+    Bool(bool),
+}
+
+impl From<Scalar> for String {
+    fn from(scalar: Scalar) -> String {
+        match scalar {
+            Scalar::String(s) => s,
+            Scalar::Integer(n) => n.to_string(),
+            Scalar::Bool(b) => b.to_string(),
+        }
+    }
+}
+
+/// pipeline-level options of a structured definition
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Options {
+    pub name: Option<String>,
+    pub restart: Option<Restart>,
+    pub timeout: Option<Duration>,
+    /// `max_log_size` and the rest, the line filters left to the flags
+    pub log_filter: LogFilter,
+    /// `windows`, `blackouts` and `outside_window`
+    pub schedule: Schedule,
+}
+
+/// just enough of a file to tell whether it's a structured definition
+#[derive(Deserialize)]
+struct Probe {
+    #[serde(default)]
+    stage: Vec<IgnoredAny>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    name: Option<String>,
+    #[serde(default, deserialize_with = "choice")]
+    restart: Option<Restart>,
+    #[serde(default, deserialize_with = "timeout")]
+    timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "parsed")]
+    max_log_size: Option<LogSize>,
+    #[serde(default, deserialize_with = "choice")]
+    on_log_full: Option<LogFull>,
+    max_log_files: Option<NonZeroU32>,
+    #[serde(default)]
+    compress_logs: bool,
+    #[serde(default, deserialize_with = "all_parsed")]
+    windows: Vec<Window>,
+    #[serde(default, deserialize_with = "all_parsed")]
+    blackouts: Vec<Window>,
+    #[serde(default, deserialize_with = "choice")]
+    outside_window: Option<Outside>,
+    stage: Vec<Stage>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Stage {
+    cmd: String,
+    #[serde(default)]
+    args: Vec<Scalar>,
+    #[serde(default)]
+    env: IndexMap<String, Scalar>,
+    cwd: Option<PathBuf>,
+    stderr_log: Option<PathBuf>,
+    #[serde(default, deserialize_with = "commands")]
+    fallbacks: Vec<PipelineCommand>,
+    #[serde(default)]
+    merge_stderr: bool,
+    #[serde(default)]
+    barrier: bool,
+    then: Option<Then>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Then {
+    OnSuccess,
+    Always,
+}
+
+/// one of the values of the flag of the same name, such as `restart = "on-failure"`
+fn choice<'de, D: Deserializer<'de>, T: ValueEnum>(deserializer: D) -> Result<Option<T>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    T::from_str(&value, false).map(Some).map_err(|_| {
+        let expected: Vec<String> = T::value_variants().iter()
+            .filter_map(|variant| Some(format!("`{}`", variant.to_possible_value()?.get_name())))
+            .collect();
+        D::Error::custom(format!("unknown value `{value}`, expected one of {}", expected.join(", ")))
+    })
+}
+
+fn parsed<'de, D: Deserializer<'de>, T: FromStr<Err: Display>>(deserializer: D) -> Result<Option<T>, D::Error> {
+    String::deserialize(deserializer)?.parse().map(Some).map_err(D::Error::custom)
+}
+
+fn all_parsed<'de, D: Deserializer<'de>, T: FromStr<Err: Display>>(deserializer: D) -> Result<Vec<T>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?.iter().map(|s| s.parse().map_err(D::Error::custom)).collect()
+}
+
+/// seconds, or a duration such as `"30m"`
+fn timeout<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged, expecting = "a duration such as \"30m\"")]
+    enum Timeout {
+        Seconds(NonZeroU32),
+        Text(String),
+    }
+    match Timeout::deserialize(deserializer)? {
+        Timeout::Seconds(seconds) => Ok(Some(Duration::from_secs(seconds.get().into()))),
+        Timeout::Text(text) => humantime::parse_duration(&text).map(Some).map_err(D::Error::custom),
+    }
+}
+
+/// arrays of a command and its arguments
+fn commands<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<PipelineCommand>, D::Error> {
+    Vec::<Vec<Scalar>>::deserialize(deserializer)?.into_iter()
+        .map(|words| {
+            let mut words = words.into_iter().map(String::from);
+            let name = words.next().ok_or_else(|| D::Error::custom("a fallback with no command"))?;
+            Ok(PipelineCommand::new(name, words.collect()))
+        })
+        .collect()
+}
+
+/// `input` as toml, with where it went wrong if it isn't `T`
+pub fn from_toml<T: DeserializeOwned>(input: &str) -> Result<T, ParseError> {
+    toml::from_str(input).map_err(|e| {
+        let at = e.span().map_or(0, |span| span.start);
+        ParseError::new(input, Problem::Definition(e.message().to_owned()), at)
+    })
+}
+
+fn from_yaml<T: DeserializeOwned>(input: &str) -> Result<T, ParseError> {
+    serde_yaml::from_str(input).map_err(|e| {
+        let reason = e.to_string();
+        // the position goes in front, as with every other error of a plumber file
+        let reason = match e.location() {
+            Some(_) => reason.rsplit_once(" at line ").map_or(reason.as_str(), |(reason, _)| reason),
+            None => &reason,
+        };
+        ParseError::new(input, Problem::Definition(reason.to_owned()), e.location().map_or(0, |at| at.index()))
+    })
+}
+
+/// `input` as a structured definition, `None` if it isn't one
+fn file(input: &str) -> Option<Result<File, ParseError>> {
+    let has_stages = |probe: Probe| !probe.stage.is_empty();
+    if let Ok(probe) = toml::from_str(input) {
+        return has_stages(probe).then(|| from_toml(input));
+    }
+    serde_yaml::from_str(input).is_ok_and(has_stages).then(|| from_yaml(input))
+}
+
+/// the stages of `input`, `None` if it isn't a structured definition
+pub fn stages(input: &str) -> Option<Result<Vec<PipelineCommand>, ParseError>> {
+    let file = match file(input)? {
+        Ok(file) => file,
+        Err(e) => return Some(Err(e)),
+    };
+    let stages = file.stage.into_iter().map(|stage| PipelineCommand {
+        name: stage.cmd,
+        args: stage.args.into_iter().map(String::from).collect(),
+        env: stage.env.into_iter().map(|(name, value)| (name, value.into())).collect(),
+        cwd: stage.cwd,
+        stderr_log: stage.stderr_log,
+        merge_stderr: stage.merge_stderr,
+        barrier: stage.barrier,
+        chain: stage.then.map(|then| match then {
+            Then::OnSuccess => Chain::OnSuccess,
+            Then::Always => Chain::Always,
+        }),
+        fallbacks: stage.fallbacks,
+        ..PipelineCommand::default()
+    });
+    Some(Ok(stages.collect()))
+}
+
+/// the pipeline-level options of `input`, `None` if it isn't a structured definition
+pub fn options(input: &str) -> Option<Result<Options, ParseError>> {
+    Some(file(input)?.map(|file| Options {
+        name: file.name,
+        restart: file.restart,
+        timeout: file.timeout,
+        log_filter: LogFilter {
+            max_size: file.max_log_size.into_iter().collect(),
+            on_full: file.on_log_full,
+            max_files: file.max_log_files.map(NonZeroU32::get),
+            compress: file.compress_logs,
+            ..LogFilter::default()
+        },
+        schedule: Schedule {
+            windows: file.windows,
+            blackouts: file.blackouts,
+            outside: file.outside_window,
+        },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ETL: &str = r#"
+# tails the app's log
+name = "etl"
+restart = "on-failure"
+timeout = "1h 30m"
+max_log_size = "100M"
+on_log_full = "rotate"
+max_log_files = 5
+windows = ["22:00-06:00"]
+outside_window = "stop"
+
+[[stage]]
+cmd = "tail"
+fallbacks = [["tail", "-f", "/var/log/app.log"], ["cat", "/var/log/app.log"]]
+args = [
+    "-F",  # follow rotations
+    '/var/log/app.log',
+]
+
+[[stage]]
+cmd = "jq"
+args = ["-c", "select(.level == \"error\")"]
+cwd = "/srv/app"
+merge_stderr = true
+barrier = true
+
+[stage.env]
+TZ = "UTC"
+DEPTH = 3
+LANG = "C"
+
+[[stage]]
+cmd = "sort"
+then = "on-success"
+
+[[stage]]
+cmd = "notify"
+"#;
+
+    #[test]
+    fn structured_definitions() {
+        let stages = stages(ETL).unwrap().unwrap();
+        assert_eq!(stages[0].name, "tail");
+        assert_eq!(stages[0].args, ["-F", "/var/log/app.log"]);
+        assert_eq!(stages[0].fallbacks[1], PipelineCommand::new("cat".to_string(), vec!["/var/log/app.log".to_string()]));
+        assert_eq!(stages[1].args, ["-c", "select(.level == \"error\")"]);
+        assert_eq!(stages[1].env, [("TZ".to_string(), "UTC".to_string()), ("DEPTH".to_string(), "3".to_string()), ("LANG".to_string(), "C".to_string())]);
+        assert_eq!(stages[1].cwd, Some(PathBuf::from("/srv/app")));
+        assert!(!stages[0].merge_stderr && stages[1].merge_stderr && stages[1].barrier);
+        assert_eq!(stages.iter().map(|stage| stage.chain).collect::<Vec<_>>(), [None, None, Some(Chain::OnSuccess), None]);
+        assert_eq!(options(ETL).unwrap().unwrap(), Options {
+            name: Some("etl".to_string()),
+            restart: Some(Restart::OnFailure),
+            timeout: Some(Duration::from_secs(5400)),
+            log_filter: LogFilter {
+                max_size: vec!["100M".parse().unwrap()],
+                on_full: Some(LogFull::Rotate),
+                max_files: Some(5),
+                ..LogFilter::default()
+            },
+            schedule: Schedule {
+                windows: vec!["22:00-06:00".parse().unwrap()],
+                outside: Some(Outside::Stop),
+                ..Schedule::default()
+            },
+        });
+    }
+
+    #[test]
+    fn falls_back_to_pipelines() {
+        for pipeline in ["cat | jq .", "", "# nothing", "x = 1", "tail -F 'a = b' | cat"] {
+            assert!(stages(pipeline).is_none(), "{pipeline}");
+        }
+        let e = stages("[[stage]]\nargs = [\"-c\"]\n").unwrap().unwrap_err();
+        assert_eq!(e.to_string(), "1:1: missing field `cmd`");
+        let e = stages("[[stage]]\ncmd = \"cat\"\nuser = \"root\"\n").unwrap().unwrap_err();
+        assert!(e.to_string().starts_with("3:1: unknown field `user`, expected one of `cmd`, `args`"), "{e}");
+        let e = stages("[[stage]]\ncmd = \"cat\"\nthen = \"later\"\n").unwrap().unwrap_err();
+        assert_eq!(e.to_string(), "3:8: unknown variant `later`, expected `on-success` or `always`");
+        let e = options("restart = \"sometimes\"\n[[stage]]\ncmd = \"cat\"\n").unwrap().unwrap_err();
+        assert_eq!(e.to_string(), "1:11: unknown value `sometimes`, expected one of `never`, `on-failure`, `always`");
+        let e = options("max_log_files = 0\n[[stage]]\ncmd = \"cat\"\n").unwrap().unwrap_err();
+        assert_eq!(e.to_string(), "1:17: invalid value: integer `0`, expected a nonzero u32");
+        let e = options("blackouts = [\"02:00\"]\n[[stage]]\ncmd = \"cat\"\n").unwrap().unwrap_err();
+        assert_eq!(e.to_string(), "1:13: invalid window '02:00', expected HH:MM-HH:MM such as 22:00-06:00");
+    }
+
+    #[test]
+    fn yaml_definitions() {
+        let etl = "name: etl\nrestart: always\nstage:\n  - cmd: tail\n    args: [-F, /var/log/app.log]\n  - cmd: jq\n    env: { TZ: UTC, DEPTH: 3 }\n";
+        let commands = stages(etl).unwrap().unwrap();
+        assert_eq!(commands[0].args, ["-F", "/var/log/app.log"]);
+        assert_eq!(commands[1].env, [("TZ".to_string(), "UTC".to_string()), ("DEPTH".to_string(), "3".to_string())]);
+        assert_eq!(options(etl).unwrap().unwrap().restart, Some(Restart::Always));
+        for pipeline in ["a: b | cat", "- cat\n- jq"] {
+            assert!(stages(pipeline).is_none(), "{pipeline}");
+        }
+        let e = stages("stage:\n  - cmd: cat\n    user: root\n").unwrap().unwrap_err();
+        assert!(e.to_string().starts_with("3:5: stage[0]: unknown field `user`"), "{e}");
+    }
+}
@@ -0,0 +1,163 @@
+//! semantic diff of two pipeline definitions
+//!
+//! stages are lined up by program (the built-in stage kind or the command name), so
+//! inserting a stage doesn't show every following stage as changed. for stages present
+//! in both, `key=value` options are compared by key and the remaining arguments in order.
+
+use crate::pipeline::PipelineCommand;
+use crate::stages;
+
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    Same(String),
+    Added(String),
+    Removed(String),
+    Modified { old: String, new: String, details: Vec<String> },
+}
+
+fn program(cmd: &PipelineCommand) -> &str {
+    stages::builtin_kind(&cmd.name).unwrap_or(&cmd.name)
+}
+
+/// stage changes from `old` to `new`, in pipeline order
+pub fn diff(old: &[PipelineCommand], new: &[PipelineCommand]) -> Vec<Change> {
+    // longest common subsequence of programs
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = match program(&old[i]) == program(&new[j]) {
+                true => lcs[i + 1][j + 1] + 1,
+                false => lcs[i + 1][j].max(lcs[i][j + 1]),
+            };
+        }
+    }
+
+    let mut changes = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && program(&old[i]) == program(&new[j]) {
+            changes.push(compare(&old[i], &new[j]));
+            i += 1;
+            j += 1;
+        } else if j < new.len() && (i == old.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            changes.push(Change::Added(new[j].command_line()));
+            j += 1;
+        } else {
+            changes.push(Change::Removed(old[i].command_line()));
+            i += 1;
+        }
+    }
+    changes
+}
+
+/// `key=value` words of a stage; for built-ins this includes options in the target
+fn split_args(cmd: &PipelineCommand) -> (Vec<(String, String)>, Vec<String>) {
+    let mut words: Vec<&str> = Vec::new();
+    match cmd.name.split_once(':') {
+        Some((_, target)) if stages::builtin_kind(&cmd.name).is_some() => {
+            words.extend(target.split(',').filter(|o| !o.is_empty()));
+        },
+        _ => {},
+    }
+    words.extend(cmd.args.iter().map(String::as_str));
+
+    let mut options = Vec::new();
+    let mut positional = Vec::new();
+    for word in words {
+        match word.trim_start_matches('-').split_once('=') {
+            Some((key, value)) if !key.is_empty() => options.push((key.to_owned(), value.to_owned())),
+            _ => positional.push(word.to_owned()),
+        }
+    }
+    (options, positional)
+}
+
+fn compare(old: &PipelineCommand, new: &PipelineCommand) -> Change {
+    if old == new {
+        return Change::Same(old.command_line());
+    }
+
+    let (old_options, old_positional) = split_args(old);
+    let (new_options, new_positional) = split_args(new);
+    let lookup = |options: &[(String, String)], key: &str| {
+        options.iter().rev().find(|(k, _)| k == key).map(|(_, v)| v.clone())
+    };
+
+    let mut details = Vec::new();
+    if old_positional != new_positional {
+        details.push(format!("arguments: {} -> {}", old_positional.join(" "), new_positional.join(" ")));
+    }
+    let mut keys: Vec<&str> = Vec::new();
+    for (key, _) in old_options.iter().chain(&new_options) {
+        if !keys.contains(&key.as_str()) {
+            keys.push(key);
+        }
+    }
+    for key in keys {
+        match (lookup(&old_options, key), lookup(&new_options, key)) {
+            (Some(a), Some(b)) if a != b => details.push(format!("{key}: {a} -> {b}")),
+            (Some(a), None) => details.push(format!("{key}: {a} removed")),
+            (None, Some(b)) => details.push(format!("{key}: {b} added")),
+            _ => {},
+        }
+    }
+    if details.is_empty() {
+        // same options written in a different order or place
+        details.push("options reordered".to_string());
+    }
+
+    Change::Modified {
+        old: old.command_line(),
+        new: new.command_line(),
+        details,
+    }
+}
+
+/// diff style text, one stage per line with its options indented below it
+pub fn format(changes: &[Change]) -> String {
+    let mut out = String::new();
+    for change in changes {
+        match change {
+            Change::Same(line) => out.push_str(&format!("  {line}\n")),
+            Change::Added(line) => out.push_str(&format!("+ {line}\n")),
+            Change::Removed(line) => out.push_str(&format!("- {line}\n")),
+            Change::Modified { old, new, details } => {
+                out.push_str(&format!("~ {old}\n"));
+                out.push_str(&format!("  => {new}\n"));
+                for detail in details {
+                    out.push_str(&format!("       {detail}\n"));
+                }
+            },
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::Pipeline;
+
+    fn changes(old: &str, new: &str) -> Vec<Change> {
+        diff(&Pipeline::parse_raw_pipeline(old).unwrap(), &Pipeline::parse_raw_pipeline(new).unwrap())
+    }
+
+    #[test]
+    fn stages_line_up_by_program() {
+        let changes = changes("cat a | grep x | wc -l", "cat a | sort | grep y | wc -l");
+        assert_eq!(changes[0], Change::Same("cat a".to_string()));
+        assert_eq!(changes[1], Change::Added("sort".to_string()));
+        assert!(matches!(&changes[2], Change::Modified { details, .. } if details == &["arguments: x -> y"]));
+        assert_eq!(changes[3], Change::Same("wc -l".to_string()));
+    }
+
+    #[test]
+    fn builtin_options_by_key() {
+        let dedupe = changes("dedupe:window=1M,field=2 mode=exact", "dedupe:field=3 mode=exact fp=0.01");
+        let Change::Modified { details, .. } = &dedupe[0] else { panic!("{dedupe:?}") };
+        assert_eq!(details, &["window: 1M removed", "field: 2 -> 3", "fp: 0.01 added"]);
+
+        let sort = changes("sort:field=2 numeric=true", "sort:numeric=true field=2");
+        assert!(matches!(&sort[0], Change::Modified { details, .. } if details == &["options reordered"]));
+    }
+}
@@ -0,0 +1,100 @@
+//! `plumber edit <file>`, changing a definition without it drifting from what is running
+//!
+//! the definition is edited as a copy in `$VISUAL` or `$EDITOR`, falling back to `vi`. once the
+//! editor exits the copy is parsed: a malformed one is reopened where it was left rather than
+//! saved, and a stage whose program isn't on `PATH` is warned about. when the pipeline is
+//! running something other than what was saved, plumber offers to reload it, or does with
+//! `--reload`: its supervisor drains the stages and starts them again as the new definition.
+
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus};
+
+use crate::pipeline::{PipelineCommand, PipelineError};
+use crate::{shell, stages};
+
+/// `$VISUAL`, `$EDITOR` or `vi`, run with the shell so it may have options of its own
+pub fn editor() -> String {
+    ["VISUAL", "EDITOR"].into_iter()
+        .filter_map(|var| env::var(var).ok())
+        .find(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| "vi".to_string())
+}
+
+/// writes `original` to a new file for the editor, readable only by us. it never follows a link
+/// or opens a file another user put in place, failing instead
+pub fn draft(name: &str, original: &str) -> io::Result<PathBuf> {
+    let path = env::temp_dir().join(format!("plumber-edit-{}-{name}.plumb", std::process::id()));
+    let mut file = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path)?;
+    file.write_all(original.as_bytes())?;
+    Ok(path)
+}
+
+/// runs `editor` on `path`, waiting for it to exit
+pub fn open(editor: &str, path: impl AsRef<OsStr>) -> io::Result<ExitStatus> {
+    Command::new("sh")
+        .arg("-c")
+        .arg(format!("{editor} \"$1\""))
+        .arg("sh")
+        .arg(path)
+        .status()
+}
+
+/// programs of stages that won't be found when they're spawned
+pub fn missing_programs(commands: &[PipelineCommand]) -> Vec<PipelineError> {
+    commands.iter()
+        .filter(|cmd| stages::builtin_kind(&cmd.name).is_none() && shell::script(&cmd.name).is_none())
+        .filter(|cmd| shell::which(&cmd.name).is_none())
+        .map(|cmd| PipelineError::CommandNotFound { stage: cmd.log_name().to_owned(), name: cmd.name.clone() })
+        .collect()
+}
+
+/// asks `question` on a terminal, no without one
+pub fn confirm(question: &str) -> bool {
+    if !io::stdin().is_terminal() {
+        return false;
+    }
+    eprint!("{question} [y/N] ");
+    let _ = io::stderr().flush();
+    let mut answer = String::new();
+    if io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::pipeline::Pipeline;
+
+    #[test]
+    fn edits_and_validates() {
+        let path = env::temp_dir().join(format!("plumber-edit-test-{}.plumb", std::process::id()));
+        std::fs::write(&path, "cat | sort\n").unwrap();
+        assert!(open("sed -i s/sort/asdf-no-such-command/", &path).unwrap().success());
+        let edited = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(edited, "cat | asdf-no-such-command\n");
+
+        let missing = missing_programs(&Pipeline::parse_raw_pipeline(&edited).unwrap());
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].to_string(), "asdf-no-such-command: command not found: asdf-no-such-command");
+        assert!(!open("false", &path).unwrap().success());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn drafts_are_private_and_new() {
+        let path = draft("draft-test", "cat\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "cat\n");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(draft("draft-test", "sort\n").unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,151 @@
+//! what the exit code of a stage means, `--on-exit [STAGE=]CODES:ACTION`
+//!
+//! the supervisor goes through the rules once a run has ended, for every stage that exited
+//! with one of `CODES`, e.g. `1`, `2,3` or `64-78`. a stage killed by a signal exits with 128
+//! and the signal, as a shell reports it:
+//! - `warn` logs the exit as a warning rather than a failure, so it's neither restarted on nor
+//!   fails the pipeline, e.g. `grep=1:warn` for a grep that found nothing
+//! - `run:<file>` runs a remediation pipeline, before the pipeline is restarted
+//! - `page:<command>` runs a command with `--shell`, to page whoever is on call
+//!
+//! remediation pipelines and pages get `$PLUMBER_PIPELINE`, `$PLUMBER_STAGE` and
+//! `$PLUMBER_EXIT_CODE`, and are waited for.
+
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+
+use crate::pipeline::{Pipeline, LOG_DIR_ENV, STATE_DIR_ENV};
+use crate::summary::{self, RunSummary};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Warn,
+    Run(PathBuf),
+    Page(String),
+}
+
+/// one `--on-exit [STAGE=]CODES:ACTION` flag
+#[derive(Debug, Clone, PartialEq)]
+pub struct OnExit {
+    /// `None` applies to every stage
+    pub stage: Option<String>,
+    pub codes: Vec<RangeInclusive<i32>>,
+    pub action: Action,
+}
+
+impl std::str::FromStr for OnExit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // a page command may contain `=`, only a prefix before the codes names a stage
+        let (stage, rule) = match s.split_once('=') {
+            Some((stage, rule)) if !stage.contains(':') => (Some(stage.to_owned()), rule),
+            _ => (None, s),
+        };
+        let (codes, action) = rule.split_once(':').ok_or_else(|| format!("expected CODES:ACTION, got '{rule}'"))?;
+        let code = |code: &str| code.parse::<i32>().map_err(|_| format!("'{code}' is not an exit code"));
+        let codes = codes.split(',')
+            .map(|codes| match codes.split_once('-') {
+                Some((from, to)) => Ok(code(from)?..=code(to)?),
+                None => code(codes).map(|code| code..=code),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let action = match action.split_once(':') {
+            None if action == "warn" => Action::Warn,
+            Some(("run", file)) if !file.is_empty() => Action::Run(PathBuf::from(file)),
+            Some(("page", command)) if !command.is_empty() => Action::Page(command.to_owned()),
+            _ => return Err(format!("unknown action '{action}', expected warn, run:<file> or page:<command>")),
+        };
+        Ok(OnExit { stage, codes, action })
+    }
+}
+
+impl OnExit {
+    fn matches(&self, stage: &str, code: i32) -> bool {
+        self.stage.as_deref().is_none_or(|s| s == stage) && self.codes.iter().any(|codes| codes.contains(&code))
+    }
+}
+
+/// whether `rules` make a warning of how `stage` exited
+pub fn warns(rules: &[OnExit], stage: &str, status: Option<ExitStatus>) -> bool {
+    let Some(status) = status.filter(|status| !status.success()) else { return false };
+    let code = summary::exit_code(status);
+    rules.iter().any(|rule| rule.action == Action::Warn && rule.matches(stage, code))
+}
+
+/// the remediation pipelines and pages `rules` call for after `summary`, in the order of the
+/// stages, every rule at most once a run
+pub fn actions<'a>(rules: &'a [OnExit], summary: &RunSummary) -> Vec<(String, i32, &'a Action)> {
+    let mut actions: Vec<(String, i32, &Action)> = Vec::new();
+    for run in &summary.stages {
+        let Some(code) = run.status.map(summary::exit_code) else { continue };
+        for rule in rules.iter().filter(|rule| rule.action != Action::Warn && rule.matches(&run.stage, code)) {
+            if !actions.iter().any(|(_, _, action)| std::ptr::eq(*action, &rule.action)) {
+                actions.push((run.stage.clone(), code, &rule.action));
+            }
+        }
+    }
+    actions
+}
+
+/// runs a remediation pipeline or page for `stage` of pipeline `name` exiting with `code`
+pub fn take(name: &str, stage: &str, code: i32, action: &Action, shell: &Path) {
+    let mut command = match action {
+        Action::Warn => return,
+        Action::Run(file) => {
+            let mut command = Command::new(std::env::current_exe().unwrap_or_else(|_| PathBuf::from("plumber")));
+            // kept next to the pipeline it remediates, namespace and all
+            let dirs = Pipeline::state_dirs();
+            command.arg("run").arg(file).env(STATE_DIR_ENV, dirs.metadata).env(LOG_DIR_ENV, dirs.logs);
+            command
+        },
+        Action::Page(page) => {
+            let mut command = Command::new(shell);
+            command.arg("-c").arg(page);
+            command
+        },
+    };
+    let what = match action {
+        Action::Run(file) => format!("remediation pipeline {}", file.display()),
+        _ => "page".to_string(),
+    };
+    log::warn!("{name}: {stage} exited with {code}, running {what}");
+    crate::audit::record("on-exit", name, &format!("{what} for {stage} exiting with {code}"));
+    let ran = command
+        .env("PLUMBER_PIPELINE", name)
+        .env("PLUMBER_STAGE", stage)
+        .env("PLUMBER_EXIT_CODE", code.to_string())
+        .status();
+    match ran {
+        Ok(status) if status.success() => {},
+        Ok(status) => log::warn!("{name}: {what} {status}"),
+        Err(e) => log::warn!("{name}: unable to run {what}: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    #[test]
+    fn parse_and_match() {
+        let grep: OnExit = "grep=1:warn".parse().unwrap();
+        assert!(grep.matches("grep", 1));
+        assert!(!grep.matches("jq", 1));
+        let page: OnExit = "2,64-78:page:curl -d text=down https://pager".parse().unwrap();
+        assert_eq!(page.stage, None);
+        assert_eq!(page.action, Action::Page("curl -d text=down https://pager".to_string()));
+        assert!(page.matches("jq", 70) && page.matches("jq", 2) && !page.matches("jq", 3));
+        assert!("rsync=23:run:/opt/fix.plumb".parse::<OnExit>().is_ok_and(|r| r.action == Action::Run(PathBuf::from("/opt/fix.plumb"))));
+        assert!("grep=x:warn".parse::<OnExit>().is_err());
+        assert!("grep=1:retry".parse::<OnExit>().is_err());
+
+        let rules = [grep, "137:warn".parse().unwrap()];
+        assert!(warns(&rules, "grep", Some(ExitStatus::from_raw(1 << 8))));
+        assert!(!warns(&rules, "grep", Some(ExitStatus::from_raw(2 << 8))));
+        // killed by SIGKILL
+        assert!(warns(&rules, "jq", Some(ExitStatus::from_raw(9))));
+    }
+}
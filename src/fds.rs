@@ -0,0 +1,132 @@
+//! extra file descriptors for stages that take auxiliary control or status fds
+//!
+//! `--fd <stage>=<n><redirect><path>` opens `path` and passes it to the stage as fd `n`,
+//! with the redirects of a shell: `<` for reading, `>` for writing, `>>` for appending and
+//! `<>` for both, e.g. `--fd 'rsync=3>/run/rsync.status'`. `<>` opens a fifo without waiting
+//! for the other end. the file is opened again for every start of the stage.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::Command;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Read,
+    Write,
+    Append,
+    ReadWrite,
+}
+
+/// one `--fd` flag
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtraFd {
+    pub stage: String,
+    pub fd: RawFd,
+    mode: Mode,
+    path: PathBuf,
+}
+
+impl std::str::FromStr for ExtraFd {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((stage, redirect)) = s.split_once('=') else {
+            return Err("expected <stage>=<n><redirect><path>".to_string());
+        };
+        let digits = redirect.find(|c: char| !c.is_ascii_digit()).unwrap_or(redirect.len());
+        let (fd, redirect) = redirect.split_at(digits);
+        let fd: RawFd = fd.parse().map_err(|_| format!("expected an fd number before the redirect in '{redirect}'"))?;
+        if fd < 3 {
+            return Err(format!("fd {fd} is the stage's stdin, stdout or stderr"));
+        }
+        let (mode, path) = [("<>", Mode::ReadWrite), (">>", Mode::Append), ("<", Mode::Read), (">", Mode::Write)]
+            .into_iter()
+            .find_map(|(op, mode)| redirect.strip_prefix(op).map(|path| (mode, path)))
+            .ok_or_else(|| format!("expected <, >, >> or <> after fd {fd}"))?;
+        if path.is_empty() {
+            return Err(format!("no path for fd {fd}"));
+        }
+        Ok(ExtraFd { stage: stage.to_owned(), fd, mode, path: PathBuf::from(path) })
+    }
+}
+
+impl ExtraFd {
+    fn open(&self) -> io::Result<File> {
+        let mut options = OpenOptions::new();
+        match self.mode {
+            Mode::Read => options.read(true),
+            Mode::Write => options.write(true).create(true).truncate(true),
+            Mode::Append => options.append(true).create(true),
+            Mode::ReadWrite => options.read(true).write(true).create(true),
+        };
+        options.open(&self.path)
+    }
+}
+
+/// opens the extra fds of a stage and has them show up at their numbers in it
+pub fn pass(command: &mut Command, fds: &[&ExtraFd]) -> io::Result<()> {
+    let files = fds.iter()
+        .map(|fd| fd.open().map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", fd.path.display()))))
+        .collect::<io::Result<Vec<File>>>()?;
+    let targets: Vec<RawFd> = fds.iter().map(|fd| fd.fd).collect();
+    let above = targets.iter().max().copied().unwrap_or(0) + 1;
+    unsafe {
+        command.pre_exec(move || {
+            // moved out of the way first, so no file is dup'd over another before it's placed
+            let mut moved = Vec::with_capacity(files.len());
+            for file in &files {
+                let fd = libc::fcntl(file.as_raw_fd(), libc::F_DUPFD_CLOEXEC, above);
+                if fd < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                moved.push(fd);
+            }
+            // dup2 leaves the targets open across exec
+            for (fd, target) in moved.iter().zip(&targets) {
+                if libc::dup2(*fd, *target) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_redirects() {
+        let fd: ExtraFd = "rsync=3>/run/rsync.status".parse().unwrap();
+        assert_eq!((fd.stage.as_str(), fd.fd, fd.mode), ("rsync", 3, Mode::Write));
+        assert_eq!(fd.path, PathBuf::from("/run/rsync.status"));
+        assert_eq!("x=10<>/run/ctl".parse::<ExtraFd>().unwrap().mode, Mode::ReadWrite);
+        assert_eq!("x=4>>log".parse::<ExtraFd>().unwrap().mode, Mode::Append);
+        assert_eq!("x=4<in".parse::<ExtraFd>().unwrap().mode, Mode::Read);
+        assert!("x=2>err".parse::<ExtraFd>().is_err());
+        assert!("x=>out".parse::<ExtraFd>().is_err());
+        assert!("x=3>".parse::<ExtraFd>().is_err());
+        assert!("3>out".parse::<ExtraFd>().is_err());
+    }
+
+    #[test]
+    fn stages_see_fds_at_their_numbers() {
+        let dir = std::env::temp_dir().join(format!("plumber-fds-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("in"), "control\n").unwrap();
+        let read: ExtraFd = format!("sh=3<{}", dir.join("in").display()).parse().unwrap();
+        let write: ExtraFd = format!("sh=4>{}", dir.join("out").display()).parse().unwrap();
+
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("cat <&3 >&4");
+        pass(&mut command, &[&read, &write]).unwrap();
+        assert!(command.status().unwrap().success());
+        assert_eq!(std::fs::read_to_string(dir.join("out")).unwrap(), "control\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,82 @@
+//! renders a pipeline as a graphviz dot or mermaid flowchart
+//!
+//! every stage is a node labelled with its command line, links between stages are edges.
+//! a link can carry an annotation such as its throughput.
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Format {
+    Dot,
+    Mermaid,
+}
+
+/// `links[i]` annotates the link from stage `i` to stage `i + 1`
+pub fn render(name: &str, stages: &[String], links: &[Option<String>], format: Format) -> String {
+    let label = |i: usize| links.get(i).cloned().flatten();
+    let mut out = String::new();
+    match format {
+        Format::Dot => {
+            out.push_str(&format!("digraph {} {{\n", dot_quote(name)));
+            out.push_str("    rankdir=LR;\n");
+            out.push_str("    node [shape=box, fontname=monospace];\n");
+            for (i, stage) in stages.iter().enumerate() {
+                out.push_str(&format!("    s{i} [label={}];\n", dot_quote(stage)));
+            }
+            for i in 1..stages.len() {
+                match label(i - 1) {
+                    Some(l) => out.push_str(&format!("    s{} -> s{i} [label={}];\n", i - 1, dot_quote(&l))),
+                    None => out.push_str(&format!("    s{} -> s{i};\n", i - 1)),
+                }
+            }
+            out.push_str("}\n");
+        },
+        Format::Mermaid => {
+            out.push_str("flowchart LR\n");
+            out.push_str(&format!("    %% {name}\n"));
+            for (i, stage) in stages.iter().enumerate() {
+                out.push_str(&format!("    s{i}[\"{}\"]\n", mermaid_escape(stage)));
+            }
+            for i in 1..stages.len() {
+                match label(i - 1) {
+                    Some(l) => out.push_str(&format!("    s{} -->|\"{}\"| s{i}\n", i - 1, mermaid_escape(&l))),
+                    None => out.push_str(&format!("    s{} --> s{i}\n", i - 1)),
+                }
+            }
+        },
+    }
+    out
+}
+
+fn dot_quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// mermaid labels can't contain raw quotes, and `#` starts an entity
+fn mermaid_escape(s: &str) -> String {
+    s.replace('#', "#35;").replace('"', "#quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stages() -> Vec<String> {
+        vec!["cat \"a\"".to_string(), "grep x".to_string(), "wc".to_string()]
+    }
+
+    #[test]
+    fn dot() {
+        let out = render("etl", &stages(), &[Some("1.2 MB/s".to_string())], Format::Dot);
+        assert!(out.starts_with("digraph \"etl\" {\n"));
+        assert!(out.contains("    s0 [label=\"cat \\\"a\\\"\"];\n"));
+        assert!(out.contains("    s0 -> s1 [label=\"1.2 MB/s\"];\n"));
+        assert!(out.contains("    s1 -> s2;\n"));
+    }
+
+    #[test]
+    fn mermaid() {
+        let out = render("etl", &stages(), &[], Format::Mermaid);
+        assert!(out.starts_with("flowchart LR\n"));
+        assert!(out.contains("    s0[\"cat #quot;a#quot;\"]\n"));
+        assert!(out.contains("    s1 --> s2\n"));
+    }
+}
@@ -0,0 +1,90 @@
+//! a file kept fresh while a pipeline is healthy, for monitoring that already watches files
+//!
+//! with `--heartbeat 30s` the supervision loop of a pipeline writes the time to `heartbeat` in
+//! its metadata dir, or to `--heartbeat-file`, every 30s while its stages run. the beats
+//! stop between runs, while backing off from restarts, once the pipeline crash-looped or stopped,
+//! and when plumber itself is wedged, so a monitor alerting on a file older than a few intervals
+//! catches all of those. the file is left behind when the pipeline ends.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// the default file name, in the pipeline's metadata dir
+pub const FILE: &str = "heartbeat";
+
+/// `--heartbeat` and `--heartbeat-file`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heartbeat {
+    pub every: Duration,
+    /// instead of `heartbeat` in the metadata dir, `{name}` standing for the pipeline's name
+    pub file: Option<PathBuf>,
+}
+
+/// the heartbeat of one supervised pipeline
+pub struct Beating {
+    path: PathBuf,
+    every: Duration,
+    last: Option<Instant>,
+    /// so a file that can't be written is warned about once, not every beat
+    failing: bool,
+}
+
+impl Beating {
+    pub fn new(heartbeat: &Heartbeat, name: &str, metadata_dir: &Path) -> Beating {
+        let path = match &heartbeat.file {
+            Some(file) => PathBuf::from(file.to_string_lossy().replace("{name}", name)),
+            None => metadata_dir.join(FILE),
+        };
+        Beating { path, every: heartbeat.every, last: None, failing: false }
+    }
+
+    /// how long until the next beat is due, zero if it is
+    pub fn due_in(&self, now: Instant) -> Duration {
+        self.last.map_or(Duration::ZERO, |last| (last + self.every).saturating_duration_since(now))
+    }
+
+    /// writes `time` to the file if a beat is due
+    pub fn beat(&mut self, now: Instant, time: SystemTime, pipeline: &str) {
+        if !self.due_in(now).is_zero() {
+            return;
+        }
+        self.last = Some(now);
+        match fs::write(&self.path, format!("{}\n", humantime::format_rfc3339_seconds(time))) {
+            Ok(()) => self.failing = false,
+            Err(e) if !self.failing => {
+                log::warn!("{pipeline}: unable to write heartbeat to {}: {e}", self.path.display());
+                self.failing = true;
+            },
+            Err(_) => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn beats_once_per_interval() {
+        let dir = std::env::temp_dir().join(format!("plumber-heartbeat-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let heartbeat = Heartbeat { every: Duration::from_secs(30), file: Some(dir.join("{name}.alive")) };
+        let mut beating = Beating::new(&heartbeat, "etl", Path::new("/nowhere"));
+        assert_eq!(beating.path, dir.join("etl.alive"));
+
+        let (now, time) = (Instant::now(), SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        assert_eq!(beating.due_in(now), Duration::ZERO);
+        beating.beat(now, time, "etl");
+        assert_eq!(fs::read_to_string(&beating.path).unwrap(), "2023-11-14T22:13:20Z\n");
+        assert_eq!(beating.due_in(now + Duration::from_secs(10)), Duration::from_secs(20));
+        beating.beat(now + Duration::from_secs(10), time + Duration::from_secs(10), "etl");
+        assert_eq!(fs::read_to_string(&beating.path).unwrap(), "2023-11-14T22:13:20Z\n");
+        beating.beat(now + Duration::from_secs(30), time + Duration::from_secs(30), "etl");
+        assert_eq!(fs::read_to_string(&beating.path).unwrap(), "2023-11-14T22:13:50Z\n");
+
+        let default = Beating::new(&Heartbeat { every: Duration::from_secs(1), file: None }, "etl", &dir);
+        assert_eq!(default.path, dir.join(FILE));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,11 +1,92 @@
-use std::time::Duration;
-use std::{path::PathBuf, process::exit, fs, vec};
+use std::time::{Duration, Instant};
+use std::{path::{Path, PathBuf}, process::exit, fs, vec};
 use std::thread;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use log::error;
 use clap::Parser;
+use regex::Regex;
 
+mod adopt;
+mod allowlist;
+mod archive;
+mod audit;
+mod budget;
+mod capture;
+mod clock;
+mod control;
+mod cores;
+mod datetime;
+mod definition;
+mod diff;
+mod edit;
+mod exits;
+mod fds;
+mod graph;
+mod heartbeat;
+mod metrics;
+mod names;
+mod namespace;
+mod numa;
+mod observe;
+mod outputs;
+mod overhead;
+mod packages;
+mod parser;
 mod pipeline;
-use crate::pipeline::Pipeline;
+mod precondition;
+mod probe;
+mod prune;
+mod reactor;
+mod readiness;
+mod recorder;
+mod restarts;
+mod revision;
+mod seccomp;
+mod sha256;
+mod shell;
+mod signature;
+mod soak;
+mod socket;
+mod spool;
+mod stages;
+mod state;
+mod status;
+mod summary;
+mod templates;
+mod transport;
+mod update;
+mod upgrade;
+mod upstream;
+mod usage;
+mod version;
+mod watchdog;
+mod window;
+mod worker;
+mod wrapper;
+use crate::allowlist::Allowlist;
+use crate::archive::Archive;
+use crate::capture::{LogFilter, LogFull, LogSize};
+use crate::cores::CoreDumps;
+use crate::exits::OnExit;
+use crate::fds::ExtraFd;
+use crate::heartbeat::Heartbeat;
+use crate::names::ValidatedName;
+use crate::numa::NumaRule;
+use crate::observe::Color;
+use crate::pipeline::{Output, Pipeline, PipelineError, Restart, SpawnOrder, StopMode};
+use crate::precondition::{OnUnmet, Precondition};
+use crate::probe::Probe;
+use crate::readiness::ReadyCheck;
+use crate::restarts::{Backoff, CrashLoop};
+use crate::seccomp::ObserveRule;
+use crate::shell::Shell;
+use crate::spool::{Compression, Spool, SpoolFull};
+use crate::summary::PipelineExitStatus;
+use crate::transport::PipeSize;
+use crate::upstream::{EofHook, UpstreamExit};
+use crate::window::{Outside, Schedule, Window};
+use crate::wrapper::Wrapper;
 
 /// unix pipelines made easy!
 #[derive(Parser)]
@@ -13,43 +94,741 @@ use crate::pipeline::Pipeline;
 struct Args {
     #[command(subcommand)]
     command: Subargs,
+    /// dir pipeline metadata is kept in, `$PLUMBER_STATE_DIR` or `lib` under the state root
+    #[arg(long, global = true, value_name = "DIR")]
+    state_dir: Option<PathBuf>,
+    /// dir the stderr logs of stages are written to, `$PLUMBER_LOG_DIR` or `log` under the state root
+    #[arg(long, global = true, value_name = "DIR")]
+    log_dir: Option<PathBuf>,
+    /// keep pipelines apart from those of other namespaces, `$PLUMBER_NAMESPACE` if not given
+    #[arg(long, global = true, value_parser = namespace::parse)]
+    namespace: Option<String>,
+    /// keep `$NAME` in pipelines as written rather than expanding environment variables
+    #[arg(long, global = true)]
+    no_expand: bool,
+    /// fill in the `{{NAME}}` placeholders of pipelines, e.g. `--set input=/data`
+    #[arg(long = "set", value_name = "NAME=VALUE", global = true)]
+    set: Vec<templates::Set>,
 }
 
 #[derive(clap::Subcommand)]
 enum Subargs {
     /// run pipelines from a plumber file
     Run {
-        /// path to plumber file or directory of files
+        /// path to plumber file or directory of files, or `<package>[@<version>]`
         path: PathBuf,
+        /// keep supervising in the background, answering on `<state dir>/<name>.sock`
+        #[arg(long)]
+        detach: bool,
+        /// start plumber files dropped into the directory, reload rewritten ones and stop removed ones
+        #[arg(long)]
+        watch: bool,
+        /// pull the git checkout of the directory this often, e.g. `1m`, the watch adopting what changed
+        #[arg(long, value_name = "DURATION", requires = "watch")]
+        git_pull: Option<humantime::Duration>,
+        #[command(flatten)]
+        approval: Approval,
+        #[command(flatten)]
+        options: PipelineOptions,
     },
     /// execute a pipeline from a string input
     Exec {
-        /// raw pipeline string
+        /// raw pipeline string, put `--` before it if it starts with `-`
         pipeline: String,
         /// name to use for logging and metadata
         #[arg(short, long)]
         name: String,
+        #[command(flatten)]
+        approval: Approval,
+        #[command(flatten)]
+        options: PipelineOptions,
     },
     /// stop pipelines using a plumber file path
     Stop {
-        /// path to plumber file or directory of files
+        /// path to plumber file or directory of files, or `<package>[@<version>]`
         path: PathBuf,
         /// shutdown timeout in seconds
         #[arg(short, long, default_value_t=30)]
         timeout: u32,
+        /// seconds to wait for stages to exit before killing every process of every stage
+        #[arg(short, long, default_value_t=10)]
+        grace: u32,
+        /// drain through the first stage, or signal every stage's process group at once
+        #[arg(short, long, value_enum, default_value_t=StopMode::Drain)]
+        mode: StopMode,
+    },
+    /// run a pipeline against synthetic input for a while, recording resource and throughput trends
+    Soak {
+        /// path to plumber file, or name of a pipeline that has been run
+        path: String,
+        /// how long to run it for
+        #[arg(long, default_value = "1h")]
+        duration: humantime::Duration,
+        /// stage put in front of the pipeline to feed it, e.g. `generate:ndjson rate=1000`
+        #[arg(long)]
+        input: Option<String>,
+        /// how often stages and links are sampled
+        #[arg(long, default_value = "10s")]
+        interval: humantime::Duration,
+        /// percent a stage's memory may grow between the first and last quarter of the run
+        #[arg(long, default_value_t = 50.0)]
+        max_rss_growth: f64,
+        #[command(flatten)]
+        options: PipelineOptions,
+    },
+    /// list pipelines with their state, uptime and number of stages
+    Status,
+    /// print the stderr logs of a pipeline's stages, interleaved and prefixed by stage
+    Logs {
+        /// path to plumber file, or name of a pipeline
+        name: String,
+        /// only the log of this stage
+        #[arg(short, long)]
+        stage: Option<String>,
+        /// lines to print from the end of each log
+        #[arg(short = 'n', long, default_value_t = 20)]
+        lines: usize,
+        /// keep printing what the stages write
+        #[arg(short, long)]
+        follow: bool,
+        /// color the stage names
+        #[arg(long, value_enum, default_value_t)]
+        color: Color,
+    },
+    /// read-only status, logs and metrics of pipelines, safe to hand to on-call
+    Observe {
+        #[command(subcommand)]
+        view: ObserveView,
+    },
+    /// set up a namespace for a team, or list them
+    Namespace {
+        #[command(subcommand)]
+        action: NamespaceAction,
+    },
+    /// pin the git checkout of a directory of plumber files to a commit, or have it follow a branch again
+    Rollback {
+        /// directory of plumber files in a git checkout, watched by `plumber run --watch`
+        path: PathBuf,
+        /// commit to pin it to, or branch to follow
+        #[arg(long, value_name = "COMMIT")]
+        to: String,
+    },
+    /// let a crash-looped pipeline start again and clear its restart backoff
+    Reset {
+        /// path to plumber file, or name of a pipeline
+        name: String,
+    },
+    /// re-execute the plumber supervising a pipeline as the installed binary, keeping its stages running
+    UpgradeDaemon {
+        /// path to plumber file, or name of a running pipeline
+        name: String,
+        /// seconds to wait for the upgraded plumber to take over
+        #[arg(short, long, default_value_t=10)]
+        timeout: u32,
+    },
+    /// replace this binary with the latest signed release
+    SelfUpdate {
+        /// where releases are downloaded from
+        #[arg(long, default_value = update::DEFAULT_URL)]
+        url: String,
+        /// ssh allowed_signers file with the keys trusted to sign releases
+        #[arg(long, default_value = "/etc/plumber/allowed_signers")]
+        allowed_signers: PathBuf,
+    },
+    /// install a pipeline package from a dir, a tarball or a url of one
+    Install {
+        /// package dir, tarball or url
+        source: String,
+        /// replace the version if it's installed already
+        #[arg(long)]
+        force: bool,
+    },
+    /// list the pipeline packages installed and their versions
+    Packages,
+    /// print a pipeline as a graphviz or mermaid diagram
+    Graph {
+        /// path to plumber file, or name of a pipeline that has been run
+        name: String,
+        /// diagram format
+        #[arg(short, long, value_enum, default_value_t=graph::Format::Dot)]
+        format: graph::Format,
+    },
+    /// check a pipeline definition, printing its stages or where it is malformed
+    #[command(visible_alias = "check")]
+    Parse {
+        /// path to plumber file, name of a pipeline that has been run, or `-` for stdin
+        name: String,
+        /// print the syntax tree, with the bytes of the definition each part was parsed from
+        #[arg(long)]
+        debug: bool,
+        /// check where the output goes too: `writable:<dir>`, `s3:<bucket>`, `cmd:<command>`, or
+        /// `path:`, `mount:`, `host:` and `port:` as --require takes them
+        #[arg(long, value_name = "PROBE")]
+        probe: Vec<Probe>,
+    },
+    /// edit a plumber file in $EDITOR, checking it before it is saved, and reload the pipeline
+    Edit {
+        /// path to plumber file, created from the last run of the pipeline named after it if missing
+        path: PathBuf,
+        /// reload a pipeline running another definition without asking
+        #[arg(long)]
+        reload: bool,
+    },
+    /// show what changes between two pipeline definitions
+    Diff {
+        /// old plumber file, or name of a pipeline that has been run
+        old: String,
+        /// new plumber file, or name of a pipeline that has been run
+        #[arg(required_unless_present = "against")]
+        new: Option<String>,
+        /// compare the plumber file against the pipeline currently running under its name
+        #[arg(long, value_enum, conflicts_with = "new")]
+        against: Option<DiffAgainst>,
+    },
+    /// print the last events the flight recorder of a pipeline kept, even those not logged
+    Dump {
+        /// path to plumber file, or name of a pipeline
+        name: String,
+        /// seconds to wait for the plumber supervising the pipeline to write them
+        #[arg(short, long, default_value_t = 5)]
+        timeout: u32,
+    },
+    /// set the wrappers stages of a pipeline are spawned under from their next restart on
+    Wrap {
+        /// path to plumber file, or name of a pipeline
+        name: String,
+        /// `[STAGE=]COMMAND` rules as with --wrapper, none to drop those set before
+        #[arg(value_name = "[STAGE=]COMMAND")]
+        rules: Vec<Wrapper>,
+    },
+    /// bundle the summary, definition and logs of a finished run for attaching to a ticket
+    Archive {
+        /// path to plumber file, or name of a pipeline that has been run
+        name: String,
+        /// id of the run, the latest when not given
+        #[arg(long, value_name = "ID")]
+        run: Option<String>,
+        /// archive format
+        #[arg(short, long, value_enum, default_value_t = archive::Format::TarZst)]
+        format: archive::Format,
+        /// where to write the archive, `<name>-<id>.<format>` when not given
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// regex for secrets to leave out, besides those given when the pipeline ran
+        #[arg(long, value_name = "REGEX")]
+        redact: Vec<Regex>,
+    },
+    /// remove the metadata, logs and scratch dirs left behind by pipelines that aren't running
+    #[command(visible_alias = "clean")]
+    Prune {
+        /// list what would be removed and the space it takes up, removing nothing
+        #[arg(long)]
+        dry_run: bool,
+        /// only prune pipelines that weren't run for this long
+        #[arg(long, value_name = "DURATION", default_value = "7d")]
+        older_than: humantime::Duration,
+    },
+    /// print the version, compiled in features, state formats and dirs
+    Version {
+        /// as json, for scripts checking what this plumber can do
+        #[arg(long)]
+        json: bool,
+    },
+    /// print the cpu, memory and fds plumber itself takes up running a pipeline, and what relaying its links costs
+    Overhead {
+        /// path to plumber file, or name of a pipeline
+        name: String,
+    },
+    /// print what the cooperative stages of a pipeline last reported
+    Progress {
+        /// path to plumber file, or name of a pipeline
+        name: String,
+    },
+    /// talk to plumber from a `--cooperative` stage
+    Control {
+        #[arg(value_enum)]
+        action: control::Action,
+        /// what to report with `progress`
+        text: Vec<String>,
+    },
+    /// stand in for a `--worker` stage in a run, used internally when spawning pipelines
+    #[command(hide = true)]
+    Shuttle,
+    /// run a built-in stage, used internally when spawning pipelines
+    #[command(hide = true)]
+    Stage {
+        /// stage spec such as `mqtt-sub:topic`
+        spec: String,
+        /// `key=value` options for the stage
+        args: Vec<String>,
+    },
+}
+
+/// settings applied to every pipeline started by `run` or `exec`
+#[derive(clap::Args)]
+struct PipelineOptions {
+    /// log watched syscalls (network, exec, ptrace) of all stages or of one stage
+    #[arg(long, value_name = "[STAGE=]CATEGORIES")]
+    seccomp_observe: Vec<ObserveRule>,
+    /// relay links through plumber to measure their throughput
+    #[arg(long)]
+    instrument: bool,
+    /// capacity of the pipes feeding all stages or one stage, e.g. `1M` or `grep=4M`
+    #[arg(long, value_name = "[STAGE=]SIZE")]
+    pipe_size: Vec<PipeSize>,
+    /// read ahead of all stages or one stage, keeping up to SIZE in memory and spilling the
+    /// rest to disk, e.g. `64M` or `jq=8M`
+    #[arg(long, value_name = "[STAGE=]SIZE")]
+    spool: Vec<Spool>,
+    /// disk each spooled link may spill to
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_spool: Option<u64>,
+    /// keep a stage with a slow start running across runs, feeding it every run's input in frames
+    #[arg(long, value_name = "STAGE")]
+    worker: Vec<String>,
+    /// end a run that moved more than SIZE between its stages, failing it with exit code 3
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_run_bytes: Option<u64>,
+    /// what a spooled link does once it spilled --max-spool
+    #[arg(long, value_enum, default_value_t = SpoolFull::Block)]
+    on_spool_full: SpoolFull,
+    /// compress what all spooled links or one spill with zstd at LEVEL, e.g. `3` or `jq=0` for none
+    #[arg(long, value_name = "[STAGE=]LEVEL")]
+    spool_compression: Vec<Compression>,
+    /// most stderr lines per second logged for a stage, the rest are counted and dropped
+    #[arg(long, value_name = "N")]
+    max_log_lines: Option<u32>,
+    /// log a run of identical stderr lines once, with how often it repeated
+    #[arg(long)]
+    collapse_repeats: bool,
+    /// largest the stderr log of all stages or one stage may grow, and the file the last
+    /// stage's stdout goes to, e.g. `100M` or `jq=1G`
+    #[arg(long, value_name = "[STAGE=]SIZE")]
+    max_log_size: Vec<LogSize>,
+    /// what a log does once it reached --max-log-size, `truncate` unless its definition says otherwise
+    #[arg(long, value_enum)]
+    on_log_full: Option<LogFull>,
+    /// rotated logs kept with --on-log-full rotate, `<log>.1` the newest
+    #[arg(long, value_name = "N")]
+    max_log_files: Option<u32>,
+    /// gzip logs as they're rotated
+    #[arg(long)]
+    compress_logs: bool,
+    /// prefix every stderr line logged with the time and the stage's name
+    #[arg(long)]
+    timestamp_logs: bool,
+    /// open a file and pass it to a stage as an extra fd, e.g. `rsync=3>/run/rsync.status`
+    #[arg(long = "fd", value_name = "STAGE=N<PATH")]
+    extra_fds: Vec<ExtraFd>,
+    /// run all stages or one stage only on the cpus of these numa nodes, e.g. `0` or `jq=0-1`
+    #[arg(long, value_name = "[STAGE=]NODES")]
+    numa_cpu: Vec<NumaRule>,
+    /// have all stages or one stage allocate memory only from these numa nodes
+    #[arg(long, value_name = "[STAGE=]NODES")]
+    numa_mem: Vec<NumaRule>,
+    /// command to spawn all stages or one stage under, e.g. `jq=strace -f -o {log_dir}/strace.out`
+    #[arg(long, value_name = "[STAGE=]COMMAND")]
+    wrapper: Vec<Wrapper>,
+    /// collect the core dumps of stages into the pipeline's metadata dir
+    #[arg(long)]
+    core_dumps: bool,
+    /// space all collected core dumps of a pipeline may take up, e.g. `2G`
+    #[arg(long, value_name = "SIZE", requires = "core_dumps", value_parser = parse_size)]
+    max_core_space: Option<u64>,
+    /// regex for secrets to leave out of archived runs, only its first group if it has one
+    #[arg(long, value_name = "REGEX")]
+    redact: Vec<Regex>,
+    /// keep the $PLUMBER_TMPDIR of finished runs for debugging
+    #[arg(long)]
+    keep_tmpdir: bool,
+    /// local times of day runs may start in, e.g. `22:00-06:00`
+    #[arg(long, value_name = "HH:MM-HH:MM")]
+    window: Vec<Window>,
+    /// local times of day no run starts in, whatever --window says
+    #[arg(long, value_name = "HH:MM-HH:MM")]
+    blackout: Vec<Window>,
+    /// what a run does once its window closes, `finish` unless its definition says otherwise
+    #[arg(long, value_enum)]
+    outside_window: Option<Outside>,
+    /// stop petting the systemd watchdog if the pipeline crash-loops, so plumber is restarted
+    #[arg(long)]
+    critical: bool,
+    /// write the time to `heartbeat` in the metadata dir this often while the stages run, e.g. `30s`
+    #[arg(long, value_name = "DURATION")]
+    heartbeat: Option<humantime::Duration>,
+    /// the file --heartbeat writes to instead, `{name}` standing for the pipeline's name
+    #[arg(long, value_name = "PATH", requires = "heartbeat")]
+    heartbeat_file: Option<PathBuf>,
+    /// fail a pipeline, and plumber's exit code, when any stage fails rather than only the last
+    #[arg(long)]
+    pipefail: bool,
+    /// spawn the pipeline again when it exits, `never` unless its definition says otherwise
+    #[arg(long, value_enum)]
+    restart: Option<Restart>,
+    /// drain a run that takes longer, e.g. `1h`
+    #[arg(long, value_name = "DURATION")]
+    timeout: Option<humantime::Duration>,
+    /// restarts within --restart-window after which a pipeline is crash-looped
+    #[arg(long, value_name = "N", default_value_t = 5)]
+    max_restarts: u32,
+    /// window in which --max-restarts are counted
+    #[arg(long, value_name = "DURATION", default_value = "60s")]
+    restart_window: humantime::Duration,
+    /// delay before the first restart, doubled for every quick restart after it
+    #[arg(long, value_name = "DURATION", default_value = "100ms")]
+    restart_delay: humantime::Duration,
+    /// longest delay between restarts
+    #[arg(long, value_name = "DURATION", default_value = "30s")]
+    max_restart_delay: humantime::Duration,
+    /// interpreter of `script:` stages, eof hooks and the crash-loop command, e.g. `bash` or
+    /// `script=python3`
+    #[arg(long, value_name = "[STAGE=]INTERPRETER")]
+    shell: Vec<Shell>,
+    /// command run with `--shell` when a pipeline is crash-looped
+    #[arg(long, value_name = "COMMAND")]
+    on_crash_loop: Option<String>,
+    /// what a stage's stdin does when the stage before it exits: `close`, `keep-open` while
+    /// --restart respawns that stage, or `sentinel:<line>` written before closing it
+    #[arg(long, value_name = "[STAGE=]POLICY")]
+    on_upstream_exit: Vec<UpstreamExit>,
+    /// command run with `--shell` once a stage's input is complete
+    #[arg(long, value_name = "[STAGE=]COMMAND")]
+    on_eof: Vec<EofHook>,
+    /// start consumers before their producers, or the other way round
+    #[arg(long, value_enum, default_value_t = SpawnOrder::DownstreamFirst)]
+    spawn_order: SpawnOrder,
+    /// hold upstream stages until a stage is ready: `log:<regex>`, `port:<n>` or `notify`
+    #[arg(long, value_name = "STAGE=CHECK")]
+    ready: Vec<ReadyCheck>,
+    /// how long to hold upstream stages for a stage that isn't ready
+    #[arg(long, value_name = "DURATION", default_value = "30s")]
+    ready_timeout: humantime::Duration,
+    /// what has to hold before a run is spawned: `path:<path>`, `mount:<path>`, `host:<name>` or
+    /// `port:<host>:<port>`
+    #[arg(long, value_name = "CHECK")]
+    require: Vec<Precondition>,
+    /// hold a run back until its preconditions hold, or fail the pipeline right away
+    #[arg(long, value_enum, default_value_t = OnUnmet::Wait)]
+    on_unmet: OnUnmet,
+    /// give a stage a control socket in $PLUMBER_CONTROL, to ask it to stop over once it said hello
+    #[arg(long, value_name = "STAGE")]
+    cooperative: Vec<String>,
+    /// give a stage an fd in $PLUMBER_METRICS_FD to report counters and gauges on
+    #[arg(long, value_name = "STAGE")]
+    report_metrics: Vec<String>,
+    /// how long a stage asked to stop has to exit before it's sent SIGTERM
+    #[arg(long, value_name = "DURATION", default_value = "10s")]
+    stop_grace: humantime::Duration,
+    /// what a stage exiting with CODES means: `warn` rather than fail, `run:<file>` a remediation
+    /// pipeline or `page:<command>`, e.g. `grep=1:warn` or `2,64-78:page:notify.sh`
+    #[arg(long, value_name = "[STAGE=]CODES:ACTION")]
+    on_exit: Vec<OnExit>,
+    /// where the last stage's stdout goes: `inherit`, `log` to `<log dir>/<stage>.stdout.log`,
+    /// `append:<path>` or `discard`
+    #[arg(long, value_name = "OUTPUT", default_value = "inherit")]
+    stdout: Output,
+}
+
+impl PipelineOptions {
+    fn apply(&self, pipeline: &mut Pipeline) {
+        pipeline.observe_syscalls(self.seccomp_observe.clone());
+        pipeline.instrument_links(self.instrument);
+        pipeline.set_pipe_sizes(self.pipe_size.clone());
+        pipeline.set_spools(self.spool.clone(), self.max_spool, self.on_spool_full, self.spool_compression.clone());
+        pipeline.set_max_run_bytes(self.max_run_bytes);
+        pipeline.set_workers(self.worker.clone());
+        pipeline.set_log_filter(LogFilter {
+            max_lines: self.max_log_lines,
+            collapse_repeats: self.collapse_repeats,
+            max_size: self.max_log_size.clone(),
+            on_full: self.on_log_full,
+            max_files: self.max_log_files,
+            compress: self.compress_logs,
+            timestamps: self.timestamp_logs,
+        });
+        pipeline.set_extra_fds(self.extra_fds.clone());
+        pipeline.set_numa(self.numa_cpu.clone(), self.numa_mem.clone());
+        pipeline.set_wrappers(self.wrapper.clone());
+        pipeline.set_shells(self.shell.clone());
+        pipeline.set_keep_tmpdir(self.keep_tmpdir);
+        pipeline.set_redact(self.redact.clone());
+        pipeline.set_core_dumps(self.core_dumps.then_some(CoreDumps { max_space: self.max_core_space }));
+        pipeline.set_critical(self.critical);
+        pipeline.set_schedule(Schedule { windows: self.window.clone(), blackouts: self.blackout.clone(), outside: self.outside_window });
+        pipeline.set_heartbeat(self.heartbeat.map(|every| Heartbeat { every: every.into(), file: self.heartbeat_file.clone() }));
+        if let Some(restart) = self.restart {
+            pipeline.set_restart(restart);
+        }
+        if let Some(timeout) = self.timeout {
+            pipeline.set_timeout(timeout.into());
+        }
+        pipeline.set_crash_loop(CrashLoop {
+            max_restarts: self.max_restarts,
+            window: self.restart_window.into(),
+            on_crash_loop: self.on_crash_loop.clone(),
+        });
+        pipeline.set_restart_backoff(Backoff {
+            initial: self.restart_delay.into(),
+            max: self.max_restart_delay.into(),
+        });
+        pipeline.set_spawn_order(self.spawn_order);
+        pipeline.set_upstream_exit(self.on_upstream_exit.clone());
+        pipeline.set_eof_hooks(self.on_eof.clone());
+        pipeline.set_readiness(self.ready.clone(), self.ready_timeout.into());
+        pipeline.set_preconditions(self.require.clone(), self.on_unmet);
+        pipeline.set_pipefail(self.pipefail);
+        pipeline.set_cooperative(self.cooperative.clone(), self.stop_grace.into());
+        pipeline.set_reporting(self.report_metrics.clone());
+        pipeline.set_output(self.stdout.clone());
+        pipeline.set_on_exit(self.on_exit.clone());
+    }
+}
+
+fn parse_size(size: &str) -> Result<u64, String> {
+    stages::parse_size(size).map_err(|e| e.to_string())
+}
+
+/// only run definitions whose sha256 is listed in a signed allowlist
+#[derive(clap::Args)]
+struct Approval {
+    /// allowlist in sha256sum format, signed with `ssh-keygen -Y sign -n plumber-allowlist`
+    #[arg(long, requires = "allowed_signers")]
+    allowlist: Option<PathBuf>,
+    /// ssh allowed_signers file with the keys trusted to sign the allowlist
+    #[arg(long, requires = "allowlist")]
+    allowed_signers: Option<PathBuf>,
+}
+
+impl Approval {
+    fn load(&self) -> Option<Allowlist> {
+        let (Some(allowlist), Some(signers)) = (&self.allowlist, &self.allowed_signers) else { return None };
+        match Allowlist::load(allowlist, signers) {
+            Ok(approved) => {
+                log::info!("only running definitions approved in {} (signed by {})", allowlist.display(), approved.signer);
+                Some(approved)
+            },
+            Err(e) => {
+                error!("{e}");
+                exit(1);
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum DiffAgainst {
+    Running,
+}
+
+#[derive(clap::Subcommand)]
+enum NamespaceAction {
+    /// create a namespace, or change its group and quotas
+    Create {
+        #[arg(value_parser = namespace::parse)]
+        name: String,
+        /// only this group, besides its owner, may access the namespace
+        #[arg(long)]
+        group: Option<String>,
+        /// pipelines that may run in the namespace at once
+        #[arg(long)]
+        max_pipelines: Option<usize>,
+        /// stages any one pipeline in the namespace may have
+        #[arg(long)]
+        max_stages: Option<usize>,
+    },
+    /// list namespaces with their quotas and running pipelines
+    List,
+}
+
+#[derive(clap::Subcommand)]
+enum ObserveView {
+    /// list pipelines with their state, uptime and number of stages
+    Status,
+    /// print the end of the stderr log of every stage of a pipeline
+    Logs {
+        /// path to plumber file, or name of a pipeline
+        name: String,
+        /// only the log of this stage
+        #[arg(short, long)]
+        stage: Option<String>,
+        /// lines to print from the end of each log
+        #[arg(short = 'n', long, default_value_t = 20)]
+        lines: usize,
+        /// keep printing what the stages write
+        #[arg(short, long)]
+        follow: bool,
+    },
+    /// print the state, last run and links of every pipeline in the prometheus text format
+    Metrics,
+}
+
+fn diff(old: &str, new: Option<&str>) {
+    let read = |name: &str| match Pipeline::read_definition(name) {
+        Ok(definition) => definition,
+        Err(_) => {
+            error!("no plumber file or previously run pipeline named '{name}'");
+            exit(2);
+        },
+    };
+
+    let (new_name, new_pipeline) = read(new.unwrap_or(old));
+    let (old_name, old_pipeline) = match new {
+        Some(_) => read(old),
+        // comparing a file against what is running: the running pipeline is the old side
+        None => {
+            if !Pipeline::is_running(&valid_name(&new_name)) {
+                error!("pipeline '{new_name}' is not running");
+                exit(2);
+            }
+            read(&new_name)
+        },
+    };
+
+    let parse = |name: &str, raw_pipeline: &str| Pipeline::parse_raw_pipeline(raw_pipeline).unwrap_or_else(|e| {
+        error!("{name}: {e}");
+        exit(2);
+    });
+    let changes = diff::diff(&parse(&old_name, &old_pipeline), &parse(&new_name, &new_pipeline));
+    println!("--- {old_name}");
+    println!("+++ {new_name}");
+    print!("{}", diff::format(&changes));
+    if changes.iter().any(|c| !matches!(c, diff::Change::Same(_))) {
+        exit(1);
+    }
+}
+
+fn edit(path: &Path, reload: bool) {
+    let Some(name) = path.file_stem().and_then(|stem| stem.to_str()).map(valid_name) else {
+        error!("'{}' is not a plumber file", path.display());
+        exit(2);
+    };
+    let running = Pipeline::read_definition(&name).ok().filter(|_| Pipeline::is_running(&name)).map(|(_, raw)| raw);
+    let saved = match fs::read_to_string(path) {
+        Ok(raw) => Some(raw),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => {
+            error!("{}: {e}", path.display());
+            exit(1);
+        },
+    };
+    // a new file starts out as what ran last under its name
+    let last_run = || fs::read_to_string(Pipeline::metadata_file(&name, ".pipeline")).ok();
+    let original = saved.clone().or_else(last_run).unwrap_or_default();
+
+    let draft = match edit::draft(&name, &original) {
+        Ok(draft) => draft,
+        Err(e) => {
+            error!("{name}: unable to write a draft to edit: {e}");
+            exit(1);
+        },
+    };
+    let editor = edit::editor();
+    let edited = loop {
+        match edit::open(&editor, &draft) {
+            Ok(status) if status.success() => {},
+            Ok(status) => {
+                error!("{editor} exited with {status}, leaving {} as it was", path.display());
+                let _ = fs::remove_file(&draft);
+                exit(1);
+            },
+            Err(e) => {
+                error!("unable to run {editor}: {e}");
+                let _ = fs::remove_file(&draft);
+                exit(1);
+            },
+        }
+        let edited = fs::read_to_string(&draft).unwrap_or_default();
+        match Pipeline::parse_raw_pipeline(&edited) {
+            Ok(commands) => {
+                for missing in edit::missing_programs(&commands) {
+                    log::warn!("{name}: {missing}");
+                }
+                break edited;
+            },
+            Err(e) => {
+                error!("{name}:{e}\n{}", e.show(&edited));
+                if !edit::confirm("edit again?") {
+                    error!("not saved, the edited definition is left in {}", draft.display());
+                    exit(1);
+                }
+            },
+        }
+    };
+    let _ = fs::remove_file(&draft);
+
+    if saved.as_deref() != Some(&edited) {
+        if let Err(e) = fs::write(path, &edited) {
+            error!("{}: {e}", path.display());
+            exit(1);
+        }
+        audit::record("edit", &name, &path.display().to_string());
+        log::info!("{name}: saved {}", path.display());
+    }
+    let Some(running) = running else { return };
+    let (Ok(old), Ok(new)) = (Pipeline::parse_raw_pipeline(&running), Pipeline::parse_raw_pipeline(&edited)) else { return };
+    let changes = diff::diff(&old, &new);
+    if changes.iter().all(|c| matches!(c, diff::Change::Same(_))) {
+        return;
+    }
+    println!("--- {name} (running)");
+    println!("+++ {}", path.display());
+    print!("{}", diff::format(&changes));
+    if !reload && !edit::confirm(&format!("reload {name} with the new definition?")) {
+        log::warn!("{name}: still running the old definition, see `plumber edit --reload`");
+        return;
+    }
+    audit::record("reload", &name, &path.display().to_string());
+    match Pipeline::reload(&name, &edited) {
+        Ok(()) => log::info!("{name}: reloading once its stages drained"),
+        Err(e) => {
+            error!("{name}: unable to reload: {e}");
+            exit(1);
+        },
     }
 }
 
-fn exec(name: String, pipeline: String) {
+fn exec(name: String, pipeline: String, allowlist: Option<Allowlist>, options: &PipelineOptions) {
+    let name = valid_name(&name);
     if pipeline.trim().is_empty() {
         error!("tried to execute empty pipeline");
         return;
     }
+    if allowlist.as_ref().is_some_and(|a| !a.allows(pipeline.as_bytes())) {
+        error!("{name}: pipeline is not in the approved allowlist, refusing to run it");
+        audit::record("refuse", &name, "not in allowlist");
+        exit(1);
+    }
+    if allowlist.is_some() && !options.wrapper.is_empty() {
+        error!("{name}: --wrapper would run programs the allowlist didn't approve, refusing to run it");
+        audit::record("refuse", &name, "wrapper with an allowlist");
+        exit(1);
+    }
 
-    let Ok(pipeline) = Pipeline::new(name.clone(), pipeline) else { return };
+    let mut pipeline = match Pipeline::new(name.to_string(), pipeline.clone()) {
+        Ok(pipeline) => pipeline,
+        Err(e @ PipelineError::ParseError { .. }) => {
+            error!("{name}:{e}\n{}", e.show(&pipeline));
+            exit(1);
+        },
+        Err(e) => {
+            error!("{name}: {e}");
+            exit(1);
+        },
+    };
+    options.apply(&mut pipeline);
+    pipeline.set_allowlist(allowlist.map(Arc::new));
+    audit::record("start", &name, "exec");
 
+    let signalled = name.clone();
     ctrlc::set_handler(move || {
-        if let Err(_) = Pipeline::stop(&name) {
+        let name = &signalled;
+        audit::record("signal", name, "termination signal forwarded to first process");
+        // the first stage may have exited by itself already
+        if let Err(e) = Pipeline::stop(name, StopMode::Drain).or_else(|e| match e {
+            PipelineError::NotRunning => Ok(()),
+            e => Err(e),
+        }) {
+            log::error!("{name}: {e}");
             log::error!("something went very wrong with the termination signal handler");
             log::error!("this may cause the pipeline to continue running in the background!");
             log::error!("you may be able to still gracefully kill the pipeline by finding the pid of the first \
@@ -58,10 +837,56 @@ fn exec(name: String, pipeline: String) {
         }
     }).unwrap();
 
-    pipeline.run();
+    match pipeline.run() {
+        Ok(status) => exit(status.code()),
+        Err(e) => {
+            error!("{name}: {e}");
+            exit(1);
+        },
+    }
+}
+
+/// runs this plumber again without `--detach`, in a session of its own logging to
+/// `<log dir>/<name>/plumber.log`, and exits
+fn run_detached(path: &Path) {
+    use std::os::unix::process::CommandExt;
+    let name = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("plumber");
+    let log_path = Pipeline::state_dirs().logs.join(name).join("plumber.log");
+    let log = fs::create_dir_all(log_path.parent().unwrap())
+        .and_then(|_| fs::OpenOptions::new().create(true).append(true).open(&log_path));
+    let log = match log {
+        Ok(log) => log,
+        Err(e) => {
+            error!("{}: {e}", log_path.display());
+            exit(1);
+        },
+    };
+    let args = std::env::args_os().skip(1).filter(|arg| arg != "--detach");
+    let mut command = std::process::Command::new(std::env::current_exe().unwrap_or_else(|_| PathBuf::from("plumber")));
+    command.args(args)
+        .stdin(std::process::Stdio::null())
+        .stdout(log.try_clone().map_or(std::process::Stdio::null(), std::process::Stdio::from))
+        .stderr(log);
+    // no longer hung up on, or signalled with the terminal's process group
+    unsafe {
+        command.pre_exec(|| match libc::setsid() {
+            -1 => Err(std::io::Error::last_os_error()),
+            _ => Ok(()),
+        });
+    }
+    match command.spawn() {
+        Ok(child) => {
+            println!("{name}: running in the background as pid {}, logging to {}", child.id(), log_path.display());
+            exit(0);
+        },
+        Err(e) => {
+            error!("unable to detach: {e}");
+            exit(1);
+        },
+    }
 }
 
-fn stop(path: PathBuf, timeout: u32) {
+fn stop(path: PathBuf, timeout: u32, grace: u32, mode: StopMode) {
     let names = match path.is_dir() {
         true => {
             let mut plumb_files = Vec::new();
@@ -70,105 +895,869 @@ fn stop(path: PathBuf, timeout: u32) {
                 let file = file.path();
                 if file.is_dir() { continue }
                 let Some(ext) = file.extension() else { continue };
-                if ext.eq_ignore_ascii_case("plumb") {
-                    let name = file.file_stem()
-                        .unwrap()
-                        .to_str()
-                        .unwrap()
-                        .to_owned();
-
-                    plumb_files.push(name);
+                if definition::is_plumber_extension(ext) {
+                    plumb_files.push(file_name(&file));
                 }
             }
             plumb_files
         },
         false => {
-            let name = path.file_stem()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_owned();
-
-            vec![name]
+            vec![file_name(&path)]
         }
     };
 
     for name in &names {
-        if let Err(e) = Pipeline::stop(&name) {
+        audit::record("stop", name, match mode {
+            StopMode::Drain => "",
+            StopMode::Group => "process groups",
+        });
+        if let Err(e) = Pipeline::stop(name, mode) {
             match e {
-                pipeline::PipelineError::FileNotFound => log::warn!("unabled to find pid for name '{}'", name),
-                pipeline::PipelineError::Other => log::error!("{:#?}", e),
+                PipelineError::MetadataIo { source, .. } if source.kind() == std::io::ErrorKind::NotFound => {
+                    log::warn!("unabled to find pid for name '{}'", name)
+                },
+                PipelineError::NotRunning => log::warn!("{name}: not running, its first stage is gone"),
+                PipelineError::PermissionDenied => log::error!("{name}: not permitted to signal its stages, they run as another user"),
+                e => log::error!("{name}: {e}"),
             }
         }
     }
 
-    for _ in 0..=timeout {
-        if !names.iter().any(|n| path.join(n).join(".pid").exists()) {
+    let started = Instant::now();
+    let mut killed = false;
+    loop {
+        let left: Vec<&ValidatedName> = names.iter().filter(|n| Pipeline::is_alive(n)).collect();
+        if left.is_empty() {
             break;
         }
-        thread::sleep(Duration::from_secs(1));
+        let waited = started.elapsed();
+        if !killed && waited >= Duration::from_secs(grace.into()) {
+            for name in &left {
+                log::warn!("{name}: still running {grace}s after being stopped, killing its stages");
+                Pipeline::kill(name);
+            }
+            killed = true;
+        }
+        if waited >= Duration::from_secs(timeout.into()) {
+            for name in &left {
+                error!("{name}: still running {timeout}s after being stopped");
+            }
+            exit(1);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// pipeline name of a plumber file, or the name itself
+fn pipeline_name(path_or_name: &str) -> ValidatedName {
+    let path = Path::new(path_or_name);
+    match path.is_file() {
+        true => file_name(path),
+        false => valid_name(path_or_name),
+    }
+}
+
+/// the name of plumber file `path` if it may name a pipeline, exits otherwise
+fn file_name(path: &Path) -> ValidatedName {
+    match names::of_file(path) {
+        Ok(name) => valid_name(&name),
+        Err(e) => {
+            error!("{e}");
+            exit(2);
+        },
+    }
+}
+
+/// `name` if it may name a pipeline, exits otherwise
+fn valid_name(name: &str) -> ValidatedName {
+    ValidatedName::new(name).unwrap_or_else(|e| {
+        error!("{e}");
+        exit(2);
+    })
+}
+
+fn rollback(path: &Path, to: &str) {
+    let pinned = match revision::rollback(path, to) {
+        Ok(pinned) => pinned,
+        Err(e) => {
+            error!("{}: unable to roll back to {to}: {e}", path.display());
+            exit(1);
+        },
+    };
+    audit::record("rollback", &path.display().to_string(), &pinned);
+    match revision::pinned(path) {
+        true => log::info!("{}: pinned to {pinned}, pulls leave it there until rolled back to a branch", path.display()),
+        false => log::info!("{}: following {to} again, at {pinned}", path.display()),
+    }
+}
+
+fn reset(name: &str) {
+    let name = &pipeline_name(name);
+    match Pipeline::reset(name) {
+        Ok(true) => {
+            audit::record("reset", name, "");
+            log::info!("{name}: crash-loop cleared, the pipeline can be started again");
+        },
+        Ok(false) => log::info!("{name}: pipeline is not crash-looped, cleared its restart backoff"),
+        Err(e) => {
+            error!("{name}: unable to reset: {e}");
+            exit(1);
+        },
+    }
+}
+
+fn upgrade_daemon(name: &str, timeout: u32) {
+    let name = &pipeline_name(name);
+    let Ok(pid) = Pipeline::supervisor(name) else {
+        error!("pipeline '{name}' is not running");
+        exit(1);
+    };
+    let supervisor_file = Pipeline::metadata_file(name, ".supervisor");
+    let modified = || fs::metadata(&supervisor_file).and_then(|m| m.modified()).ok();
+    let before = modified();
+
+    audit::record("upgrade", name, &format!("supervisor pid {pid}"));
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGUSR2) } < 0 {
+        error!("unable to signal plumber (pid {pid}): {}", std::io::Error::last_os_error());
+        exit(1);
+    }
+    for _ in 0..timeout * 10 {
+        thread::sleep(Duration::from_millis(100));
+        if modified() != before {
+            log::info!("{name}: upgraded plumber (pid {pid}) took over");
+            return;
+        }
+    }
+    error!("{name}: plumber (pid {pid}) did not take over within {timeout}s, see its log");
+    exit(1);
+}
+
+fn self_update(url: &str, allowed_signers: &Path) {
+    match update::self_update(url, allowed_signers) {
+        Ok(update::Updated::AlreadyCurrent) => log::info!("already running the latest release"),
+        Ok(update::Updated::Installed { signer, version }) => {
+            audit::record("self-update", "", &format!("{version} from {url}, signed by {signer}"));
+            log::info!("installed {version} signed by {signer}");
+            log::info!("running pipelines keep the old binary until `plumber upgrade-daemon <name>`");
+        },
+        Err(e) => {
+            error!("{e}");
+            exit(1);
+        },
+    }
+}
+
+/// the plumber files `path` stands for, the pipelines of an installed package if there's no
+/// such file
+fn package_path(path: &Path) -> PathBuf {
+    if path.exists() {
+        return path.to_owned();
+    }
+    match path.to_str().and_then(packages::find) {
+        Some(installed) => {
+            log::info!("running package {} {}", installed.manifest.name, installed.manifest.version);
+            installed.set_env();
+            installed.pipelines()
+        },
+        None => path.to_owned(),
+    }
+}
+
+fn install(source: &str, force: bool) {
+    match packages::install(source, force) {
+        Ok(installed) => {
+            let manifest = &installed.manifest;
+            audit::record("install", &manifest.name, &format!("{} from {source}", manifest.version));
+            log::info!("installed {} {}, `plumber run {}` to run it", manifest.name, manifest.version, manifest.name);
+        },
+        Err(e) => {
+            error!("{source}: {e}");
+            exit(1);
+        },
+    }
+}
+
+fn list_packages() {
+    let packages = packages::list();
+    let width = packages.iter().map(|(name, _)| name.len()).max().unwrap_or(0).max("PACKAGE".len());
+    let newest = packages.iter().map(|(_, versions)| versions.last().unwrap().manifest.version.len()).max().unwrap_or(0).max("VERSION".len());
+    println!("{:width$}  {:newest$}  {:<24}  DESCRIPTION", "PACKAGE", "VERSION", "ALSO INSTALLED");
+    for (name, versions) in packages {
+        let (current, older) = versions.split_last().unwrap();
+        let older: Vec<_> = older.iter().rev().map(|installed| installed.manifest.version.as_str()).collect();
+        let older = if older.is_empty() { "-".to_string() } else { older.join(",") };
+        let line = format!("{name:width$}  {:newest$}  {older:<24}  {}", current.manifest.version, current.manifest.description.as_deref().unwrap_or(""));
+        println!("{}", line.trim_end());
+    }
+}
+
+fn parse(name: &str, debug: bool, probes: &[Probe]) {
+    let definition = match name {
+        "-" => std::io::read_to_string(std::io::stdin()).map(|raw| ("stdin".to_string(), raw)).map_err(|source| PipelineError::MetadataIo { path: PathBuf::from("-"), source }),
+        name => Pipeline::read_definition(name),
+    };
+    let Ok((name, raw_pipeline)) = definition else {
+        error!("no plumber file or previously run pipeline named '{name}'");
+        exit(1);
+    };
+    if let Err(e) = Pipeline::parse_raw_pipeline(&raw_pipeline) {
+        error!("{name}:{e}\n{}", e.show(&raw_pipeline));
+        exit(1);
+    }
+    // a structured definition has no words to show the spans of
+    match (debug, parser::parse(&raw_pipeline)) {
+        (true, Ok(stages)) => print!("{}", parser::tree(&stages)),
+        _ => {
+            for line in Pipeline::stage_command_lines(&raw_pipeline).unwrap_or_default() {
+                println!("{line}");
+            }
+        },
+    }
+    let mut failed = false;
+    for probe in probes {
+        match probe.check() {
+            Ok(()) => log::info!("{name}: probe {probe} passed"),
+            Err(reason) => {
+                error!("{name}: probe {probe} failed: {reason}");
+                failed = true;
+            },
+        }
+    }
+    if failed {
+        exit(1);
+    }
+}
+
+fn graph(name: &str, format: graph::Format) {
+    let Ok((name, raw_pipeline)) = Pipeline::read_definition(name) else {
+        error!("no plumber file or previously run pipeline named '{name}'");
+        exit(1);
+    };
+    let stages = Pipeline::stage_command_lines(&raw_pipeline).unwrap_or_else(|e| {
+        error!("{name}: {e}");
+        exit(1);
+    });
+    let links = Pipeline::link_annotations(&valid_name(&name));
+    print!("{}", graph::render(&name, &stages, &links, format));
+}
+
+fn dump(name: &ValidatedName, timeout: u32) {
+    let flight = Pipeline::metadata_file(name, recorder::FLIGHT);
+    let print = || match fs::read_to_string(&flight) {
+        Ok(events) => print!("{events}"),
+        Err(e) => {
+            error!("{name}: unable to read {}: {e}", flight.display());
+            exit(1);
+        },
+    };
+    let Ok(pid) = Pipeline::supervisor(name) else {
+        if !flight.exists() {
+            error!("pipeline '{name}' is not running and no failed run left events behind");
+            exit(1);
+        }
+        log::info!("{name}: not running, events as of its last failed run");
+        print();
+        return;
+    };
+    let modified = || fs::metadata(&flight).and_then(|m| m.modified()).ok();
+    let before = modified();
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGUSR1) } < 0 {
+        error!("unable to signal plumber (pid {pid}): {}", std::io::Error::last_os_error());
+        exit(1);
+    }
+    for _ in 0..timeout * 10 {
+        thread::sleep(Duration::from_millis(100));
+        if modified() != before {
+            print();
+            return;
+        }
+    }
+    error!("{name}: plumber (pid {pid}) did not write its events within {timeout}s");
+    exit(1);
+}
+
+fn wrap(name: &ValidatedName, rules: &[Wrapper]) {
+    let metadata_dir = Pipeline::metadata_file(name, "");
+    if !metadata_dir.is_dir() {
+        error!("no pipeline named '{name}' has been run");
+        exit(1);
+    }
+    if let Err(e) = wrapper::save(&metadata_dir, rules) {
+        error!("{name}: unable to set wrappers: {e}");
+        exit(1);
+    }
+    let rules: Vec<String> = rules.iter().map(Wrapper::to_string).collect();
+    audit::record("wrap", name, &rules.join(", "));
+    match Pipeline::is_running(name) {
+        true => log::info!("{name}: wrappers apply once its stages are restarted"),
+        false => log::info!("{name}: wrappers apply from its next run"),
+    }
+}
+
+fn archive(name: &ValidatedName, archive: Archive) {
+    let runs = Pipeline::metadata_file(name, archive::RUNS);
+    match archive.write(&runs) {
+        Ok(output) => log::info!("{name}: archived run to {}", output.display()),
+        Err(e) => {
+            error!("{name}: unable to archive: {e}");
+            exit(1);
+        },
+    }
+}
+
+fn soak(path: &str, duration: Duration, input: Option<&str>, interval: Duration, max_rss_growth: f64, options: &PipelineOptions) {
+    let (name, raw_pipeline) = match Pipeline::read_definition(path) {
+        Ok(definition) => definition,
+        Err(e) => {
+            error!("{path}: unable to read pipeline definition: {e}");
+            exit(1);
+        },
+    };
+    // never the name of the pipeline running for real
+    let name = valid_name(&soak::name(&name));
+    let raw_pipeline = match input {
+        Some(input) => format!("{input} | {}", raw_pipeline.trim()),
+        None => raw_pipeline,
+    };
+    let mut pipeline = match Pipeline::new(name.to_string(), raw_pipeline.clone()) {
+        Ok(pipeline) => pipeline,
+        Err(e @ PipelineError::ParseError { .. }) => {
+            error!("{name}:{e}\n{}", e.show(&raw_pipeline));
+            exit(1);
+        },
+        Err(e) => {
+            error!("{name}: unable to set up pipeline: {e}");
+            exit(1);
+        },
+    };
+    options.apply(&mut pipeline);
+    pipeline.instrument_links(true);
+    // what it writes would drown out the trends
+    pipeline.set_output(Output::Discard);
+    let stages = pipeline.stage_names();
+    let metadata_dir = Pipeline::metadata_file(&name, "");
+    let _ = fs::remove_file(metadata_dir.join("soak.ndjson"));
+    audit::record("start", &name, "soak");
+    let stopping = name.clone();
+    ctrlc::set_handler(move || {
+        let _ = Pipeline::stop(&stopping, StopMode::Drain);
+    }).unwrap();
+
+    log::info!("{name}: soaking for {}", humantime::format_duration(duration));
+    let running = thread::spawn(move || pipeline.run());
+    let started = Instant::now();
+    let mut soak = soak::Soak::new(max_rss_growth);
+    let mut next = started;
+    while started.elapsed() < duration && !running.is_finished() {
+        next = (next + interval).min(started + duration);
+        while Instant::now() < next && !running.is_finished() {
+            thread::sleep(next.saturating_duration_since(Instant::now()).min(Duration::from_millis(100)));
+        }
+        let pids = Pipeline::stage_pids(&name).into_iter().chain(std::iter::repeat(None));
+        let stages: Vec<(String, Option<u32>)> = stages.iter().cloned().zip(pids).collect();
+        match soak.record(soak::sample(started.elapsed(), &stages, &metadata_dir), &metadata_dir) {
+            Ok(line) => println!("{line}"),
+            Err(e) => log::warn!("{name}: unable to record soak sample: {e}"),
+        }
+    }
+
+    let mut problems = Vec::new();
+    match running.is_finished() {
+        true => {
+            let ended = match running.join().unwrap() {
+                Ok(status) => format!("with {status}"),
+                Err(e) => format!("unable to start: {e}"),
+            };
+            problems.push(format!("pipeline ended after {}s of {}s {ended}", started.elapsed().as_secs(), duration.as_secs()));
+        },
+        false => {
+            audit::record("stop", &name, "soak");
+            let _ = Pipeline::stop(&name, StopMode::Drain);
+            let stopped = Instant::now();
+            while !running.is_finished() {
+                if stopped.elapsed() >= Duration::from_secs(10) {
+                    log::warn!("{name}: still running 10s after being stopped, killing its stages");
+                    Pipeline::kill(&name);
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+            let _ = running.join();
+        },
+    }
+    problems.extend(soak.problems());
+    match problems.is_empty() {
+        true => println!("{name}: stable for {}", humantime::format_duration(duration)),
+        false => {
+            for problem in &problems {
+                println!("{name}: {problem}");
+            }
+            exit(1);
+        },
+    }
+}
+
+fn status() {
+    let statuses = match status::statuses(&Pipeline::state_dirs().metadata, std::time::SystemTime::now()) {
+        Ok(statuses) => statuses,
+        Err(e) => {
+            error!("unable to list pipelines: {e}");
+            exit(1);
+        },
+    };
+    let width = statuses.iter().map(|s| s.name.len()).max().unwrap_or(0).max("NAME".len());
+    println!("{:width$}  {:8}  {:>6}  {:12}  METRICS", "NAME", "STATE", "STAGES", "UPTIME");
+    for status in statuses {
+        let stages = status.stages.map_or("?".to_string(), |stages| stages.to_string());
+        let uptime = status.uptime.map_or("-".to_string(), status::format_uptime);
+        let metrics = ValidatedName::new(status.name.as_str())
+            .map_or_else(|_| String::new(), |name| metrics::Metrics::load(&Pipeline::metadata_file(&name, "")).summary());
+        let line = format!("{:width$}  {:8}  {stages:>6}  {uptime:12}  {metrics}", status.name, status.state.to_string());
+        println!("{}", line.trim_end());
+    }
+}
+
+fn create_namespace(name: &str, group: Option<&str>, quota: namespace::Quota) {
+    let root = namespace::root(&pipeline::shared_root(), name);
+    if let Err(e) = namespace::create(&root, group).and_then(|_| quota.save(&pipeline::shared_root(), name)) {
+        error!("{name}: unable to create namespace in {}: {e}", root.display());
+        exit(1);
+    }
+    log::info!("{name}: namespace in {}", root.display());
+}
+
+fn list_namespaces() {
+    let shared_root = pipeline::shared_root();
+    let namespaces = match namespace::list(&shared_root) {
+        Ok(namespaces) => namespaces,
+        Err(e) => {
+            error!("unable to list namespaces: {e}");
+            exit(1);
+        },
+    };
+    let width = namespaces.iter().map(|(name, _)| name.len()).max().unwrap_or(0).max("NAMESPACE".len());
+    println!("{:width$}  {:>7}  {:>13}  MAX STAGES", "NAMESPACE", "RUNNING", "MAX PIPELINES");
+    let limit = |limit: Option<usize>| limit.map_or("-".to_string(), |limit| limit.to_string());
+    for (name, quota) in namespaces {
+        let metadata = namespace::root(&shared_root, &name).join("lib");
+        let running = status::statuses(&metadata, std::time::SystemTime::now()).unwrap_or_default().iter()
+            .filter(|s| s.state == status::State::Running)
+            .count();
+        println!("{name:width$}  {running:>7}  {:>13}  {}", limit(quota.max_pipelines), limit(quota.max_stages));
+    }
+}
+
+fn observe_logs(name: &str, stage: Option<&str>, lines: usize, follow: bool, color: bool) {
+    let logs = match observe::logs(&Pipeline::state_dirs().logs.join(name), stage) {
+        Ok(logs) if !logs.is_empty() => logs,
+        Ok(_) => {
+            error!("{name}: no stderr logs{}", stage.map_or(String::new(), |stage| format!(" of stage '{stage}'")));
+            exit(1);
+        },
+        Err(e) => {
+            error!("{name}: unable to read its logs: {e}");
+            exit(1);
+        },
+    };
+    let prefixes: Vec<String> = logs.iter().enumerate().map(|(i, (stage, _))| observe::prefix(stage, i, color)).collect();
+    let mut offsets = Vec::new();
+    for ((_, path), prefix) in logs.iter().zip(&prefixes) {
+        let (tail, offset) = observe::tail(path, lines).unwrap_or_default();
+        for line in tail {
+            println!("{prefix}{line}");
+        }
+        offsets.push(offset);
+    }
+    if !follow {
+        return;
+    }
+    loop {
+        thread::sleep(Duration::from_millis(250));
+        for (((_, path), offset), prefix) in logs.iter().zip(&mut offsets).zip(&prefixes) {
+            for line in observe::appended(path, offset).unwrap_or_default().lines() {
+                println!("{prefix}{line}");
+            }
+        }
     }
 }
 
-fn run(path: PathBuf) {
+fn observe_metrics() {
+    match observe::metrics(&Pipeline::state_dirs().metadata, std::time::SystemTime::now()) {
+        Ok(metrics) => print!("{metrics}"),
+        Err(e) => {
+            error!("unable to list pipelines: {e}");
+            exit(1);
+        },
+    }
+}
+
+fn prune(dry_run: bool, older_than: Duration) {
+    let leftovers = match prune::leftovers(&Pipeline::state_dirs(), older_than, std::time::SystemTime::now()) {
+        Ok(leftovers) => leftovers,
+        Err(e) => {
+            error!("unable to find what to prune: {e}");
+            exit(1);
+        },
+    };
+    let mut reclaimed = 0;
+    for (pipeline, leftovers) in leftovers.chunk_by(|a, b| a.pipeline == b.pipeline).map(|l| (&l[0].pipeline, l)) {
+        let size: u64 = leftovers.iter().map(|l| l.size).sum();
+        if dry_run {
+            println!("{pipeline}: {} reclaimable", prune::human_size(size));
+            for leftover in leftovers {
+                println!("  {:>8}  {}", prune::human_size(leftover.size), leftover.path.display());
+            }
+            reclaimed += size;
+            continue;
+        }
+        audit::record("prune", pipeline, &format!("{size} bytes"));
+        for leftover in leftovers {
+            match leftover.remove() {
+                Ok(()) => reclaimed += leftover.size,
+                Err(e) => log::warn!("{pipeline}: unable to remove {}: {e}", leftover.path.display()),
+            }
+        }
+        log::info!("{pipeline}: pruned {}", prune::human_size(size));
+    }
+    match dry_run {
+        true => println!("{} reclaimable in total", prune::human_size(reclaimed)),
+        false => log::info!("reclaimed {}", prune::human_size(reclaimed)),
+    }
+}
+
+fn run(path: PathBuf, watch: bool, git_pull: Option<Duration>, allowlist: Option<Allowlist>, options: &PipelineOptions) {
+    // shared with every pipeline, which checks it again when reloaded
+    let allowlist = allowlist.map(Arc::new);
+    if allowlist.is_some() && !options.wrapper.is_empty() {
+        error!("--wrapper would run programs the allowlist didn't approve, refusing to run");
+        exit(1);
+    }
+    // set up before the first pipeline starts, so no file dropped in meanwhile is missed
+    let watching = match watch {
+        true => match adopt::Watch::new(&path) {
+            Ok(watching) => Some(watching),
+            Err(e) => {
+                error!("{}: unable to watch for plumber files: {e}", path.display());
+                exit(1);
+            },
+        },
+        false => None,
+    };
 
     let files = match path.is_dir() {
         true => {
             let mut plumb_files = Vec::new();
-            for file in fs::read_dir(path).unwrap() {
+            for file in fs::read_dir(&path).unwrap() {
                 let Ok(file) = file else { continue };
                 let file = file.path();
                 if file.is_dir() { continue }
                 let Some(ext) = file.extension() else { continue };
-                if ext.eq_ignore_ascii_case("plumb") {
+                if definition::is_plumber_extension(ext) {
                     plumb_files.push(file);
                 }
             }
             plumb_files
         },
-        false => vec![path]
+        false => vec![path.clone()]
     };
 
-    let mut handles = Vec::new();
-    let mut names = Vec::new();
+    let mut running = Vec::new();
     for f in files {
-        let Ok(pipeline) = Pipeline::new_from_file(&f) else { continue };
-        let name = pipeline.get_name();
-        handles.push(thread::spawn(move || pipeline.run()));
-        names.push(name);
+        running.extend(start(f, allowlist.as_ref(), options));
     }
 
+    // the pipelines adopted later are stopped on a termination signal too
+    let names = Arc::new(Mutex::new(running.iter().map(|(_, name, _)| ValidatedName::clone(name)).collect::<Vec<_>>()));
+    let stopping = Arc::new(AtomicBool::new(false));
+    let (signalled, stopped) = (names.clone(), stopping.clone());
     ctrlc::set_handler(move || {
-        for name in &names {
-            if let Err(e) = Pipeline::stop(&name) {
+        stopped.store(true, Ordering::SeqCst);
+        for name in signalled.lock().unwrap().iter() {
+            audit::record("signal", name, "termination signal forwarded to first process");
+            if let Err(e) = Pipeline::stop(name, StopMode::Drain).or_else(|e| match e {
+                PipelineError::NotRunning => Ok(()),
+                e => Err(e),
+            }) {
                 log::error!("something went very wrong with the termination signal handler");
                 log::error!("this may cause the pipeline to continue running in the background!");
                 log::error!("you may be able to still gracefully kill the pipeline by finding the pid of the first \
                             process in the pipeline and killing it manually");
-                log::error!("{:?}", e);
+                log::error!("{name}: {e}");
             }
         }
     }).unwrap();
 
-    for handle in handles {
-        handle.join().unwrap();
+    let exited = |name: &ValidatedName, handle: Supervising| match handle.join().unwrap() {
+        Ok(status) => status.code(),
+        Err(e) => {
+            error!("{name}: {e}");
+            1
+        },
+    };
+    // that of the first pipeline that failed, in the order they were started
+    let mut codes = Vec::new();
+    if let Some(mut watching) = watching {
+        log::info!("{}: watching for plumber files", path.display());
+        let mut pulled = Instant::now();
+        while !stopping.load(Ordering::SeqCst) {
+            if git_pull.is_some_and(|every| pulled.elapsed() >= every) {
+                pulled = Instant::now();
+                match revision::pull(&path) {
+                    Ok(Some(head)) => log::info!("{}: pulled {head}", path.display()),
+                    Ok(None) => {},
+                    Err(e) => log::warn!("{}: unable to pull, running what's checked out: {e}", path.display()),
+                }
+            }
+            let changes = match watching.changes(Duration::from_secs(1)) {
+                Ok(changes) => changes,
+                Err(e) => {
+                    error!("{}: unable to watch for plumber files, no longer adopting them: {e}", path.display());
+                    break;
+                },
+            };
+            for change in changes {
+                match change {
+                    adopt::Change::Written(f) => match running.iter().position(|(file, ..)| *file == f) {
+                        Some(i) if !running[i].2.is_finished() => readopt(&f, &running[i].1, allowlist.as_deref()),
+                        found => {
+                            if let Some(i) = found {
+                                let (_, name, handle) = running.remove(i);
+                                codes.push(exited(&name, handle));
+                            }
+                            let Some(started) = start(f, allowlist.as_ref(), options) else { continue };
+                            names.lock().unwrap().push(started.1.clone());
+                            running.push(started);
+                        },
+                    },
+                    adopt::Change::Removed(f) => {
+                        let Some((_, name, _)) = running.iter().find(|(file, ..)| *file == f) else { continue };
+                        audit::record("stop", name, "definition removed");
+                        log::info!("{name}: {} was removed, stopping it", f.display());
+                        match Pipeline::stop(name, StopMode::Drain) {
+                            Ok(()) | Err(PipelineError::NotRunning) => {},
+                            Err(e) => error!("{name}: unable to stop: {e}"),
+                        }
+                        names.lock().unwrap().retain(|signalled| signalled != name);
+                    },
+                }
+            }
+        }
     }
 
+    codes.extend(running.into_iter().map(|(_, name, handle)| exited(&name, handle)));
+    exit(codes.into_iter().find(|code| *code != 0).unwrap_or(0));
+}
+
+/// the thread supervising a pipeline
+type Supervising = thread::JoinHandle<Result<PipelineExitStatus, PipelineError>>;
+
+/// starts supervising the pipeline of plumber file `f` on a thread of its own
+fn start(f: PathBuf, allowlist: Option<&Arc<Allowlist>>, options: &PipelineOptions) -> Option<(PathBuf, ValidatedName, Supervising)> {
+    let mut pipeline = match Pipeline::new_from_file(&f) {
+        Ok(pipeline) => pipeline,
+        Err(e @ PipelineError::ParseError { .. }) => {
+            let line = fs::read_to_string(&f).map(|raw| e.show(&raw)).unwrap_or_default();
+            error!("{}:{e}\n{line}", f.display());
+            return None;
+        },
+        Err(e) => {
+            error!("{}: {e}", f.display());
+            return None;
+        },
+    };
+    if allowlist.is_some_and(|a| !a.allows(pipeline.get_raw_pipeline().as_bytes())) {
+        error!("{}: definition is not in the approved allowlist, not running it", f.display());
+        audit::record("refuse", &pipeline.get_name(), "not in allowlist");
+        return None;
+    }
+    audit::record("start", &pipeline.get_name(), &f.display().to_string());
+    options.apply(&mut pipeline);
+    pipeline.set_allowlist(allowlist.cloned());
+    let name = pipeline.validated_name().clone();
+    Some((f, name, thread::spawn(move || pipeline.run())))
+}
+
+/// reloads a running pipeline whose plumber file was written again while watching its directory
+fn readopt(f: &Path, name: &ValidatedName, allowlist: Option<&Allowlist>) {
+    let raw_pipeline = match fs::read_to_string(f) {
+        Ok(raw_pipeline) => raw_pipeline,
+        Err(e) => {
+            error!("{}: {e}", f.display());
+            return;
+        },
+    };
+    if allowlist.is_some_and(|a| !a.allows(raw_pipeline.as_bytes())) {
+        error!("{}: definition is not in the approved allowlist, still running the old one", f.display());
+        audit::record("refuse", name, "not in allowlist");
+        return;
+    }
+    audit::record("reload", name, &f.display().to_string());
+    match Pipeline::reload(name, &raw_pipeline) {
+        Ok(()) => log::info!("{name}: {} changed, reloading once its stages drained", f.display()),
+        Err(e @ PipelineError::ParseError { .. }) => error!("{}:{e}\n{}", f.display(), e.show(&raw_pipeline)),
+        Err(e) => error!("{name}: unable to reload: {e}"),
+    }
+}
+
+fn control(action: control::Action, text: &str) {
+    let mut client = match control::Client::connect() {
+        Some(Ok(client)) => client,
+        Some(Err(e)) => {
+            error!("unable to connect to ${}: {e}", control::ENV);
+            exit(1);
+        },
+        None => {
+            error!("${} isn't set, the stage isn't run with --cooperative", control::ENV);
+            exit(1);
+        },
+    };
+    let done = match action {
+        control::Action::Ready => client.ready().map(|_| true),
+        control::Action::Progress => client.progress(text).map(|_| true),
+        control::Action::WaitStop => client.wait_for_stop(),
+    };
+    match done {
+        Ok(true) => {},
+        Ok(false) => exit(1),
+        Err(e) => {
+            error!("unable to talk to plumber: {e}");
+            exit(1);
+        },
+    }
 }
 
 fn main() {
+    recorder::init();
+    upgrade::init();
     let args = Args::parse();
-    env_logger::init();
+    pipeline::set_dirs(args.state_dir.clone(), args.log_dir.clone());
+    pipeline::set_expand(!args.no_expand);
+    templates::set(args.set.clone());
+    if let Err(e) = namespace::set(args.namespace.clone()) {
+        error!("${}: {e}", namespace::ENV);
+        exit(2);
+    }
 
     match &args.command {
-        Subargs::Exec { pipeline, name  } => {
-            exec(name.to_string(), pipeline.to_string());
+        Subargs::Exec { pipeline, name, approval, options } => {
+            version::banner();
+            watchdog::start();
+            exec(name.to_string(), pipeline.to_string(), approval.load(), options);
+        },
+        Subargs::Run { path, detach, watch, git_pull, approval, options } => {
+            if *detach {
+                run_detached(path);
+            }
+            let path = package_path(path);
+            version::banner();
+            watchdog::start();
+            run(path, *watch, git_pull.map(Into::into), approval.load(), options);
+        },
+        Subargs::Stop { path, timeout, grace, mode } => {
+            stop(package_path(path), *timeout, *grace, *mode);
+        },
+        Subargs::Rollback { path, to } => {
+            rollback(path, to);
+        },
+        Subargs::Reset { name } => {
+            reset(name);
+        },
+        Subargs::UpgradeDaemon { name, timeout } => {
+            upgrade_daemon(name, *timeout);
+        },
+        Subargs::SelfUpdate { url, allowed_signers } => {
+            self_update(url, allowed_signers);
+        },
+        Subargs::Install { source, force } => {
+            install(source, *force);
         },
-        Subargs::Run { path } => {
-            run(path.into());
+        Subargs::Packages => {
+            list_packages();
         },
-        Subargs::Stop { path , timeout} => {
-            stop(path.into(), *timeout);
+        Subargs::Parse { name, debug, probe } => {
+            parse(name, *debug, probe);
+        },
+        Subargs::Edit { path, reload } => {
+            edit(path, *reload);
+        },
+        Subargs::Graph { name, format } => {
+            graph(name, *format);
+        },
+        Subargs::Diff { old, new, against } => {
+            match against {
+                Some(DiffAgainst::Running) => diff(old, None),
+                None => diff(old, new.as_deref()),
+            }
+        },
+        Subargs::Dump { name, timeout } => {
+            dump(&pipeline_name(name), *timeout);
+        },
+        Subargs::Wrap { name, rules } => {
+            wrap(&pipeline_name(name), rules);
+        },
+        Subargs::Archive { name, run, format, output, redact } => {
+            let name = &pipeline_name(name);
+            archive(name, Archive {
+                name,
+                run: run.as_deref(),
+                format: *format,
+                output: output.as_deref(),
+                redact,
+            });
+        },
+        Subargs::Soak { path, duration, input, interval, max_rss_growth, options } => {
+            soak(path, (*duration).into(), input.as_deref(), (*interval).into(), *max_rss_growth, options);
+        },
+        Subargs::Status => {
+            status();
+        },
+        Subargs::Namespace { action } => match action {
+            NamespaceAction::Create { name, group, max_pipelines, max_stages } => {
+                let quota = namespace::Quota { max_pipelines: *max_pipelines, max_stages: *max_stages };
+                create_namespace(name, group.as_deref(), quota);
+            },
+            NamespaceAction::List => list_namespaces(),
+        },
+        Subargs::Logs { name, stage, lines, follow, color } => {
+            observe_logs(&pipeline_name(name), stage.as_deref(), *lines, *follow, color.enabled());
+        },
+        Subargs::Observe { view } => match view {
+            ObserveView::Status => status(),
+            ObserveView::Logs { name, stage, lines, follow } => observe_logs(&pipeline_name(name), stage.as_deref(), *lines, *follow, false),
+            ObserveView::Metrics => observe_metrics(),
+        },
+        Subargs::Prune { dry_run, older_than } => {
+            prune(*dry_run, (*older_than).into());
+        },
+        Subargs::Version { json } => {
+            match json {
+                true => println!("{}", version::json()),
+                false => print!("{}", version::text()),
+            }
+        },
+        Subargs::Overhead { name } => {
+            let name = &pipeline_name(name);
+            match overhead::report(&Pipeline::metadata_file(name, "")) {
+                Some(lines) => lines.iter().for_each(|line| println!("{line}")),
+                None => {
+                    error!("{name}: no overhead recorded, it hasn't been run");
+                    exit(1);
+                },
+            }
+        },
+        Subargs::Progress { name } => {
+            for (stage, text) in control::progress(&Pipeline::metadata_file(&pipeline_name(name), "")) {
+                println!("{stage}: {text}");
+            }
+        },
+        Subargs::Control { action, text } => {
+            control(*action, &text.join(" "));
+        },
+        Subargs::Shuttle => exit(worker::shuttle()),
+        Subargs::Stage { spec, args } => {
+            if let Err(e) = stages::run(spec, args) {
+                error!("{spec}: {e}");
+                exit(1);
+            }
         }
     }
 }
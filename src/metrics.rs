@@ -0,0 +1,222 @@
+//! counters and gauges stages report, kept in `.metrics` in the pipeline's metadata dir
+//!
+//! a stage named with `--report-metrics` is spawned with the write end of a pipe plumber
+//! reads at fd `$PLUMBER_METRICS_FD`, the lowest from 3 an `--fd` of the stage doesn't take, and
+//! reports domain-level progress by writing lines to it:
+//! ```text
+//! plumber-metric:records+=100
+//! plumber-metric:queue_depth=12
+//! ```
+//! `+=` adds to a counter and `=` sets a gauge. names are letters, digits and `_`, other lines
+//! are ignored. counters keep counting across runs and restarts of plumber, and every value is
+//! shown by `plumber status`, the socket's `status` and `plumber observe metrics`.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Read};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+use serde_json::{json, Value};
+use crate::reactor::{self, Finished, Handler, Wait};
+use crate::transport;
+
+/// the fd a stage reports on
+pub const ENV: &str = "PLUMBER_METRICS_FD";
+const METRICS: &str = ".metrics";
+const PREFIX: &str = "plumber-metric:";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+}
+
+/// what the stages of a pipeline reported, in the order names were first seen
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Metrics {
+    pub values: Vec<(String, Kind, f64)>,
+}
+
+impl Metrics {
+    pub fn load(metadata_dir: &Path) -> Metrics {
+        let Some(metrics) = fs::read_to_string(metadata_dir.join(METRICS)).ok().and_then(|raw| serde_json::from_str::<Value>(raw.trim()).ok()) else {
+            return Metrics::default();
+        };
+        let mut values = Vec::new();
+        for (key, kind) in [("counters", Kind::Counter), ("gauges", Kind::Gauge)] {
+            let Some(Value::Object(fields)) = metrics.get(key) else { continue };
+            for (name, value) in fields {
+                values.extend(value.as_f64().map(|n| (name.clone(), kind, n)));
+            }
+        }
+        Metrics { values }
+    }
+
+    pub fn save(&self, metadata_dir: &Path) -> io::Result<()> {
+        let of = |kind| Value::Object(self.values.iter()
+            .filter(|(_, k, _)| *k == kind)
+            // as rust writes them, so whole numbers have no `.0`
+            .map(|(name, _, value)| (name.clone(), value.to_string().parse().map_or(Value::Null, Value::Number)))
+            .collect());
+        let metrics = json!({ "counters": of(Kind::Counter), "gauges": of(Kind::Gauge) });
+        fs::write(metadata_dir.join(METRICS), metrics.to_string())
+    }
+
+    /// applies a line a stage wrote, false if it isn't a metric
+    pub fn apply(&mut self, line: &str) -> bool {
+        let Some(metric) = line.trim().strip_prefix(PREFIX) else { return false };
+        let Some((name, value)) = metric.split_once('=') else { return false };
+        let (name, kind) = match name.strip_suffix('+') {
+            Some(name) => (name.trim(), Kind::Counter),
+            None => (name.trim(), Kind::Gauge),
+        };
+        let Ok(value) = value.trim().parse::<f64>() else { return false };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') || !value.is_finite() {
+            return false;
+        }
+        // counters only count up
+        if kind == Kind::Counter && value < 0.0 {
+            return false;
+        }
+        match self.values.iter_mut().find(|(n, _, _)| n == name) {
+            Some((_, k, v)) if kind == Kind::Counter && *k == Kind::Counter => *v += value,
+            Some((_, k, v)) => (*k, *v) = (kind, value),
+            None => self.values.push((name.to_owned(), kind, value)),
+        }
+        true
+    }
+
+    /// `name=value` of every metric, for `plumber status`
+    pub fn summary(&self) -> String {
+        let mut summary = String::new();
+        for (name, _, value) in &self.values {
+            let _ = write!(summary, "{}{name}={value}", if summary.is_empty() { "" } else { " " });
+        }
+        summary
+    }
+}
+
+/// the fd a stage reports on, the lowest from 3 none of its `taken` fds are at
+pub fn fd(taken: &[RawFd]) -> RawFd {
+    (3..).find(|fd| !taken.contains(fd)).unwrap()
+}
+
+/// has `command` report on the fd after its `taken` ones, applying what it writes to `metrics`
+/// and saving them in `metadata_dir`. to be called after the stage's other fds are passed
+pub fn pass(command: &mut Command, taken: &[RawFd], metrics: &Arc<Mutex<Metrics>>, metadata_dir: &Path) -> io::Result<Finished> {
+    let target = fd(taken);
+    let (read, write) = transport::pipe()?;
+    // above every fd the stage is passed, so placing those doesn't close it
+    let above = taken.iter().copied().chain([target]).max().unwrap() + 1;
+    let write = match unsafe { libc::fcntl(write.as_raw_fd(), libc::F_DUPFD_CLOEXEC, above) } {
+        fd if fd < 0 => return Err(io::Error::last_os_error()),
+        fd => unsafe { OwnedFd::from_raw_fd(fd) },
+    };
+    let reading = read_reports(read, metrics.clone(), metadata_dir.to_owned());
+    command.env(ENV, target.to_string());
+    unsafe {
+        command.pre_exec(move || {
+            // dup2 leaves the target open across exec
+            if libc::dup2(write.as_raw_fd(), target) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(reading)
+}
+
+/// reads what a stage reports until it and every child it passed the fd on to have exited
+fn read_reports(read: OwnedFd, metrics: Arc<Mutex<Metrics>>, metadata_dir: PathBuf) -> Finished {
+    unsafe { libc::fcntl(read.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) };
+    reactor::register(Box::new(Reports { read: fs::File::from(read), partial: Vec::new(), metrics, metadata_dir }))
+}
+
+struct Reports {
+    read: fs::File,
+    /// the start of a line not yet written in full
+    partial: Vec<u8>,
+    metrics: Arc<Mutex<Metrics>>,
+    metadata_dir: PathBuf,
+}
+
+impl Reports {
+    /// applies the complete lines read so far, or every line once `eof`
+    fn apply(&mut self, eof: bool) {
+        let end = match eof {
+            true => self.partial.len(),
+            false => self.partial.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1),
+        };
+        let lines: Vec<u8> = self.partial.drain(..end).collect();
+        // no metric is this long, don't keep buffering whatever else the stage writes
+        if self.partial.len() > 64 * 1024 {
+            self.partial.clear();
+        }
+        let mut metrics = self.metrics.lock().unwrap();
+        let mut applied = false;
+        for line in String::from_utf8_lossy(&lines).lines() {
+            applied |= metrics.apply(line);
+        }
+        // saved while the lock is held, so an older value never overwrites a newer one
+        if applied {
+            if let Err(e) = metrics.save(&self.metadata_dir) {
+                log::warn!("unable to save metrics to {}: {e}", self.metadata_dir.display());
+            }
+        }
+    }
+}
+
+impl Handler for Reports {
+    fn poll(&mut self) -> Wait {
+        let mut buf = [0u8; 4096];
+        loop {
+            match self.read.read(&mut buf) {
+                Ok(0) => {
+                    self.apply(true);
+                    return Wait::Done;
+                },
+                Ok(n) => self.partial.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.apply(false);
+                    return Wait::Readable(self.read.as_raw_fd());
+                },
+                Err(_) => {
+                    self.apply(true);
+                    return Wait::Done;
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_and_gauges() {
+        let dir = std::env::temp_dir().join(format!("plumber-metrics-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut metrics = Metrics::default();
+        for line in ["plumber-metric:records+=100\n", "plumber-metric:queue_depth=12", "plumber-metric:records+=2.5", "plumber-metric:queue_depth=3"] {
+            assert!(metrics.apply(line), "{line}");
+        }
+        for line in ["records+=1", "plumber-metric:records+=-1", "plumber-metric:a b=1", "plumber-metric:x=nan", "plumber-metric:x"] {
+            assert!(!metrics.apply(line), "{line}");
+        }
+        assert_eq!(metrics.summary(), "records=102.5 queue_depth=3");
+
+        metrics.save(&dir).unwrap();
+        let mut loaded = Metrics::load(&dir);
+        assert_eq!(loaded.values, [("records".to_string(), Kind::Counter, 102.5), ("queue_depth".to_string(), Kind::Gauge, 3.0)]);
+        loaded.apply("plumber-metric:records+=1");
+        assert_eq!(loaded.summary(), "records=103.5 queue_depth=3");
+        assert_eq!(fd(&[3, 4, 6]), 5);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
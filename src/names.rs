@@ -0,0 +1,139 @@
+//! what pipelines may be named
+//!
+//! a name becomes a dir under the metadata and log roots, the name of a socket and a field of
+//! the audit log, so it's kept to what means the same in all of them, whatever the locale:
+//! 1 to 64 ascii letters, digits, `.`, `_` and `-`, starting with a letter or a digit.
+//! `plumber run` names a pipeline after its file, `etl.plumb` is `etl`, and a file that isn't
+//! a valid name is refused rather than renamed, so two files can't end up as one pipeline.
+//!
+//! run ids are the time a run started in utc, see `archive::claim_run_id`.
+//!
+//! everything finding a pipeline's state by its name takes a `ValidatedName`, so a name such
+//! as `../../etc/foo` never gets joined onto the state root.
+
+use std::ffi::OsStr;
+use std::fmt;
+use std::ops::Deref;
+use std::path::Path;
+
+pub const MAX_LEN: usize = 64;
+
+/// a name `check` let through, safe to join onto a dir
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ValidatedName(String);
+
+impl ValidatedName {
+    pub fn new(name: impl Into<String>) -> Result<Self, NameError> {
+        let name = name.into();
+        match check(&name) {
+            Ok(()) => Ok(ValidatedName(name)),
+            Err(reason) => Err(NameError { name, reason }),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::str::FromStr for ValidatedName {
+    type Err = NameError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ValidatedName::new(s)
+    }
+}
+
+impl Deref for ValidatedName {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<OsStr> for ValidatedName {
+    fn as_ref(&self) -> &OsStr {
+        OsStr::new(&self.0)
+    }
+}
+
+impl AsRef<Path> for ValidatedName {
+    fn as_ref(&self) -> &Path {
+        Path::new(&self.0)
+    }
+}
+
+impl fmt::Display for ValidatedName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// a name that isn't one, and why
+#[derive(Debug, Clone, PartialEq)]
+pub struct NameError {
+    pub name: String,
+    pub reason: String,
+}
+
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} can't name a pipeline, {}", self.name, self.reason)
+    }
+}
+
+impl std::error::Error for NameError {}
+
+/// what a plumber file names its pipeline, `etl.plumb` `etl`, unless its stem isn't utf-8
+pub fn of_file(path: &Path) -> Result<String, NameError> {
+    let stem = path.file_stem().unwrap_or_default();
+    match stem.to_str() {
+        Some(name) => Ok(name.to_owned()),
+        None => Err(NameError { name: stem.to_string_lossy().into_owned(), reason: "it isn't utf-8".to_string() }),
+    }
+}
+
+/// why `name` can't be a pipeline's
+pub fn check(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("it's empty".to_string());
+    }
+    if name.len() > MAX_LEN {
+        return Err(format!("it's longer than {MAX_LEN} bytes"));
+    }
+    if !name.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        return Err("it doesn't start with a letter or a digit".to_string());
+    }
+    match name.chars().find(|c| !c.is_ascii_alphanumeric() && !matches!(c, '.' | '_' | '-')) {
+        Some(c) => Err(format!("{c:?} isn't allowed, only ascii letters, digits, `.`, `_` and `-`")),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_names() {
+        for name in ["etl", "nginx-log-to-s3", "backup_2.1", "0day"] {
+            assert_eq!(check(name), Ok(()), "{name}");
+        }
+        for name in ["", ".hidden", "-x", "../etc", "a/b", "a b", "caf\u{e9}", "a\0b", &"a".repeat(MAX_LEN + 1)] {
+            assert!(check(name).is_err(), "{name:?}");
+        }
+        let name: ValidatedName = "etl".parse().unwrap();
+        assert_eq!(Path::new("/tmp/plumber/lib").join(&name), Path::new("/tmp/plumber/lib/etl"));
+        assert_eq!(ValidatedName::new("../../etc/foo").unwrap_err().to_string(), "\"../../etc/foo\" can't name a pipeline, it doesn't start with a letter or a digit");
+    }
+
+    #[test]
+    fn names_of_files() {
+        use std::os::unix::ffi::OsStrExt;
+
+        assert_eq!(of_file(Path::new("/srv/etl.plumb")), Ok("etl".to_string()));
+        let e = of_file(Path::new(OsStr::from_bytes(b"/srv/caf\xe9.plumb"))).unwrap_err();
+        assert_eq!(e.to_string(), "\"caf\u{fffd}\" can't name a pipeline, it isn't utf-8");
+    }
+}
@@ -0,0 +1,247 @@
+//! namespaces, `--namespace <name>` or `$PLUMBER_NAMESPACE`, for teams sharing a host
+//!
+//! every namespace has a state root of its own, `namespaces/<name>` under the usual one, so
+//! its pipelines, metadata, logs, sockets and audit log are apart from those of every other
+//! namespace and two teams may run pipelines of the same name. `--state-dir` and `--log-dir`
+//! are used as given.
+//!
+//! `plumber namespace create` makes the root of a namespace accessible only to its owner and a
+//! group, new files taking on the group, and sets its quotas in `namespaces/<name>.quota.json`,
+//! beside rather than in the namespace's root, which its group may write to:
+//! - `max_pipelines` running at once, checked when a pipeline starts
+//! - `max_stages` of any one pipeline
+//!
+//! a pipeline starting counts those running and claims a slot of its own in `slots/<name>` in
+//! the namespace's root under a lock, so two starting at once can't both take the last one.
+
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use serde_json::Value;
+use crate::status;
+
+pub const ENV: &str = "PLUMBER_NAMESPACE";
+const SLOTS: &str = "slots";
+
+/// `--namespace`, which wins over the environment
+static NAMESPACE: OnceLock<Option<String>> = OnceLock::new();
+
+/// sets the namespace given on the command line, or else in the environment, before any
+/// pipeline is touched. a `$PLUMBER_NAMESPACE` that isn't one is an error rather than ignored,
+/// which would leave its pipelines in the default namespace with everyone else's
+pub fn set(namespace: Option<String>) -> Result<(), String> {
+    let namespace = resolve(namespace, std::env::var(ENV).ok())?;
+    let _ = NAMESPACE.set(namespace);
+    Ok(())
+}
+
+/// `--namespace`, or else the environment's, an empty one being none
+fn resolve(flag: Option<String>, env: Option<String>) -> Result<Option<String>, String> {
+    match flag {
+        Some(namespace) => Ok(Some(namespace)),
+        None => env.filter(|namespace| !namespace.is_empty()).map(|namespace| parse(&namespace)).transpose(),
+    }
+}
+
+/// the namespace plumber runs in, `None` for the default one
+pub fn current() -> Option<String> {
+    NAMESPACE.get().cloned().flatten()
+}
+
+/// a namespace is a single path component anyone can type
+pub fn parse(name: &str) -> Result<String, String> {
+    match !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        true => Ok(name.to_owned()),
+        false => Err(format!("'{name}' is not a namespace, use letters, digits, `-` and `_`")),
+    }
+}
+
+/// the state root of `namespace` under `root`
+pub fn root(root: &Path, namespace: &str) -> PathBuf {
+    root.join("namespaces").join(namespace)
+}
+
+/// where the quota of `namespace` under `root` is kept, out of reach of the namespace's group
+fn quota_file(root: &Path, namespace: &str) -> PathBuf {
+    root.join("namespaces").join(format!("{namespace}.quota.json"))
+}
+
+/// what a namespace may take up, nothing is limited unless set
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Quota {
+    pub max_pipelines: Option<usize>,
+    pub max_stages: Option<usize>,
+}
+
+impl Quota {
+    pub fn load(root: &Path, namespace: &str) -> Quota {
+        let Some(quota) = fs::read_to_string(quota_file(root, namespace)).ok().and_then(|raw| serde_json::from_str::<Value>(&raw).ok()) else {
+            return Quota::default();
+        };
+        let limit = |key| quota.get(key).and_then(Value::as_u64).map(|n| n as usize);
+        Quota { max_pipelines: limit("max_pipelines"), max_stages: limit("max_stages") }
+    }
+
+    pub fn save(&self, root: &Path, namespace: &str) -> io::Result<()> {
+        let limits = [("max_pipelines", self.max_pipelines), ("max_stages", self.max_stages)];
+        let fields = limits.into_iter()
+            .filter_map(|(key, limit)| Some((key.to_string(), Value::from(limit?))))
+            .collect();
+        let path = quota_file(root, namespace);
+        fs::write(&path, Value::Object(fields).to_string())?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644))
+    }
+
+    /// checks a pipeline of `stages` against the quota and claims a slot for it under the
+    /// namespace's lock, which it holds until the slot is dropped. slots of pipelines whose
+    /// plumber is gone don't count
+    pub fn claim(&self, namespace_root: &Path, name: &str, stages: usize) -> Result<Slot, String> {
+        let slots = namespace_root.join(SLOTS);
+        fs::create_dir_all(&slots).map_err(|e| format!("{}: {e}", slots.display()))?;
+        let lock_path = namespace_root.join(".slots.lock");
+        let lock = fs::OpenOptions::new().write(true).create(true).truncate(false).open(&lock_path)
+            .and_then(|lock| lock.lock().map(|_| lock))
+            .map_err(|e| format!("{}: {e}", lock_path.display()))?;
+        let mut running = 0;
+        for entry in fs::read_dir(&slots).map_err(|e| format!("{}: {e}", slots.display()))?.flatten() {
+            let Ok(other) = entry.file_name().into_string() else { continue };
+            match status::recorded_alive(&slots, &other) {
+                true if other != name => running += 1,
+                true => {},
+                false => {
+                    let _ = fs::remove_file(entry.path());
+                },
+            }
+        }
+        self.check(running, stages)?;
+        let path = slots.join(name);
+        fs::write(&path, std::process::id().to_string()).map_err(|e| format!("{}: {e}", path.display()))?;
+        drop(lock);
+        Ok(Slot(path))
+    }
+
+    /// why a pipeline of `stages` can't start with `running` others already running
+    pub fn check(&self, running: usize, stages: usize) -> Result<(), String> {
+        self.check_stages(stages)?;
+        if let Some(max) = self.max_pipelines.filter(|max| running >= *max) {
+            return Err(format!("{running} pipelines running already, as many as the {max} allowed"));
+        }
+        Ok(())
+    }
+
+    /// why a pipeline can't have `stages`, as when one running is reloaded
+    pub fn check_stages(&self, stages: usize) -> Result<(), String> {
+        match self.max_stages.filter(|max| stages > *max) {
+            Some(max) => Err(format!("{stages} stages, more than the {max} a pipeline may have")),
+            None => Ok(()),
+        }
+    }
+}
+
+/// a pipeline's claim on one of the `max_pipelines` of its namespace, given up when dropped
+#[derive(Debug)]
+pub struct Slot(PathBuf);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// creates the root of a namespace, only accessible to its owner and `group` if given
+pub fn create(namespace_root: &Path, group: Option<&str>) -> io::Result<()> {
+    // every other namespace's group passes through, but none may write in it, as it holds the
+    // quotas
+    if let Some(namespaces) = namespace_root.parent().filter(|namespaces| !namespaces.exists()) {
+        fs::create_dir_all(namespaces)?;
+        fs::set_permissions(namespaces, fs::Permissions::from_mode(0o755))?;
+    }
+    match fs::DirBuilder::new().mode(0o700).create(namespace_root) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+        _ => {},
+    }
+    let Some(group) = group else { return Ok(()) };
+    let name = CString::new(group).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("no group '{group}'")));
+    }
+    let gid = unsafe { (*entry).gr_gid };
+    std::os::unix::fs::chown(namespace_root, None, Some(gid))?;
+    // new files and dirs take on the group
+    fs::set_permissions(namespace_root, fs::Permissions::from_mode(0o2770))
+}
+
+/// every namespace under `root` with its quota
+pub fn list(root: &Path) -> io::Result<Vec<(String, Quota)>> {
+    let mut namespaces = Vec::new();
+    let entries = match fs::read_dir(root.join("namespaces")) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(namespaces),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else { continue };
+        if entry.file_type()?.is_dir() {
+            namespaces.push((name.clone(), Quota::load(root, &name)));
+        }
+    }
+    namespaces.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(namespaces)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_namespaces_in_the_environment_are_errors() {
+        assert_eq!(resolve(None, Some("team-a".to_string())), Ok(Some("team-a".to_string())));
+        assert_eq!(resolve(Some("ops".to_string()), Some("team.a".to_string())), Ok(Some("ops".to_string())));
+        assert_eq!(resolve(None, Some(String::new())), Ok(None));
+        assert_eq!(resolve(None, None), Ok(None));
+        assert!(resolve(None, Some("team.a".to_string())).unwrap_err().contains("'team.a' is not a namespace"));
+    }
+
+    #[test]
+    fn quotas() {
+        let root = std::env::temp_dir().join(format!("plumber-namespace-test-{}", std::process::id()));
+        let team = super::root(&root, "team-a");
+        create(&team, None).unwrap();
+        assert_eq!(fs::metadata(&team).unwrap().permissions().mode() & 0o777, 0o700);
+        assert_eq!(Quota::load(&root, "team-a"), Quota::default());
+        assert_eq!(fs::metadata(team.parent().unwrap()).unwrap().permissions().mode() & 0o777, 0o755);
+
+        let quota = Quota { max_pipelines: Some(2), max_stages: None };
+        quota.save(&root, "team-a").unwrap();
+        assert!(root.join("namespaces/team-a.quota.json").exists());
+        assert_eq!(list(&root).unwrap(), [("team-a".to_string(), quota.clone())]);
+        assert!(quota.check(1, 50).is_ok());
+        assert_eq!(quota.check(2, 3).unwrap_err(), "2 pipelines running already, as many as the 2 allowed");
+        let quota = Quota { max_stages: Some(3), ..quota };
+        assert_eq!(quota.check(0, 4).unwrap_err(), "4 stages, more than the 3 a pipeline may have");
+        // a running pipeline reloaded doesn't count against max_pipelines
+        assert!(quota.check_stages(3).is_ok() && quota.check_stages(4).is_err());
+
+        let quota = Quota { max_pipelines: Some(1), max_stages: None };
+        let slot = quota.claim(&team, "etl", 2).unwrap();
+        assert_eq!(quota.claim(&team, "backup", 2).unwrap_err(), "1 pipelines running already, as many as the 1 allowed");
+        // a pipeline started again keeps its slot
+        drop(quota.claim(&team, "etl", 2).unwrap());
+        drop(slot);
+        assert!(quota.claim(&team, "backup", 2).is_ok());
+        // left by a plumber that's gone
+        fs::write(team.join("slots/gone"), "999999999").unwrap();
+        assert!(quota.claim(&team, "backup", 2).is_ok());
+
+        assert!(parse("team_b-2").is_ok());
+        assert!(parse("../etc").is_err());
+        assert!(create(&super::root(&root, "team-b"), Some("asdf-no-such-group")).is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+//! numa placement of stages, `--numa-cpu [STAGE=]NODES` and `--numa-mem [STAGE=]NODES`
+//!
+//! on a machine with more than one numa node, a pipe between stages running on different
+//! nodes moves every byte across the interconnect. `--numa-cpu` runs a stage only on the cpus
+//! of `NODES` and `--numa-mem` has it allocate memory only from them, like numactl's
+//! `--cpunodebind` and `--membind`. `NODES` is a list such as `0`, `0,2` or `0-1`. the binding
+//! is set up between fork and exec, so the stage and whatever it spawns inherit it.
+//!
+//! the last rule naming a stage wins, then the last for all stages.
+
+use std::fs;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+const NODES: &str = "/sys/devices/system/node";
+/// from linux/mempolicy.h
+const MPOL_BIND: libc::c_int = 2;
+
+/// one `--numa-cpu` or `--numa-mem` flag
+#[derive(Debug, Clone, PartialEq)]
+pub struct NumaRule {
+    /// `None` applies to every stage
+    pub stage: Option<String>,
+    pub nodes: Vec<usize>,
+}
+
+/// a list of numbers and ranges, as in `0-3,8`
+fn parse_list(list: &str) -> Result<Vec<usize>, String> {
+    let mut numbers = Vec::new();
+    for part in list.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+        let number = |n: &str| n.parse::<usize>().map_err(|_| format!("invalid number '{n}' in '{list}'"));
+        match part.split_once('-') {
+            Some((first, last)) => numbers.extend(number(first)?..=number(last)?),
+            None => numbers.push(number(part)?),
+        }
+    }
+    match numbers.is_empty() {
+        true => Err(format!("no numbers in '{list}'")),
+        false => Ok(numbers),
+    }
+}
+
+impl std::str::FromStr for NumaRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (stage, nodes) = match s.split_once('=') {
+            Some((stage, nodes)) => (Some(stage.to_owned()), nodes),
+            None => (None, s),
+        };
+        Ok(NumaRule { stage, nodes: parse_list(nodes)? })
+    }
+}
+
+/// nodes `stage` is bound to
+pub fn nodes<'a>(rules: &'a [NumaRule], stage: &str) -> Option<&'a [usize]> {
+    rules.iter()
+        .rev()
+        .find(|r| r.stage.as_deref() == Some(stage))
+        .or_else(|| rules.iter().rev().find(|r| r.stage.is_none()))
+        .map(|r| r.nodes.as_slice())
+}
+
+/// the cpus of `nodes`
+fn cpus(nodes: &[usize]) -> io::Result<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for node in nodes {
+        let list = fs::read_to_string(format!("{NODES}/node{node}/cpulist"))
+            .map_err(|e| io::Error::new(e.kind(), format!("no numa node {node}")))?;
+        cpus.extend(parse_list(list.trim()).map_err(io::Error::other)?);
+    }
+    Ok(cpus)
+}
+
+/// binds what `command` spawns to the cpus of `cpu_nodes` and the memory of `mem_nodes`
+pub fn bind(command: &mut Command, cpu_nodes: Option<&[usize]>, mem_nodes: Option<&[usize]>) -> io::Result<()> {
+    // built before the fork, only syscalls are left for the child
+    let cpu_set = match cpu_nodes {
+        Some(nodes) => {
+            let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+            for cpu in cpus(nodes)? {
+                unsafe { libc::CPU_SET(cpu, &mut set) };
+            }
+            Some(set)
+        },
+        None => None,
+    };
+    let node_mask = match mem_nodes {
+        Some(nodes) => {
+            if let Some(node) = nodes.iter().find(|node| fs::metadata(format!("{NODES}/node{node}")).is_err()) {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("no numa node {node}")));
+            }
+            let bits = libc::c_ulong::BITS as usize;
+            let mut mask = vec![0 as libc::c_ulong; nodes.iter().max().unwrap() / bits + 1];
+            for node in nodes {
+                mask[node / bits] |= 1 << (node % bits);
+            }
+            Some(mask)
+        },
+        None => None,
+    };
+    if cpu_set.is_none() && node_mask.is_none() {
+        return Ok(());
+    }
+    unsafe {
+        command.pre_exec(move || {
+            if let Some(set) = &cpu_set {
+                if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), set) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            if let Some(mask) = &node_mask {
+                // the kernel reads one bit less than it's told
+                let max_node = mask.len() * libc::c_ulong::BITS as usize + 1;
+                if libc::syscall(libc::SYS_set_mempolicy, MPOL_BIND, mask.as_ptr(), max_node) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rules() {
+        let rules: Vec<NumaRule> = ["0", "jq=1-2,4"].iter().map(|s| s.parse().unwrap()).collect();
+        assert_eq!(nodes(&rules, "jq"), Some(&[1, 2, 4][..]));
+        assert_eq!(nodes(&rules, "wc"), Some(&[0][..]));
+        assert_eq!(nodes(&[], "wc"), None);
+        assert!("jq=".parse::<NumaRule>().is_err());
+        assert!("jq=a".parse::<NumaRule>().is_err());
+    }
+
+    #[test]
+    fn binds_to_node_zero() {
+        let node0 = fs::read_to_string(format!("{NODES}/node0/cpulist")).unwrap();
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("grep Cpus_allowed_list /proc/self/status; grep -c bind:0 /proc/self/numa_maps");
+        bind(&mut command, Some(&[0]), Some(&[0])).unwrap();
+        let output = String::from_utf8(command.output().unwrap().stdout).unwrap();
+        let mut lines = output.lines();
+        assert_eq!(lines.next().unwrap().split_whitespace().last(), Some(node0.trim()));
+        assert!(lines.next().unwrap().parse::<u32>().unwrap() > 0);
+
+        assert!(bind(&mut Command::new("true"), Some(&[4096]), None).is_err());
+    }
+}
@@ -0,0 +1,217 @@
+//! `plumber observe`, a read-only view of the pipelines on this machine
+//!
+//! it only ever reads what supervisors keep in the metadata and logging dirs, and never
+//! writes, signals or spawns anything, so it can be handed to on-call, e.g. with a sudoers rule
+//! for `plumber observe *`, without letting them stop or change a pipeline:
+//! - `status` lists the pipelines, as `plumber status` does
+//! - `logs <name>` prints the end of the stderr log of every stage, `--follow` what is added,
+//!   as `plumber logs <name>` does
+//! - `metrics` prints the state, last run, links and reported metrics of every pipeline in the
+//!   prometheus text format, for a node exporter's textfile collector
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, IsTerminal, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde_json::Value;
+use crate::metrics::{Kind, Metrics};
+use crate::soak;
+use crate::status::{self, State};
+
+/// the stderr logs of a pipeline's stages, by stage, in the order of their names
+pub fn logs(logging_dir: &Path, stage: Option<&str>) -> io::Result<Vec<(String, PathBuf)>> {
+    let mut logs: Vec<(String, PathBuf)> = fs::read_dir(logging_dir)?
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let name = entry.file_name().to_str()?.strip_suffix(".stderr.log")?.to_owned();
+            Some((name, entry.path()))
+        })
+        .filter(|(name, _)| stage.is_none_or(|stage| stage == name))
+        .collect();
+    logs.sort();
+    Ok(logs)
+}
+
+/// the last `lines` lines of `path`, and how far into it they end
+pub fn tail(path: &Path, lines: usize) -> io::Result<(Vec<String>, u64)> {
+    let mut file = fs::File::open(path)?;
+    let mut raw = Vec::new();
+    file.read_to_end(&mut raw)?;
+    let text = String::from_utf8_lossy(&raw);
+    let all: Vec<&str> = text.lines().collect();
+    let tail = all[all.len().saturating_sub(lines)..].iter().map(|line| line.to_string()).collect();
+    Ok((tail, raw.len() as u64))
+}
+
+/// what was appended to `path` since `offset`, starting over when it was truncated for a new run
+pub fn appended(path: &Path, offset: &mut u64) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    if len < *offset {
+        *offset = 0;
+    }
+    file.seek(SeekFrom::Start(*offset))?;
+    let mut raw = Vec::new();
+    file.read_to_end(&mut raw)?;
+    // a line still being written is left for the next call
+    let complete = raw.iter().rposition(|b| *b == b'\n').map_or(0, |end| end + 1);
+    *offset += complete as u64;
+    Ok(String::from_utf8_lossy(&raw[..complete]).into_owned())
+}
+
+/// whether `plumber logs` colors the stage names
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum Color {
+    /// on a terminal, unless `$NO_COLOR` is set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl Color {
+    pub fn enabled(self) -> bool {
+        match self {
+            Color::Auto => io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none_or(|no| no.is_empty()),
+            Color::Always => true,
+            Color::Never => false,
+        }
+    }
+}
+
+/// what the lines of the `index`th stage's log are prefixed with, each stage in a color of its own
+pub fn prefix(stage: &str, index: usize, color: bool) -> String {
+    // cyan, yellow, magenta, green, blue and red
+    const COLORS: [u8; 6] = [36, 33, 35, 32, 34, 31];
+    match color {
+        true => format!("\x1b[{}m{stage}\x1b[0m: ", COLORS[index % COLORS.len()]),
+        false => format!("{stage}: "),
+    }
+}
+
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// every pipeline under `metadata_root` in the prometheus text format
+pub fn metrics(metadata_root: &Path, now: SystemTime) -> io::Result<String> {
+    let statuses = status::statuses(metadata_root, now)?;
+    let mut families: Vec<(&str, &str, &str, Vec<String>)> = vec![
+        ("plumber_pipeline_up", "gauge", "whether the pipeline is running", Vec::new()),
+        ("plumber_pipeline_uptime_seconds", "gauge", "since the supervisor started the pipeline", Vec::new()),
+        ("plumber_pipeline_stages", "gauge", "stages in the pipeline's definition", Vec::new()),
+        ("plumber_last_run_failed", "gauge", "whether a stage of the last finished run failed", Vec::new()),
+        ("plumber_stage_exit_code", "gauge", "of every stage in the last finished run, 128 and the signal if killed", Vec::new()),
+        ("plumber_stage_max_rss_bytes", "gauge", "peak memory of every stage in the last finished run", Vec::new()),
+        ("plumber_link_bytes_total", "counter", "moved through an instrumented link", Vec::new()),
+        ("plumber_reported_total", "counter", "counted by the stages on $PLUMBER_METRICS_FD", Vec::new()),
+        ("plumber_reported", "gauge", "set by the stages on $PLUMBER_METRICS_FD", Vec::new()),
+    ];
+    for status in &statuses {
+        let pipeline = format!("pipeline=\"{}\"", label(&status.name));
+        let dir = metadata_root.join(&status.name);
+        families[0].3.push(format!("{{{pipeline}}} {}", (status.state == State::Running) as u8));
+        if let Some(uptime) = status.uptime {
+            families[1].3.push(format!("{{{pipeline}}} {}", uptime.as_secs()));
+        }
+        if let Some(stages) = status.stages {
+            families[2].3.push(format!("{{{pipeline}}} {stages}"));
+        }
+        let summary = fs::read_to_string(dir.join(".summary")).ok().and_then(|raw| serde_json::from_str::<Value>(raw.trim()).ok());
+        if let Some(summary) = &summary {
+            if let Some(Value::Bool(failed)) = summary.get("failed") {
+                families[3].3.push(format!("{{{pipeline}}} {}", *failed as u8));
+            }
+            let stages = match summary.get("stages") {
+                Some(Value::Array(stages)) => stages.as_slice(),
+                _ => &[],
+            };
+            for (i, stage) in stages.iter().enumerate() {
+                let number = |key| stage.get(key).and_then(Value::as_u64);
+                let Some(Value::String(name)) = stage.get("stage") else { continue };
+                let labels = format!("{pipeline},stage=\"{}\",index=\"{i}\"", label(name));
+                if let Some(code) = number("exit_code").or_else(|| number("signal").map(|signal| 128 + signal)) {
+                    families[4].3.push(format!("{{{labels}}} {code}"));
+                }
+                if let Some(rss) = number("max_rss_kb") {
+                    families[5].3.push(format!("{{{labels}}} {}", rss * 1024));
+                }
+            }
+        }
+        for link in soak::links(&dir) {
+            families[6].3.push(format!("{{{pipeline},from=\"{}\",to=\"{}\"}} {}", label(&link.from), label(&link.to), link.bytes));
+        }
+        for (name, kind, value) in Metrics::load(&dir).values {
+            let family = match kind {
+                Kind::Counter => 7,
+                Kind::Gauge => 8,
+            };
+            families[family].3.push(format!("{{{pipeline},name=\"{name}\"}} {value}"));
+        }
+    }
+    let mut text = String::new();
+    for (name, kind, help, samples) in families {
+        let _ = writeln!(text, "# HELP {name} {help}");
+        let _ = writeln!(text, "# TYPE {name} {kind}");
+        for sample in samples {
+            let _ = writeln!(text, "{name}{sample}");
+        }
+    }
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_of_the_last_run() {
+        let root = std::env::temp_dir().join(format!("plumber-observe-test-{}", std::process::id()));
+        let etl = root.join("etl");
+        fs::create_dir_all(&etl).unwrap();
+        fs::write(etl.join(".pipeline"), "cat | jq .").unwrap();
+        fs::write(etl.join(".summary"), r#"{"failed":true,"stages":[{"stage":"cat","exit_code":0,"max_rss_kb":2},{"stage":"jq","signal":9}]}"#).unwrap();
+        fs::write(etl.join(".links"), r#"[{"from":"cat","to":"jq","bytes":42}]"#).unwrap();
+        fs::write(etl.join(".metrics"), r#"{"counters":{"records":1200},"gauges":{"lag":0.5}}"#).unwrap();
+
+        let metrics = metrics(&root, SystemTime::now()).unwrap();
+        for line in [
+            "# TYPE plumber_link_bytes_total counter",
+            r#"plumber_pipeline_up{pipeline="etl"} 0"#,
+            r#"plumber_pipeline_stages{pipeline="etl"} 2"#,
+            r#"plumber_last_run_failed{pipeline="etl"} 1"#,
+            r#"plumber_stage_exit_code{pipeline="etl",stage="jq",index="1"} 137"#,
+            r#"plumber_stage_max_rss_bytes{pipeline="etl",stage="cat",index="0"} 2048"#,
+            r#"plumber_link_bytes_total{pipeline="etl",from="cat",to="jq"} 42"#,
+            r#"plumber_reported_total{pipeline="etl",name="records"} 1200"#,
+            r#"plumber_reported{pipeline="etl",name="lag"} 0.5"#,
+        ] {
+            assert!(metrics.lines().any(|l| l == line), "no '{line}' in\n{metrics}");
+        }
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn follows_logs() {
+        let dir = std::env::temp_dir().join(format!("plumber-observe-logs-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("jq.stderr.log");
+        fs::write(&log, "a\nb\nc\n").unwrap();
+        fs::write(dir.join("cat.stderr.log"), "").unwrap();
+        assert_eq!(logs(&dir, None).unwrap().iter().map(|(s, _)| s.as_str()).collect::<Vec<_>>(), ["cat", "jq"]);
+        assert_eq!(logs(&dir, Some("jq")).unwrap().len(), 1);
+
+        let (lines, mut offset) = tail(&log, 2).unwrap();
+        assert_eq!(lines, ["b", "c"]);
+        fs::write(&log, "a\nb\nc\nd\npartial").unwrap();
+        assert_eq!(appended(&log, &mut offset).unwrap(), "d\n");
+        // truncated by the next run
+        fs::write(&log, "x\n").unwrap();
+        assert_eq!(appended(&log, &mut offset).unwrap(), "x\n");
+        assert_eq!(prefix("jq", 1, false), "jq: ");
+        assert_eq!(prefix("jq", 7, true), "\x1b[33mjq\x1b[0m: ");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
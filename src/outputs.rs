@@ -0,0 +1,120 @@
+//! values a run hands on to later pipelines, and `{{runs.<name>.last.<key>}}` in stage arguments
+//!
+//! every stage gets a file in `$PLUMBER_OUTPUT` to append `key=value` lines to, such as the
+//! path of what it wrote. once the run ends they're kept in its summary under `outputs`, the
+//! last value of a key winning. a stage of another pipeline refers to them as
+//! ```text
+//! load {{runs.extract.last.output_file}}
+//! ```
+//! which plumber resolves whenever it spawns the stage, from the summary of the last run of
+//! `extract`. besides outputs, `started`, `ended` and `failed` of the summary can be referred
+//! to. a reference that can't be resolved is logged and passed on as written.
+
+use std::fs;
+use std::path::Path;
+
+use serde_json::Value;
+
+/// where a stage finds the file to write its outputs to
+pub const ENV: &str = "PLUMBER_OUTPUT";
+const OUTPUTS: &str = ".outputs";
+
+/// the file stages of the pipeline in `metadata_dir` append their outputs to
+pub fn path(metadata_dir: &Path) -> std::path::PathBuf {
+    metadata_dir.join(OUTPUTS)
+}
+
+/// empties the outputs of the previous run
+pub fn reset(metadata_dir: &Path) -> std::io::Result<()> {
+    fs::write(path(metadata_dir), "")
+}
+
+/// what the stages wrote, in the order keys were first written, the last value of each
+pub fn read(metadata_dir: &Path) -> Vec<(String, String)> {
+    let mut outputs: Vec<(String, String)> = Vec::new();
+    let raw = fs::read_to_string(path(metadata_dir)).unwrap_or_default();
+    for (key, value) in raw.lines().filter_map(|line| line.split_once('=')) {
+        let key = key.trim();
+        if key.is_empty() {
+            continue;
+        }
+        match outputs.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value.to_owned(),
+            None => outputs.push((key.to_owned(), value.to_owned())),
+        }
+    }
+    outputs
+}
+
+/// the value `reference`, as in `runs.extract.last.output_file`, refers to
+fn resolve(reference: &str, metadata_root: &Path) -> Result<String, String> {
+    let parts: Vec<&str> = reference.trim().splitn(4, '.').collect();
+    let [_, name, _, key] = parts[..] else {
+        return Err(format!("'{reference}' is not of the form runs.<name>.last.<key>"));
+    };
+    if parts[0] != "runs" || parts[2] != "last" || name.is_empty() || name.contains('/') {
+        return Err(format!("'{reference}' is not of the form runs.<name>.last.<key>"));
+    }
+    let raw = fs::read_to_string(metadata_root.join(name).join(".summary"))
+        .map_err(|_| format!("{name} hasn't finished a run"))?;
+    let summary = serde_json::from_str::<Value>(raw.trim()).map_err(|e| format!("summary of {name} is malformed: {e}"))?;
+    let value = summary.get("outputs")
+        .and_then(|outputs| outputs.get(key))
+        .or_else(|| summary.get(key));
+    match value {
+        Some(Value::String(s)) => Ok(s.clone()),
+        Some(Value::Number(n)) => Ok(n.to_string()),
+        Some(Value::Bool(b)) => Ok(b.to_string()),
+        _ => Err(format!("the last run of {name} has no output '{key}'")),
+    }
+}
+
+/// `arg` with every `{{reference}}` replaced, and why each that couldn't be was left as written
+pub fn interpolate(arg: &str, metadata_root: &Path) -> (String, Vec<String>) {
+    let (mut interpolated, mut unresolved) = (String::new(), Vec::new());
+    let mut rest = arg;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}").map(|end| start + end) else { break };
+        interpolated.push_str(&rest[..start]);
+        match resolve(&rest[start + 2..end], metadata_root) {
+            Ok(value) => interpolated.push_str(&value),
+            Err(e) => {
+                interpolated.push_str(&rest[start..end + 2]);
+                unresolved.push(e);
+            },
+        }
+        rest = &rest[end + 2..];
+    }
+    interpolated.push_str(rest);
+    (interpolated, unresolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolates_outputs_of_the_last_run() {
+        let root = std::env::temp_dir().join(format!("plumber-outputs-test-{}", std::process::id()));
+        let extract = root.join("extract");
+        fs::create_dir_all(&extract).unwrap();
+        reset(&extract).unwrap();
+        fs::write(path(&extract), "rows=10\noutput_file=/data/a.ndjson\n=ignored\nrows=12\n").unwrap();
+        assert_eq!(read(&extract), [("rows".to_string(), "12".to_string()), ("output_file".to_string(), "/data/a.ndjson".to_string())]);
+
+        let outputs = Value::Object(read(&extract).into_iter().map(|(k, v)| (k, Value::String(v))).collect());
+        let summary = serde_json::json!({ "failed": false, "outputs": outputs });
+        fs::write(extract.join(".summary"), summary.to_string()).unwrap();
+
+        let (arg, unresolved) = interpolate("--in={{runs.extract.last.output_file}},{{ runs.extract.last.failed }}", &root);
+        assert_eq!((arg.as_str(), unresolved.len()), ("--in=/data/a.ndjson,false", 0));
+        let (arg, unresolved) = interpolate("{{runs.extract.last.missing}} {{runs.load.last.rows}} {{oops}} {{open", &root);
+        assert_eq!(arg, "{{runs.extract.last.missing}} {{runs.load.last.rows}} {{oops}} {{open");
+        assert_eq!(unresolved, [
+            "the last run of extract has no output 'missing'",
+            "load hasn't finished a run",
+            "'oops' is not of the form runs.<name>.last.<key>",
+        ]);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
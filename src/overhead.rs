@@ -0,0 +1,186 @@
+//! plumber's own overhead, to tell whether a slow pipeline is plumber's doing or a stage's
+//!
+//! every second while a pipeline runs, `.overhead` in its metadata dir gets the cpu time,
+//! resident memory, open fds and threads of the plumber supervising it, the cpu time of its
+//! reactor thread, which relays instrumented links and captures stderr, and what capturing
+//! stderr took. `.links` of an instrumented pipeline has the syscalls, busy time and bytes
+//! copied through plumber of every link. `plumber overhead <name>` prints both.
+//!
+//! the numbers are those of the whole plumber, which may supervise other pipelines as well.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::capture;
+use serde_json::{json, Value};
+use crate::transport;
+
+const OVERHEAD: &str = ".overhead";
+
+/// what the plumber process takes up
+#[derive(Debug, Default, PartialEq)]
+pub struct Process {
+    pub cpu: Duration,
+    /// `None` before anything started the reactor
+    pub reactor_cpu: Option<Duration>,
+    pub rss_kb: u64,
+    pub fds: u64,
+    pub threads: u64,
+}
+
+/// user and system time in a `/proc/<pid>/stat` line
+pub fn cpu_time(stat: &str) -> Option<Duration> {
+    // the command name may hold spaces and parens, fields are counted after the last paren
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let ticks: u64 = fields.get(11)?.parse::<u64>().ok()? + fields.get(12)?.parse::<u64>().ok()?;
+    let per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+    Some(Duration::from_millis(ticks * 1000 / per_second))
+}
+
+/// the value of a `Key:  value kB` line of `/proc/<pid>/status`
+pub fn status_field(status: &str, key: &str) -> Option<u64> {
+    status.lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))
+        .and_then(|value| value.split_whitespace().next()?.parse().ok())
+}
+
+pub fn sample() -> io::Result<Process> {
+    let status = fs::read_to_string("/proc/self/status")?;
+    let reactor_cpu = fs::read_dir("/proc/self/task")?
+        .flatten()
+        .filter(|task| fs::read_to_string(task.path().join("comm")).is_ok_and(|comm| comm.trim() == "reactor"))
+        .find_map(|task| cpu_time(&fs::read_to_string(task.path().join("stat")).ok()?));
+    Ok(Process {
+        cpu: cpu_time(&fs::read_to_string("/proc/self/stat")?).unwrap_or_default(),
+        reactor_cpu,
+        rss_kb: status_field(&status, "VmRSS").unwrap_or(0),
+        fds: fs::read_dir("/proc/self/fd")?.count() as u64,
+        threads: status_field(&status, "Threads").unwrap_or(0),
+    })
+}
+
+/// writes `.overhead` with the cpu used since the previous write
+pub struct Overhead {
+    last: Option<(Instant, Duration, Option<Duration>)>,
+}
+
+impl Overhead {
+    pub fn new() -> Self {
+        Overhead { last: None }
+    }
+
+    pub fn write(&mut self, metadata_dir: &Path) -> io::Result<()> {
+        let process = sample()?;
+        let now = Instant::now();
+        let percent = |used: Duration, before: Duration, at: Instant| {
+            let elapsed = now.duration_since(at).as_secs_f64().max(0.001);
+            // to a tenth of a percent
+            let percent = used.saturating_sub(before).as_secs_f64() * 100.0 / elapsed;
+            json!((percent * 10.0).round() / 10.0)
+        };
+        let millis = |d: Duration| json!(d.as_millis() as u64);
+        let (captured, capturing) = capture::overhead();
+        let mut overhead = json!({
+            "pid": std::process::id(),
+            "cpu_ms": millis(process.cpu),
+            "rss_kb": process.rss_kb,
+            "fds": process.fds,
+            "threads": process.threads,
+            "captured_bytes": captured,
+            "capture_ms": millis(capturing),
+        });
+        if let Some(reactor) = process.reactor_cpu {
+            overhead["reactor_cpu_ms"] = millis(reactor);
+        }
+        if let Some((at, cpu, reactor)) = self.last {
+            overhead["cpu_percent"] = percent(process.cpu, cpu, at);
+            if let (Some(now), Some(before)) = (process.reactor_cpu, reactor) {
+                overhead["reactor_cpu_percent"] = percent(now, before, at);
+            }
+        }
+        self.last = Some((now, process.cpu, process.reactor_cpu));
+        let tmp = metadata_dir.join(".overhead.tmp");
+        fs::write(&tmp, format!("{overhead}\n"))?;
+        fs::rename(tmp, metadata_dir.join(OVERHEAD))
+    }
+}
+
+/// `.overhead` and `.links` of a pipeline as lines for people, `None` if it hasn't run
+pub fn report(metadata_dir: &Path) -> Option<Vec<String>> {
+    let overhead = serde_json::from_str::<Value>(fs::read_to_string(metadata_dir.join(OVERHEAD)).ok()?.trim()).ok()?;
+    let number = |value: &Value, key: &str| match value.get(key) {
+        Some(Value::Number(n)) => n.to_string(),
+        _ => "?".to_string(),
+    };
+    let bytes = |value: &Value, key: &str| match value.get(key) {
+        Some(Value::Number(n)) => transport::format_bytes(n.as_f64().unwrap_or(0.0)),
+        _ => "?".to_string(),
+    };
+    let reactor = match overhead.get("reactor_cpu_percent") {
+        Some(_) => format!(" (reactor {}%)", number(&overhead, "reactor_cpu_percent")),
+        None => String::new(),
+    };
+    let mut lines = vec![
+        format!(
+            "plumber {}: {}% cpu{reactor}, {} resident, {} fds, {} threads",
+            number(&overhead, "pid"),
+            number(&overhead, "cpu_percent"),
+            transport::format_bytes(number(&overhead, "rss_kb").parse::<f64>().unwrap_or(0.0) * 1024.0),
+            number(&overhead, "fds"),
+            number(&overhead, "threads"),
+        ),
+        format!("log capture: {} in {}ms", bytes(&overhead, "captured_bytes"), number(&overhead, "capture_ms")),
+    ];
+    let links = fs::read_to_string(metadata_dir.join(".links")).ok().and_then(|raw| serde_json::from_str::<Value>(raw.trim()).ok());
+    if let Some(Value::Array(links)) = links {
+        for link in &links {
+            let name = |key| match link.get(key) {
+                Some(Value::String(s)) => s.clone(),
+                _ => "?".to_string(),
+            };
+            lines.push(format!(
+                "{} -> {}: {}, {} syscalls, {}ms busy, {} copied",
+                name("from"),
+                name("to"),
+                bytes(link, "bytes"),
+                number(link, "calls"),
+                number(link, "busy_us").parse::<u64>().map_or("?".to_string(), |us| (us / 1000).to_string()),
+                bytes(link, "copied_bytes"),
+            ));
+        }
+    }
+    Some(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_proc() {
+        let stat = "4242 (plumber (x) y) S 1 4242 4242 0 -1 4194560 1 0 0 0 250 50 0 0 20 0 3 0";
+        let per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as u64;
+        assert_eq!(cpu_time(stat), Some(Duration::from_millis(300 * 1000 / per_second)));
+        assert_eq!(status_field("Name:\tplumber\nVmRSS:\t  1234 kB\nThreads:\t3\n", "VmRSS"), Some(1234));
+        assert_eq!(status_field("Threads:\t3\n", "VmRSS"), None);
+
+        let process = sample().unwrap();
+        assert!(process.rss_kb > 0 && process.fds > 0 && process.threads > 0);
+    }
+
+    #[test]
+    fn reports_overhead() {
+        let dir = std::env::temp_dir().join(format!("plumber-overhead-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        assert_eq!(report(&dir), None);
+        let mut overhead = Overhead::new();
+        overhead.write(&dir).unwrap();
+        overhead.write(&dir).unwrap();
+        let lines = report(&dir).unwrap();
+        assert!(lines[0].starts_with(&format!("plumber {}: ", std::process::id())), "{}", lines[0]);
+        assert!(lines[1].starts_with("log capture: "));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,364 @@
+//! installable pipeline packages, `plumber install <path|url>`
+//!
+//! a package is a directory, or a tarball of one, that teams share ready-made pipelines in:
+//! ```text
+//! nginx-log-to-s3/
+//!   package.toml
+//!   pipelines/ship.plumb    plumber files, as `plumber run <dir>` takes them
+//!   hooks/install           optional, run once the package is installed
+//! ```
+//! `package.toml` names the package and its version, and has the defaults of the environment
+//! variables its pipelines expand:
+//! ```toml
+//! name = "nginx-log-to-s3"
+//! version = "1.2.0"
+//! description = "ships nginx access logs to s3"
+//! env = { BUCKET = "logs", ACCESS_LOG = "/var/log/nginx/access.log" }
+//! ```
+//! every version is kept in `packages/<name>/<version>` under the state root, and
+//! `packages/<name>/current` links the newest. `plumber run <name>` runs the
+//! pipelines of the newest, `plumber run <name>@<version>` those of another.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use indexmap::IndexMap;
+use serde::Deserialize;
+
+use crate::definition::{self, Scalar};
+use crate::pipeline;
+
+const MANIFEST: &str = "package.toml";
+const PIPELINES: &str = "pipelines";
+const CURRENT: &str = "current";
+
+#[derive(Debug)]
+pub enum PackageError {
+    Download(String),
+    Unpack(String),
+    Manifest(String),
+    Exists { name: String, version: String },
+    Hook(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for PackageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PackageError::Download(reason) => write!(f, "download failed: {reason}"),
+            PackageError::Unpack(reason) => write!(f, "unable to unpack package: {reason}"),
+            PackageError::Manifest(reason) => write!(f, "malformed {MANIFEST}: {reason}"),
+            PackageError::Exists { name, version } => write!(f, "{name} {version} is already installed, --force to replace it"),
+            PackageError::Hook(reason) => write!(f, "install hook failed: {reason}"),
+            PackageError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<io::Error> for PackageError {
+    fn from(e: io::Error) -> Self {
+        PackageError::Io(e)
+    }
+}
+
+/// what `package.toml` says
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Manifest {
+    pub name: String,
+    pub version: String,
+    pub description: Option<String>,
+    /// defaults of environment variables, for those not set
+    pub env: Vec<(String, String)>,
+}
+
+/// `package.toml` as written
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawManifest {
+    name: String,
+    version: String,
+    description: Option<String>,
+    #[serde(default)]
+    env: IndexMap<String, Scalar>,
+}
+
+impl Manifest {
+    pub fn parse(raw: &str) -> Result<Manifest, PackageError> {
+        let raw: RawManifest = definition::from_toml(raw).map_err(|e| PackageError::Manifest(e.to_string()))?;
+        let manifest = Manifest {
+            name: raw.name,
+            version: raw.version,
+            description: raw.description,
+            env: raw.env.into_iter().map(|(name, value)| (name, value.into())).collect(),
+        };
+        // both end up in paths
+        let valid = |s: &str| !s.is_empty() && !s.starts_with('.') && s.chars().all(|c| c.is_ascii_alphanumeric() || "-_.+".contains(c));
+        if !valid(&manifest.name) || manifest.name == CURRENT {
+            return Err(PackageError::Manifest(format!("'{}' is not a package name", manifest.name)));
+        }
+        if !valid(&manifest.version) {
+            return Err(PackageError::Manifest(format!("'{}' is not a version", manifest.version)));
+        }
+        Ok(manifest)
+    }
+
+    fn load(dir: &Path) -> Result<Manifest, PackageError> {
+        let path = dir.join(MANIFEST);
+        let raw = fs::read_to_string(&path).map_err(|e| PackageError::Manifest(format!("{}: {e}", path.display())))?;
+        Manifest::parse(&raw)
+    }
+}
+
+/// an installed version of a package
+#[derive(Debug, Clone)]
+pub struct Installed {
+    pub manifest: Manifest,
+    pub dir: PathBuf,
+}
+
+impl Installed {
+    /// the dir of the package's plumber files, for `plumber run`
+    pub fn pipelines(&self) -> PathBuf {
+        self.dir.join(PIPELINES)
+    }
+
+    /// sets the package's default environment variables not set already, before any pipeline
+    /// is parsed
+    pub fn set_env(&self) {
+        for (name, value) in &self.manifest.env {
+            if std::env::var_os(name).is_none() {
+                std::env::set_var(name, value);
+            }
+        }
+    }
+}
+
+/// where packages are installed
+pub fn root() -> PathBuf {
+    pipeline::state_root().join("packages")
+}
+
+/// the installed package `spec` names, `<name>` for the newest version or `<name>@<version>`
+pub fn find(spec: &str) -> Option<Installed> {
+    let (name, version) = spec.split_once('@').unwrap_or((spec, CURRENT));
+    if name.is_empty() || name.contains('/') || version.contains('/') {
+        return None;
+    }
+    let dir = root().join(name).join(version);
+    let manifest = Manifest::load(&dir).ok()?;
+    Some(Installed { manifest, dir })
+}
+
+/// every installed package, each with its versions from the oldest
+pub fn list() -> Vec<(String, Vec<Installed>)> {
+    let Ok(names) = fs::read_dir(root()) else { return Vec::new() };
+    let mut packages: Vec<(String, Vec<Installed>)> = names
+        .flatten()
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_str()?.to_owned();
+            let mut versions: Vec<Installed> = fs::read_dir(entry.path()).ok()?
+                .flatten()
+                .filter(|version| version.file_name() != CURRENT)
+                .filter_map(|version| Manifest::load(&version.path()).ok().map(|manifest| Installed { manifest, dir: version.path() }))
+                .collect();
+            versions.sort_by(|a, b| compare_versions(&a.manifest.version, &b.manifest.version));
+            (!versions.is_empty()).then_some((name, versions))
+        })
+        .collect();
+    packages.sort_by(|a, b| a.0.cmp(&b.0));
+    packages
+}
+
+/// orders versions such as `1.2.0` and `1.10.0` by their numbers, comparing parts that
+/// aren't numbers as text
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let mut a_parts = a.split(['.', '-', '+']);
+    let mut b_parts = b.split(['.', '-', '+']);
+    loop {
+        let ordering = match (a_parts.next(), b_parts.next()) {
+            (None, None) => return Ordering::Equal,
+            (Some(_), None) => return Ordering::Greater,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(a), Some(b)) => match (a.parse::<u64>(), b.parse::<u64>()) {
+                (Ok(a), Ok(b)) => a.cmp(&b),
+                _ => a.cmp(b),
+            },
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+/// removed on drop, so a failed install leaves nothing behind
+struct Staging(PathBuf);
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+fn is_url(source: &str) -> bool {
+    ["https://", "http://", "file://"].iter().any(|scheme| source.starts_with(scheme))
+}
+
+fn download(url: &str, to: &Path) -> Result<(), PackageError> {
+    let output = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location", "--proto", "=https,http,file", "--output"])
+        .arg(to)
+        .arg(url)
+        .output()
+        .map_err(|e| PackageError::Download(format!("unable to run curl: {e}")))?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(PackageError::Download(format!("{url}: {}", String::from_utf8_lossy(&output.stderr).trim()))),
+    }
+}
+
+fn unpack(tarball: &Path, into: &Path) -> Result<(), PackageError> {
+    // tar tells gzip, zstd and the others apart itself
+    let output = Command::new("tar")
+        .arg("--extract")
+        .arg("--file")
+        .arg(tarball)
+        .arg("--directory")
+        .arg(into)
+        .output()
+        .map_err(|e| PackageError::Unpack(format!("unable to run tar: {e}")))?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(PackageError::Unpack(String::from_utf8_lossy(&output.stderr).trim().to_owned())),
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        match entry.file_type()?.is_dir() {
+            true => copy_dir(&entry.path(), &target)?,
+            false => fs::copy(entry.path(), target).map(|_| ())?,
+        }
+    }
+    Ok(())
+}
+
+/// the dir in `unpacked` holding `package.toml`, itself or the one dir a tarball had
+fn package_dir(unpacked: &Path) -> Result<PathBuf, PackageError> {
+    if unpacked.join(MANIFEST).exists() {
+        return Ok(unpacked.to_owned());
+    }
+    let entries: Vec<_> = fs::read_dir(unpacked)?.flatten().map(|entry| entry.path()).collect();
+    match entries.as_slice() {
+        [dir] if dir.join(MANIFEST).exists() => Ok(dir.clone()),
+        _ => Err(PackageError::Manifest(format!("no {MANIFEST} in the package"))),
+    }
+}
+
+/// installs the package at `source`, a dir, a tarball or a url of one. `force` replaces the
+/// same version installed before
+pub fn install(source: &str, force: bool) -> Result<Installed, PackageError> {
+    let root = root();
+    fs::create_dir_all(&root)?;
+    let staging = Staging(root.join(format!(".staging-{}", std::process::id())));
+    fs::create_dir_all(&staging.0)?;
+    match is_url(source) {
+        true => {
+            let tarball = Staging(root.join(format!(".download-{}", std::process::id())));
+            download(source, &tarball.0)?;
+            unpack(&tarball.0, &staging.0)?;
+        },
+        false if Path::new(source).is_dir() => copy_dir(Path::new(source), &staging.0)?,
+        false => unpack(Path::new(source), &staging.0)?,
+    }
+    let unpacked = package_dir(&staging.0)?;
+    let manifest = Manifest::load(&unpacked)?;
+    let has_pipelines = fs::read_dir(unpacked.join(PIPELINES)).is_ok_and(|mut files| files.any(|file| {
+        file.is_ok_and(|file| file.path().extension().is_some_and(definition::is_plumber_extension))
+    }));
+    if !has_pipelines {
+        return Err(PackageError::Manifest(format!("no plumber files in {PIPELINES}/")));
+    }
+
+    let dir = root.join(&manifest.name).join(&manifest.version);
+    if dir.exists() {
+        if !force {
+            return Err(PackageError::Exists { name: manifest.name, version: manifest.version });
+        }
+        fs::remove_dir_all(&dir)?;
+    }
+    fs::create_dir_all(dir.parent().unwrap())?;
+    fs::rename(&unpacked, &dir)?;
+    let installed = Installed { manifest, dir };
+
+    let hook = installed.dir.join("hooks").join("install");
+    if hook.exists() {
+        if let Err(e) = run_hook(&hook, &installed) {
+            let _ = fs::remove_dir_all(&installed.dir);
+            return Err(e);
+        }
+    }
+    link_newest(&root.join(&installed.manifest.name))?;
+    Ok(installed)
+}
+
+fn run_hook(hook: &Path, installed: &Installed) -> Result<(), PackageError> {
+    let output = Command::new(hook)
+        .current_dir(&installed.dir)
+        .env("PLUMBER_PACKAGE", &installed.manifest.name)
+        .env("PLUMBER_PACKAGE_VERSION", &installed.manifest.version)
+        .env("PLUMBER_PACKAGE_DIR", &installed.dir)
+        .output()
+        .map_err(|e| PackageError::Hook(format!("{}: {e}", hook.display())))?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(PackageError::Hook(format!("{}, {}", output.status, String::from_utf8_lossy(&output.stderr).trim()))),
+    }
+}
+
+/// points `current` at the newest version of the package in `dir`
+fn link_newest(dir: &Path) -> io::Result<()> {
+    let newest = fs::read_dir(dir)?
+        .flatten()
+        .filter(|version| version.file_name() != CURRENT)
+        .filter_map(|version| version.file_name().into_string().ok())
+        .max_by(|a, b| compare_versions(a, b));
+    let Some(newest) = newest else { return Ok(()) };
+    // renamed over the old link, so `current` is never missing
+    let link = dir.join(format!(".{CURRENT}-{}", std::process::id()));
+    let _ = fs::remove_file(&link);
+    symlink(&newest, &link)?;
+    fs::rename(link, dir.join(CURRENT))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_versions() {
+        assert_eq!(compare_versions("1.10.0", "1.2.0"), Ordering::Greater);
+        assert_eq!(compare_versions("1.2", "1.2.0"), Ordering::Less);
+        assert_eq!(compare_versions("2.0.0-rc1", "2.0.0-rc2"), Ordering::Less);
+        assert_eq!(compare_versions("0.3.1", "0.3.1"), Ordering::Equal);
+    }
+
+    #[test]
+    fn parses_manifests() {
+        let manifest = Manifest::parse("name = \"nginx-log-to-s3\"\nversion = \"1.2.0\"\nenv = { BUCKET = \"logs\", RETRIES = 3 }\n").unwrap();
+        assert_eq!(manifest.name, "nginx-log-to-s3");
+        assert_eq!(manifest.env, [("BUCKET".to_string(), "logs".to_string()), ("RETRIES".to_string(), "3".to_string())]);
+        assert!(Manifest::parse("name = \"../etc\"\nversion = \"1\"").is_err());
+        assert!(Manifest::parse("name = \"a\"").is_err());
+        assert!(Manifest::parse("name = \"a\"\nversion = \"1\"\nlicense = \"MIT\"").is_err());
+    }
+}
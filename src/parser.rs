@@ -0,0 +1,720 @@
+//! the pipeline language, parsed without touching anything outside the input
+//!
+//! ```text
+//! chain    := pipeline (('&&' | ';') pipeline)* ';'?
+//! pipeline := stage (('|' | '|&' | '|||') stage)*
+//! stage    := command ('||' command)*
+//! command  := '--'? word+ redirect*     redirects may come anywhere among the words
+//! redirect := ('<' | '>' | '>>' | '2>') word
+//! word     := (bare | '\'' [^']* '\'' | '"' quoted* '"' | '\\' any)+
+//! quoted   := [^"\\] | '\\' any
+//! comment  := '#' [^\n]*      at the start of a word, up to the end of the line
+//! ```
+//!
+//! words are separated by blanks and newlines, a `|` outside quotes ends a stage and `#` at
+//! the start of a word comments out the rest of the line, like in sh. `|&` ends a stage as `|`
+//! does and sends its stderr down the pipe too, rather than to its log. `|||` is a barrier: the
+//! stages after it only start once every stage before it exited successfully. `||` separates a
+//! stage's command from its fallbacks, run in its place one after the other when it can't be
+//! spawned or exits unsuccessfully, `curl -sf $URL || wget -qO- $URL | gunzip`. `&&` and `;`
+//! end a pipeline and start another once it's done, after `&&` only if every stage of it
+//! exited successfully, `dump | zstd > dump.zst && upload dump.zst`. within double quotes a
+//! backslash only escapes `$`, `` ` ``, `"`, `\` and newline and is kept before anything else.
+//! a backslash before a newline, `\n` or `\r\n`, outside quotes continues the line.
+//!
+//! `< file` is the stdin of the first stage of a pipeline, `> file` or `>> file` the stdout of
+//! the last and `2> file` the stderr of any stage, instead of its log. an unquoted `<`, `>`,
+//! `;` or `&&` ends a word, `2>` is only a redirect at the start of one. a redirect that would
+//! take the place of a pipe, such as `<` on the second stage, is an error, and so is `2>&1`,
+//! which is written `|&`. a fallback shares the stdin and stdout of its stage, only `2>` may be
+//! written on one.
+//!
+//! `parse_expanding` expands `$NAME`, `${NAME}` and `${NAME:-default}` outside single quotes,
+//! looking variables up with the function it's given, and `parse` leaves them as written. an
+//! expanded value is part of the word it's in rather than split into words, a `$` not followed
+//! by a name is kept, `\$` is a literal `$` and a variable that isn't set and has no default is
+//! an error. there is no expansion of globs, commands or anything else.
+//!
+//! a command starting with `-` is most likely an option that lost its command, and an error.
+//! one that really does is run by putting `--` before it, `-- -weird arg`, which is left out
+//! of the stage's words.
+//!
+//! malformed input, such as an unterminated quote or a `|` with no command on one side, is an
+//! error pointing at where it went wrong. this module depends on nothing else in plumber, so
+//! the fuzz targets in `fuzz/` build it on its own.
+
+use std::fmt;
+use std::iter::Peekable;
+use std::ops::Range;
+use std::str::CharIndices;
+
+/// one stage, the command and its arguments
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stage {
+    pub words: Vec<Word>,
+    /// in the order they were written, the last of those for a stream winning
+    pub redirects: Vec<Redirect>,
+    /// bytes of the input from the first word to the end of the last
+    pub span: Range<usize>,
+    /// ended by `|&`, its stderr goes into the pipe with its stdout
+    pub merge_stderr: bool,
+    /// ended by `|||`
+    pub barrier: bool,
+    /// ended by `&&` or `;`, the last stage of a pipeline another follows
+    pub chain: Option<Chain>,
+    /// the commands after `||`, in order
+    pub fallbacks: Vec<Stage>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Word {
+    /// with quotes and escapes removed
+    pub value: String,
+    pub span: Range<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redirect {
+    pub op: Op,
+    /// the file, with quotes and escapes removed
+    pub target: String,
+    /// from the operator to the end of the file
+    pub span: Range<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    /// `<`
+    In,
+    /// `>`
+    Out,
+    /// `>>`
+    Append,
+    /// `2>`
+    Err,
+}
+
+/// how the pipeline after `&&` or `;` follows the one before it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Chain {
+    /// `&&`, once every stage of it exited successfully
+    OnSuccess,
+    /// `;`, once it's done however it exited
+    Always,
+}
+
+impl fmt::Display for Chain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Chain::OnSuccess => "&&",
+            Chain::Always => ";",
+        })
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Op::In => "<",
+            Op::Out => ">",
+            Op::Append => ">>",
+            Op::Err => "2>",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    Empty,
+    /// a `|` with no command before it
+    LeadingPipe,
+    /// a `|` with no command after it
+    TrailingPipe,
+    /// two `|` with nothing between them, the 1-based number of the stage missing
+    EmptyStage(usize),
+    /// a `&&` or `;` with no pipeline before it
+    LeadingChain(Chain),
+    /// a `&&` with no pipeline after it
+    TrailingChain,
+    /// `<`, `>` or `>>` on a fallback rather than the command before it
+    FallbackRedirect(Op),
+    /// a stage of only `--`
+    MissingCommand,
+    /// a command starting with `-` without `--` before it
+    OptionAsCommand(String),
+    /// a redirect with no file after it
+    MissingTarget(Op),
+    /// a stage of only redirects
+    RedirectWithoutCommand,
+    /// a redirect in place of a pipe, on the 1-based stage
+    RedirectConflict(Op, usize),
+    /// `2>&1`
+    StderrToStdout,
+    UnterminatedQuote(char),
+    TrailingBackslash,
+    /// a `$NAME` that isn't set and has no default
+    UnsetVariable(String),
+    /// a `${` not of the form `${NAME}` or `${NAME:-default}`
+    BadSubstitution,
+    /// of a structured definition, see `definition`
+    Definition(String),
+    /// `{{NAME}}` placeholders without a `--set` value, see `templates`
+    Unfilled(Vec<String>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub problem: Problem,
+    /// byte offset into the input
+    pub at: usize,
+    /// 1-based, for reporting
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: ", self.line, self.column)?;
+        match &self.problem {
+            Problem::Empty => write!(f, "empty pipeline"),
+            Problem::LeadingPipe => write!(f, "`|` with no command before it"),
+            Problem::TrailingPipe => write!(f, "trailing `|` with no command after it"),
+            Problem::EmptyStage(stage) => write!(f, "stage {stage} is empty, two `|` with nothing between them"),
+            Problem::LeadingChain(chain) => write!(f, "`{chain}` with no pipeline before it"),
+            Problem::TrailingChain => write!(f, "trailing `&&` with no pipeline after it"),
+            Problem::FallbackRedirect(op) => write!(f, "`{op}` on a fallback, write it on the first command of the stage"),
+            Problem::MissingCommand => write!(f, "`--` with no command after it"),
+            Problem::OptionAsCommand(command) => {
+                write!(f, "'{command}' looks like an option, not a command, write `-- {command}` to run it")
+            },
+            Problem::MissingTarget(op) => write!(f, "`{op}` with no file after it"),
+            Problem::RedirectWithoutCommand => write!(f, "redirect with no command"),
+            Problem::RedirectConflict(Op::In, stage) => write!(f, "`<` on stage {stage} conflicts with the pipe into it"),
+            Problem::RedirectConflict(Op::Err, stage) => write!(f, "`2>` on stage {stage} conflicts with the `|&` after it"),
+            Problem::RedirectConflict(op, stage) => write!(f, "`{op}` on stage {stage} conflicts with the pipe out of it"),
+            Problem::StderrToStdout => write!(f, "`2>&1` isn't supported, write `|&` to send stderr down the pipe"),
+            Problem::UnterminatedQuote(quote) => write!(f, "unterminated {quote} quote"),
+            Problem::TrailingBackslash => write!(f, "backslash at the end of the input"),
+            Problem::UnsetVariable(name) => {
+                write!(f, "${name} is not set, give it a default with ${{{name}:-...}} or keep dollars literal with --no-expand")
+            },
+            Problem::BadSubstitution => write!(f, "bad substitution, expected ${{NAME}} or ${{NAME:-default}}"),
+            Problem::Definition(reason) => f.write_str(reason),
+            Problem::Unfilled(names) => {
+                let names: Vec<_> = names.iter().map(|name| format!("{{{{{name}}}}}")).collect();
+                write!(f, "no value for {}, give them one with --set NAME=VALUE", names.join(", "))
+            },
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl ParseError {
+    pub fn new(input: &str, problem: Problem, at: usize) -> Self {
+        let (line, column) = line_column(input, at);
+        ParseError { problem, at, line, column }
+    }
+
+    /// the line of `input` the error is on, with a caret under where
+    pub fn show(&self, input: &str) -> String {
+        show(input, self.at)
+    }
+}
+
+/// 1-based line and column of byte offset `at`
+fn line_column(input: &str, at: usize) -> (usize, usize) {
+    let before = &input[..at.min(input.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().map_or(0, |l| l.chars().count()) + 1;
+    (line, column)
+}
+
+/// the line of `input` byte offset `at` is on, with a caret under it
+pub fn show(input: &str, at: usize) -> String {
+    let (line, column) = line_column(input, at);
+    let line = input.lines().nth(line - 1).unwrap_or("");
+    format!("{line}\n{}^", " ".repeat(column - 1))
+}
+
+/// looks up a variable, `None` when it isn't set
+pub type Env<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+/// the stages of `input`, variables left as written
+pub fn parse(input: &str) -> Result<Vec<Stage>, ParseError> {
+    parse_with(input, None)
+}
+
+/// the stages of `input`, expanding variables to what `env` says they're set to
+pub fn parse_expanding(input: &str, env: Env) -> Result<Vec<Stage>, ParseError> {
+    parse_with(input, Some(env))
+}
+
+/// the value of the variable named after a `$` at `at`, `None` if nothing names one
+fn variable(input: &str, chars: &mut Peekable<CharIndices>, at: usize, env: Env) -> Result<Option<String>, ParseError> {
+    let braced = chars.next_if(|&(_, c)| c == '{').is_some();
+    let mut name = String::new();
+    while let Some((_, c)) = chars.next_if(|&(_, c)| c == '_' || c.is_ascii_alphabetic() || (!name.is_empty() && c.is_ascii_digit())) {
+        name.push(c);
+    }
+    if !braced {
+        return match name.is_empty() {
+            true => Ok(None),
+            false => env(&name).map(Some).ok_or_else(|| ParseError::new(input, Problem::UnsetVariable(name), at)),
+        };
+    }
+    let bad = || ParseError::new(input, Problem::BadSubstitution, at);
+    let default = match chars.next() {
+        Some((_, '}')) if !name.is_empty() => None,
+        Some((_, ':')) if !name.is_empty() && chars.next_if(|&(_, c)| c == '-').is_some() => {
+            let mut default = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, '}')) => break Some(default),
+                    Some((_, c)) => default.push(c),
+                    None => return Err(bad()),
+                }
+            }
+        },
+        _ => return Err(bad()),
+    };
+    match (env(&name).filter(|value| default.is_none() || !value.is_empty()), default) {
+        (Some(value), _) | (None, Some(value)) => Ok(Some(value)),
+        (None, None) => Err(ParseError::new(input, Problem::UnsetVariable(name), at)),
+    }
+}
+
+fn parse_with(input: &str, env: Option<Env>) -> Result<Vec<Stage>, ParseError> {
+    let mut stages = Vec::new();
+    let mut words: Vec<Word> = Vec::new();
+    let mut redirects: Vec<Redirect> = Vec::new();
+    // a redirect waiting for its file, and where it starts
+    let mut pending: Option<(Op, usize)> = None;
+    // the `|` that ended the last stage
+    let mut pipe = None;
+    // the `&&` or `;` that ended the last pipeline
+    let mut chained: Option<(Chain, usize)> = None;
+    // the last stage ended with `||`, the next is its fallback
+    let mut fallback = false;
+    let mut chars = input.char_indices().peekable();
+    let error = |problem, at| Err(ParseError::new(input, problem, at));
+
+    while let Some(&(start, c)) = chars.peek() {
+        match c {
+            ' ' | '\t' | '\r' | '\n' => {
+                chars.next();
+            },
+            '#' => {
+                while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+            },
+            '|' => {
+                chars.next();
+                if let Some((op, at)) = pending {
+                    return error(Problem::MissingTarget(op), at);
+                }
+                if words.is_empty() && redirects.is_empty() {
+                    return match pipe {
+                        None => error(Problem::LeadingPipe, start),
+                        Some(_) => error(Problem::EmptyStage(stages.len() + 1), start),
+                    };
+                }
+                let mut stage = stage(input, std::mem::take(&mut words), std::mem::take(&mut redirects))?;
+                stage.barrier = input[start..].starts_with("|||");
+                if stage.barrier {
+                    chars.next();
+                    chars.next();
+                }
+                let ends_in_fallback = !stage.barrier && chars.next_if(|&(_, c)| c == '|').is_some();
+                stage.merge_stderr = !ends_in_fallback && chars.next_if(|&(_, c)| c == '&').is_some();
+                push(&mut stages, stage, fallback);
+                fallback = ends_in_fallback;
+                pipe = Some(start);
+                chained = None;
+            },
+            ';' | '&' if c == ';' || input[start..].starts_with("&&") => {
+                chars.next();
+                let chain = match c {
+                    ';' => Chain::Always,
+                    _ => {
+                        chars.next();
+                        Chain::OnSuccess
+                    },
+                };
+                if let Some((op, at)) = pending {
+                    return error(Problem::MissingTarget(op), at);
+                }
+                if words.is_empty() && redirects.is_empty() {
+                    return match pipe {
+                        Some(at) => error(Problem::TrailingPipe, at),
+                        None => error(Problem::LeadingChain(chain), start),
+                    };
+                }
+                let mut stage = stage(input, std::mem::take(&mut words), std::mem::take(&mut redirects))?;
+                stage.chain = Some(chain);
+                push(&mut stages, stage, fallback);
+                fallback = false;
+                pipe = None;
+                chained = Some((chain, start));
+            },
+            '<' | '>' => {
+                if let Some((op, at)) = pending {
+                    return error(Problem::MissingTarget(op), at);
+                }
+                chars.next();
+                let op = match c {
+                    '<' => Op::In,
+                    _ => match chars.next_if(|&(_, c)| c == '>') {
+                        Some(_) => Op::Append,
+                        None => Op::Out,
+                    },
+                };
+                pending = Some((op, start));
+            },
+            '2' if input[start..].starts_with("2>") => {
+                if let Some((op, at)) = pending {
+                    return error(Problem::MissingTarget(op), at);
+                }
+                chars.next();
+                chars.next();
+                if chars.peek().is_some_and(|&(_, c)| c == '&') {
+                    return error(Problem::StderrToStdout, start);
+                }
+                pending = Some((Op::Err, start));
+            },
+            _ => {
+                let mut value = String::new();
+                // a line continuation alone isn't a word
+                let mut quoted = false;
+                while let Some(&(at, c)) = chars.peek() {
+                    match c {
+                        ' ' | '\t' | '\r' | '\n' | '|' | '<' | '>' | ';' => break,
+                        '&' if input[at..].starts_with("&&") => break,
+                        '\'' | '"' => {
+                            chars.next();
+                            quoted = true;
+                            loop {
+                                match chars.next() {
+                                    None => return error(Problem::UnterminatedQuote(c), at),
+                                    Some((_, end)) if end == c => break,
+                                    Some((dollar, '$')) if c == '"' && env.is_some() => {
+                                        match variable(input, &mut chars, dollar, env.unwrap())? {
+                                            Some(expanded) => value.push_str(&expanded),
+                                            None => value.push('$'),
+                                        }
+                                    },
+                                    Some((_, '\\')) if c == '"' => match chars.next() {
+                                        None => return error(Problem::UnterminatedQuote(c), at),
+                                        Some((_, '\n')) => {},
+                                        Some((_, '\r')) if chars.next_if(|&(_, c)| c == '\n').is_some() => {},
+                                        Some((_, e @ ('$' | '`' | '"' | '\\'))) => value.push(e),
+                                        Some((_, e)) => {
+                                            value.push('\\');
+                                            value.push(e);
+                                        },
+                                    },
+                                    Some((_, inner)) => value.push(inner),
+                                }
+                            }
+                        },
+                        '\\' => {
+                            chars.next();
+                            match chars.next() {
+                                None => return error(Problem::TrailingBackslash, at),
+                                Some((_, '\n')) => {},
+                                // files edited on windows continue lines with `\` too
+                                Some((_, '\r')) if chars.next_if(|&(_, c)| c == '\n').is_some() => {},
+                                Some((_, escaped)) => {
+                                    value.push(escaped);
+                                    quoted = true;
+                                },
+                            }
+                        },
+                        '$' if env.is_some() => {
+                            chars.next();
+                            match variable(input, &mut chars, at, env.unwrap())? {
+                                // an empty one unquoted is no word at all, as in sh
+                                Some(expanded) => value.push_str(&expanded),
+                                None => value.push('$'),
+                            }
+                        },
+                        _ => {
+                            chars.next();
+                            value.push(c);
+                        },
+                    }
+                }
+                let end = chars.peek().map_or(input.len(), |&(at, _)| at);
+                match pending.take() {
+                    Some((op, at)) if value.is_empty() => return error(Problem::MissingTarget(op), at),
+                    Some((op, at)) => redirects.push(Redirect { op, target: value, span: at..end }),
+                    None if !value.is_empty() || quoted => words.push(Word { value, span: start..end }),
+                    None => {},
+                }
+            },
+        }
+    }
+
+    if let Some((op, at)) = pending {
+        return error(Problem::MissingTarget(op), at);
+    }
+    match (words.is_empty() && redirects.is_empty(), pipe, chained) {
+        (true, Some(at), _) => return error(Problem::TrailingPipe, at),
+        (true, None, Some((Chain::OnSuccess, at))) => return error(Problem::TrailingChain, at),
+        // a `;` may end the last pipeline too, as in sh
+        (true, None, Some((Chain::Always, _))) => {
+            stages.last_mut().unwrap().chain = None;
+        },
+        (true, None, None) => return error(Problem::Empty, input.len()),
+        (false, _, _) => push(&mut stages, stage(input, words, redirects)?, fallback),
+    }
+
+    let last = stages.len() - 1;
+    for (i, stage) in stages.iter().enumerate() {
+        for redirect in stage.fallbacks.iter().flat_map(|fallback| &fallback.redirects) {
+            if redirect.op != Op::Err {
+                return error(Problem::FallbackRedirect(redirect.op), redirect.span.start);
+            }
+        }
+        for redirect in stage.redirects.iter().chain(stage.fallbacks.iter().flat_map(|fallback| &fallback.redirects)) {
+            // the first and last stage of every pipeline of a chain
+            let conflicts = match redirect.op {
+                Op::In => i > 0 && stages[i - 1].chain.is_none(),
+                Op::Out | Op::Append => i < last && stage.chain.is_none(),
+                Op::Err => stage.merge_stderr,
+            };
+            if conflicts {
+                return error(Problem::RedirectConflict(redirect.op, i + 1), redirect.span.start);
+            }
+        }
+    }
+    Ok(stages)
+}
+
+/// adds `stage` to `stages`, or as a fallback of the last one, which then ends as it does
+fn push(stages: &mut Vec<Stage>, stage: Stage, fallback: bool) {
+    match (fallback, stages.last_mut()) {
+        (true, Some(primary)) => {
+            primary.span.end = stage.span.end;
+            primary.merge_stderr = stage.merge_stderr;
+            primary.barrier = stage.barrier;
+            primary.chain = stage.chain;
+            primary.fallbacks.push(stage);
+        },
+        _ => stages.push(stage),
+    }
+}
+
+fn stage(input: &str, mut words: Vec<Word>, redirects: Vec<Redirect>) -> Result<Stage, ParseError> {
+    if words.is_empty() {
+        return Err(ParseError::new(input, Problem::RedirectWithoutCommand, redirects[0].span.start));
+    }
+    let spans = words.iter().map(|word| &word.span).chain(redirects.iter().map(|redirect| &redirect.span));
+    let span = spans.clone().map(|span| span.start).min().unwrap_or(0)..spans.map(|span| span.end).max().unwrap_or(0);
+    if words[0].value == "--" {
+        let dashes = words.remove(0);
+        if words.is_empty() {
+            return Err(ParseError::new(input, Problem::MissingCommand, dashes.span.start));
+        }
+    } else if words[0].value.starts_with('-') {
+        return Err(ParseError::new(input, Problem::OptionAsCommand(words[0].value.clone()), words[0].span.start));
+    }
+    Ok(Stage { words, redirects, span, merge_stderr: false, barrier: false, chain: None, fallbacks: Vec::new() })
+}
+
+/// the stages as a tree, for `plumber parse --debug`
+pub fn tree(stages: &[Stage]) -> String {
+    let mut out = format!("pipeline, {} stages\n", stages.len());
+    for (i, stage) in stages.iter().enumerate() {
+        let merged = match stage.merge_stderr {
+            true => ", stderr piped",
+            false => "",
+        };
+        let barrier = match stage.barrier {
+            true => ", barrier after",
+            false => "",
+        };
+        let chain = stage.chain.map_or(String::new(), |chain| format!(", `{chain}` after"));
+        out.push_str(&format!("  stage {i}, bytes {:?}{merged}{barrier}{chain}\n", stage.span));
+        for word in &stage.words {
+            out.push_str(&format!("    word {:?}, bytes {:?}\n", word.value, word.span));
+        }
+        for redirect in &stage.redirects {
+            out.push_str(&format!("    redirect {} {:?}, bytes {:?}\n", redirect.op, redirect.target, redirect.span));
+        }
+        for fallback in &stage.fallbacks {
+            out.push_str(&format!("    fallback, bytes {:?}\n", fallback.span));
+            for word in &fallback.words {
+                out.push_str(&format!("      word {:?}, bytes {:?}\n", word.value, word.span));
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(input: &str) -> Vec<Vec<String>> {
+        parse(input).unwrap().into_iter()
+            .map(|stage| stage.words.into_iter().map(|w| w.value).collect())
+            .collect()
+    }
+
+    #[test]
+    fn parses_stages_and_words() {
+        assert_eq!(values("grep 'a|b' | tr -d \"\\\"x\\n\"|wc"), [
+            vec!["grep", "a|b"],
+            vec!["tr", "-d", "\"x\\n"],
+            vec!["wc"],
+        ]);
+        assert_eq!(values("# the source\ncat log \\\n  | jq '' # keep it all\n"), [vec!["cat", "log"], vec!["jq", ""]]);
+        assert_eq!(values("# the source\r\ncat log \\\r\n  | jq \"\\\r\n\" # keep it all\r\n"), [vec!["cat", "log"], vec!["jq", ""]]);
+        assert_eq!(values("echo a\\ b"), [vec!["echo", "a b"]]);
+        assert_eq!(values("-- -weird -x | -- -- --"), [vec!["-weird", "-x"], vec!["--", "--"]]);
+        let stages = parse("cat  x | wc").unwrap();
+        assert_eq!(stages[0].span, 0..6);
+        assert_eq!(stages[1].words[0].span, 9..11);
+        let stages = parse("ffmpeg -i x |& tr '\\r' '\\n'|&grep 'a|&b' | wc").unwrap();
+        assert_eq!(stages.iter().map(|stage| stage.merge_stderr).collect::<Vec<_>>(), [true, true, false, false]);
+        assert_eq!(stages[2].words[1].value, "a|&b");
+        let stages = parse("curl -sf x || -- -wget x||fetch x 2> e | gunzip").unwrap();
+        assert_eq!(stages.len(), 2);
+        assert_eq!(stages[0].fallbacks.iter().map(|f| f.words[0].value.as_str()).collect::<Vec<_>>(), ["-wget", "fetch"]);
+        assert_eq!(stages[0].fallbacks[1].redirects[0].target, "e");
+        assert_eq!(stages[0].span.end, "curl -sf x || -- -wget x||fetch x 2> e".len());
+        assert!(parse("curl x || wget x |& gunzip").unwrap()[0].merge_stderr);
+
+        let stages = parse("extract ||| sort | uniq |||load").unwrap();
+        assert_eq!(stages.iter().map(|stage| stage.barrier).collect::<Vec<_>>(), [true, false, true, false]);
+
+        let stages = parse("pg_dump app | zstd > app.zst&&upload app.zst; echo done;notify 'a;b' a\\&&b;\n").unwrap();
+        assert_eq!(stages.iter().map(|stage| stage.chain).collect::<Vec<_>>(),
+            [None, Some(Chain::OnSuccess), Some(Chain::Always), Some(Chain::Always), None]);
+        assert_eq!(stages[4].words.iter().map(|w| w.value.as_str()).collect::<Vec<_>>(), ["notify", "a;b", "a&&b"]);
+        assert_eq!(stages[1].redirects[0].target, "app.zst");
+        assert_eq!(parse("sort < a > b && sort < b >> c").unwrap().len(), 2);
+        assert_eq!(parse("curl x || wget x && gunzip").unwrap()[0].chain, Some(Chain::OnSuccess));
+
+        let stages = parse("sort <in.txt -r 2> 'sort errors.log' | uniq -c >>out.txt '>' x2>y").unwrap();
+        let redirects = |stage: &Stage| stage.redirects.iter().map(|r| (r.op, r.target.clone())).collect::<Vec<_>>();
+        assert_eq!(redirects(&stages[0]), [(Op::In, "in.txt".to_string()), (Op::Err, "sort errors.log".to_string())]);
+        assert_eq!(redirects(&stages[1]), [(Op::Append, "out.txt".to_string()), (Op::Out, "y".to_string())]);
+        assert_eq!(stages[1].words.iter().map(|w| w.value.as_str()).collect::<Vec<_>>(), ["uniq", "-c", ">", "x2"]);
+        assert_eq!(stages[0].span, 0..36);
+    }
+
+    #[test]
+    fn expands_variables() {
+        let env = |name: &str| match name {
+            "DATE" => Some("2024-05-01".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+        let values = |input| parse_expanding(input, &env).map(|stages| stages.into_iter()
+            .map(|stage| stage.words.into_iter().map(|w| w.value).collect::<Vec<_>>())
+            .collect::<Vec<_>>());
+        assert_eq!(values("aws s3 cp s3://bucket/$DATE/x - | zcat").unwrap(), [vec!["aws", "s3", "cp", "s3://bucket/2024-05-01/x", "-"], vec!["zcat"]]);
+        assert_eq!(values(r#"echo "${DATE}T" '$DATE' \$DATE ${REGION:-eu west} ${EMPTY:-none} $ $1x"#).unwrap(),
+            [vec!["echo", "2024-05-01T", "$DATE", "$DATE", "eu west", "none", "$", "$1x"]]);
+        assert_eq!(values("echo $EMPTY \"$EMPTY\"").unwrap(), [vec!["echo", ""]]);
+        assert_eq!(values("echo a$REGION").unwrap_err().to_string(),
+            "1:7: $REGION is not set, give it a default with ${REGION:-...} or keep dollars literal with --no-expand");
+        assert_eq!(values("echo ${DATE").unwrap_err().problem, Problem::BadSubstitution);
+        assert_eq!(values("echo ${}").unwrap_err().problem, Problem::BadSubstitution);
+        assert_eq!(parse("echo $REGION").unwrap()[0].words[1].value, "$REGION");
+    }
+
+    #[test]
+    fn malformed_input_is_an_error() {
+        let problem = |input| parse(input).unwrap_err().problem;
+        assert_eq!(problem(""), Problem::Empty);
+        assert_eq!(problem("  # nothing\n"), Problem::Empty);
+        assert_eq!(problem("| wc"), Problem::LeadingPipe);
+        assert_eq!(problem("cat | |wc"), Problem::EmptyStage(2));
+        assert_eq!(problem("cat ||"), Problem::TrailingPipe);
+        assert_eq!(problem("cat || || wc"), Problem::EmptyStage(2));
+        assert_eq!(problem("curl x || wget x > out"), Problem::FallbackRedirect(Op::Out));
+        assert_eq!(problem("cat | grep x | # wc |\n |"), Problem::EmptyStage(3));
+        assert_eq!(problem("cat |"), Problem::TrailingPipe);
+        assert_eq!(problem("cat |&"), Problem::TrailingPipe);
+        assert_eq!(problem("cat |||"), Problem::TrailingPipe);
+        assert_eq!(problem("cat |||| wc"), Problem::EmptyStage(2));
+        assert_eq!(problem("|& wc"), Problem::LeadingPipe);
+        assert_eq!(problem("&& wc"), Problem::LeadingChain(Chain::OnSuccess));
+        assert_eq!(problem("cat ;; wc"), Problem::LeadingChain(Chain::Always));
+        assert_eq!(problem("cat &&"), Problem::TrailingChain);
+        assert_eq!(problem("cat | && wc"), Problem::TrailingPipe);
+        assert_eq!(problem("cat && | wc"), Problem::LeadingPipe);
+        assert_eq!(problem(";"), Problem::LeadingChain(Chain::Always));
+        assert_eq!(problem("cat > out | sort && wc"), Problem::RedirectConflict(Op::Out, 1));
+        assert_eq!(problem("cat && sort | wc < in"), Problem::RedirectConflict(Op::In, 3));
+        assert_eq!(problem("cat '|' |\n  # wc | sort\n"), Problem::TrailingPipe);
+        assert_eq!(problem("cat | -- | wc"), Problem::MissingCommand);
+        assert_eq!(problem("cat | -v"), Problem::OptionAsCommand("-v".to_string()));
+        assert_eq!(problem("cat | '-v'"), Problem::OptionAsCommand("-v".to_string()));
+        assert_eq!(problem("sort < | wc"), Problem::MissingTarget(Op::In));
+        assert_eq!(problem("sort >"), Problem::MissingTarget(Op::Out));
+        assert_eq!(problem("sort > >> x"), Problem::MissingTarget(Op::Out));
+        assert_eq!(problem("> out"), Problem::RedirectWithoutCommand);
+        assert_eq!(problem("cat | sort < in"), Problem::RedirectConflict(Op::In, 2));
+        assert_eq!(problem("cat >> out | sort"), Problem::RedirectConflict(Op::Append, 1));
+        assert_eq!(problem("cat 2> err |& sort"), Problem::RedirectConflict(Op::Err, 1));
+        assert_eq!(problem("cat 2>&1 | sort"), Problem::StderrToStdout);
+        assert_eq!(problem("echo \"a"), Problem::UnterminatedQuote('"'));
+        assert_eq!(problem("echo \\"), Problem::TrailingBackslash);
+
+        let input = "cat x |\n  grep 'oops";
+        let e = parse(input).unwrap_err();
+        assert_eq!((e.line, e.column), (2, 8));
+        assert_eq!(e.to_string(), "2:8: unterminated ' quote");
+        assert_eq!(e.show(input), "  grep 'oops\n       ^");
+
+        let input = "cat x | sort | |\n  wc -l";
+        let e = parse(input).unwrap_err();
+        assert_eq!(e.to_string(), "1:16: stage 3 is empty, two `|` with nothing between them");
+        let e = parse("cat x | sort | # wc -l").unwrap_err();
+        assert_eq!((e.at, e.to_string().as_str()), (13, "1:14: trailing `|` with no command after it"));
+    }
+
+    /// xorshift, so the properties are checked against the same inputs every run
+    fn random(seed: &mut u64) -> u64 {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 7;
+        *seed ^= *seed << 17;
+        *seed
+    }
+
+    #[test]
+    fn arbitrary_input_never_panics() {
+        let alphabet: Vec<char> = "ab |'\"\\#\n\té$<>2&;".chars().collect();
+        let mut seed = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..20_000 {
+            let len = random(&mut seed) % 24;
+            let input: String = (0..len).map(|_| alphabet[random(&mut seed) as usize % alphabet.len()]).collect();
+            if let Err(e) = parse(&input) {
+                assert!(e.at <= input.len() && input.is_char_boundary(e.at), "{input:?}: {e:?}");
+                e.show(&input);
+            }
+        }
+    }
+
+    #[test]
+    fn quoted_words_parse_back() {
+        let alphabet: Vec<char> = "ab -|'\"\\#\n$=".chars().collect();
+        let mut seed = 0x9e37_79b9_7f4a_7c15;
+        for _ in 0..5_000 {
+            let stages: Vec<Vec<String>> = (0..1 + random(&mut seed) % 3)
+                .map(|_| (0..1 + random(&mut seed) % 3)
+                    .map(|_| (0..random(&mut seed) % 6).map(|_| alphabet[random(&mut seed) as usize % alphabet.len()]).collect())
+                    .collect())
+                .collect();
+            let input = stages.iter()
+                .map(|words| words.iter().map(|w| format!("'{}'", w.replace('\'', "'\\''"))).collect::<Vec<_>>().join(" "))
+                .map(|words| format!("-- {words}"))
+                .collect::<Vec<_>>()
+                .join(" | ");
+            assert_eq!(values(&input), stages, "{input:?}");
+        }
+    }
+}
@@ -1,104 +1,1028 @@
-use std::io::Write;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::fs;
-use std::process::{Child, Stdio, Command};
-use std::os::unix::process::CommandExt;
+use std::io::Write;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
 use log::error;
+use regex::Regex;
 
-const LOGGING_DIR: &str = "/tmp/plumber/log";
-const METADATA_DIR: &str = "/tmp/plumber/lib";
+use crate::allowlist::Allowlist;
+use crate::archive::{self, LogSpan, Run};
+use crate::audit;
+use crate::budget::Budget;
+use crate::capture::{self, LogFilter};
+use crate::clock::{Clock, SystemClock};
+use crate::control::{self, Control};
+use crate::cores::{self, CoreDumps};
+use crate::definition;
+use crate::exits::{self, OnExit};
+use crate::fds::{self, ExtraFd};
+use crate::heartbeat::{Beating, Heartbeat};
+use crate::metrics::{self, Metrics};
+use crate::names::{self, NameError, ValidatedName};
+use crate::namespace::{self, Quota};
+use crate::numa::{self, NumaRule};
+use crate::outputs;
+use crate::overhead::Overhead;
+use crate::parser::{self, Chain, Op, ParseError};
+use crate::precondition::{self, OnUnmet, Precondition};
+use crate::prune::StateDirs;
+use crate::reactor::{self, Finished, Handler, Wait};
+use crate::readiness::{Outcome, ReadyCheck};
+use crate::recorder;
+use crate::restarts::{self, Backoff, CrashLoop, Restarts};
+use crate::revision;
+use crate::seccomp::{self, ObserveRule};
+use crate::shell::{self, Shell};
+use crate::socket::{self, Socket};
+use crate::spool::{self, Compression, Limits, Spool, SpoolFull};
+use crate::stages;
+use crate::state;
+use crate::templates;
+use crate::status;
+use crate::transport::{self, Link, PipeSize, Stats};
+use crate::summary::{self, PipelineExitStatus, RunSummary, StageRun};
+use crate::upgrade::{self, Adopted};
+use crate::upstream::{self, EofHook, OnUpstreamExit, UpstreamExit};
+use crate::usage::{self, Usage};
+use crate::watchdog;
+use crate::window::Schedule;
+use crate::worker::{self, Worker};
+use crate::wrapper::{self, Wrapper};
 
-#[derive(Debug, PartialEq)]
-struct PipelineCommand {
-    name: String,
-    args: Vec<String>,
+/// overrides where plumber keeps its state, for tests and side-by-side installs
+pub const ROOT_ENV: &str = "PLUMBER_ROOT";
+/// where pipeline metadata is kept, rather than under the state root
+pub const STATE_DIR_ENV: &str = "PLUMBER_STATE_DIR";
+/// where the stderr logs of stages are written, rather than under the state root
+pub const LOG_DIR_ENV: &str = "PLUMBER_LOG_DIR";
+
+/// where state is kept, resolved once so everything plumber does agrees on it
+struct Dirs {
+    /// `$PLUMBER_ROOT`, `$XDG_STATE_HOME/plumber` or `/tmp/plumber`
+    root: PathBuf,
+    /// whether the root or the metadata dir was given, rather than defaulted, so no other root is
+    /// looked in for a pipeline, see `metadata_dir`
+    given: bool,
+    /// `--state-dir` or `$PLUMBER_STATE_DIR`
+    state: Option<PathBuf>,
+    /// `--log-dir` or `$PLUMBER_LOG_DIR`
+    log: Option<PathBuf>,
 }
 
-impl PipelineCommand {
-    pub fn new(mut cmd: Vec<String>) -> Self {
-        let name = cmd.remove(0);
-        let args = cmd;
+static DIRS: OnceLock<Dirs> = OnceLock::new();
+
+/// sets the metadata and logging dirs given on the command line, before any pipeline is touched
+pub fn set_dirs(state: Option<PathBuf>, log: Option<PathBuf>) {
+    let _ = DIRS.set(resolve_dirs(state, log));
+}
+
+fn dirs() -> &'static Dirs {
+    DIRS.get_or_init(|| resolve_dirs(None, None))
+}
+
+fn resolve_dirs(state: Option<PathBuf>, log: Option<PathBuf>) -> Dirs {
+    // stages are handed their metadata dir, and may not run where plumber does
+    let absolute = |dir: PathBuf| std::path::absolute(&dir).unwrap_or(dir);
+    let root = env_dir(ROOT_ENV);
+    let state = state.or_else(|| env_dir(STATE_DIR_ENV));
+    Dirs {
+        given: root.is_some() || state.is_some(),
+        root: absolute(root
+            .or_else(|| env_dir("XDG_STATE_HOME").map(|state| state.join("plumber")))
+            .unwrap_or_else(|| PathBuf::from("/tmp/plumber"))),
+        state: state.map(absolute),
+        log: log.or_else(|| env_dir(LOG_DIR_ENV)).map(absolute),
+    }
+}
+
+/// the roots plumbers of this user defaulted to, a line each. it's under the home dir in the
+/// passwd database, which no variable moves, so a `plumber stop` run from a login shell with
+/// `$XDG_STATE_HOME` set finds the pipeline a service without it started in `/tmp/plumber`
+fn roots_file() -> Option<PathBuf> {
+    let uid = unsafe { libc::getuid() };
+    let passwd = fs::read_to_string("/etc/passwd").ok()?;
+    passwd.lines()
+        .map(|l| l.split(':').collect::<Vec<_>>())
+        .find(|f| f.len() > 5 && f[2].parse() == Ok(uid) && !f[5].is_empty())
+        .map(|f| Path::new(f[5]).join(".local/state/plumber/roots"))
+}
+
+fn recorded_roots(file: &Path) -> Vec<PathBuf> {
+    fs::read_to_string(file).unwrap_or_default().lines().filter(|l| !l.is_empty()).map(PathBuf::from).collect()
+}
+
+/// adds `root` to those recorded in `file` unless it's there already
+fn record_root(file: &Path, root: &Path) -> std::io::Result<()> {
+    if recorded_roots(file).iter().any(|recorded| recorded == root) {
+        return Ok(());
+    }
+    if let Some(dir) = file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut line = root.as_os_str().as_bytes().to_vec();
+    line.push(b'\n');
+    fs::OpenOptions::new().create(true).append(true).open(file)?.write_all(&line)
+}
+
+/// the metadata dir of pipeline `name`, where a pipeline of that name was started under any
+/// recorded root when there's none under ours. as it is, when the root was given
+fn metadata_dir(name: &ValidatedName) -> PathBuf {
+    let dir = metadata_root().join(name);
+    if dirs().given || dir.exists() {
+        return dir;
+    }
+    let relative = |root: &Path| match namespace::current() {
+        Some(namespace) => namespace::root(root, &namespace),
+        None => root.to_owned(),
+    };
+    roots_file().map(|file| recorded_roots(&file)).unwrap_or_default().iter()
+        .map(|root| relative(root).join("lib").join(name))
+        .find(|recorded| recorded.exists())
+        .unwrap_or(dir)
+}
+
+/// whether `$NAME` in pipelines is expanded, unless `--no-expand`
+static EXPAND: OnceLock<bool> = OnceLock::new();
+
+/// sets whether environment variables in pipelines are expanded, before any is parsed
+pub fn set_expand(expand: bool) {
+    let _ = EXPAND.set(expand);
+}
+
+fn env_dir(var: &str) -> Option<PathBuf> {
+    std::env::var_os(var).filter(|dir| !dir.is_empty()).map(PathBuf::from)
+}
+
+/// what every namespace keeps its state under: `$PLUMBER_ROOT`, `$XDG_STATE_HOME/plumber` or
+/// `/tmp/plumber`
+pub fn shared_root() -> PathBuf {
+    dirs().root.clone()
+}
+
+/// where plumber keeps its state, the shared root or that of the namespace it runs in
+pub fn state_root() -> PathBuf {
+    match namespace::current() {
+        Some(namespace) => namespace::root(&shared_root(), &namespace),
+        None => shared_root(),
+    }
+}
+
+fn logging_root() -> PathBuf {
+    dirs().log.clone().unwrap_or_else(|| state_root().join("log"))
+}
+
+fn metadata_root() -> PathBuf {
+    dirs().state.clone().unwrap_or_else(|| state_root().join("lib"))
+}
+
+/// scratch space handed to stages as `$PLUMBER_TMPDIR`, one dir per run
+fn tmp_root() -> PathBuf {
+    state_root().join("tmp")
+}
+
+/// a file in place of a pipe or a log, `<`, `>`, `>>` or `2>` in a plumber file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redirect {
+    pub path: PathBuf,
+    /// `>>`, written after what's there rather than emptying it
+    pub append: bool,
+}
+
+impl Redirect {
+    fn open(&self, write: bool) -> std::io::Result<OwnedFd> {
+        let mut options = fs::OpenOptions::new();
+        match write {
+            true => options.create(true).write(true).append(self.append).truncate(!self.append),
+            false => options.read(true),
+        };
+        Ok(options.open(&self.path)?.into())
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct PipelineCommand {
+    pub name: String,
+    pub args: Vec<String>,
+    /// set on top of plumber's own, see `definition`
+    pub env: Vec<(String, String)>,
+    pub cwd: Option<PathBuf>,
+    /// instead of `<logging dir>/<stage>.stderr.log`, relative to the logging dir, see `stderr_logs`
+    pub stderr_log: Option<PathBuf>,
+    /// `|&`, stderr goes into the pipe to the next stage rather than to the log
+    pub merge_stderr: bool,
+    /// `|||`, the stages after it wait for it and those before it to exit successfully
+    pub barrier: bool,
+    /// `&&` or `;`, the last stage of a pipeline another one follows
+    pub chain: Option<Chain>,
+    /// of the first stage of a pipeline only, the parser sees to that
+    pub stdin: Option<Redirect>,
+    /// of the last stage of a pipeline only, instead of `--output`
+    pub stdout: Option<Redirect>,
+    /// instead of the stderr log
+    pub stderr: Option<Redirect>,
+    /// run in its place in turn when it can't be spawned or exits unsuccessfully, `||`
+    pub fallbacks: Vec<PipelineCommand>,
+}
 
+impl PipelineCommand {
+    pub fn new(name: String, args: Vec<String>) -> Self {
         PipelineCommand {
             name,
-            args
+            args,
+            ..PipelineCommand::default()
+        }
+    }
+
+    /// a barrier or the end of a pipeline another follows, the stages after it waiting for it
+    fn ends_phase(&self) -> bool {
+        self.barrier || self.chain.is_some()
+    }
+
+    /// file name used for the stage's stderr log
+    pub fn log_name(&self) -> &str {
+        match shell::script(&self.name) {
+            Some(_) => "script",
+            None => stages::builtin_kind(&self.name).unwrap_or(&self.name),
+        }
+    }
+
+    /// the stage as it would be written in a plumber file
+    pub fn command_line(&self) -> String {
+        let quote = |word: &str| match !word.is_empty() && word.chars().all(|c| c.is_alphanumeric() || "-_./:=,@%+^#".contains(c)) {
+            true => word.to_owned(),
+            false => shlex::quote(word).into_owned(),
+        };
+        let mut words = std::iter::once(&self.name)
+            .chain(&self.args)
+            .map(|word| quote(word))
+            .collect::<Vec<_>>()
+            .join(" ");
+        for (op, redirect) in [("<", &self.stdin), (">", &self.stdout), ("2>", &self.stderr)] {
+            if let Some(redirect) = redirect {
+                let op = if redirect.append { ">>" } else { op };
+                words.push_str(&format!(" {op} {}", quote(&redirect.path.to_string_lossy())));
+            }
+        }
+        for fallback in &self.fallbacks {
+            words.push_str(&format!(" || {}", fallback.command_line()));
+        }
+        match self.name.starts_with('-') {
+            true => format!("-- {words}"),
+            false => words,
         }
     }
 }
 
+impl From<parser::Stage> for PipelineCommand {
+    fn from(stage: parser::Stage) -> Self {
+        let mut words = stage.words.into_iter().map(|word| word.value);
+        // the parser never returns a stage without words
+        let mut command = PipelineCommand::new(words.next().unwrap_or_default(), words.collect());
+        command.merge_stderr = stage.merge_stderr;
+        command.barrier = stage.barrier;
+        command.chain = stage.chain;
+        for redirect in stage.redirects {
+            let to = Some(Redirect { path: redirect.target.into(), append: redirect.op == Op::Append });
+            match redirect.op {
+                Op::In => command.stdin = to,
+                Op::Out | Op::Append => command.stdout = to,
+                Op::Err => command.stderr = to,
+            }
+        }
+        command.fallbacks = stage.fallbacks.into_iter().map(PipelineCommand::from).collect();
+        command
+    }
+}
+
 pub struct Pipeline {
-    name: String,
+    name: ValidatedName,
     raw_pipeline: String,
     commands: Vec<PipelineCommand>,
-    jobs: Vec<Child>,
+    jobs: Vec<Job>,
     metadata_dir: PathBuf,
     logging_dir: PathBuf,
+    /// `.lock` in the metadata dir, held while the pipeline is around, see `lock`
+    _lock: fs::File,
+    observe: Vec<ObserveRule>,
+    instrument: bool,
+    links: Vec<Arc<Link>>,
+    /// bytes the stages may pass on in a run, see `budget`
+    budget: Option<Arc<Budget>>,
+    relays: Vec<Finished>,
+    pipe_sizes: Vec<PipeSize>,
+    spools: Vec<Spool>,
+    /// disk each spooled link may spill to, and what happens beyond it, see `spool`
+    max_spool: Option<u64>,
+    on_spool_full: SpoolFull,
+    spool_compression: Vec<Compression>,
+    /// executable of every stage, resolved once so restarts don't search PATH again
+    programs: Vec<PathBuf>,
+    /// stderr log of every stage, opened once and shared by all of its restarts
+    logs: Vec<fs::File>,
+    /// where those are, see `stderr_logs`
+    log_paths: Vec<PathBuf>,
+    restart: Restart,
+    spawn_order: SpawnOrder,
+    ready: Vec<ReadyCheck>,
+    ready_timeout: Duration,
+    crash_loop: CrashLoop,
+    backoff: Backoff,
+    log_filter: LogFilter,
+    captures: Mutex<Vec<Finished>>,
+    /// of the last stage, whose stdout a `--max-log-size` may end it for
+    last_pid: Arc<AtomicU32>,
+    /// `$PLUMBER_TMPDIR` of the current run
+    tmpdir: Option<PathBuf>,
+    keep_tmpdir: bool,
+    core_dumps: Option<CoreDumps>,
+    /// when the current run started
+    started: SystemTime,
+    extra_fds: Vec<ExtraFd>,
+    upstream_exit: Vec<UpstreamExit>,
+    eof_hooks: Vec<EofHook>,
+    /// sentinels being written and eof hooks running, see `end_input`
+    ending: Vec<JoinHandle<()>>,
+    /// pipe ends of every stage kept for the stage downstream of it, see `upstream`
+    held: Vec<Option<Held>>,
+    /// stages after a barrier with their stdin and stdout, until those before it are done
+    pending: Vec<Unspawned>,
+    /// of every stage, 0 for its command and from 1 the fallback running in its place
+    alternatives: Vec<AtomicUsize>,
+    restarts: Restarts,
+    /// what scheduling and restarts go by, see `clock`
+    clock: Arc<dyn Clock>,
+    wrappers: Vec<Wrapper>,
+    /// interpreters of scripts and hooks, see `shell`
+    shells: Vec<Shell>,
+    /// the systemd watchdog isn't petted once it crash-loops, see `watchdog`
+    critical: bool,
+    /// the file kept fresh while stages run, see `heartbeat`
+    heartbeat: Option<Beating>,
+    /// the git work tree the plumber file is in, see `revision`
+    checkout: Option<PathBuf>,
+    /// the commit it was at when the current run started
+    revision: Option<String>,
+    /// the times of day runs may start, see `window`
+    schedule: Schedule,
+    /// what has to hold before a run is spawned, see `precondition`
+    preconditions: Vec<Precondition>,
+    /// the definitions it may be reloaded as, see `allowlist`
+    allowlist: Option<Arc<Allowlist>>,
+    /// of the namespace it runs in, checked again when it's reloaded
+    quota: Option<(String, Quota)>,
+    /// held while it runs, counting against the namespace's `max_pipelines`
+    slot: Option<namespace::Slot>,
+    on_unmet: OnUnmet,
+    /// length of every stderr log when the current run started
+    log_offsets: Vec<u64>,
+    /// secrets left out of archived runs, see `archive`
+    redact: Vec<Regex>,
+    /// stages asked to stop over their control socket, see `control`
+    cooperative: Vec<String>,
+    /// how long a stage asked to stop has before it's signalled
+    stop_grace: Duration,
+    /// control sockets of the cooperative stages while the pipeline is supervised
+    control: Option<Control>,
+    /// a pipeline fails when any stage does, not only the last
+    pipefail: bool,
+    /// numa nodes stages run on and allocate from, see `numa`
+    numa_cpu: Vec<NumaRule>,
+    numa_mem: Vec<NumaRule>,
+    /// where the last stage writes
+    output: Output,
+    /// the file it writes to, opened once and shared by all of its restarts
+    output_file: Option<fs::File>,
+    /// answering `status`, `stop` and `signal` while the pipeline is supervised, see `socket`
+    socket: Option<Socket>,
+    /// what exit codes of stages mean, see `exits`
+    on_exit: Vec<OnExit>,
+    /// how long a run may take before it's drained
+    timeout: Option<Duration>,
+    /// counters and gauges the stages reported, see `metrics`
+    metrics: Arc<Mutex<Metrics>>,
+    /// stages given an fd to report metrics on
+    reporting: Vec<String>,
+    /// reading what those stages report until they exit
+    reports: Mutex<Vec<Finished>>,
+    /// stages kept running across runs, see `worker`
+    worker_stages: Vec<String>,
+    /// by stage, those running
+    workers: Mutex<HashMap<usize, Worker>>,
+}
+
+/// ends of a stage's pipes plumber holds on to after spawning it
+struct Held {
+    /// for respawning the stage, `None` for the first one or when it isn't respawned
+    stdin: Option<OwnedFd>,
+    stdout: OwnedFd,
+}
+
+impl Held {
+    /// clones of `stdin`, as the stages' stdins are kept, and `stdout`
+    fn of(stdin: Option<&Option<OwnedFd>>, stdout: &OwnedFd) -> std::io::Result<Held> {
+        Ok(Held { stdin: stdin.and_then(Option::as_ref).map(OwnedFd::try_clone).transpose()?, stdout: stdout.try_clone()? })
+    }
+}
+
+/// order in which the stages of a pipeline are spawned
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum SpawnOrder {
+    /// consumers are running before their producers start writing
+    DownstreamFirst,
+    UpstreamFirst,
+}
+
+/// where the last stage's stdout goes, one `--stdout` flag
+#[derive(Debug, Clone, PartialEq)]
+pub enum Output {
+    /// plumber's own stdout
+    Inherit,
+    /// `<logging dir>/<stage>.stdout.log`, emptied when the pipeline starts
+    Log,
+    /// appended to a file
+    Append(PathBuf),
+    /// /dev/null
+    Discard,
+}
+
+impl std::str::FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("append", path)) if !path.is_empty() => Ok(Output::Append(PathBuf::from(path))),
+            None if s == "inherit" => Ok(Output::Inherit),
+            None if s == "log" => Ok(Output::Log),
+            None if s == "discard" => Ok(Output::Discard),
+            _ => Err(format!("unknown output '{s}', expected inherit, log, append:<path> or discard")),
+        }
+    }
+}
+
+/// what `plumber stop` signals
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum StopMode {
+    /// SIGTERM the first stage and let the rest drain as their input ends
+    Drain,
+    /// SIGTERM the process group of every stage, for stages that don't exit on EOF
+    Group,
+}
+
+/// how a stage exited and what it used, `None` if its exit status was lost in an upgrade
+type Exit = Option<(ExitStatus, Option<Usage>)>;
+
+/// a stage yet to be spawned with its stdin and stdout, plumber's own when `None`
+type Unspawned = (usize, (Option<OwnedFd>, Option<OwnedFd>));
+
+/// a stage, spawned by this plumber or adopted from the one it replaced, see `upgrade`
+enum Job {
+    Spawned(Child),
+    Adopted(Adopted),
+    /// exited while plumber was being upgraded
+    Gone,
+    /// after a barrier, see `Pipeline::spawn_phase`
+    Pending,
+}
+
+impl Job {
+    fn id(&self) -> Option<u32> {
+        match self {
+            Job::Spawned(child) => Some(child.id()),
+            Job::Adopted(adopted) => Some(adopted.pid),
+            Job::Gone | Job::Pending => None,
+        }
+    }
+
+    /// readable once the stage exited, `None` if there's none to wait for
+    fn pidfd(&self) -> Option<std::io::Result<OwnedFd>> {
+        match self {
+            Job::Spawned(child) => Some(usage::pidfd(child.id())),
+            Job::Adopted(adopted) => Some(adopted.pidfd()),
+            Job::Gone | Job::Pending => None,
+        }
+    }
+
+    /// waits for the stage to exit
+    fn wait(&mut self) -> Exit {
+        match self {
+            Job::Spawned(child) => Some(match usage::reap(child.id()) {
+                Ok((status, usage)) => (status, Some(usage)),
+                // already reaped by a readiness gate that saw it exit
+                Err(_) => (child.wait().unwrap(), None),
+            }),
+            Job::Adopted(adopted) => Some(match adopted.wait() {
+                Ok((status, usage)) => (status, Some(usage)),
+                Err(_) => (ExitStatus::from_raw(1 << 8), None),
+            }),
+            Job::Gone | Job::Pending => None,
+        }
+    }
+}
+
+/// when a pipeline is spawned again after it exited
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Restart {
+    Never,
+    /// when any stage exited unsuccessfully
+    OnFailure,
+    Always,
 }
 
 #[derive(Debug)]
 pub enum PipelineError {
-    FileNotFound,
-    Other
+    /// the program of a stage, or the wrapper it runs in, isn't on `PATH`
+    CommandNotFound { stage: String, name: String },
+    SpawnFailed { stage: String, source: std::io::Error },
+    /// a file plumber keeps state in, or reads a definition from
+    MetadataIo { path: PathBuf, source: std::io::Error },
+    /// `position` is the byte offset into the definition
+    ParseError { position: usize, reason: String },
+    /// the process to signal is gone
+    NotRunning,
+    /// the process to signal runs as another user
+    PermissionDenied,
+    SignalFailed { pid: libc::pid_t, source: std::io::Error },
+    /// starting the pipeline would take the namespace over its quota
+    QuotaExceeded { namespace: String, reason: String },
+    /// not a name a pipeline may have, see `names`
+    InvalidName(NameError),
+    /// another plumber holds the pipeline's `.lock`, `pid` if it recorded it yet
+    AlreadyRunning { pid: Option<u32> },
+    /// a `--require` that didn't hold with `--on-unmet fail`
+    PreconditionUnmet { precondition: String, reason: String },
 }
 
-impl From<std::io::Error> for PipelineError {
-    fn from(value: std::io::Error) -> Self {
-        match value.kind() {
-            std::io::ErrorKind::NotFound => PipelineError::FileNotFound,
-            _ => PipelineError::Other,
+impl PipelineError {
+    /// for a parse error, the line of `input` it's on with a caret under where
+    pub fn show(&self, input: &str) -> String {
+        match self {
+            PipelineError::ParseError { position, .. } => parser::show(input, *position),
+            _ => String::new(),
         }
     }
 }
 
+impl std::fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PipelineError::CommandNotFound { stage, name } => write!(f, "{stage}: command not found: {name}"),
+            PipelineError::SpawnFailed { stage, source } => write!(f, "{stage}: unable to spawn: {source}"),
+            PipelineError::MetadataIo { path, source } => write!(f, "{}: {source}", path.display()),
+            PipelineError::ParseError { reason, .. } => write!(f, "{reason}"),
+            PipelineError::NotRunning => write!(f, "not running"),
+            PipelineError::PermissionDenied => write!(f, "not permitted to signal it"),
+            PipelineError::SignalFailed { pid, source } => write!(f, "unable to signal {pid}: {source}"),
+            PipelineError::QuotaExceeded { namespace, reason } => write!(f, "over the quota of namespace {namespace}: {reason}"),
+            PipelineError::InvalidName(e) => write!(f, "{e}"),
+            PipelineError::AlreadyRunning { pid: Some(pid) } => write!(f, "already running, supervised by pid {pid}"),
+            PipelineError::AlreadyRunning { pid: None } => write!(f, "already running"),
+            PipelineError::PreconditionUnmet { precondition, reason } => write!(f, "precondition {precondition} not met: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for PipelineError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PipelineError::SpawnFailed { source, .. }
+            | PipelineError::MetadataIo { source, .. }
+            | PipelineError::SignalFailed { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<ParseError> for PipelineError {
+    fn from(e: ParseError) -> Self {
+        PipelineError::ParseError { position: e.at, reason: e.to_string() }
+    }
+}
+
+/// for `map_err`, an io error on `path`
+fn metadata_io(path: &Path) -> impl FnOnce(std::io::Error) -> PipelineError + '_ {
+    move |source| PipelineError::MetadataIo { path: path.to_owned(), source }
+}
+
+/// a file that doesn't hold what plumber wrote to it
+fn malformed(path: &Path) -> PipelineError {
+    let source = std::io::Error::new(std::io::ErrorKind::InvalidData, "not a pid");
+    PipelineError::MetadataIo { path: path.to_owned(), source }
+}
+
+/// sends `signal` to `pid`, or to the process group `-pid`
+fn signal(pid: libc::pid_t, signal: libc::c_int) -> Result<(), PipelineError> {
+    if unsafe { libc::kill(pid, signal) } == 0 {
+        return Ok(());
+    }
+    let source = std::io::Error::last_os_error();
+    match source.raw_os_error() {
+        Some(libc::ESRCH) => Err(PipelineError::NotRunning),
+        Some(libc::EPERM) => Err(PipelineError::PermissionDenied),
+        _ => Err(PipelineError::SignalFailed { pid, source }),
+    }
+}
+
 impl Pipeline {
-    pub fn stop(name: &str) -> Result<(), PipelineError> {
-        let metadata_dir = Path::new(METADATA_DIR).join(&name);
-        let first_job_pid = fs::read_to_string(metadata_dir.join(".pid"))?;
+    pub fn stop(name: &ValidatedName, mode: StopMode) -> Result<(), PipelineError> {
+        let metadata_dir = metadata_dir(name);
+        let stop = metadata_dir.join(".stop");
+        if mode == StopMode::Group {
+            fs::write(&stop, "").map_err(metadata_io(&stop))?;
+            let stages = metadata_dir.join(".stages");
+            let groups = match fs::read_to_string(&stages) {
+                Ok(_) => Pipeline::stage_groups(name),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound && metadata_dir.join(".supervisor").exists() => return Ok(()),
+                Err(e) => return Err(metadata_io(&stages)(e)),
+            };
+            for group in groups {
+                log::debug!("{name}: stopping process group of a stage => kill -SIGTERM -{group}");
+                match signal(-group, libc::SIGTERM) {
+                    // exited meanwhile
+                    Ok(()) | Err(PipelineError::NotRunning) => {},
+                    Err(e) => return Err(e),
+                }
+            }
+            return Ok(());
+        }
+        // keeps a pipeline with a restart policy from coming back, also while backing off
+        if control::cooperating(&metadata_dir) {
+            log::debug!("{name}: leaving the stop to its supervisor, which asks cooperative stages");
+            fs::write(&stop, control::STOP).map_err(metadata_io(&stop))?;
+            return Ok(());
+        }
+        fs::write(&stop, "").map_err(metadata_io(&stop))?;
+        let pid_file = metadata_dir.join(".pid");
+        let first_job_pid = match fs::read_to_string(&pid_file) {
+            Ok(pid) => pid,
+            // backing off between restarts, the supervisor sees `.stop` and won't restart it
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && metadata_dir.join(".supervisor").exists() => return Ok(()),
+            Err(e) => return Err(metadata_io(&pid_file)(e)),
+        };
 
+        let first_job_pid = status::parse_pid(&first_job_pid, false).ok_or_else(|| malformed(&pid_file))?;
         log::debug!("{name}: stopping first process in pipeline => kill -SIGTERM {first_job_pid}");
-        let _ = Command::new("kill")
-            .arg("-SIGTERM")
-            .arg(&first_job_pid)
-            .status()?;
+        signal(first_job_pid, libc::SIGTERM)
+    }
 
-        Ok(())
+    /// has the supervisor of a running pipeline start it again as `raw_pipeline` once the stages
+    /// it runs now drained, see `plumber edit`
+    pub fn reload(name: &ValidatedName, raw_pipeline: &str) -> Result<(), PipelineError> {
+        Pipeline::parse_raw_pipeline(raw_pipeline)?;
+        let metadata_dir = metadata_dir(name);
+        let reload = metadata_dir.join(".reload");
+        fs::write(&reload, raw_pipeline).map_err(metadata_io(&reload))?;
+        let pid_file = metadata_dir.join(".pid");
+        let first_job_pid = match fs::read_to_string(&pid_file) {
+            Ok(pid) => pid,
+            // backing off between restarts, the next start picks it up
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && metadata_dir.join(".supervisor").exists() => return Ok(()),
+            Err(e) => return Err(metadata_io(&pid_file)(e)),
+        };
+        let first_job_pid = status::parse_pid(&first_job_pid, false).ok_or_else(|| malformed(&pid_file))?;
+        log::debug!("{name}: draining pipeline to reload it => kill -SIGTERM {first_job_pid}");
+        signal(first_job_pid, libc::SIGTERM)
+    }
+
+    /// pid of every stage of a running pipeline, in pipeline order
+    pub fn stage_pids(name: &ValidatedName) -> Vec<Option<u32>> {
+        fs::read_to_string(Pipeline::metadata_file(name, ".stages"))
+            .unwrap_or_default()
+            .lines()
+            .map(|pid| status::parse_pid(pid, false).map(|pid| pid as u32))
+            .collect()
+    }
+
+    /// process groups of the stages of a pipeline that still have a process in them
+    pub fn stage_groups(name: &ValidatedName) -> Vec<libc::pid_t> {
+        fs::read_to_string(Pipeline::metadata_file(name, ".stages"))
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|pid| status::parse_pid(pid, true))
+            .filter(|&group| unsafe { libc::killpg(group, 0) } == 0)
+            .collect()
+    }
+
+    /// whether anything of a pipeline is left, a stage or a supervisor still to clean up
+    pub fn is_alive(name: &ValidatedName) -> bool {
+        !Pipeline::stage_groups(name).is_empty() || status::running(&metadata_dir(name))
+    }
+
+    /// sends `sig` to stage `index` of a running pipeline
+    pub fn signal_stage(name: &ValidatedName, index: usize, sig: libc::c_int) -> Result<(), PipelineError> {
+        let stages = Pipeline::metadata_file(name, ".stages");
+        // an empty line is a stage that exited
+        let pid = fs::read_to_string(&stages).ok().and_then(|pids| pids.lines().nth(index).map(str::to_owned));
+        let Some(pid) = pid.filter(|pid| !pid.trim().is_empty()) else {
+            return Err(PipelineError::NotRunning);
+        };
+        let pid = status::parse_pid(&pid, false).ok_or_else(|| malformed(&stages))?;
+        log::debug!("{name}: signalling stage {index} => kill -{sig} {pid}");
+        signal(pid, sig)
+    }
+
+    /// SIGKILLs every process of every stage of a pipeline, what they spawned included
+    pub fn kill(name: &ValidatedName) {
+        for group in Pipeline::stage_groups(name) {
+            log::debug!("{name}: killing process group {group} => kill -SIGKILL -{group}");
+            unsafe { libc::killpg(group, libc::SIGKILL) };
+        }
+    }
+
+    /// dirs every pipeline keeps its state in, see `prune`
+    pub fn state_dirs() -> StateDirs {
+        StateDirs {
+            metadata: metadata_root(),
+            logs: logging_root(),
+            tmp: tmp_root(),
+        }
+    }
+
+    /// a file in a pipeline's metadata dir, such as `.pid`
+    pub fn metadata_file(name: &ValidatedName, file: &str) -> PathBuf {
+        metadata_dir(name).join(file)
+    }
+
+    /// pid of the plumber supervising a running pipeline
+    pub fn supervisor(name: &ValidatedName) -> Result<u32, PipelineError> {
+        let path = Pipeline::metadata_file(name, ".supervisor");
+        let pid = fs::read_to_string(&path).map_err(metadata_io(&path))?;
+        status::parse_pid(&pid, false).map(|pid| pid as u32).ok_or_else(|| malformed(&path))
+    }
+
+    /// whether a pipeline with this name is currently running, or waiting to be restarted
+    pub fn is_running(name: &ValidatedName) -> bool {
+        status::running(&metadata_dir(name))
+    }
+
+    /// log watched syscalls of stages, see `seccomp`
+    pub fn observe_syscalls(&mut self, rules: Vec<ObserveRule>) {
+        self.observe = rules;
+    }
+
+    /// relay links through plumber to measure them, see `transport`
+    pub fn instrument_links(&mut self, enabled: bool) {
+        self.instrument = enabled;
+    }
+
+    /// capacity of the pipes feeding stages, see `transport::PipeSize`
+    pub fn set_pipe_sizes(&mut self, sizes: Vec<PipeSize>) {
+        self.pipe_sizes = sizes;
+    }
+
+    pub fn set_restart(&mut self, restart: Restart) {
+        self.restart = restart;
+    }
+
+    /// drain a run that takes longer than `timeout`
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
+    pub fn set_spawn_order(&mut self, order: SpawnOrder) {
+        self.spawn_order = order;
+    }
+
+    /// when restarts count as a crash loop, see `restarts`
+    pub fn set_crash_loop(&mut self, crash_loop: CrashLoop) {
+        self.crash_loop = crash_loop;
+    }
+
+    /// keep `$PLUMBER_TMPDIR` of finished runs for debugging
+    pub fn set_keep_tmpdir(&mut self, keep: bool) {
+        self.keep_tmpdir = keep;
+    }
+
+    /// what stdins do when the stage upstream exits, see `upstream`
+    pub fn set_upstream_exit(&mut self, rules: Vec<UpstreamExit>) {
+        self.upstream_exit = rules;
+    }
+
+    /// commands run once a stage's input is complete, see `upstream`
+    pub fn set_eof_hooks(&mut self, hooks: Vec<EofHook>) {
+        self.eof_hooks = hooks;
+    }
+
+    /// secrets to redact from archives of the pipeline's runs, see `archive`
+    pub fn set_redact(&mut self, patterns: Vec<Regex>) {
+        self.redact = patterns;
+    }
+
+    /// commands stages are spawned under for debugging, see `wrapper`
+    pub fn set_wrappers(&mut self, wrappers: Vec<Wrapper>) {
+        self.wrappers = wrappers;
+    }
+
+    /// stop petting the systemd watchdog when the pipeline crash-loops, see `watchdog`
+    pub fn set_critical(&mut self, critical: bool) {
+        self.critical = critical;
+    }
+
+    /// the times of day runs may start, see `window`. the definition's apply where the flags
+    /// give none
+    pub fn set_schedule(&mut self, schedule: Schedule) {
+        let defined = std::mem::take(&mut self.schedule);
+        self.schedule = match schedule.is_empty() {
+            true => Schedule { outside: schedule.outside.or(defined.outside), ..defined },
+            false => Schedule { outside: schedule.outside.or(defined.outside), ..schedule },
+        };
+    }
+
+    /// only reload definitions the allowlist approves, see `allowlist`
+    pub fn set_allowlist(&mut self, allowlist: Option<Arc<Allowlist>>) {
+        self.allowlist = allowlist;
+    }
+
+    /// what has to hold before a run is spawned, and what to do when it doesn't, see `precondition`
+    pub fn set_preconditions(&mut self, preconditions: Vec<Precondition>, on_unmet: OnUnmet) {
+        self.preconditions = preconditions;
+        self.on_unmet = on_unmet;
+    }
+
+    /// keep a file fresh while the stages run, see `heartbeat`
+    pub fn set_heartbeat(&mut self, heartbeat: Option<Heartbeat>) {
+        self.heartbeat = heartbeat.map(|heartbeat| Beating::new(&heartbeat, &self.name, &self.metadata_dir));
+    }
+
+    /// stages kept running across runs, see `worker`
+    pub fn set_workers(&mut self, stages: Vec<String>) {
+        self.worker_stages = stages;
+    }
+
+    /// most bytes a run may move between stages, see `budget`
+    pub fn set_max_run_bytes(&mut self, max: Option<u64>) {
+        self.budget = max.map(|max| Arc::new(Budget::new(max)));
+    }
+
+    /// links plumber reads ahead on, spilling to disk, see `spool`
+    pub fn set_spools(&mut self, spools: Vec<Spool>, max_spool: Option<u64>, on_spool_full: SpoolFull, compression: Vec<Compression>) {
+        self.spools = spools;
+        self.max_spool = max_spool;
+        self.on_spool_full = on_spool_full;
+        self.spool_compression = compression;
+    }
+
+    /// interpreters of script stages and hooks, after those of `$PLUMBER_SHELL`, see `shell`
+    pub fn set_shells(&mut self, rules: Vec<Shell>) {
+        self.shells = shell::global().into_iter().chain(rules).collect();
+        for (cmd, program) in self.commands.iter().zip(&mut self.programs) {
+            if shell::script(&cmd.name).is_some() {
+                *program = shell::shell(&self.shells, Some(cmd.log_name())).to_owned();
+            }
+        }
+    }
+
+    /// files passed to stages as extra fds, see `fds`
+    pub fn set_extra_fds(&mut self, extra_fds: Vec<ExtraFd>) {
+        self.extra_fds = extra_fds;
+    }
+
+    /// collect the cores of stages killed with one, see `cores`
+    pub fn set_core_dumps(&mut self, core_dumps: Option<CoreDumps>) {
+        self.core_dumps = core_dumps;
+    }
+
+    /// filter stderr on its way to the logs, see `capture`
+    pub fn set_log_filter(&mut self, filter: LogFilter) {
+        // the definition's log sizes where the flags leave them out
+        let defined = std::mem::take(&mut self.log_filter);
+        self.log_filter = LogFilter {
+            max_size: if filter.max_size.is_empty() { defined.max_size } else { filter.max_size },
+            on_full: filter.on_full.or(defined.on_full),
+            max_files: filter.max_files.or(defined.max_files),
+            compress: filter.compress || defined.compress,
+            ..filter
+        };
+    }
+
+    /// delays between restarts, see `restarts`
+    pub fn set_restart_backoff(&mut self, backoff: Backoff) {
+        self.backoff = backoff;
+    }
+
+    /// lets a crash-looped or backed off pipeline start again, false if it wasn't crash-looped
+    pub fn reset(name: &ValidatedName) -> Result<bool, PipelineError> {
+        let metadata_dir = metadata_dir(name);
+        restarts::reset(&metadata_dir).map_err(metadata_io(&metadata_dir))
+    }
+
+    /// stages given an fd to report counters and gauges on, see `metrics`
+    pub fn set_reporting(&mut self, stages: Vec<String>) {
+        self.reporting = stages;
+    }
+
+    /// ask stages to stop over a control socket rather than signalling them, see `control`
+    pub fn set_cooperative(&mut self, stages: Vec<String>, grace: Duration) {
+        self.cooperative = stages;
+        self.stop_grace = grace;
+    }
+
+    /// numa nodes to bind the cpus and memory of stages to, see `numa`
+    pub fn set_numa(&mut self, cpu: Vec<NumaRule>, mem: Vec<NumaRule>) {
+        self.numa_cpu = cpu;
+        self.numa_mem = mem;
+    }
+
+    /// where what the last stage writes goes
+    pub fn set_output(&mut self, output: Output) {
+        self.output = output;
+        self.output_file = None;
+    }
+
+    /// warnings, remediation pipelines and pages for exit codes of stages, see `exits`
+    pub fn set_on_exit(&mut self, rules: Vec<OnExit>) {
+        self.on_exit = rules;
+    }
+
+    /// fail the pipeline when any of its stages fails, as with `set -o pipefail`
+    pub fn set_pipefail(&mut self, pipefail: bool) {
+        self.pipefail = pipefail;
+    }
+
+    /// hold upstream stages until gated stages are ready, see `readiness`
+    pub fn set_readiness(&mut self, checks: Vec<ReadyCheck>, timeout: Duration) {
+        self.ready = checks;
+        self.ready_timeout = timeout;
+    }
+
+    /// throughput of each link of an instrumented pipeline that has been run
+    pub fn link_annotations(name: &ValidatedName) -> Vec<Option<String>> {
+        transport::read_annotations(&metadata_dir(name))
     }
 
     pub fn get_name(&self) -> String {
-        self.name.clone()
+        self.name.to_string()
     }
 
-    pub fn get_first_pid(&self) -> String {
-        self.jobs.first()
-            .unwrap()
-            .id()
-            .to_string()
+    pub fn validated_name(&self) -> &ValidatedName {
+        &self.name
     }
 
-    fn parse_raw_pipeline(raw_pipeline: &str) -> Vec<PipelineCommand> {
-        let split_on_pipe = raw_pipeline.split('|'); // split pipes
+    /// log name of every stage, in pipeline order
+    pub fn stage_names(&self) -> Vec<String> {
+        self.commands.iter().map(|cmd| cmd.log_name().to_owned()).collect()
+    }
 
-        let split_on_whitespace: Vec<Vec<String>> = split_on_pipe.map(|cmd_string|
-            shlex::split(cmd_string)
-            .unwrap_or_default())
-            .collect();
+    /// the stderr log of every stage by its name, in pipeline order, see `stderr_logs`
+    pub fn log_paths(&self) -> Vec<(&str, &Path)> {
+        self.commands.iter().map(PipelineCommand::log_name).zip(self.log_paths.iter().map(PathBuf::as_path)).collect()
+    }
 
-        let commands: Vec<PipelineCommand> = split_on_whitespace
-            .into_iter().map(|cmd|
-            PipelineCommand::new(cmd))
-            .collect();
+    pub fn get_raw_pipeline(&self) -> &str {
+        &self.raw_pipeline
+    }
 
-        assert!(!commands.is_empty(), "unable to parse commands - empty list: {}", raw_pipeline);
+    pub fn get_first_pid(&self) -> Option<String> {
+        self.jobs.first()
+            .and_then(Job::id)
+            .map(|pid| pid.to_string())
+    }
 
-        commands
+    /// stages of a pipeline definition, see `parser` for the language
+    pub fn parse_raw_pipeline(raw_pipeline: &str) -> Result<Vec<PipelineCommand>, ParseError> {
+        let mut commands = match definition::stages(raw_pipeline) {
+            Some(stages) => stages?,
+            None => {
+                let stages = match EXPAND.get().copied().unwrap_or(true) {
+                    true => parser::parse_expanding(raw_pipeline, &|name| std::env::var(name).ok())?,
+                    false => parser::parse(raw_pipeline)?,
+                };
+                stages.into_iter().map(PipelineCommand::from).collect()
+            },
+        };
+        templates::fill(raw_pipeline, &mut commands)?;
+        Ok(commands)
     }
 
     pub fn new(name: String, raw_pipeline: String) -> Result<Self, PipelineError> {
-        let commands = Pipeline::parse_raw_pipeline(&raw_pipeline);
-        let metadata_dir = Path::new(METADATA_DIR).join(&name);
-        let logging_dir = Path::new(LOGGING_DIR).join(&name);
-        create_dir_with_nice_error(&metadata_dir)?;
-        create_dir_with_nice_error(&logging_dir)?;
+        let name = ValidatedName::new(name).map_err(PipelineError::InvalidName)?;
+        let commands = Pipeline::parse_raw_pipeline(&raw_pipeline)?;
+        let options = definition::options(&raw_pipeline).transpose()?.unwrap_or_default();
+        let metadata_dir = metadata_root().join(&name);
+        let logging_dir = logging_root().join(&name);
+        create_dir_with_nice_error(&metadata_dir).map_err(metadata_io(&metadata_dir))?;
+        create_dir_with_nice_error(&logging_dir).map_err(metadata_io(&logging_dir))?;
+        if let Some(file) = roots_file().filter(|_| !dirs().given) {
+            if let Err(e) = record_root(&file, &shared_root()) {
+                log::warn!("{name}: unable to record {} in {}, commands run with another root won't find it: {e}", shared_root().display(), file.display());
+            }
+        }
+        // before the logs are opened, which would truncate those of the run going on
+        let lock = lock(&metadata_dir)?;
+
+        let shells: Vec<Shell> = shell::global().into_iter().collect();
+        let programs = programs(&commands, &shells);
+        // adopted stages are still writing to their logs
+        let log_paths = stderr_logs(&commands, &logging_dir);
+        let logs = open_logs(&log_paths, !upgrade::handed_off(&name))?;
 
         Ok(Pipeline {
             name,
@@ -106,98 +1030,1442 @@ impl Pipeline {
             commands,
             jobs: Vec::new(),
             metadata_dir,
-            logging_dir
+            logging_dir,
+            _lock: lock,
+            observe: Vec::new(),
+            instrument: false,
+            links: Vec::new(),
+            budget: None,
+            relays: Vec::new(),
+            pipe_sizes: Vec::new(),
+            spools: Vec::new(),
+            max_spool: None,
+            on_spool_full: SpoolFull::Block,
+            spool_compression: Vec::new(),
+            programs,
+            logs,
+            log_paths,
+            restart: options.restart.unwrap_or(Restart::Never),
+            spawn_order: SpawnOrder::DownstreamFirst,
+            ready: Vec::new(),
+            ready_timeout: Duration::from_secs(30),
+            crash_loop: CrashLoop::default(),
+            backoff: Backoff::default(),
+            log_filter: options.log_filter,
+            captures: Mutex::new(Vec::new()),
+            last_pid: Arc::default(),
+            tmpdir: None,
+            keep_tmpdir: false,
+            core_dumps: None,
+            started: SystemTime::now(),
+            extra_fds: Vec::new(),
+            upstream_exit: Vec::new(),
+            eof_hooks: Vec::new(),
+            ending: Vec::new(),
+            held: Vec::new(),
+            pending: Vec::new(),
+            alternatives: Vec::new(),
+            restarts: Restarts::default(),
+            clock: Arc::new(SystemClock),
+            wrappers: Vec::new(),
+            shells,
+            critical: false,
+            heartbeat: None,
+            checkout: None,
+            revision: None,
+            schedule: options.schedule,
+            preconditions: Vec::new(),
+            allowlist: None,
+            quota: None,
+            slot: None,
+            on_unmet: OnUnmet::Wait,
+            log_offsets: Vec::new(),
+            redact: Vec::new(),
+            cooperative: Vec::new(),
+            stop_grace: Duration::from_secs(10),
+            control: None,
+            pipefail: false,
+            numa_cpu: Vec::new(),
+            numa_mem: Vec::new(),
+            output: Output::Inherit,
+            output_file: None,
+            socket: None,
+            on_exit: Vec::new(),
+            timeout: options.timeout,
+            metrics: Arc::default(),
+            reporting: Vec::new(),
+            reports: Mutex::default(),
+            worker_stages: Vec::new(),
+            workers: Mutex::default(),
         })
     }
 
     pub fn new_from_file(path: &Path) -> Result<Self, PipelineError> {
-        let name = path.file_stem()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_owned();
+        let name = names::of_file(path).map_err(PipelineError::InvalidName)?;
+
+        let raw_pipeline = fs::read_to_string(path).map_err(metadata_io(path))?;
+        let name = definition::options(&raw_pipeline).and_then(Result::ok).and_then(|options| options.name).unwrap_or(name);
+
+        let mut pipeline = Self::new(name, raw_pipeline)?;
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        pipeline.checkout = revision::head(dir).map(|_| dir.to_owned());
+        Ok(pipeline)
+    }
+
+    /// name and raw pipeline of a plumber file, or of the pipeline last run under that name
+    pub fn read_definition(path_or_name: &str) -> Result<(String, String), PipelineError> {
+        let path = Path::new(path_or_name);
+        if path.is_file() {
+            let name = names::of_file(path).map_err(PipelineError::InvalidName)?;
+            let raw_pipeline = fs::read_to_string(path).map_err(metadata_io(path))?;
+            let name = definition::options(&raw_pipeline).and_then(Result::ok).and_then(|options| options.name).unwrap_or(name);
+            return Ok((name, raw_pipeline));
+        }
+
+        let name = ValidatedName::new(path_or_name).map_err(PipelineError::InvalidName)?;
+        let definition = metadata_dir(&name).join(".pipeline");
+        let raw_pipeline = fs::read_to_string(&definition).map_err(metadata_io(&definition))?;
+        Ok((path_or_name.to_owned(), raw_pipeline))
+    }
 
-        let raw_pipeline = fs::read_to_string(path)?;
+    /// command line of every stage, in pipeline order
+    pub fn stage_command_lines(raw_pipeline: &str) -> Result<Vec<String>, ParseError> {
+        Ok(Pipeline::parse_raw_pipeline(raw_pipeline)?
+            .iter()
+            .map(PipelineCommand::command_line)
+            .collect())
+    }
 
-        Self::new(name, raw_pipeline)
+    /// spawns stage `index`, its stdin and stdout plumber's own when `None`, moving on to its
+    /// next fallback while the command isn't found
+    fn spawn_process(&self, index: usize, stdin: Option<OwnedFd>, stdout: Option<OwnedFd>) -> Result<Child, PipelineError> {
+        let stage = &self.commands[index];
+        if self.worker_stages.iter().any(|worker| worker == stage.log_name()) {
+            return self.spawn_shuttle(index, stdin, stdout);
+        }
+        loop {
+            let alternative = self.alternatives[index].load(Ordering::Relaxed);
+            if alternative == stage.fallbacks.len() {
+                return self.spawn_command(index, alternative, stdin, stdout, false);
+            }
+            let clone = |fd: &Option<OwnedFd>| fd.as_ref().map(OwnedFd::try_clone).transpose();
+            let fds = clone(&stdin).and_then(|stdin| Ok((stdin, clone(&stdout)?)))
+                .map_err(|source| PipelineError::SpawnFailed { stage: stage.log_name().to_owned(), source })?;
+            match self.spawn_command(index, alternative, fds.0, fds.1, false) {
+                Err(PipelineError::CommandNotFound { name, .. }) => {
+                    let next = &stage.fallbacks[alternative];
+                    log::warn!("{}: {}: command not found: {name}, falling back to '{}'", self.name, stage.log_name(), next.command_line());
+                    self.alternatives[index].store(alternative + 1, Ordering::Relaxed);
+                },
+                spawned => return spawned,
+            }
+        }
+    }
+
+    /// spawns the shuttle standing in for worker stage `index` in this run, and the worker first
+    /// unless it's running
+    fn spawn_shuttle(&self, index: usize, stdin: Option<OwnedFd>, stdout: Option<OwnedFd>) -> Result<Child, PipelineError> {
+        let log_name = self.commands[index].log_name();
+        let failed = |source| PipelineError::SpawnFailed { stage: log_name.to_owned(), source };
+        let mut workers = self.workers.lock().unwrap();
+        if !workers.get_mut(&index).is_some_and(Worker::alive) {
+            let (input_read, input) = transport::pipe().map_err(failed)?;
+            let (output, output_write) = transport::pipe().map_err(failed)?;
+            // its stderr and reports are read for as long as it runs, not waited for with the run's
+            let (captures, reports) = (self.captures.lock().unwrap().len(), self.reports.lock().unwrap().len());
+            let alternative = self.alternatives[index].load(Ordering::Relaxed);
+            let child = self.spawn_command(index, alternative, Some(input_read), Some(output_write), true)?;
+            self.captures.lock().unwrap().truncate(captures);
+            self.reports.lock().unwrap().truncate(reports);
+            log::info!("{}: started worker {log_name}, pid {}", self.name, child.id());
+            workers.insert(index, Worker::new(child, input, output));
+        }
+        let mut shuttle = Command::new(upgrade::current_exe());
+        shuttle.arg("shuttle");
+        workers[&index].attach(&mut shuttle).map_err(failed)?;
+        let log = self.logs[index].try_clone().map_err(failed)?;
+        let child = shuttle
+            .env("PLUMBER_PIPELINE", &self.name)
+            .stdin(stdin.map_or(Stdio::inherit(), Stdio::from))
+            .stdout(stdout.map_or(Stdio::inherit(), Stdio::from))
+            .stderr(log)
+            .process_group(0)
+            .spawn()
+            .map_err(failed)?;
+        if index + 1 == self.commands.len() {
+            self.last_pid.store(child.id(), Ordering::Relaxed);
+        }
+        Ok(child)
     }
 
-    fn spawn_process(
-        name: &String,
-        args: &Vec<String>,
-        stdin: Stdio,
-        stdout: Stdio,
-        stderr: Stdio) -> Child {
-        let mut child = Command::new(name);
+    /// spawns stage `index` as its command, or the fallback numbered `alternative` from 1, as
+    /// a `worker` if told
+    fn spawn_command(&self, index: usize, alternative: usize, stdin: Option<OwnedFd>, stdout: Option<OwnedFd>, worker: bool) -> Result<Child, PipelineError> {
+        let stage = &self.commands[index];
+        let (cmd, program) = match alternative {
+            0 => (stage, self.programs[index].clone()),
+            n => (&stage.fallbacks[n - 1], programs(std::slice::from_ref(&stage.fallbacks[n - 1]), &self.shells).remove(0)),
+        };
+        let log_name = stage.log_name();
+        let failed = |source| PipelineError::SpawnFailed { stage: log_name.to_owned(), source };
+        // those set with `plumber wrap` win, and apply from the next spawn on
+        let mut wrappers: Vec<Wrapper> = self.wrappers.iter().cloned().chain(wrapper::load(&self.metadata_dir)).collect();
+        // a program put in front of the stage would run what nobody approved
+        if self.allowlist.is_some() && wrapper::wrapper(&wrappers, log_name).is_some() {
+            log::warn!("{}: not wrapping {log_name}, only approved definitions run", self.name);
+            audit::record("refuse", &self.name, &format!("wrapper of {log_name} with an allowlist"));
+            wrappers.clear();
+        }
+        let wrapped = wrapper::wrapper(&wrappers, log_name).map(|command| command.iter()
+            .map(|word| wrapper::expand(word, &self.logging_dir, log_name, self.tmpdir.as_deref()))
+            .collect::<Vec<_>>());
+        let mut child = match &wrapped {
+            Some(command) => {
+                log::info!("{}: wrapping {log_name} in '{}'", self.name, command.join(" "));
+                let mut child = Command::new(&command[0]);
+                child.args(&command[1..]).arg(&program);
+                child
+            },
+            None => Command::new(&program),
+        };
+        match (shell::script(&cmd.name), stages::builtin_kind(&cmd.name)) {
+            // the stage's arguments follow as `$1` and on
+            (Some(script), _) => {
+                child.arg("-c").arg(script).arg(log_name);
+            },
+            (None, Some(_)) => {
+                // options are the stage's own, even those looking like plumber's
+                child.arg("stage").arg(&cmd.name).arg("--");
+            },
+            // the program was resolved to its path, but it still sees the name it was given
+            (None, None) if wrapped.is_none() => {
+                child.arg0(&cmd.name);
+            },
+            (None, None) => {},
+        }
+
+        if let Some(cwd) = &cmd.cwd {
+            child.current_dir(cwd);
+        }
+        for arg in &cmd.args {
+            let (arg, unresolved) = outputs::interpolate(arg, &metadata_root());
+            for e in unresolved {
+                log::warn!("{}: passing {log_name} an unresolved reference, {e}", self.name);
+            }
+            child.arg(arg);
+        }
+
+        let watched = seccomp::watched(&self.observe, log_name);
+        let _handoff = match watched.is_empty() {
+            true => None,
+            false => Some(seccomp::observe(&mut child, &self.name, log_name, watched).map_err(failed)?),
+        };
+
+        if let Some(core_dumps) = &self.core_dumps {
+            core_dumps.allow(&mut child);
+        }
+        let (cpu_nodes, mem_nodes) = (numa::nodes(&self.numa_cpu, log_name), numa::nodes(&self.numa_mem, log_name));
+        if let Err(e) = numa::bind(&mut child, cpu_nodes, mem_nodes) {
+            log::warn!("{}: unable to bind {log_name} to numa nodes, running it unbound: {e}", self.name);
+        }
+        // after the syscall observer, which hands its socket over before these take their numbers
+        let extra_fds: Vec<&ExtraFd> = self.extra_fds.iter().filter(|fd| fd.stage == log_name).collect();
+        if !extra_fds.is_empty() {
+            fds::pass(&mut child, &extra_fds).map_err(failed)?;
+        }
+        let taken: Vec<_> = extra_fds.iter().map(|fd| fd.fd).collect();
+        if self.reporting.iter().any(|s| s == log_name) {
+            match metrics::pass(&mut child, &taken, &self.metrics, &self.metadata_dir) {
+                Ok(reading) => self.reports.lock().unwrap().push(reading),
+                Err(e) => log::warn!("{}: unable to give {log_name} an fd to report metrics on: {e}", self.name),
+            }
+        }
 
-        child.args(args);
+        if self.ready.iter().any(|c| c.stage == log_name && c.notifies()) {
+            child.env("NOTIFY_SOCKET", self.notify_socket(stage));
+        }
 
+        // the last stage's stdout may be captured too, the pid is for ending it
+        let pid = match index + 1 == self.commands.len() {
+            true => self.last_pid.clone(),
+            false => Arc::default(),
+        };
+        let log = self.logs[index].try_clone().map_err(failed)?;
+        let stderr = match (&cmd.stderr, stage.merge_stderr, self.log_filter.captures(log_name)) {
+            (Some(redirect), _, _) => Stdio::from(redirect.open(true).map_err(failed)?),
+            (None, true, _) => {
+                let merged = match &stdout {
+                    Some(fd) => fd.try_clone(),
+                    None => std::io::stdout().as_fd().try_clone_to_owned(),
+                };
+                Stdio::from(merged.map_err(failed)?)
+            },
+            (None, false, true) => {
+                let (read, write) = transport::pipe().map_err(failed)?;
+                let limit = self.log_filter.size_limit(log_name, &self.log_paths[index], &pid);
+                let captured = capture::capture(read, log, &self.log_filter, limit, &self.name, log_name);
+                self.captures.lock().unwrap().push(captured);
+                Stdio::from(write)
+            },
+            (None, false, false) => Stdio::from(log),
+        };
         child
-            .stdin(stdin)
-            .stdout(stdout)
+            .env_remove("WATCHDOG_USEC")
+            .env_remove("WATCHDOG_PID")
+            .env_remove(control::ENV)
+            .envs(self.control.as_ref().and_then(|c| c.path(index)).map(|path| (control::ENV, path)))
+            .env("PLUMBER_PIPELINE", &self.name)
+            .env("PLUMBER_METADATA_DIR", &self.metadata_dir)
+            .env(outputs::ENV, outputs::path(&self.metadata_dir))
+            .envs(cmd.env.iter().map(|(name, value)| (name, value)))
+            .envs(self.tmpdir.as_ref().map(|dir| ("PLUMBER_TMPDIR", dir)))
+            .envs(worker.then_some((worker::ENV, "1")))
+            .stdin(stdin.map_or(Stdio::inherit(), Stdio::from))
+            .stdout(stdout.map_or(Stdio::inherit(), Stdio::from))
             .stderr(stderr)
             .process_group(0)
             .spawn()
-            .expect(&format!("Failed to spawn command: {} {}", name, args.join(" ")))
+            .inspect(|child| pid.store(child.id(), Ordering::Relaxed))
+            .map_err(|source| match source.kind() {
+                std::io::ErrorKind::NotFound => PipelineError::CommandNotFound {
+                    stage: log_name.to_owned(),
+                    name: wrapped.as_ref().map_or(&cmd.name, |command| &command[0]).clone(),
+                },
+                _ => failed(source),
+            })
+    }
+
+    fn notify_socket(&self, cmd: &PipelineCommand) -> PathBuf {
+        self.metadata_dir.join(cmd.log_name()).with_extension("notify")
+    }
+
+    /// spawns a stage and, when stages are spawned downstream-first, holds until it passes
+    /// its readiness check
+    fn spawn_gated(&self, index: usize, stdin: Option<OwnedFd>, stdout: Option<OwnedFd>) -> Result<Child, PipelineError> {
+        let cmd = &self.commands[index];
+        let check = match self.spawn_order {
+            SpawnOrder::DownstreamFirst => self.ready.iter().rev().find(|c| c.stage == cmd.log_name()),
+            SpawnOrder::UpstreamFirst => None,
+        };
+        let gate = check.and_then(|check| check.gate(&self.log_paths[index], &self.notify_socket(cmd))
+            .map_err(|e| log::warn!("{}: unable to check readiness of {}: {e}", self.name, cmd.log_name()))
+            .ok());
+
+        let mut child = self.spawn_process(index, stdin, stdout)?;
+        let Some(mut gate) = gate else { return Ok(child) };
+        let started = self.clock.now();
+        match gate.wait(&mut child, self.ready_timeout, self.clock.as_ref()) {
+            Outcome::Ready => log::info!("{}: {} is ready after {}ms", self.name, cmd.log_name(), (self.clock.now() - started).as_millis()),
+            Outcome::Exited => log::warn!("{}: {} exited before it was ready", self.name, cmd.log_name()),
+            Outcome::TimedOut => log::warn!("{}: {} not ready after {}, starting upstream stages anyway",
+                self.name, cmd.log_name(), humantime::format_duration(self.ready_timeout)),
+        }
+        Ok(child)
+    }
+
+    /// spawns every stage, killing those already spawned when one can't be
+    fn spawn_all(&mut self) -> Result<(), PipelineError> {
+        self.links.clear();
+        // every run starts with the commands themselves
+        self.alternatives = self.commands.iter().map(|_| AtomicUsize::new(0)).collect();
+
+        // all pipes and redirected files of a phase exist before its first stage is spawned, so
+        // stages can start in any order and a missing file keeps any from starting
+        let mut stdins = vec![None];
+        let mut stdouts = Vec::new();
+        self.held.clear();
+        for (cmd, next) in self.commands.iter().zip(&self.commands[1..]) {
+            // opened once the pipeline is about to run, see `open_ends`
+            if cmd.chain.is_some() {
+                self.held.push(None);
+                stdouts.push(None);
+                stdins.push(None);
+                continue;
+            }
+            // read by the phase after it once it's done, see `spawn_phase`
+            if cmd.barrier {
+                let path = self.barrier_file(stdouts.len());
+                let file = fs::File::create(&path).map_err(metadata_io(&path))?.into();
+                let held = match cmd.fallbacks.is_empty() {
+                    true => None,
+                    false => Some(Held::of(stdins.last(), &file).map_err(metadata_io(&path))?),
+                };
+                self.held.push(held);
+                stdouts.push(Some(file));
+                stdins.push(None);
+                continue;
+            }
+            let pipe_size = transport::pipe_size(&self.pipe_sizes, next.log_name());
+            let resize = |fd: &dyn AsRawFd| if let Some(bytes) = pipe_size {
+                if let Err(e) = transport::set_pipe_size(&fd.as_raw_fd(), bytes) {
+                    log::warn!("{}: unable to resize pipe into {}: {e}", self.name, next.log_name());
+                }
+            };
+            let failed = |source| PipelineError::SpawnFailed { stage: next.log_name().to_owned(), source };
+            let (read, write) = transport::pipe().map_err(failed)?;
+            resize(&write);
+
+            let read = match (spool::memory(&self.spools, next.log_name()), self.instrument || self.budget.is_some()) {
+                (Some(memory), _) => {
+                    let (spooled, spool_write) = transport::pipe().map_err(failed)?;
+                    resize(&spool_write);
+                    let link = Arc::new(Link::new(&self.name, cmd.log_name(), next.log_name()).within(self.budget.clone()));
+                    let limits = Limits {
+                        memory,
+                        disk: self.max_spool,
+                        full: self.on_spool_full,
+                        compression: spool::compression(&self.spool_compression, next.log_name()),
+                    };
+                    let dir = self.tmpdir.as_deref().unwrap_or(&self.metadata_dir);
+                    self.relays.push(spool::spool(read, spool_write, link.clone(), limits, dir));
+                    if self.instrument {
+                        self.links.push(link);
+                    }
+                    spooled
+                },
+                (None, true) => {
+                    let (relayed, relay_write) = transport::pipe().map_err(failed)?;
+                    resize(&relay_write);
+                    let link = Arc::new(Link::new(&self.name, cmd.log_name(), next.log_name()).within(self.budget.clone()));
+                    // an explicit size is kept, otherwise the relay grows busy pipes
+                    let grow = pipe_size.is_none();
+                    self.relays.push(transport::relay(read, relay_write, link.clone(), grow));
+                    if self.instrument {
+                        self.links.push(link);
+                    }
+                    relayed
+                },
+                (None, false) => read,
+            };
+            let held = match upstream::policy(&self.upstream_exit, next.log_name()) {
+                OnUpstreamExit::Close if cmd.fallbacks.is_empty() => None,
+                OnUpstreamExit::Sentinel(_) if cmd.fallbacks.is_empty() => Some(Held { stdin: None, stdout: write.try_clone().map_err(failed)? }),
+                // kept open, or for a fallback to run on
+                _ => Some(Held::of(stdins.last(), &write).map_err(failed)?),
+            };
+            self.held.push(held);
+            stdouts.push(Some(write));
+            stdins.push(Some(read));
+        }
+        stdouts.push(None);
+        self.held.push(None);
+
+        let mut stages: Vec<_> = stdins.into_iter().zip(stdouts).enumerate().collect();
+        let phase = self.commands.iter().position(PipelineCommand::ends_phase).map_or(stages.len(), |end| end + 1);
+        self.pending = stages.split_off(phase);
+        let jobs = match self.open_ends(&mut stages).and_then(|()| self.spawn_stages(stages)) {
+            Ok(jobs) => jobs,
+            Err(e) => {
+                self.held.clear();
+                self.pending.clear();
+                return Err(e);
+            },
+        };
+        self.jobs = jobs.into_iter().map(|(_, child)| Job::Spawned(child)).chain(self.pending.iter().map(|_| Job::Pending)).collect();
+        Ok(())
+    }
+
+    /// spawns `stages` in the spawn order, in pipeline order once they all were, killing those
+    /// already spawned when one can't be
+    fn spawn_stages(&self, mut stages: Vec<Unspawned>) -> Result<Vec<(usize, Child)>, PipelineError> {
+        if self.spawn_order == SpawnOrder::DownstreamFirst {
+            stages.reverse();
+        }
+        let mut jobs: Vec<(usize, Child)> = Vec::new();
+        for (i, (stdin, stdout)) in stages {
+            match self.spawn_gated(i, stdin, stdout) {
+                Ok(child) => jobs.push((i, child)),
+                Err(e) => {
+                    for (_, mut child) in jobs {
+                        unsafe { libc::killpg(child.id() as libc::pid_t, libc::SIGKILL) };
+                        let _ = child.wait();
+                    }
+                    return Err(e);
+                },
+            }
+        }
+        jobs.sort_by_key(|(i, _)| *i);
+        Ok(jobs)
+    }
+
+    /// opens what the first stage of a pipeline among `stages` reads and the last one writes
+    /// once they're about to be spawned, so a pipeline after `&&` or `;` can read a file the one
+    /// before it wrote. the stdout of the last goes to the parent process unless told otherwise
+    fn open_ends(&mut self, stages: &mut [Unspawned]) -> Result<(), PipelineError> {
+        for (i, (stdin, stdout)) in stages.iter_mut() {
+            let i = *i;
+            let cmd = &self.commands[i];
+            let starts = i == 0 || self.commands[i - 1].chain.is_some();
+            let ends = cmd.chain.is_some() || i + 1 == self.commands.len();
+            if !starts && !ends {
+                continue;
+            }
+            let (stage, redirects, fallbacks) = (cmd.log_name().to_owned(), (cmd.stdin.clone(), cmd.stdout.clone()), !cmd.fallbacks.is_empty());
+            let failed = |source| PipelineError::SpawnFailed { stage: stage.clone(), source };
+            if starts {
+                *stdin = redirects.0.map(|redirect| redirect.open(false)).transpose().map_err(failed)?;
+            }
+            if ends {
+                *stdout = match redirects.1 {
+                    Some(redirect) => Some(redirect.open(true).map_err(failed)?),
+                    None => self.open_output()?,
+                };
+            }
+            // what the stage is respawned or falls back on
+            match &mut self.held[i] {
+                Some(held) => held.stdin = stdin.as_ref().map(OwnedFd::try_clone).transpose().map_err(failed)?,
+                held @ None if fallbacks => {
+                    let stdout = match stdout {
+                        Some(fd) => fd.try_clone(),
+                        None => std::io::stdout().as_fd().try_clone_to_owned(),
+                    };
+                    *held = Some(Held::of(Some(stdin), &stdout.map_err(failed)?).map_err(failed)?);
+                },
+                None => {},
+            }
+        }
+        Ok(())
     }
 
-    fn spawn_all(&mut self) {
-        let mut prev_stdout = Stdio::inherit();
+    /// where the barrier after stage `i` keeps what it wrote
+    fn barrier_file(&self, i: usize) -> PathBuf {
+        self.tmpdir.as_deref().unwrap_or(&self.metadata_dir).join(format!(".barrier-{i}"))
+    }
+
+    /// spawns the stages up to the next barrier or pipeline, the first of them reading what the
+    /// phase before it wrote after a barrier
+    fn spawn_phase(&mut self) -> Result<Vec<(usize, Child)>, PipelineError> {
+        let end = self.pending.iter().position(|(i, _)| self.commands[*i].ends_phase()).map_or(self.pending.len(), |at| at + 1);
+        let mut stages: Vec<_> = self.pending.drain(..end).collect();
+        let first = stages[0].0;
+        if self.commands[first - 1].barrier {
+            let path = self.barrier_file(first - 1);
+            let input = fs::File::open(&path).map_err(|source| PipelineError::SpawnFailed { stage: self.commands[first].log_name().to_owned(), source })?;
+            let _ = fs::remove_file(&path);
+            stages[0].1.0 = Some(input.into());
+        }
+        self.open_ends(&mut stages)?;
+        self.spawn_stages(stages)
+    }
 
-        let commands_except_last = &self.commands[..self.commands.len() - 1];
-        for cmd in commands_except_last.iter() {
-            let stderr_out = fs::File::create(&self.logging_dir
-                        .join(&cmd.name)
-                        .with_extension("stderr.log"))
-                        .unwrap();
+    /// spawns every stage, or adopts them after an upgrade, and records the pid of the first one
+    fn start(&mut self) -> Result<(), PipelineError> {
+        let mut supervised = upgrade::supervised();
+        // for adopted stages, when this plumber took over
+        self.started = self.clock.system_now();
+        self.revision = self.checkout.as_deref().and_then(revision::head);
+        self.log_offsets = self.logs.iter().map(|log| log.metadata().map_or(0, |m| m.len())).collect();
+        if let Some(budget) = &self.budget {
+            budget.reset();
+        }
+        match upgrade::adopt(&self.name) {
+            Some(stages) => {
+                log::info!("{}: adopted {} stages after upgrade", &self.name, stages.iter().flatten().count());
+                self.jobs = stages.into_iter()
+                    .map(|stage| stage.map_or(Job::Gone, Job::Adopted))
+                    .collect();
+            },
+            None => {
+                self.fresh_tmpdir();
+                if let Err(e) = outputs::reset(&self.metadata_dir) {
+                    log::warn!("{}: unable to reset outputs of the previous run: {e}", &self.name);
+                }
+                self.spawn_all()?;
+            },
+        }
+        let stages: Vec<Option<u32>> = self.jobs.iter().map(Job::id).collect();
+        self.record_stages(&stages);
+        supervised.insert(self.name.to_string(), upgrade::Supervised {
+            stages,
+            transferable: !self.instrument
+                && self.worker_stages.is_empty()
+                && self.budget.is_none()
+                && self.spools.is_empty()
+                && self.cooperative.is_empty()
+                && self.observe.is_empty()
+                && !self.log_filter.enabled()
+                && self.held.iter().all(Option::is_none)
+                && self.pending.is_empty(),
+        });
+        drop(supervised);
+
+        if let Some(first_job_pid) = self.get_first_pid() {
+            log::debug!("{}: pid of first job in pipeline is {}", &self.name, &first_job_pid);
+            let pid_file = self.metadata_dir.join(".pid");
+            fs::write(&pid_file, first_job_pid).map_err(metadata_io(&pid_file))?;
+        }
+        Ok(())
+    }
+
+    /// records the pid of every stage on a line of its own, empty once it exited. each is the
+    /// leader of its process group, which `plumber stop` kills when it doesn't exit in time
+    fn record_stages(&self, stages: &[Option<u32>]) {
+        let groups: String = stages.iter().map(|pid| pid.map_or("\n".to_string(), |pid| format!("{pid}\n"))).collect();
+        if let Err(e) = fs::write(self.metadata_dir.join(".stages"), groups) {
+            log::warn!("{}: unable to record stage pids, plumber stop can't kill them: {e}", &self.name);
+        }
+        // and in state.json, so it has the pids of respawned stages rather than those they replaced
+        let named: Vec<(&str, Option<u32>)> = self.commands.iter().map(PipelineCommand::log_name).zip(stages.iter().copied()).collect();
+        if let Err(e) = state::write(&self.metadata_dir, &state::running(&self.name, &self.raw_pipeline, self.started, &named)) {
+            log::warn!("{}: unable to write {}: {e}", &self.name, state::FILE);
+        }
+    }
+
+    /// creates an empty `$PLUMBER_TMPDIR` for the run about to start
+    fn fresh_tmpdir(&mut self) {
+        // left behind by a plumber that didn't get to clean up
+        self.remove_tmpdir();
+        let since_epoch = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        let dir = tmp_root().join(&self.name).join(since_epoch.as_millis().to_string());
+        let created = fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)
+            // recorded, so whoever supervises the run last removes it, even after an upgrade
+            .and_then(|_| fs::write(self.metadata_dir.join(".tmpdir"), dir.as_os_str().as_bytes()));
+        match created {
+            Ok(()) => self.tmpdir = Some(dir),
+            Err(e) => {
+                log::warn!("{}: unable to create {}, stages get no $PLUMBER_TMPDIR: {e}", &self.name, dir.display());
+                self.tmpdir = None;
+            },
+        }
+    }
+
+    fn remove_tmpdir(&mut self) {
+        self.tmpdir = None;
+        let Ok(dir) = fs::read(self.metadata_dir.join(".tmpdir")) else { return };
+        let dir = PathBuf::from(std::ffi::OsString::from_vec(dir));
+        match self.keep_tmpdir {
+            true => log::info!("{}: keeping tmpdir {}", &self.name, dir.display()),
+            false => if let Err(e) = fs::remove_dir_all(&dir) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    log::warn!("{}: unable to remove tmpdir {}: {e}", &self.name, dir.display());
+                }
+            },
+        }
+        let _ = fs::remove_file(self.metadata_dir.join(".tmpdir"));
+    }
+
+    /// waits for every stage and relay, true if any stage failed
+    fn wait(&mut self) -> RunSummary {
+        let (exits, exited) = mpsc::channel();
+        let mut pids = Vec::new();
+        let mut running_stages = Vec::new();
+        for (i, job) in std::mem::take(&mut self.jobs).into_iter().enumerate() {
+            pids.push(job.id());
+            running_stages.push(!matches!(job, Job::Pending));
+            if !matches!(job, Job::Pending) {
+                watch(i, job, &exits);
+            }
+        }
+        let mut spawned_at = vec![self.clock.now(); pids.len()];
+        let mut running = running_stages.iter().filter(|running| **running).count();
+        // the first stage of the phase running, after the last barrier passed
+        let mut phase = 0;
+        // and of the pipeline running, after the last `&&` or `;`
+        let mut pipeline = 0;
+        let mut respawns: Vec<(Instant, usize)> = Vec::new();
+        let mut stages: Vec<(usize, StageRun)> = Vec::new();
+        let mut asked: Option<(Instant, Vec<usize>)> = None;
+        // `plumber stop` leaves it to this loop to see `.stop` once a stage said hello
+        let stop_poll = self.control.as_ref().map(|_| Duration::from_millis(100));
+        // drained once the run has taken longer
+        let mut deadline = self.timeout.map(|timeout| self.clock.now() + timeout);
+        // or once its window closed
+        let mut closing = self.schedule.closes_in(self.clock.system_now()).map(|closes| self.clock.now() + closes);
+        // or once it moved more than its budget
+        let budget_poll = self.budget.as_ref().map(|_| Duration::from_millis(100));
+        let mut over_budget = false;
+        while running > 0 || !respawns.is_empty() {
+            let next_respawn = respawns.iter().map(|(at, _)| at.saturating_duration_since(self.clock.now())).min();
+            let timing_out = deadline.into_iter().chain(closing).map(|at| at.saturating_duration_since(self.clock.now())).min();
+            let beat = self.heartbeat.as_ref().map(|heartbeat| heartbeat.due_in(self.clock.now()));
+            let received = match next_respawn.into_iter().chain(watchdog::check_in_every()).chain(beat).chain(stop_poll).chain(budget_poll).chain(timing_out).min() {
+                Some(timeout) => exited.recv_timeout(self.clock.wait_at_most(timeout)),
+                None => exited.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+            };
+            watchdog::check_in(&self.name);
+            if let Some(heartbeat) = &mut self.heartbeat {
+                heartbeat.beat(self.clock.now(), self.clock.system_now(), &self.name);
+            }
+            if let Some(budget) = self.budget.as_ref().filter(|budget| !over_budget && budget.exceeded()) {
+                log::error!("{}: run moved more than its budget of {} bytes, ending it", &self.name, budget.max());
+                over_budget = true;
+                // stages lead their own process groups
+                for pid in pids.iter().zip(&running_stages).filter_map(|(pid, running)| pid.filter(|_| *running)) {
+                    unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGTERM) };
+                }
+            }
+            let Ok((i, exit)) = received else {
+                self.stop_cooperatively(&pids, &running_stages, &mut asked);
+                let now = self.clock.now();
+                if let Some(timeout) = self.timeout.filter(|_| deadline.is_some_and(|at| at <= now)) {
+                    log::warn!("{}: run still going after {}, draining it", &self.name, humantime::format_duration(timeout));
+                    if let (Some(pid), true) = (pids[phase], running_stages[phase]) {
+                        unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+                    }
+                    deadline = None;
+                }
+                if closing.is_some_and(|at| at <= now) {
+                    log::warn!("{}: window closed, draining the run", &self.name);
+                    if let (Some(pid), true) = (pids[phase], running_stages[phase]) {
+                        unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+                    }
+                    closing = None;
+                }
+                for (_, i) in respawns.extract_if(.., |(at, _)| *at <= now).collect::<Vec<_>>() {
+                    let Some(child) = self.respawn(i) else { continue };
+                    if let Some((_, run)) = stages.iter_mut().rev().find(|(stage, _)| *stage == i) {
+                        run.respawned = true;
+                    }
+                    pids[i] = Some(child.id());
+                    spawned_at[i] = self.clock.now();
+                    running_stages[i] = true;
+                    running += 1;
+                    watch(i, Job::Spawned(child), &exits);
+                }
+                continue;
+            };
+            running -= 1;
+            running_stages[i] = false;
+            if let Some(pipeline) = upgrade::supervised().get_mut(self.name.as_str()) {
+                pipeline.stages[i] = None;
+                self.record_stages(&pipeline.stages);
+            }
+            let (status, usage) = exit.unzip();
+            // a shuttle cut short leaves the rest of its run for the next one to read
+            if let Some(worker) = status.filter(|status| status.signal().is_some()).and_then(|_| self.workers.get_mut().unwrap().remove(&i)) {
+                log::warn!("{}: {} was cut short, ending its worker", &self.name, self.commands[i].log_name());
+                // which may take seconds, while the other stages go on exiting
+                thread::spawn(move || drop(worker));
+            }
+            let core = match (status, pids[i]) {
+                (Some(status), Some(pid)) if status.core_dumped() => self.collect_core(i, pid, status.signal().unwrap()),
+                _ => None,
+            };
+            let downstream_running = running_stages.get(i + 1).copied().unwrap_or(false);
+            let ran = self.clock.now().saturating_duration_since(spawned_at[i]);
+            let stage = self.commands[i].log_name().to_owned();
+            let warned = exits::warns(&self.on_exit, &stage, status);
+            let alternative = self.alternatives.get(i).map_or(0, |alternative| alternative.load(Ordering::Relaxed));
+            let failed = !warned && !status.is_none_or(|status| status.success());
+            let delay = match failed && self.fall_back(i) {
+                true => Some(Duration::ZERO),
+                false => self.upstream_exited(i, status, ran, downstream_running),
+            };
+            if let Some(delay) = delay {
+                respawns.push((self.clock.now() + delay, i));
+            } else if let Some(held) = i.checked_sub(1).and_then(|upstream| self.held.get_mut(upstream)) {
+                // nobody left to read what a respawned upstream stage would write
+                *held = None;
+            }
+            if warned {
+                log::warn!("{}: {stage} exited with {}, a warning by --on-exit", &self.name, status.map_or(0, summary::exit_code));
+            }
+            let fallback = alternative.checked_sub(1).map(|n| self.commands[i].fallbacks[n].command_line());
+            let run = StageRun { stage, pid: pids[i], status, usage: usage.flatten(), core, respawned: false, warned, fallback };
+            stages.push((i, run));
+
+            // every stage before the barrier, `&&` or `;` is done, the next phase starts if they
+            // all succeeded or it's after a `;`
+            if running > 0 || !respawns.is_empty() || self.pending.is_empty() {
+                continue;
+            }
+            if self.metadata_dir.join(".stop").exists() {
+                self.pending.clear();
+                continue;
+            }
+            let first = self.pending[0].0;
+            let next = self.commands[first].log_name().to_owned();
+            let chain = self.commands[first - 1].chain;
+            if chain != Some(Chain::Always) && !stages.iter().filter(|(i, _)| *i >= pipeline).all(|(_, run)| run.succeeded()) {
+                match chain {
+                    Some(chain) => log::warn!("{}: a stage before `{chain}` failed, not starting {next}", &self.name),
+                    None => log::warn!("{}: a stage before the barrier failed, not starting {next} or the stages after it", &self.name),
+                }
+                // as in sh, a failed pipeline skips those up to the next `;`
+                let skipped = self.pending.iter()
+                    .position(|(i, _)| self.commands[i - 1].chain == Some(Chain::Always))
+                    .unwrap_or(self.pending.len());
+                self.pending.drain(..skipped);
+                if self.pending.is_empty() {
+                    continue;
+                }
+            }
+            let first = self.pending[0].0;
+            let next = self.commands[first].log_name().to_owned();
+            match self.commands[first - 1].chain {
+                Some(chain) => {
+                    log::info!("{}: starting {next}, the pipeline after `{chain}`", &self.name);
+                    pipeline = first;
+                },
+                None => log::info!("{}: every stage before the barrier succeeded, starting {next}", &self.name),
+            }
+            phase = first;
+            match self.spawn_phase() {
+                Ok(jobs) => for (i, child) in jobs {
+                    pids[i] = Some(child.id());
+                    spawned_at[i] = self.clock.now();
+                    running_stages[i] = true;
+                    running += 1;
+                    watch(i, Job::Spawned(child), &exits);
+                },
+                Err(e) => {
+                    error!("{}: {e}", &self.name);
+                    self.pending.clear();
+                    // as a shell reports a command it couldn't run
+                    let run = StageRun { stage: next, pid: None, status: Some(ExitStatus::from_raw(127 << 8)), usage: None, core: None, respawned: false, warned: false, fallback: None };
+                    stages.push((first, run));
+                },
+            }
+            self.record_stages(&pids);
+            // for `plumber stop` to drain the phase running now
+            if let Some(pid) = pids[phase].filter(|_| running_stages[phase]) {
+                let _ = fs::write(self.metadata_dir.join(".pid"), pid.to_string());
+            }
+        }
+        self.held.clear();
+        for ending in self.ending.drain(..) {
+            let _ = ending.join();
+        }
+        for relay in self.relays.drain(..) {
+            relay.wait();
+        }
+        // a stage's children may hold on to its stderr, don't wait for them for long
+        let deadline = Instant::now() + Duration::from_secs(1);
+        for capture in self.captures.get_mut().unwrap().drain(..) {
+            capture.wait_timeout(deadline.saturating_duration_since(Instant::now()));
+        }
+        // nor for what they report
+        for reading in self.reports.get_mut().unwrap().drain(..) {
+            reading.wait_timeout(deadline.saturating_duration_since(Instant::now()));
+        }
+        // in pipeline order, respawns of a stage in the order they ran
+        stages.sort_by_key(|(i, _)| *i);
+        let stages = stages.into_iter().map(|(_, run)| run).collect();
+        RunSummary {
+            started: self.started,
+            ended: self.clock.system_now(),
+            stages,
+            outputs: outputs::read(&self.metadata_dir),
+            revision: self.revision.clone(),
+            over_budget,
+        }
+    }
+
+    /// asks the stages that said hello to stop once `plumber stop` left it to plumber, signalling the
+    /// first stage if it isn't one of them and those that don't exit within the grace period
+    fn stop_cooperatively(&self, pids: &[Option<u32>], running: &[bool], asked: &mut Option<(Instant, Vec<usize>)>) {
+        let Some(control) = &self.control else { return };
+        let terminate = |i: usize| if let (Some(pid), true) = (pids[i], running[i]) {
+            unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+        };
+        match asked {
+            None if fs::read(self.metadata_dir.join(".stop")).is_ok_and(|stop| stop == control::STOP.as_bytes()) => {
+                let stages: Vec<usize> = (0..pids.len()).filter(|&i| running[i] && control.ask_to_stop(i)).collect();
+                for &i in &stages {
+                    log::info!("{}: asked {} to stop", &self.name, self.commands[i].log_name());
+                }
+                if !stages.contains(&0) {
+                    terminate(0);
+                }
+                *asked = Some((self.clock.now(), stages));
+            },
+            Some((at, stages)) if !stages.is_empty() && self.clock.now().saturating_duration_since(*at) >= self.stop_grace => {
+                for i in stages.drain(..).filter(|&i| running[i]) {
+                    log::warn!("{}: {} didn't stop within {}, sending SIGTERM", &self.name,
+                        self.commands[i].log_name(), humantime::format_duration(self.stop_grace));
+                    terminate(i);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    /// passes on the exit of stage `i` to the stage downstream of it, returns the delay
+    /// before respawning it when the downstream stage keeps its stdin open
+    fn upstream_exited(&mut self, i: usize, status: Option<ExitStatus>, ran: Duration, downstream_running: bool) -> Option<Duration> {
+        if i + 1 == self.commands.len() {
+            return None;
+        }
+        let held = self.held.get_mut(i).and_then(Option::take);
+        let next = self.commands[i + 1].log_name();
+        if held.is_some() && upstream::policy(&self.upstream_exit, next) == &OnUpstreamExit::KeepOpen {
+            let failed = !status.is_none_or(|status| status.success());
+            let respawn = match self.restart {
+                Restart::Never => false,
+                Restart::OnFailure => failed,
+                Restart::Always => true,
+            };
+            if respawn && !self.metadata_dir.join(".stop").exists() && restarts::crash_looped(&self.metadata_dir).is_none() {
+                let now = self.clock.system_now();
+                let crash_looped = !self.restarts.record(now, &self.crash_loop);
+                let delay = self.restarts.delay(now, ran, &self.crash_loop, &self.backoff);
+                if let Err(e) = self.restarts.save(&self.metadata_dir) {
+                    log::warn!("{}: unable to save restart history: {e}", &self.name);
+                }
+                if crash_looped {
+                    restarts::trip(&self.name, &self.metadata_dir, &self.crash_loop, shell::shell(&self.shells, None));
+                } else {
+                    let stage = self.commands[i].log_name();
+                    let delay_text = humantime::format_duration(Duration::from_millis(delay.as_millis() as u64));
+                    log::warn!("{}: {stage} exited, respawning it in {delay_text} (restart {}), {next} keeps its stdin open",
+                        &self.name, self.restarts.count());
+                    self.held[i] = held;
+                    return Some(delay);
+                }
+            }
+        }
+        if downstream_running {
+            self.end_input(i, held);
+        }
+        None
+    }
+
+    /// moves stage `i` on to its next fallback after it exited unsuccessfully, `false` when it
+    /// has none left
+    fn fall_back(&mut self, i: usize) -> bool {
+        let stage = &self.commands[i];
+        let alternative = self.alternatives[i].load(Ordering::Relaxed);
+        if alternative == stage.fallbacks.len() || self.held.get(i).is_none_or(Option::is_none) || self.metadata_dir.join(".stop").exists() {
+            return false;
+        }
+        log::warn!("{}: {} exited unsuccessfully, falling back to '{}'", &self.name, stage.log_name(), stage.fallbacks[alternative].command_line());
+        self.alternatives[i].store(alternative + 1, Ordering::Relaxed);
+        true
+    }
+
+    /// the stage after stage `i` has all of its input: closes what plumber held of it, after
+    /// writing its sentinel, and runs its eof hook
+    fn end_input(&mut self, i: usize, held: Option<Held>) {
+        let (stage, next) = (self.commands[i].log_name(), self.commands[i + 1].log_name());
+        let sentinel = match upstream::policy(&self.upstream_exit, next) {
+            OnUpstreamExit::Sentinel(line) => held.map(|held| (held.stdout, format!("{line}\n"))),
+            _ => None,
+        };
+        let hook = upstream::eof_hook(&self.eof_hooks, next).map(|command| {
+            let mut hook = Command::new(shell::shell(&self.shells, Some(next)));
+            hook.arg("-c")
+                .arg(command)
+                .env("PLUMBER_PIPELINE", &self.name)
+                .env("PLUMBER_EVENT", "eof")
+                .env("PLUMBER_STAGE", next)
+                .env("PLUMBER_UPSTREAM", stage);
+            hook
+        });
+        if sentinel.is_none() && hook.is_none() {
+            return;
+        }
+        let label = format!("{}: {next}", &self.name);
+        // a slow consumer with a full pipe mustn't hold up the other stages' exits
+        self.ending.push(thread::spawn(move || {
+            if let Some((stdout, line)) = sentinel {
+                let _ = fs::File::from(stdout).write_all(line.as_bytes());
+            }
+            match hook.map(|mut hook| hook.status()) {
+                Some(Ok(status)) if !status.success() => log::warn!("{label}: eof hook {status}"),
+                Some(Err(e)) => log::warn!("{label}: unable to run eof hook: {e}"),
+                _ => {},
+            }
+        }));
+    }
+
+    /// spawns stage `i` again onto the pipes held for it, unless the pipeline was stopped meanwhile
+    fn respawn(&mut self, i: usize) -> Option<Child> {
+        let held = self.held[i].take()?;
+        if self.metadata_dir.join(".stop").exists() {
+            self.end_input(i, Some(held));
+            return None;
+        }
+        let fds = held.stdin.as_ref().map(OwnedFd::try_clone).transpose().and_then(|stdin| Ok((stdin, held.stdout.try_clone()?)));
+        let spawned = fds
+            .map_err(|source| PipelineError::SpawnFailed { stage: self.commands[i].log_name().to_owned(), source })
+            .and_then(|(stdin, stdout)| self.spawn_process(i, stdin, Some(stdout)));
+        let child = match spawned {
+            Ok(child) => child,
+            Err(e) => {
+                error!("{}: unable to respawn, {e}", &self.name);
+                self.end_input(i, Some(held));
+                return None;
+            },
+        };
+        self.held[i] = Some(held);
+        if let Some(pipeline) = upgrade::supervised().get_mut(self.name.as_str()) {
+            pipeline.stages[i] = Some(child.id());
+            self.record_stages(&pipeline.stages);
+        }
+        if i == 0 {
+            // so `plumber stop` signals the stage that is running now
+            if let Err(e) = fs::write(self.metadata_dir.join(".pid"), child.id().to_string()) {
+                log::warn!("{}: unable to record the pid of the respawned first stage: {e}", &self.name);
+            }
+        }
+        Some(child)
+    }
+
+    /// where the stage's core dump was collected to
+    fn collect_core(&self, index: usize, pid: u32, signal: i32) -> Option<PathBuf> {
+        let stage = self.commands[index].log_name();
+        let Some(core_dumps) = &self.core_dumps else {
+            log::warn!("{}: {stage} dumped core on signal {signal}", &self.name);
+            return None;
+        };
+        let dumped = cores::Dumped { stage, pid, program: &self.programs[index], signal };
+        match core_dumps.collect(&dumped, &self.metadata_dir.join("cores")) {
+            Ok(Some(core)) => {
+                log::warn!("{}: {stage} dumped core on signal {signal} => '{}'", &self.name, core.display());
+                Some(core)
+            },
+            Ok(None) => {
+                log::warn!("{}: {stage} dumped core on signal {signal}, but it wasn't found to collect", &self.name);
+                None
+            },
+            Err(e) => {
+                log::warn!("{}: {stage} dumped core on signal {signal}, unable to collect it: {e}", &self.name);
+                None
+            },
+        }
+    }
 
-            let stderr_out = Stdio::from(stderr_out);
+    /// writes plumber's overhead, and the link counters of an instrumented pipeline, every
+    /// second until the returned sender is dropped
+    fn write_stats(&self) -> (mpsc::Sender<()>, JoinHandle<()>) {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let mut stats = self.instrument.then(|| Stats::new(self.links.clone()));
+        let mut overhead = Overhead::new();
+        let (name, metadata_dir) = (self.name.clone(), self.metadata_dir.clone());
+        let writer = thread::spawn(move || loop {
+            let done = stopped.recv_timeout(Duration::from_secs(1)) == Err(mpsc::RecvTimeoutError::Disconnected);
+            if let Some(Err(e)) = stats.as_mut().map(|stats| stats.write(&metadata_dir)) {
+                log::warn!("{name}: unable to write link stats: {e}");
+            }
+            if let Err(e) = overhead.write(&metadata_dir) {
+                log::warn!("{name}: unable to write plumber's overhead: {e}");
+            }
+            if done {
+                break;
+            }
+        });
+        (stop, writer)
+    }
 
-            let mut child = Self::spawn_process(
-                &cmd.name, &cmd.args,
-                prev_stdout, Stdio::piped(), stderr_out
-            );
-            prev_stdout = Stdio::from(child.stdout.take().unwrap());
-            self.jobs.push(child);
+    /// waits before a restart, unless the pipeline is stopped meanwhile
+    fn back_off(&self, delay: Duration) {
+        let until = self.clock.now() + delay;
+        while !self.metadata_dir.join(".stop").exists() {
+            watchdog::check_in(&self.name);
+            let left = until.saturating_duration_since(self.clock.now());
+            if left.is_zero() {
+                break;
+            }
+            self.clock.sleep(left.min(Duration::from_millis(100)));
         }
+    }
 
-        // this is to pipe the stdout of the last command to the parent process
-        let last_cmd = self.commands.last().unwrap();
+    /// keeps what's needed to archive the run that just ended, see `archive`
+    fn keep_run(&self, summary: &RunSummary) -> std::io::Result<()> {
+        let mut logs: Vec<LogSpan> = Vec::new();
+        for (((_, path), log), from) in self.log_paths().into_iter().zip(&self.logs).zip(&self.log_offsets) {
+            let path = path.to_owned();
+            // stages may be given the same log
+            if logs.iter().any(|span| span.path == path) {
+                continue;
+            }
+            logs.push(LogSpan { path, from: *from, to: log.metadata()?.len() });
+        }
+        let runs = self.metadata_dir.join(archive::RUNS);
+        Run {
+            id: archive::claim_run_id(&runs, self.started)?,
+            summary,
+            pipeline: &self.raw_pipeline,
+            command: std::env::args().collect(),
+            logs,
+            redact: &self.redact,
+        }.keep(&runs)
+    }
 
-        let stderr_out = fs::File::create(&self.logging_dir
-            .join(&last_cmd.name)
-            .with_extension("stderr.log")
-        ).unwrap();
+    /// a control socket for every cooperative stage, by its index in the pipeline
+    fn open_control(&mut self) {
+        self.control = None;
+        let cooperative: Vec<(usize, &str)> = self.commands.iter()
+            .map(PipelineCommand::log_name)
+            .enumerate()
+            .filter(|(_, stage)| self.cooperative.iter().any(|c| c == stage))
+            .collect();
+        if !cooperative.is_empty() {
+            self.control = Some(Control::open(&self.name, &self.metadata_dir, &cooperative));
+        }
+    }
 
-        let stderr_out = Stdio::from(stderr_out);
+    /// the file the last stage writes to, `None` to inherit plumber's stdout
+    fn open_output(&mut self) -> Result<Option<OwnedFd>, PipelineError> {
+        let (path, truncate) = match &self.output {
+            Output::Inherit => return Ok(None),
+            Output::Log => {
+                let last = self.commands.last().map_or("", |cmd| cmd.log_name());
+                (self.logging_dir.join(last).with_extension("stdout.log"), true)
+            },
+            Output::Append(path) => (path.clone(), false),
+            Output::Discard => (PathBuf::from("/dev/null"), false),
+        };
+        if self.output_file.is_none() {
+            let file = fs::OpenOptions::new().create(true).append(true).open(&path).map_err(metadata_io(&path))?;
+            if truncate {
+                file.set_len(0).map_err(metadata_io(&path))?;
+            }
+            self.output_file = Some(file);
+        }
+        let file = self.output_file.as_ref().unwrap().try_clone().map_err(metadata_io(&path))?;
+        let last = self.commands.last().map_or("", |cmd| cmd.log_name());
+        let limit = match self.output {
+            Output::Discard => None,
+            _ => self.log_filter.size_limit(last, &path, &self.last_pid),
+        };
+        let Some(limit) = limit else { return Ok(Some(file.into())) };
+        let (read, write) = transport::pipe().map_err(metadata_io(&path))?;
+        let captured = capture::capture(read, file, &LogFilter::default(), Some(limit), &self.name, &format!("{last} stdout"));
+        self.captures.lock().unwrap().push(captured);
+        Ok(Some(write))
+    }
 
-        let child = Self::spawn_process(
-            &last_cmd.name, &last_cmd.args,
-            prev_stdout, Stdio::inherit(), stderr_out
-        );
-        self.jobs.push(child);
+    /// swaps in the definition `Pipeline::reload` left, keeping the old one if it's unusable
+    fn take_reload(&mut self) {
+        let reload = self.metadata_dir.join(".reload");
+        let Ok(raw_pipeline) = fs::read_to_string(&reload) else { return };
+        let _ = fs::remove_file(&reload);
+        let redefined = Pipeline::parse_raw_pipeline(&raw_pipeline).map_err(PipelineError::from).and_then(|commands| {
+            let log_paths = stderr_logs(&commands, &self.logging_dir);
+            let logs = open_logs(&log_paths, true)?;
+            Ok((commands, logs, log_paths))
+        });
+        let (commands, logs, log_paths) = match redefined {
+            Ok(redefined) => redefined,
+            Err(e) => {
+                error!("{}: unable to reload, keeping the running definition: {e}", &self.name);
+                return;
+            },
+        };
+        // `.reload` may be written by anyone who may write the metadata dir, not only by what
+        // checked the definition before asking for the reload
+        if self.allowlist.as_ref().is_some_and(|a| !a.allows(raw_pipeline.as_bytes())) {
+            error!("{}: reloaded definition is not in the approved allowlist, keeping the running one", &self.name);
+            audit::record("refuse", &self.name, "not in allowlist");
+            return;
+        }
+        if let Some((namespace, reason)) = self.quota.as_ref().and_then(|(namespace, quota)| Some((namespace, quota.check_stages(commands.len()).err()?))) {
+            error!("{}: reloaded definition is over the quota of namespace {namespace}: {reason}, keeping the running one", &self.name);
+            audit::record("refuse", &self.name, &format!("over quota: {reason}"));
+            return;
+        }
+        // a new last stage writes to a log of its own
+        if self.output == Output::Log && self.commands.last().map(PipelineCommand::log_name) != commands.last().map(PipelineCommand::log_name) {
+            self.output_file = None;
+        }
+        self.programs = programs(&commands, &self.shells);
+        // started again as the new definition has them
+        self.workers.get_mut().unwrap().clear();
+        self.commands = commands;
+        self.logs = logs;
+        self.log_paths = log_paths;
+        self.raw_pipeline = raw_pipeline;
+        let definition = self.metadata_dir.join(".pipeline");
+        if let Err(e) = fs::write(&definition, &self.raw_pipeline) {
+            log::warn!("{}: unable to record the reloaded definition: {e}", &self.name);
+        }
+        // stages may have moved
+        self.open_control();
+        log::info!("{}: reloaded pipeline => '{}'", &self.name, self.raw_pipeline.trim());
     }
 
-    pub fn run(mut self) {
+    /// supervises the pipeline until it's done, returning how its last run exited, or why
+    /// it couldn't be started
+    pub fn run(mut self) -> Result<PipelineExitStatus, PipelineError> {
+        let mut status = PipelineExitStatus { stages: Vec::new(), pipefail: self.pipefail, over_budget: false };
+        if let Some(reason) = restarts::crash_looped(&self.metadata_dir) {
+            error!("{}: not starting, pipeline crash-looped ({reason}), see `plumber reset {}`", &self.name, &self.name);
+            return Ok(status);
+        }
+        if let Some(namespace) = namespace::current() {
+            let quota = Quota::load(&shared_root(), &namespace);
+            let slot = quota.claim(&state_root(), self.name.as_str(), self.commands.len());
+            self.slot = Some(slot.map_err(|reason| PipelineError::QuotaExceeded { namespace: namespace.clone(), reason })?);
+            self.quota = Some((namespace, quota));
+        }
         log::info!("{}: executing pipeline => '{}'", &self.name, &self.raw_pipeline.trim());
         log::info!("{}: logging command stderr to => '{}'", &self.name, &self.logging_dir.join("*.stderr.log").display());
-        self.spawn_all();
+        // kept after the run so the pipeline can still be inspected by name
+        let definition = self.metadata_dir.join(".pipeline");
+        fs::write(&definition, &self.raw_pipeline).map_err(metadata_io(&definition))?;
+        if let Err(e) = shell::save(&self.metadata_dir, &self.shells) {
+            log::warn!("{}: unable to record interpreters: {e}", &self.name);
+        }
+        // rewritten by an upgraded plumber, which is how `upgrade-daemon` knows it took over
+        let supervisor = self.metadata_dir.join(".supervisor");
+        fs::write(&supervisor, std::process::id().to_string()).map_err(metadata_io(&supervisor))?;
+        match Socket::open(&self.name) {
+            Ok(socket) => self.socket = Some(socket),
+            Err(e) => log::warn!("{}: unable to open {}: {e}", &self.name, socket::path(&self.name).display()),
+        }
+        watchdog::supervise(&self.name, self.critical);
+        let _ = fs::remove_file(self.metadata_dir.join(".stop"));
+        // asked for before this supervisor started, the definition it was given is newer
+        let _ = fs::remove_file(self.metadata_dir.join(".reload"));
+        self.open_control();
+        if !self.ready.is_empty() && self.spawn_order == SpawnOrder::UpstreamFirst {
+            log::warn!("{}: readiness checks are ignored when spawning upstream-first", &self.name);
+        }
+        if self.restart == Restart::Never && self.upstream_exit.iter().any(|r| r.policy == OnUpstreamExit::KeepOpen) {
+            log::warn!("{}: keep-open stdins close like any other without --restart respawning the stage before them", &self.name);
+        }
+        if let Some(handler) = self.core_dumps.as_ref().and_then(|_| cores::handler()) {
+            log::warn!("{}: kernel.core_pattern pipes core dumps to '{handler}', they can't be collected", &self.name);
+        }
+        if !self.instrument {
+            // counters of an earlier instrumented run no longer apply
+            let _ = fs::remove_file(self.metadata_dir.join(".links"));
+        }
 
-        let first_job_pid = self.get_first_pid();
+        self.restarts = Restarts::load(&self.metadata_dir);
+        self.metrics = Arc::new(Mutex::new(Metrics::load(&self.metadata_dir)));
+        let pending = self.restarts.pending(self.clock.system_now());
+        if let Some(delay) = pending.filter(|_| !upgrade::handed_off(&self.name)) {
+            log::info!("{}: backing off from earlier restarts, starting in {}", &self.name, humantime::format_duration(delay));
+            self.back_off(delay);
+        }
+        let mut failed_to_start = None;
+        // checks in a row a precondition didn't pass
+        let mut unmet = 0;
+        loop {
+            if self.metadata_dir.join(".stop").exists() {
+                break;
+            }
+            self.take_reload();
+            watchdog::check_in(&self.name);
+            match self.schedule.opens_in(self.clock.system_now()) {
+                Some(Duration::ZERO) => {},
+                opens => {
+                    // blacked out around the clock, so check again in a while
+                    let delay = opens.unwrap_or(Duration::from_secs(60));
+                    log::info!("{}: outside its window, starting in {}", &self.name, humantime::format_duration(delay));
+                    self.back_off(delay);
+                    continue;
+                },
+            }
+            if let Some((precondition, reason)) = precondition::unmet(&self.preconditions) {
+                if self.on_unmet == OnUnmet::Fail {
+                    failed_to_start = Some(PipelineError::PreconditionUnmet { precondition: precondition.to_string(), reason });
+                    break;
+                }
+                let delay = precondition::delay(unmet);
+                unmet += 1;
+                log::warn!("{}: precondition {precondition} not met ({reason}), checking again in {}", &self.name, humantime::format_duration(delay));
+                self.back_off(delay);
+                continue;
+            }
+            unmet = 0;
+            let started = self.clock.now();
+            if let Err(e) = self.start() {
+                self.remove_tmpdir();
+                failed_to_start = Some(e);
+                break;
+            }
+            let stats = self.write_stats();
+            let summary = self.wait();
+            // nothing left to signal until the next start
+            let _ = fs::remove_file(self.metadata_dir.join(".pid"));
+            let _ = fs::remove_file(self.metadata_dir.join(".stages"));
+            if let Err(e) = summary.write(&self.metadata_dir) {
+                log::warn!("{}: unable to write run summary: {e}", &self.name);
+            }
+            if let Err(e) = self.keep_run(&summary) {
+                log::warn!("{}: unable to keep run for archiving: {e}", &self.name);
+            }
+            for (stage, code, action) in exits::actions(&self.on_exit, &summary) {
+                exits::take(&self.name, &stage, code, action, shell::shell(&self.shells, None));
+            }
+            status = summary.exit_status(self.pipefail);
+            if let Err(e) = state::write(&self.metadata_dir, &state::ended(&self.name, &self.raw_pipeline, &summary, &status)) {
+                log::warn!("{}: unable to write {}: {e}", &self.name, state::FILE);
+            }
+            let failed = summary.failed();
+            if failed {
+                // while what led up to it is still recorded
+                if let Err(e) = recorder::dump(&self.name) {
+                    log::warn!("{}: unable to write flight recorder events: {e}", &self.name);
+                }
+            }
+            self.remove_tmpdir();
+            let (stop, writer) = stats;
+            drop(stop);
+            writer.join().unwrap();
 
-        log::debug!("{}: pid of first job in pipeline is {}", &self.name, &first_job_pid);
+            // drained to be started again as the new definition, which isn't a restart
+            if self.metadata_dir.join(".reload").exists() && !self.metadata_dir.join(".stop").exists() {
+                continue;
+            }
+            let restart = match self.restart {
+                Restart::Never => false,
+                Restart::OnFailure => failed,
+                Restart::Always => true,
+            };
+            // the history outlives a stop, so a plumber restarted mid-backoff doesn't respawn
+            // a crash-looping pipeline right away
+            if self.metadata_dir.join(".stop").exists() {
+                break;
+            }
+            // a stage respawned in place tripped it
+            if restarts::crash_looped(&self.metadata_dir).is_some() {
+                break;
+            }
+            if !restart {
+                Restarts::clear(&self.metadata_dir);
+                break;
+            }
+            let now = self.clock.system_now();
+            let crash_looped = !self.restarts.record(now, &self.crash_loop);
+            let ran = self.clock.now().saturating_duration_since(started);
+            let delay = self.restarts.delay(now, ran, &self.crash_loop, &self.backoff);
+            if let Err(e) = self.restarts.save(&self.metadata_dir) {
+                log::warn!("{}: unable to save restart history: {e}", &self.name);
+            }
+            if crash_looped {
+                restarts::trip(&self.name, &self.metadata_dir, &self.crash_loop, shell::shell(&self.shells, None));
+                break;
+            }
+            let delay_text = humantime::format_duration(Duration::from_millis(delay.as_millis() as u64));
+            let count = self.restarts.count();
+            match failed {
+                true => log::warn!("{}: a stage failed, restarting pipeline in {delay_text} (restart {count})", &self.name),
+                false => log::info!("{}: pipeline exited, restarting it in {delay_text} (restart {count})", &self.name),
+            }
+            self.back_off(delay);
+        }
 
-        let mut pid_file = fs::File::create(&self.metadata_dir.join(".pid")).unwrap();
-        pid_file.write_all(first_job_pid.as_bytes()).unwrap();
-        pid_file.flush().unwrap();
+        upgrade::supervised().remove(self.name.as_str());
+        self.workers.get_mut().unwrap().clear();
+        self.control = None;
+        self.socket = None;
+        watchdog::release(&self.name, restarts::crash_looped(&self.metadata_dir).map(|_| "crash-looped".to_string()));
+        let _ = fs::remove_file(self.metadata_dir.join(".pid"));
+        let _ = fs::remove_file(self.metadata_dir.join(".stop"));
+        let _ = fs::remove_file(self.metadata_dir.join(".reload"));
+        let _ = fs::remove_file(self.metadata_dir.join(".supervisor"));
+        if let Some(e) = failed_to_start {
+            return Err(e);
+        }
+        match status.success() {
+            true => log::info!("{}: pipeline exited with {status}", &self.name),
+            false => log::warn!("{}: pipeline exited with {status}", &self.name),
+        }
+        Ok(status)
+    }
+}
+
+/// reaps stage `i` on the reactor once its pidfd says it exited, so stages are seen exiting in
+/// any order
+fn watch(i: usize, mut job: Job, exits: &mpsc::Sender<(usize, Exit)>) {
+    let exits = exits.clone();
+    match job.pidfd() {
+        Some(Ok(pidfd)) => {
+            reactor::register(Box::new(Reap { i, job, pidfd, exits }));
+        },
+        Some(Err(e)) => {
+            // a kernel before pidfds, wait on a thread of its own instead
+            log::debug!("unable to open a pidfd of stage {i}, waiting on a thread: {e}");
+            thread::spawn(move || {
+                let _ = exits.send((i, job.wait()));
+            });
+        },
+        None => {
+            let _ = exits.send((i, job.wait()));
+        },
+    }
+}
+
+struct Reap {
+    i: usize,
+    job: Job,
+    pidfd: OwnedFd,
+    exits: mpsc::Sender<(usize, Exit)>,
+}
 
-        for jobs in &mut self.jobs {
-            jobs.wait().unwrap();
+impl Handler for Reap {
+    fn poll(&mut self) -> Wait {
+        let mut pollfd = libc::pollfd { fd: self.pidfd.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        if unsafe { libc::poll(&mut pollfd, 1, 0) } <= 0 {
+            return Wait::Readable(self.pidfd.as_raw_fd());
         }
+        // exited, so reaping it doesn't block
+        let _ = self.exits.send((self.i, self.job.wait()));
+        Wait::Done
+    }
+}
 
-        drop(pid_file);
-        fs::remove_file(&self.metadata_dir.join(".pid")).unwrap();
+/// what to spawn for every stage
+fn programs(commands: &[PipelineCommand], shells: &[Shell]) -> Vec<PathBuf> {
+    commands.iter().map(|cmd| match shell::script(&cmd.name) {
+        Some(_) => shell::shell(shells, Some(cmd.log_name())).to_owned(),
+        None => resolve_program(&cmd.name),
+    }).collect()
+}
+
+/// where the stderr log of every stage is in `logging_dir`, `<stage>.stderr.log` unless the
+/// stage names another. stages of the same program are told apart by their position, as in
+/// `01-grep.stderr.log` and `02-grep.stderr.log`, rather than writing over each other's lines
+fn stderr_logs(commands: &[PipelineCommand], logging_dir: &Path) -> Vec<PathBuf> {
+    let shared = |name: &str| commands.iter().filter(|cmd| cmd.stderr_log.is_none() && cmd.log_name() == name).count() > 1;
+    commands.iter()
+        .enumerate()
+        .map(|(i, cmd)| match &cmd.stderr_log {
+            Some(path) => logging_dir.join(path),
+            None if shared(cmd.log_name()) => logging_dir.join(format!("{:02}-{}.stderr.log", i + 1, cmd.log_name())),
+            None => logging_dir.join(format!("{}.stderr.log", cmd.log_name())),
+        })
+        .collect()
+}
+
+/// the stderr log at every path, emptied unless `truncate` is false
+fn open_logs(paths: &[PathBuf], truncate: bool) -> Result<Vec<fs::File>, PipelineError> {
+    paths.iter()
+        .map(|path| {
+            fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(truncate)
+                .append(!truncate)
+                .open(path)
+                .map_err(metadata_io(path))
+        })
+        .collect()
+}
+
+/// full path of a stage's program, so respawning skips the PATH search
+fn resolve_program(name: &str) -> PathBuf {
+    if stages::builtin_kind(name).is_some() {
+        // built-in stages run as a copy of plumber itself
+        return std::env::current_exe().unwrap();
+    }
+    // left to fail when spawning, with the usual error
+    shell::which(name).unwrap_or_else(|| PathBuf::from(name))
+}
+
+/// takes an advisory lock on `.lock` in `metadata_dir`, so no two plumbers run a pipeline of
+/// the same name and write over each other's pid files and logs. it's released when the file is
+/// closed, by plumber exiting however it does, or re-executing itself in an upgrade
+fn lock(metadata_dir: &Path) -> Result<fs::File, PipelineError> {
+    let path = metadata_dir.join(".lock");
+    let mut file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path).map_err(metadata_io(&path))?;
+    match file.try_lock() {
+        Ok(()) => {},
+        Err(fs::TryLockError::WouldBlock) => {
+            let pid = fs::read_to_string(&path).ok().and_then(|pid| pid.trim().parse().ok());
+            return Err(PipelineError::AlreadyRunning { pid });
+        },
+        Err(fs::TryLockError::Error(e)) => return Err(metadata_io(&path)(e)),
     }
+    file.set_len(0).and_then(|_| file.write_all(std::process::id().to_string().as_bytes())).map_err(metadata_io(&path))?;
+    Ok(file)
 }
 
 fn create_dir_with_nice_error(dir: &Path) -> Result<(), std::io::Error> {
@@ -218,27 +2486,100 @@ fn create_dir_with_nice_error(dir: &Path) -> Result<(), std::io::Error> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::Write;
+    use crate::clock::SimulatedClock;
+
+    #[test]
+    fn records_every_root_once() {
+        let file = std::env::temp_dir().join(format!("plumber-roots-test-{}", std::process::id())).join("roots");
+        assert!(recorded_roots(&file).is_empty());
+        for root in ["/tmp/plumber", "/home/ops/.local/state/plumber", "/tmp/plumber"] {
+            record_root(&file, Path::new(root)).unwrap();
+        }
+        assert_eq!(recorded_roots(&file), [Path::new("/tmp/plumber"), Path::new("/home/ops/.local/state/plumber")]);
+        fs::remove_dir_all(file.parent().unwrap()).unwrap();
+    }
 
     #[test]
     fn logging_dir_permissions() {
-        let path = Path::new(LOGGING_DIR);
+        let path = logging_root();
         let test_dir = "asdf_plumber_test";
         create_dir_with_nice_error(&path.join(test_dir)).unwrap();
-        fs::remove_dir(&path.join(test_dir)).unwrap();
+        fs::remove_dir(path.join(test_dir)).unwrap();
     }
 
     #[test]
     fn metadata_dir_permissions() {
-        let path = Path::new(METADATA_DIR);
+        let path = metadata_root();
         let test_dir = "asdf_plumber_test";
         let path = &path.join(test_dir);
         create_dir_with_nice_error(path).unwrap();
         fs::remove_dir(path).unwrap();
     }
 
+    #[test]
+    fn signalling_tells_gone_processes_apart() {
+        assert!(signal(std::process::id() as libc::pid_t, 0).is_ok());
+        assert!(matches!(signal(i32::MAX, 0), Err(PipelineError::NotRunning)));
+    }
+
+    #[test]
+    fn one_pipeline_per_name() {
+        let name = "asdf_plumber_test_locked".to_string();
+        let pipeline = Pipeline::new(name.clone(), "true".to_string()).unwrap();
+        match Pipeline::new(name.clone(), "true".to_string()) {
+            Err(e @ PipelineError::AlreadyRunning { .. }) => {
+                assert_eq!(e.to_string(), format!("already running, supervised by pid {}", std::process::id()));
+            },
+            other => panic!("expected already running, got {:?}", other.err()),
+        }
+        drop(pipeline);
+        assert!(Pipeline::new(name.clone(), "true".to_string()).is_ok());
+        fs::remove_dir_all(metadata_root().join(&name)).unwrap();
+        fs::remove_dir_all(logging_root().join(&name)).unwrap();
+    }
+
+    #[test]
+    fn refuses_pids_that_signal_everything() {
+        let name: ValidatedName = "asdf_plumber_test_bad_pids".parse().unwrap();
+        let dir = metadata_root().join(&name);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(".pid"), "-1").unwrap();
+        fs::write(dir.join(".stages"), "0\n1\n4294967295\n\n").unwrap();
+        assert!(matches!(Pipeline::stop(&name, StopMode::Drain), Err(PipelineError::MetadataIo { .. })));
+        assert!(matches!(Pipeline::reload(&name, "cat"), Err(PipelineError::MetadataIo { .. })));
+        assert!(Pipeline::stage_groups(&name).is_empty());
+        assert_eq!(Pipeline::stage_pids(&name), [None, Some(1), None, None]);
+        assert!(matches!(Pipeline::signal_stage(&name, 0, 0), Err(PipelineError::MetadataIo { .. })));
+        assert!(matches!(Pipeline::signal_stage(&name, 3, 0), Err(PipelineError::NotRunning)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn errors_say_what_went_wrong() {
+        let name = "asdf_plumber_test_errors".to_string();
+        let e = Pipeline::new(name.clone(), "cat |\n  grep 'oops".to_string()).err().unwrap();
+        assert!(matches!(e, PipelineError::ParseError { position: 13, .. }));
+        assert_eq!(e.show("cat |\n  grep 'oops"), "  grep 'oops\n       ^");
+
+        let pipeline = Pipeline::new(name.clone(), "sleep 5 | asdf-no-such-command".to_string()).unwrap();
+        match pipeline.run() {
+            Err(e @ PipelineError::CommandNotFound { .. }) => {
+                assert_eq!(e.to_string(), "asdf-no-such-command: command not found: asdf-no-such-command");
+            },
+            other => panic!("expected command not found, got {other:?}"),
+        }
+        assert!(!Pipeline::metadata_file(&name.parse().unwrap(), ".supervisor").exists());
+
+        let missing = Pipeline::supervisor(&"asdf_plumber_test_never_ran".parse().unwrap()).err().unwrap();
+        assert!(std::error::Error::source(&missing).is_some());
+        fs::remove_dir_all(metadata_root().join(&name)).unwrap();
+        fs::remove_dir_all(logging_root().join(&name)).unwrap();
+    }
+
     #[test]
     fn writing_pid_file() {
-        let path = Path::new(METADATA_DIR);
+        let path = metadata_root();
         let test_dir = "asdf_plumber_test_2";
         let path = &path.join(test_dir);
         create_dir_with_nice_error(path).unwrap();
@@ -247,7 +2588,7 @@ mod tests {
         pid_file.write_all("12345".as_bytes()).unwrap();
         pid_file.flush().unwrap();
         drop(pid_file);
-        fs::remove_file(&path.join(".pid")).unwrap();
+        fs::remove_file(path.join(".pid")).unwrap();
         fs::remove_dir(path).unwrap();
     }
 
@@ -262,26 +2603,195 @@ mod tests {
                     "-a".to_string(),
                     "-v".to_string(),
                 ],
+                ..PipelineCommand::default()
             },
             PipelineCommand {
                 name: "pv".to_string(),
                 args: vec![
                     "--force".to_string(),
                 ],
+                ..PipelineCommand::default()
             },
             PipelineCommand {
                 name: "oops_two_spaces".to_string(),
                 args: vec![],
+                ..PipelineCommand::default()
             },
             PipelineCommand {
                 name: "grep".to_string(),
                 args: vec![
                     "a".to_string(),
                 ],
+                ..PipelineCommand::default()
             },
         ];
 
-        assert_eq!(res, Pipeline::parse_raw_pipeline(pipeline));
+        assert_eq!(res, Pipeline::parse_raw_pipeline(pipeline).unwrap());
+    }
+
+    #[test]
+    fn quoted_pipes_are_data() {
+        let pipeline = "grep \"a|b\" | awk -F'|' '{ print $1 | \"sort\" }' | wc -l";
+        let commands = Pipeline::parse_raw_pipeline(pipeline).unwrap();
+        assert_eq!(commands.len(), 3);
+        assert_eq!(commands[0].args, ["a|b"]);
+        assert_eq!(commands[1].args, ["-F|", "{ print $1 | \"sort\" }"]);
+
+        // and stay quoted when written back
+        let lines = Pipeline::stage_command_lines(pipeline).unwrap();
+        assert_eq!(lines[0], "grep \"a|b\"");
+        assert_eq!(Pipeline::parse_raw_pipeline(&lines.join(" | ")).unwrap(), commands);
+    }
+
+    #[test]
+    fn stages_of_one_program_keep_their_own_logs() {
+        let name = "asdf_plumber_test_log_paths".to_string();
+        let pipeline = Pipeline::new(name.clone(), "sh -c 'echo one >&2; echo a' | sh -c 'echo two >&2; cat' | wc -l".to_string()).unwrap();
+        let dir = logging_root().join(&name);
+        assert_eq!(pipeline.log_paths(), [
+            ("sh", dir.join("01-sh.stderr.log").as_path()),
+            ("sh", dir.join("02-sh.stderr.log").as_path()),
+            ("wc", dir.join("wc.stderr.log").as_path()),
+        ]);
+        pipeline.run().unwrap();
+        assert_eq!(fs::read_to_string(dir.join("01-sh.stderr.log")).unwrap(), "one\n");
+        assert_eq!(fs::read_to_string(dir.join("02-sh.stderr.log")).unwrap(), "two\n");
+
+        let commands = Pipeline::parse_raw_pipeline("[[stage]]\ncmd = \"grep\"\nstderr_log = \"a.log\"\n[[stage]]\ncmd = \"grep\"\n").unwrap();
+        assert_eq!(stderr_logs(&commands, &dir), [dir.join("a.log"), dir.join("grep.stderr.log")]);
+        let _ = fs::remove_dir_all(&dir);
+        let _ = fs::remove_dir_all(metadata_root().join(&name));
+    }
+
+    #[test]
+    fn resolves_programs_once() {
+        let sh = resolve_program("sh");
+        assert!(sh.is_absolute() && sh.ends_with("sh"), "{}", sh.display());
+        assert_eq!(resolve_program("./local"), Path::new("./local"));
+        assert_eq!(resolve_program("asdf-no-such-program"), Path::new("asdf-no-such-program"));
+    }
+
+    #[test]
+    fn restarts_in_milliseconds() {
+        let mut pipeline = Pipeline::new("asdf_plumber_test_restart".to_string(), "true | true | true".to_string()).unwrap();
+        let mut took: Vec<Duration> = (0..21)
+            .map(|_| {
+                let started = Instant::now();
+                pipeline.start().unwrap();
+                let took = started.elapsed();
+                assert!(!pipeline.wait().failed());
+                took
+            })
+            .collect();
+        took.sort();
+        assert!(took[10] < Duration::from_millis(10), "median restart took {:?}", took[10]);
+
+        fs::remove_dir_all(metadata_root().join("asdf_plumber_test_restart")).unwrap();
+        fs::remove_dir_all(logging_root().join("asdf_plumber_test_restart")).unwrap();
+    }
+
+    #[test]
+    fn backs_off_on_a_simulated_clock() {
+        let name = "asdf_plumber_test_backoff".to_string();
+        let mut pipeline = Pipeline::new(name.clone(), "false".to_string()).unwrap();
+        pipeline.set_restart(Restart::OnFailure);
+        pipeline.set_crash_loop(CrashLoop { max_restarts: 2, window: Duration::from_secs(3600), on_crash_loop: None });
+        pipeline.set_restart_backoff(Backoff { initial: Duration::from_secs(10), max: Duration::from_secs(60) });
+        let clock = Arc::new(SimulatedClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+        pipeline.clock = clock.clone();
+
+        let started = Instant::now();
+        pipeline.run().unwrap();
+        // two restarts backed off for 10s and 20s, the third one tripped the crash loop
+        assert_eq!(clock.elapsed(), Duration::from_secs(30));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(restarts::crash_looped(&metadata_root().join(&name)).is_some());
+
+        fs::remove_dir_all(metadata_root().join(&name)).unwrap();
+        fs::remove_dir_all(logging_root().join(&name)).unwrap();
+        let _ = fs::remove_dir_all(tmp_root().join(&name));
+    }
+
+    #[test]
+    fn spawn_orders() {
+        for order in [SpawnOrder::DownstreamFirst, SpawnOrder::UpstreamFirst] {
+            let name = "asdf_plumber_test_order".to_string();
+            let mut pipeline = Pipeline::new(name.clone(), "true | true".to_string()).unwrap();
+            pipeline.set_spawn_order(order);
+            pipeline.spawn_all().unwrap();
+            let pids: Vec<u32> = pipeline.jobs.iter().flat_map(Job::id).collect();
+            assert!(!pipeline.wait().failed());
+            // jobs stay in pipeline order whatever order they were spawned in, pids tell that order
+            assert_eq!(pids[0] < pids[1], order == SpawnOrder::UpstreamFirst);
+            fs::remove_dir_all(metadata_root().join(&name)).unwrap();
+            fs::remove_dir_all(logging_root().join(&name)).unwrap();
+        }
+    }
+
+    #[test]
+    fn upstream_exit_policies() {
+        let out = std::env::temp_dir().join(format!("plumber-upstream-test-{}", std::process::id()));
+        let name = "asdf_plumber_test_upstream_exit".to_string();
+        let consume = format!("sh -c 'head -n 3 > {}'", out.display());
+
+        let mut pipeline = Pipeline::new(name.clone(), format!("echo a | {consume}")).unwrap();
+        pipeline.set_upstream_exit(vec!["sentinel:EOF".parse().unwrap()]);
+        let hooked = out.with_extension("eof");
+        pipeline.set_eof_hooks(vec![format!("sh=echo $PLUMBER_UPSTREAM > {}", hooked.display()).parse().unwrap()]);
+        pipeline.start().unwrap();
+        assert!(!pipeline.wait().failed());
+        assert_eq!(fs::read_to_string(&out).unwrap(), "a\nEOF\n");
+        assert_eq!(fs::read_to_string(&hooked).unwrap(), "echo\n");
+        fs::remove_file(hooked).unwrap();
+        drop(pipeline);
+
+        // the producer fails after every line and is respawned, the consumer reads on
+        let mut pipeline = Pipeline::new(name.clone(), format!("sh -c 'echo $$; exit 1' | {consume}")).unwrap();
+        pipeline.set_upstream_exit(vec!["keep-open".parse().unwrap()]);
+        pipeline.set_restart(Restart::OnFailure);
+        pipeline.set_restart_backoff(Backoff { initial: Duration::from_millis(10), max: Duration::from_millis(10) });
+        pipeline.start().unwrap();
+        let summary = pipeline.wait();
+        let pids: Vec<String> = fs::read_to_string(&out).unwrap().lines().map(str::to_owned).collect();
+        assert_eq!(pids.len(), 3);
+        assert!(pids[0] != pids[1] && pids[1] != pids[2]);
+        assert!(summary.stages.iter().filter(|s| s.respawned).count() >= 2);
+        // its last run failed too, but wasn't respawned with the consumer gone
+        assert!(summary.failed());
+
+        fs::remove_file(out).unwrap();
+        fs::remove_dir_all(metadata_root().join(&name)).unwrap();
+        fs::remove_dir_all(logging_root().join(&name)).unwrap();
+    }
+
+    #[test]
+    fn tmpdir_per_run() {
+        let name = "asdf_plumber_test_tmpdir".to_string();
+        let mut pipeline = Pipeline::new(name.clone(), "sh -c 'test -d \"$PLUMBER_TMPDIR\" && touch \"$PLUMBER_TMPDIR/x\"'".to_string()).unwrap();
+        pipeline.start().unwrap();
+        let tmpdir = pipeline.tmpdir.clone().unwrap();
+        assert!(!pipeline.wait().failed());
+        assert!(tmpdir.join("x").exists());
+        pipeline.remove_tmpdir();
+        assert!(!tmpdir.exists());
+
+        fs::remove_dir_all(metadata_root().join(&name)).unwrap();
+        fs::remove_dir_all(logging_root().join(&name)).unwrap();
+        fs::remove_dir_all(tmp_root().join(&name)).unwrap();
+    }
+
+    #[test]
+    fn stage_command_lines() {
+        let lines = Pipeline::stage_command_lines("cat 'my file' | grep -v \"a b\" | sort:field=2,numeric=true").unwrap();
+        assert_eq!(lines, ["cat \"my file\"", "grep -v \"a b\"", "sort:field=2,numeric=true"]);
+
+        let commands = Pipeline::parse_raw_pipeline("-- -weird -x | wc").unwrap();
+        assert_eq!(commands[0], PipelineCommand::new("-weird".to_string(), vec!["-x".to_string()]));
+        assert_eq!(commands[0].command_line(), "-- -weird -x");
+
+        let lines = Pipeline::stage_command_lines("sort<in.txt 2>'my errors' | uniq >> out.txt").unwrap();
+        assert_eq!(lines, ["sort < in.txt 2> \"my errors\"", "uniq >> out.txt"]);
+        assert_eq!(Pipeline::stage_command_lines(&lines.join(" | ")).unwrap(), lines);
     }
 
 
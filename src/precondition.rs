@@ -0,0 +1,181 @@
+//! what has to hold before a run is spawned, `--require`
+//!
+//! - `path:<path>` exists
+//! - `mount:<path>` is a mount point, so a share that isn't mounted yet isn't mistaken for the
+//!   empty dir under it
+//! - `host:<name>` resolves
+//! - `port:<host>:<port>` takes connections
+//!
+//! all of them are checked before every run. with `--on-unmet wait`, the default, a run that
+//! can't start yet is held back, checking again after 1s, then 2s and so on up to a minute,
+//! until they hold or the pipeline is stopped. `--on-unmet fail` ends the pipeline right away
+//! with the precondition that didn't hold, rather than letting its first stage die of it.
+
+use std::fs;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// how long a `port:` check waits for a connection, per address the host resolves to
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Precondition {
+    Path(PathBuf),
+    Mount(PathBuf),
+    Host(String),
+    Port(String, u16),
+}
+
+impl std::str::FromStr for Precondition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let unknown = || format!("unknown precondition '{s}', expected path:<path>, mount:<path>, host:<name> or port:<host>:<port>");
+        let (kind, arg) = s.split_once(':').filter(|(_, arg)| !arg.is_empty()).ok_or_else(unknown)?;
+        match kind {
+            "path" => Ok(Precondition::Path(PathBuf::from(arg))),
+            "mount" => Ok(Precondition::Mount(PathBuf::from(arg))),
+            "host" => Ok(Precondition::Host(arg.to_owned())),
+            "port" => {
+                let (host, port) = arg.rsplit_once(':').ok_or_else(|| format!("invalid precondition '{s}', expected port:<host>:<port>"))?;
+                let port = port.parse().map_err(|_| format!("invalid port '{port}' in precondition '{s}'"))?;
+                // [::1]:5432
+                let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+                Ok(Precondition::Port(host.to_owned(), port))
+            },
+            _ => Err(unknown()),
+        }
+    }
+}
+
+impl std::fmt::Display for Precondition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Precondition::Path(path) => write!(f, "path:{}", path.display()),
+            Precondition::Mount(path) => write!(f, "mount:{}", path.display()),
+            Precondition::Host(host) => write!(f, "host:{host}"),
+            Precondition::Port(host, port) if host.contains(':') => write!(f, "port:[{host}]:{port}"),
+            Precondition::Port(host, port) => write!(f, "port:{host}:{port}"),
+        }
+    }
+}
+
+/// what a pipeline does when a precondition doesn't hold
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum OnUnmet {
+    /// hold the run back until it does
+    #[default]
+    Wait,
+    /// end the pipeline with it
+    Fail,
+}
+
+impl Precondition {
+    /// why it doesn't hold, if it doesn't
+    pub fn check(&self) -> Result<(), String> {
+        match self {
+            Precondition::Path(path) => match fs::metadata(path) {
+                Ok(_) => Ok(()),
+                Err(e) => Err(format!("{}: {e}", path.display())),
+            },
+            Precondition::Mount(path) => {
+                let path = fs::canonicalize(path).map_err(|e| format!("{}: {e}", path.display()))?;
+                let mounts = fs::read_to_string("/proc/self/mounts").map_err(|e| format!("/proc/self/mounts: {e}"))?;
+                match mounted(&mounts, &path) {
+                    true => Ok(()),
+                    false => Err(format!("{}: not a mount point", path.display())),
+                }
+            },
+            Precondition::Host(host) => match (host.as_str(), 0).to_socket_addrs().map(|mut addrs| addrs.next()) {
+                Ok(Some(_)) => Ok(()),
+                Ok(None) => Err(format!("{host}: resolves to no address")),
+                Err(e) => Err(format!("{host}: unable to resolve: {e}")),
+            },
+            Precondition::Port(host, port) => {
+                let addrs: Vec<_> = (host.as_str(), *port).to_socket_addrs().map_err(|e| format!("{host}: unable to resolve: {e}"))?.collect();
+                let mut last = format!("{host}: resolves to no address");
+                for addr in addrs {
+                    match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+                        Ok(_) => return Ok(()),
+                        Err(e) => last = format!("{host}:{port}: unable to connect to {addr}: {e}"),
+                    }
+                }
+                Err(last)
+            },
+        }
+    }
+}
+
+/// the first of `preconditions` that doesn't hold and why
+pub fn unmet(preconditions: &[Precondition]) -> Option<(&Precondition, String)> {
+    preconditions.iter().find_map(|precondition| precondition.check().err().map(|reason| (precondition, reason)))
+}
+
+/// how long to wait before checking again, after `attempt` checks that failed
+pub fn delay(attempt: u32) -> Duration {
+    Duration::from_secs(1 << attempt.min(6)).min(Duration::from_secs(60))
+}
+
+/// whether `/proc/self/mounts` has something mounted on `path`
+fn mounted(mounts: &str, path: &Path) -> bool {
+    mounts.lines().filter_map(|line| line.split(' ').nth(1)).any(|point| Path::new(&unescape(point)) == path)
+}
+
+/// mount points with spaces and such are written as octal escapes, `\040`
+fn unescape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(i) = rest.find('\\') {
+        out.push_str(&rest[..i]);
+        let escape = rest.get(i + 1..i + 4).and_then(|octal| u8::from_str_radix(octal, 8).ok());
+        match escape {
+            Some(byte) => {
+                out.push(byte as char);
+                rest = &rest[i + 4..];
+            },
+            None => {
+                out.push('\\');
+                rest = &rest[i + 1..];
+            },
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_preconditions() {
+        assert_eq!("path:/data/in".parse(), Ok(Precondition::Path(PathBuf::from("/data/in"))));
+        assert_eq!("host:db.internal".parse(), Ok(Precondition::Host("db.internal".to_string())));
+        assert_eq!("port:db.internal:5432".parse(), Ok(Precondition::Port("db.internal".to_string(), 5432)));
+        assert_eq!("port:[::1]:5432".parse(), Ok(Precondition::Port("::1".to_string(), 5432)));
+        assert_eq!("port:[::1]:5432".parse::<Precondition>().unwrap().to_string(), "port:[::1]:5432");
+        assert!("port:db.internal".parse::<Precondition>().is_err());
+        assert!("path:".parse::<Precondition>().is_err());
+        assert!("url:http://x".parse::<Precondition>().is_err());
+    }
+
+    #[test]
+    fn checks_paths_and_mounts() {
+        assert!(Precondition::Path(PathBuf::from("/")).check().is_ok());
+        let e = Precondition::Path(PathBuf::from("/nonexistent/plumber")).check().unwrap_err();
+        assert!(e.starts_with("/nonexistent/plumber: "), "{e}");
+
+        let mounts = "/dev/sda1 / ext4 rw 0 0\nnas:/share /mnt/nas\\040share nfs rw 0 0\n";
+        assert!(mounted(mounts, Path::new("/mnt/nas share")));
+        assert!(!mounted(mounts, Path::new("/mnt")));
+        assert!(Precondition::Mount(PathBuf::from("/")).check().is_ok());
+    }
+
+    #[test]
+    fn backs_off_up_to_a_minute() {
+        assert_eq!(delay(0), Duration::from_secs(1));
+        assert_eq!(delay(3), Duration::from_secs(8));
+        assert_eq!(delay(40), Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,140 @@
+//! probes of where a pipeline's output goes, `plumber check --probe`
+//!
+//! catching a misconfigured sink before the run that produces the data, rather than after
+//! hours of it. every probe is cheap and leaves nothing behind:
+//! - `writable:<dir>` creates and removes a file in the dir
+//! - `s3:<bucket>` asks for the bucket with `aws s3api head-bucket`, so the credentials the
+//!   aws cli finds must be valid and allowed to see it
+//! - `cmd:<command>` runs the command with `sh -c`, passing if it exits 0, e.g.
+//!   `cmd:pg_isready -h db.internal`
+//! - `path:`, `mount:`, `host:` and `port:` check what `--require` does, see `precondition`
+//!
+//! a probe that takes longer than 30s is killed and fails.
+
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::precondition::Precondition;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Probe {
+    Writable(PathBuf),
+    S3(String),
+    Cmd(String),
+    Require(Precondition),
+}
+
+impl std::str::FromStr for Probe {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let unknown = || format!("unknown probe '{s}', expected writable:<dir>, s3:<bucket>, cmd:<command>, path:<path>, mount:<path>, host:<name> or port:<host>:<port>");
+        let (kind, arg) = s.split_once(':').filter(|(_, arg)| !arg.is_empty()).ok_or_else(unknown)?;
+        match kind {
+            "writable" => Ok(Probe::Writable(PathBuf::from(arg))),
+            // s3://bucket/prefix too, it's the bucket that's asked for
+            "s3" => match arg.trim_start_matches('/').split('/').next() {
+                Some(bucket) if !bucket.is_empty() => Ok(Probe::S3(bucket.to_owned())),
+                _ => Err(format!("invalid probe '{s}', expected s3:<bucket>")),
+            },
+            "cmd" => Ok(Probe::Cmd(arg.to_owned())),
+            "path" | "mount" | "host" | "port" => s.parse().map(Probe::Require),
+            _ => Err(unknown()),
+        }
+    }
+}
+
+impl std::fmt::Display for Probe {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Probe::Writable(dir) => write!(f, "writable:{}", dir.display()),
+            Probe::S3(bucket) => write!(f, "s3:{bucket}"),
+            Probe::Cmd(command) => write!(f, "cmd:{command}"),
+            Probe::Require(precondition) => write!(f, "{precondition}"),
+        }
+    }
+}
+
+impl Probe {
+    /// why it failed, if it did
+    pub fn check(&self) -> Result<(), String> {
+        match self {
+            Probe::Writable(dir) => {
+                let file = dir.join(format!(".plumber-probe-{}", std::process::id()));
+                fs::OpenOptions::new().write(true).create_new(true).open(&file).map_err(|e| format!("{}: not writable: {e}", dir.display()))?;
+                fs::remove_file(&file).map_err(|e| format!("{}: unable to remove: {e}", file.display()))
+            },
+            Probe::S3(bucket) => run(Command::new("aws").args(["s3api", "head-bucket", "--bucket", bucket]), "aws"),
+            Probe::Cmd(command) => run(Command::new("sh").arg("-c").arg(command), "sh"),
+            Probe::Require(precondition) => precondition.check(),
+        }
+    }
+}
+
+/// runs `command` to completion or for `TIMEOUT`, failing with the last line it wrote to stderr
+fn run(command: &mut Command, program: &str) -> Result<(), String> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("unable to run {program}: {e}"))?;
+    let deadline = Instant::now() + TIMEOUT;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(50)),
+            Ok(None) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("no answer in {}", humantime::format_duration(TIMEOUT)));
+            },
+            Err(e) => return Err(e.to_string()),
+        }
+    };
+    if status.success() {
+        return Ok(());
+    }
+    let mut stderr = String::new();
+    let _ = child.stderr.take().unwrap().read_to_string(&mut stderr);
+    match stderr.lines().rev().map(str::trim).find(|line| !line.is_empty()) {
+        Some(line) => Err(format!("{status}: {line}")),
+        None => Err(status.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_probes() {
+        assert_eq!("writable:/data/out".parse(), Ok(Probe::Writable(PathBuf::from("/data/out"))));
+        assert_eq!("s3:exports".parse(), Ok(Probe::S3("exports".to_string())));
+        assert_eq!("s3://exports/daily/".parse(), Ok(Probe::S3("exports".to_string())));
+        assert_eq!("cmd:pg_isready -h db".parse(), Ok(Probe::Cmd("pg_isready -h db".to_string())));
+        assert_eq!("port:db:5432".parse(), Ok(Probe::Require(Precondition::Port("db".to_string(), 5432))));
+        assert!("s3:".parse::<Probe>().is_err());
+        assert!("ftp:host".parse::<Probe>().is_err());
+    }
+
+    #[test]
+    fn probes_leave_nothing_behind() {
+        let dir = std::env::temp_dir().join(format!("plumber-probe-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        Probe::Writable(dir.clone()).check().unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        assert!(Probe::Writable(dir.join("missing")).check().unwrap_err().contains("not writable"));
+        fs::remove_dir_all(dir).unwrap();
+
+        Probe::Cmd("true".to_string()).check().unwrap();
+        let e = Probe::Cmd("echo connecting >&2; echo refused >&2; exit 2".to_string()).check().unwrap_err();
+        assert_eq!(e, "exit status: 2: refused");
+    }
+}
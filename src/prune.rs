@@ -0,0 +1,172 @@
+//! `plumber prune`, or `plumber clean`, removing what pipelines leave behind under `/tmp/plumber`
+//!
+//! of a pipeline that isn't running and wasn't run for `--older-than`, 7 days by default,
+//! its metadata dir, stderr logs, kept runs, core dumps and scratch dirs are removed. of a
+//! running pipeline only the scratch dirs of earlier runs are, such as those kept with
+//! `--keep-tmpdir`. a pipeline counts as running while the plumber supervising it is alive,
+//! as `status` tells, so the leftovers of a plumber that was killed are pruned too, even once
+//! its pid was reused.
+//!
+//! `--dry-run` lists what would be removed and how much space that reclaims per pipeline,
+//! removing nothing.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::status;
+
+/// where pipelines keep their state, each in a dir named after the pipeline
+pub struct StateDirs {
+    pub metadata: PathBuf,
+    pub logs: PathBuf,
+    pub tmp: PathBuf,
+}
+
+/// a dir a pipeline left behind
+#[derive(Debug, PartialEq)]
+pub struct Leftover {
+    pub pipeline: String,
+    pub path: PathBuf,
+    /// bytes on disk
+    pub size: u64,
+}
+
+impl Leftover {
+    fn new(pipeline: &str, path: PathBuf) -> io::Result<Self> {
+        Ok(Leftover { pipeline: pipeline.to_owned(), size: disk_usage(&path)?, path })
+    }
+
+    pub fn remove(&self) -> io::Result<()> {
+        fs::remove_dir_all(&self.path)
+    }
+}
+
+/// what pruning would remove, ordered by pipeline
+pub fn leftovers(dirs: &StateDirs, older_than: Duration, now: SystemTime) -> io::Result<Vec<Leftover>> {
+    let mut names = BTreeSet::new();
+    for root in [&dirs.metadata, &dirs.logs, &dirs.tmp] {
+        let Ok(entries) = fs::read_dir(root) else { continue };
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                names.extend(entry.file_name().into_string());
+            }
+        }
+    }
+
+    let mut leftovers = Vec::new();
+    for name in names {
+        let metadata = dirs.metadata.join(&name);
+        let tmp = dirs.tmp.join(&name);
+        if status::running(&metadata) {
+            let current = fs::read(metadata.join(".tmpdir")).ok().map(|dir| PathBuf::from(String::from_utf8_lossy(&dir).into_owned()));
+            let Ok(entries) = fs::read_dir(&tmp) else { continue };
+            for entry in entries {
+                let path = entry?.path();
+                if Some(&path) != current.as_ref() {
+                    leftovers.push(Leftover::new(&name, path)?);
+                }
+            }
+            continue;
+        }
+
+        let logs = dirs.logs.join(&name);
+        let last_used = [&metadata, &logs].into_iter().filter_map(|dir| last_modified(dir).ok()).max();
+        if last_used.is_some_and(|used| now.duration_since(used).unwrap_or_default() < older_than) {
+            continue;
+        }
+        for dir in [metadata, logs, tmp] {
+            if dir.exists() {
+                leftovers.push(Leftover::new(&name, dir)?);
+            }
+        }
+    }
+    Ok(leftovers)
+}
+
+/// latest modification of a dir or anything directly in it
+fn last_modified(dir: &Path) -> io::Result<SystemTime> {
+    let mut last = fs::metadata(dir)?.modified()?;
+    for entry in fs::read_dir(dir)? {
+        last = last.max(entry?.metadata()?.modified()?);
+    }
+    Ok(last)
+}
+
+fn disk_usage(path: &Path) -> io::Result<u64> {
+    let metadata = fs::symlink_metadata(path)?;
+    let mut size = metadata.blocks() * 512;
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            size += disk_usage(&entry?.path())?;
+        }
+    }
+    Ok(size)
+}
+
+/// bytes as `12.4M`
+pub fn human_size(bytes: u64) -> String {
+    let mut size = bytes as f64;
+    for unit in ["", "K", "M", "G"] {
+        if size < 1024.0 {
+            return match unit {
+                "" => format!("{bytes}B"),
+                unit => format!("{size:.1}{unit}"),
+            };
+        }
+        size /= 1024.0;
+    }
+    format!("{size:.1}T")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn touch(dir: &Path, file: &str, age: Duration) {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join(file), "x").unwrap();
+        let modified = SystemTime::now() - age;
+        for path in [dir.join(file), dir.to_owned()] {
+            fs::File::open(path).unwrap().set_modified(modified).unwrap();
+        }
+    }
+
+    #[test]
+    fn finds_leftovers() {
+        let root = std::env::temp_dir().join(format!("plumber-prune-test-{}", std::process::id()));
+        let dirs = StateDirs { metadata: root.join("lib"), logs: root.join("log"), tmp: root.join("tmp") };
+        let week = Duration::from_secs(7 * 86_400);
+        touch(&dirs.metadata.join("stale"), ".pipeline", week * 2);
+        touch(&dirs.logs.join("stale"), "cat.stderr.log", week * 2);
+        touch(&dirs.metadata.join("recent"), ".pipeline", Duration::ZERO);
+        touch(&dirs.metadata.join("running"), ".pipeline", week * 2);
+        fs::write(dirs.metadata.join("running/.supervisor"), std::process::id().to_string()).unwrap();
+        fs::write(dirs.metadata.join("running/.tmpdir"), dirs.tmp.join("running/2").display().to_string()).unwrap();
+        touch(&dirs.tmp.join("running/1"), "scratch", week * 2);
+        touch(&dirs.tmp.join("running/2"), "scratch", week * 2);
+
+        let found = leftovers(&dirs, week, SystemTime::now()).unwrap();
+        let paths: Vec<_> = found.iter().map(|l| (l.pipeline.as_str(), l.path.clone())).collect();
+        assert_eq!(paths, vec![
+            ("running", dirs.tmp.join("running/1")),
+            ("stale", dirs.metadata.join("stale")),
+            ("stale", dirs.logs.join("stale")),
+        ]);
+        assert!(found.iter().all(|l| l.size > 0));
+        found.iter().for_each(|l| l.remove().unwrap());
+        assert!(leftovers(&dirs, week, SystemTime::now()).unwrap().is_empty());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn human_sizes() {
+        assert_eq!(human_size(512), "512B");
+        assert_eq!(human_size(12 * 1024 + 400), "12.4K");
+        assert_eq!(human_size(3 << 30), "3.0G");
+    }
+}
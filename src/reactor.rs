@@ -0,0 +1,343 @@
+//! shared epoll reactor for plumber's own io
+//!
+//! relays between stages, seccomp supervisors, spools, the connections to a supervisor's
+//! sockets and waiting for stages to exit used to get a thread each, which adds up to
+//! thousands of threads for a daemon running a few hundred pipelines. instead they are
+//! handlers driven by a single reactor thread: a handler makes as much progress as it can
+//! without blocking and then says which descriptors it is waiting for.
+
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+pub enum Wait {
+    Readable(RawFd),
+    Writable(RawFd),
+    /// whichever of these comes first, none of them `Any` or `Done`
+    Any(Vec<Wait>),
+    Done,
+}
+
+pub trait Handler: Send {
+    /// progresses without blocking until it has to wait, or is done
+    fn poll(&mut self) -> Wait;
+}
+
+struct Entry {
+    handler: Box<dyn Handler>,
+    /// descriptors added to the epoll set for this handler
+    registered: Vec<RawFd>,
+    /// dropped with the entry, which wakes whoever waits on the `Finished`
+    _done: mpsc::Sender<()>,
+}
+
+/// resolves once a registered handler is done
+pub struct Finished(mpsc::Receiver<()>);
+
+impl Finished {
+    /// for work done outside the reactor: finished once the sender is dropped
+    pub fn channel() -> (mpsc::Sender<()>, Finished) {
+        let (done, finished) = mpsc::channel();
+        (done, Finished(finished))
+    }
+
+    pub fn wait(self) {
+        // the sender never sends, recv returns once it is dropped
+        let _ = self.0.recv();
+    }
+
+    /// false if still not finished after `timeout`
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        self.0.recv_timeout(timeout) != Err(mpsc::RecvTimeoutError::Timeout)
+    }
+}
+
+struct Reactor {
+    epoll: RawFd,
+    entries: Mutex<HashMap<u64, Entry>>,
+    /// registered by handlers while they're polled, which happens with `entries` locked
+    deferred: Mutex<Vec<(u64, Entry)>>,
+    next_id: Mutex<u64>,
+}
+
+thread_local! {
+    /// whether this is the reactor thread, polling handlers
+    static POLLING: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+fn reactor() -> &'static Reactor {
+    static REACTOR: OnceLock<Reactor> = OnceLock::new();
+    REACTOR.get_or_init(|| {
+        let epoll = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        assert!(epoll >= 0, "unable to create epoll instance: {}", io::Error::last_os_error());
+        thread::Builder::new()
+            .name("reactor".to_string())
+            .spawn(run)
+            .expect("unable to start reactor thread");
+        Reactor {
+            epoll,
+            entries: Mutex::new(HashMap::new()),
+            deferred: Mutex::new(Vec::new()),
+            next_id: Mutex::new(0),
+        }
+    })
+}
+
+/// drives `handler` on the reactor thread until it is done
+pub fn register(handler: Box<dyn Handler>) -> Finished {
+    let reactor = reactor();
+    let id = {
+        let mut next_id = reactor.next_id.lock().unwrap();
+        *next_id += 1;
+        *next_id
+    };
+    let (done, finished) = mpsc::channel();
+    let entry = Entry { handler, registered: Vec::new(), _done: done };
+    if POLLING.get() {
+        // first polled once the reactor is done with what it's polling
+        reactor.deferred.lock().unwrap().push((id, entry));
+        return Finished(finished);
+    }
+    let mut entries = reactor.entries.lock().unwrap();
+    entries.insert(id, entry);
+    // nothing is armed for the handler yet, so the reactor can't race this first poll
+    reactor.advance(&mut entries, id);
+    Finished(finished)
+}
+
+/// accepts connections on `listener`, non-blocking, for as long as `accepted` returns true
+pub fn listen(listener: UnixListener, accepted: impl FnMut(io::Result<UnixStream>) -> bool + Send + 'static) -> io::Result<Finished> {
+    listener.set_nonblocking(true)?;
+    Ok(register(Box::new(Listen { listener, accepted })))
+}
+
+struct Listen<F> {
+    listener: UnixListener,
+    accepted: F,
+}
+
+impl<F: FnMut(io::Result<UnixStream>) -> bool + Send> Handler for Listen<F> {
+    fn poll(&mut self) -> Wait {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream.set_nonblocking(true).map(|_| stream),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Wait::Readable(self.listener.as_raw_fd()),
+                Err(e) if matches!(e.kind(), io::ErrorKind::Interrupted | io::ErrorKind::ConnectionAborted) => continue,
+                Err(e) => Err(e),
+            };
+            if !(self.accepted)(stream) {
+                return Wait::Done;
+            }
+        }
+    }
+}
+
+/// the complete lines `stream` has to read without blocking, `partial` keeping the start of
+/// the next. false once the other end is gone, the last line counted even without a newline
+pub fn read_lines(stream: &mut impl Read, partial: &mut Vec<u8>) -> (Vec<String>, bool) {
+    let mut buf = [0u8; 4096];
+    let open = loop {
+        match stream.read(&mut buf) {
+            Ok(0) => break false,
+            Ok(n) => partial.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => break e.kind() == io::ErrorKind::WouldBlock,
+        }
+    };
+    let end = match open {
+        true => partial.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1),
+        false => partial.len(),
+    };
+    let lines = String::from_utf8_lossy(&partial[..end]).lines().map(str::to_owned).collect();
+    partial.drain(..end);
+    (lines, open)
+}
+
+impl Reactor {
+    /// polls the handler and arms its next wait, or removes it once done
+    fn advance(&self, entries: &mut HashMap<u64, Entry>, id: u64) {
+        let Some(entry) = entries.get_mut(&id) else { return };
+        let mut waits: Vec<(RawFd, libc::c_int)> = Vec::new();
+        let wait = entry.handler.poll();
+        for wait in match wait {
+            Wait::Any(waits) => waits,
+            wait => vec![wait],
+        } {
+            let (fd, events) = match wait {
+                Wait::Readable(fd) => (fd, libc::EPOLLIN),
+                Wait::Writable(fd) => (fd, libc::EPOLLOUT),
+                Wait::Any(_) | Wait::Done => continue,
+            };
+            match waits.iter_mut().find(|(f, _)| *f == fd) {
+                Some((_, e)) => *e |= events,
+                None => waits.push((fd, events)),
+            }
+        }
+
+        // the handler may have closed what it no longer waits for, its number is dropped
+        // before anyone registers it again, which happens under the same lock
+        entry.registered.retain(|fd| match waits.iter().any(|(f, _)| f == fd) {
+            true => true,
+            false => {
+                unsafe { libc::epoll_ctl(self.epoll, libc::EPOLL_CTL_DEL, *fd, std::ptr::null_mut()) };
+                false
+            },
+        });
+        if waits.is_empty() {
+            entries.remove(&id);
+            return;
+        }
+
+        for (fd, events) in waits {
+            // one shot, so an event is handled once and the handler re-arms what it needs
+            let mut event = libc::epoll_event { events: (events | libc::EPOLLONESHOT) as u32, u64: id };
+            let op = match entry.registered.contains(&fd) {
+                true => libc::EPOLL_CTL_MOD,
+                false => {
+                    entry.registered.push(fd);
+                    libc::EPOLL_CTL_ADD
+                },
+            };
+            if unsafe { libc::epoll_ctl(self.epoll, op, fd, &mut event) } < 0 {
+                log::error!("unable to watch fd {fd}: {}", io::Error::last_os_error());
+                let entry = entries.remove(&id).unwrap();
+                for fd in entry.registered {
+                    unsafe { libc::epoll_ctl(self.epoll, libc::EPOLL_CTL_DEL, fd, std::ptr::null_mut()) };
+                }
+                return;
+            }
+        }
+    }
+}
+
+fn run() {
+    let reactor = reactor();
+    let mut events = vec![libc::epoll_event { events: 0, u64: 0 }; 64];
+    loop {
+        let n = unsafe { libc::epoll_wait(reactor.epoll, events.as_mut_ptr(), events.len() as i32, -1) };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            log::error!("reactor stopped: {e}");
+            return;
+        }
+        let mut entries = reactor.entries.lock().unwrap();
+        POLLING.set(true);
+        for event in &events[..n as usize] {
+            reactor.advance(&mut entries, event.u64);
+        }
+        // and what those handlers registered, which may register more in turn
+        loop {
+            let deferred = std::mem::take(&mut *reactor.deferred.lock().unwrap());
+            if deferred.is_empty() {
+                break;
+            }
+            for (id, entry) in deferred {
+                entries.insert(id, entry);
+                reactor.advance(&mut entries, id);
+            }
+        }
+        POLLING.set(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+    use std::os::fd::{AsRawFd, OwnedFd};
+
+    /// reads from a pipe until eof and reports what it saw
+    struct Collect {
+        fd: OwnedFd,
+        seen: Vec<u8>,
+        out: mpsc::Sender<Vec<u8>>,
+    }
+
+    impl Handler for Collect {
+        fn poll(&mut self) -> Wait {
+            let mut buf = [0u8; 16];
+            match unsafe { libc::read(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) } {
+                0 => {
+                    self.out.send(std::mem::take(&mut self.seen)).unwrap();
+                    Wait::Done
+                },
+                n if n > 0 => {
+                    self.seen.extend_from_slice(&buf[..n as usize]);
+                    self.poll()
+                },
+                _ => Wait::Readable(self.fd.as_raw_fd()),
+            }
+        }
+    }
+
+    #[test]
+    fn handlers_run_until_done() {
+        let (tx, rx) = mpsc::channel();
+        let mut writers = Vec::new();
+        let mut finished = Vec::new();
+        for _ in 0..3 {
+            let (read, write) = crate::transport::pipe().unwrap();
+            unsafe { libc::fcntl(read.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) };
+            finished.push(register(Box::new(Collect { fd: read, seen: Vec::new(), out: tx.clone() })));
+            writers.push(File::from(write));
+        }
+        for (i, mut writer) in writers.into_iter().enumerate() {
+            writer.write_all(format!("pipe {i}").as_bytes()).unwrap();
+        }
+        for f in finished {
+            f.wait();
+        }
+        let mut seen: Vec<String> = rx.try_iter().map(|s| String::from_utf8(s).unwrap()).collect();
+        seen.sort();
+        assert_eq!(seen, ["pipe 0", "pipe 1", "pipe 2"]);
+    }
+
+    /// sends on the lines read from a connection
+    struct Lines {
+        stream: UnixStream,
+        partial: Vec<u8>,
+        out: mpsc::Sender<String>,
+    }
+
+    impl Handler for Lines {
+        fn poll(&mut self) -> Wait {
+            let (lines, open) = read_lines(&mut self.stream, &mut self.partial);
+            for line in lines {
+                self.out.send(line).unwrap();
+            }
+            match open {
+                true => Wait::Readable(self.stream.as_raw_fd()),
+                false => Wait::Done,
+            }
+        }
+    }
+
+    #[test]
+    fn listeners_register_connections() {
+        let path = std::env::temp_dir().join(format!("plumber-reactor-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (tx, rx) = mpsc::channel();
+        // registered from the reactor thread, as the listener is polled there
+        listen(UnixListener::bind(&path).unwrap(), move |stream| {
+            register(Box::new(Lines { stream: stream.unwrap(), partial: Vec::new(), out: tx.clone() }));
+            true
+        }).unwrap();
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.write_all(b"first\nsec").unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), "first");
+        stream.write_all(b"ond\nlast").unwrap();
+        drop(stream);
+        let seen: Vec<String> = (0..2).map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap()).collect();
+        assert_eq!(seen, ["second", "last"]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
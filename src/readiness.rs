@@ -0,0 +1,199 @@
+//! readiness gates for stages that need to warm up
+//!
+//! `--ready <stage>=<check>` makes plumber wait for the stage to pass the check before the
+//! stages upstream of it are spawned, so nothing is written to it before it can take it:
+//! - `log:<regex>` waits for a line of the stage's stderr log to match
+//! - `port:<n>` waits for something to listen on tcp port `n`
+//! - `notify` waits for `READY=1` on the datagram socket in `$NOTIFY_SOCKET`, like systemd's
+//!   `Type=notify`
+//!
+//! gates rely on stages being spawned downstream-first.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::time::Duration;
+
+use regex::bytes::Regex;
+
+use crate::clock::Clock;
+
+#[derive(Debug, Clone)]
+enum Check {
+    Log(Regex),
+    Port(u16),
+    Notify,
+}
+
+/// one `--ready <stage>=<check>` flag
+#[derive(Debug, Clone)]
+pub struct ReadyCheck {
+    pub stage: String,
+    check: Check,
+}
+
+impl std::str::FromStr for ReadyCheck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((stage, check)) = s.split_once('=') else {
+            return Err("expected <stage>=<check>".to_string());
+        };
+        let check = match check.split_once(':') {
+            Some(("log", regex)) => Check::Log(Regex::new(regex).map_err(|e| e.to_string())?),
+            Some(("port", port)) => Check::Port(port.parse().map_err(|_| format!("invalid port '{port}'"))?),
+            None if check == "notify" => Check::Notify,
+            _ => return Err(format!("unknown check '{check}', expected log:<regex>, port:<n> or notify")),
+        };
+        Ok(ReadyCheck { stage: stage.to_owned(), check })
+    }
+}
+
+impl ReadyCheck {
+    pub fn notifies(&self) -> bool {
+        matches!(self.check, Check::Notify)
+    }
+
+    /// sets the check up before the stage is spawned, so nothing it does early is missed.
+    /// `log` is the stage's stderr log, `notify_socket` where a `notify` stage reports to
+    pub fn gate(&self, log: &Path, notify_socket: &Path) -> io::Result<Gate> {
+        Ok(match &self.check {
+            Check::Log(regex) => {
+                let mut file = File::open(log)?;
+                // restarted stages append to the log, only what this run writes counts
+                file.seek(SeekFrom::End(0))?;
+                Gate::Log { file, pending: Vec::new(), regex: regex.clone() }
+            },
+            Check::Port(port) => Gate::Port(*port),
+            Check::Notify => {
+                let _ = fs::remove_file(notify_socket);
+                let socket = UnixDatagram::bind(notify_socket)?;
+                socket.set_nonblocking(true)?;
+                Gate::Notify { socket, path: notify_socket.to_owned() }
+            },
+        })
+    }
+}
+
+pub enum Gate {
+    Log { file: File, pending: Vec<u8>, regex: Regex },
+    Port(u16),
+    Notify { socket: UnixDatagram, path: PathBuf },
+}
+
+pub enum Outcome {
+    Ready,
+    Exited,
+    TimedOut,
+}
+
+impl Gate {
+    /// whether the stage passed the check, without blocking
+    fn passed(&mut self) -> bool {
+        match self {
+            Gate::Log { file, pending, regex } => {
+                let start = pending.len();
+                if file.read_to_end(pending).is_err() {
+                    return false;
+                }
+                // only complete lines, a line being written may match once it's done
+                let end = pending.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+                let passed = start < end && pending[..end].split(|b| *b == b'\n').any(|line| regex.is_match(line));
+                pending.drain(..end);
+                passed
+            },
+            Gate::Port(port) => ["/proc/net/tcp", "/proc/net/tcp6"].iter()
+                .filter_map(|table| fs::read_to_string(table).ok())
+                .any(|table| listening(&table, *port)),
+            Gate::Notify { socket, .. } => {
+                let mut message = [0u8; 4096];
+                while let Ok(n) = socket.recv(&mut message) {
+                    if message[..n].split(|b| *b == b'\n').any(|line| line == b"READY=1") {
+                        return true;
+                    }
+                }
+                false
+            },
+        }
+    }
+
+    /// waits until the stage is ready, has exited or `timeout` passed on `clock`
+    pub fn wait(&mut self, child: &mut Child, timeout: Duration, clock: &dyn Clock) -> Outcome {
+        let deadline = clock.now() + timeout;
+        loop {
+            if self.passed() {
+                return Outcome::Ready;
+            }
+            if matches!(child.try_wait(), Ok(Some(_))) {
+                return Outcome::Exited;
+            }
+            if clock.now() >= deadline {
+                return Outcome::TimedOut;
+            }
+            clock.sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+impl Drop for Gate {
+    fn drop(&mut self) {
+        if let Gate::Notify { path, .. } = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// whether a `/proc/net/tcp` table has a listening socket on `port`
+fn listening(table: &str, port: u16) -> bool {
+    const LISTEN: &str = "0A";
+    table.lines().skip(1).any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        fields.len() > 3
+            && fields[3] == LISTEN
+            && fields[1].rsplit_once(':').and_then(|(_, p)| u16::from_str_radix(p, 16).ok()) == Some(port)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_checks() {
+        let check: ReadyCheck = "jq=log:^listening on \\d+".parse().unwrap();
+        assert_eq!(check.stage, "jq");
+        assert!(matches!(check.check, Check::Log(r) if r.is_match(b"listening on 80")));
+        assert!(matches!("nc=port:8080".parse::<ReadyCheck>().unwrap().check, Check::Port(8080)));
+        assert!("db=notify".parse::<ReadyCheck>().unwrap().notifies());
+        assert!("db=port:http".parse::<ReadyCheck>().is_err());
+        assert!("notify".parse::<ReadyCheck>().is_err());
+    }
+
+    #[test]
+    fn listening_ports() {
+        let table = "  sl  local_address rem_address   st tx_queue rx_queue\n\
+                     0: 00000000:1F90 00000000:0000 0A 00000000:00000000\n\
+                     1: 0100007F:0050 0100007F:C350 01 00000000:00000000\n";
+        assert!(listening(table, 8080));
+        // established, not listening
+        assert!(!listening(table, 80));
+    }
+
+    #[test]
+    fn log_lines_match_once_complete() {
+        let path = std::env::temp_dir().join(format!("plumber-readiness-test-{}", std::process::id()));
+        fs::write(&path, "ready from an earlier run\n").unwrap();
+        let check: ReadyCheck = "x=log:ready".parse().unwrap();
+        let mut gate = check.gate(&path, Path::new("unused")).unwrap();
+        assert!(!gate.passed());
+
+        let mut log = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        io::Write::write_all(&mut log, b"warming up\nrea").unwrap();
+        assert!(!gate.passed());
+        io::Write::write_all(&mut log, b"dy\n").unwrap();
+        assert!(gate.passed());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+//! flight recorder, the last events of every pipeline kept in memory
+//!
+//! every log record of plumber, whatever `RUST_LOG` lets through, also goes into a ring buffer
+//! of the last 1000 events of the pipeline it's about, going by the `<name>: ` records start
+//! with. records about plumber itself are kept in one more. instrumented links add a sample of
+//! the data passing through them at most once a second, as trace records.
+//!
+//! `plumber dump <name>` has the plumber supervising the pipeline write its events, merged
+//! with plumber's own, to `.flight` in its metadata dir with SIGUSR1, and prints them. a
+//! run that fails writes them too, so they're there to look at after the fact.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io;
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::names::ValidatedName;
+use crate::pipeline::Pipeline;
+
+const EVENTS: usize = 1000;
+pub const FLIGHT: &str = ".flight";
+
+struct Event {
+    time: SystemTime,
+    line: String,
+}
+
+/// events by pipeline, `""` for those about plumber itself
+static RECORDED: OnceLock<Mutex<HashMap<String, VecDeque<Event>>>> = OnceLock::new();
+
+struct Recorder {
+    logger: env_logger::Logger,
+}
+
+impl Log for Recorder {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        ours(metadata) || self.logger.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if ours(record.metadata()) {
+            record_event(record);
+        }
+        if self.logger.matches(record) {
+            self.logger.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.logger.flush();
+    }
+}
+
+fn ours(metadata: &Metadata) -> bool {
+    metadata.target().starts_with("plumber")
+}
+
+/// sets up logging as `RUST_LOG` says, recording everything of plumber's regardless
+pub fn init() {
+    let logger = env_logger::Builder::from_default_env().build();
+    if log::set_boxed_logger(Box::new(Recorder { logger })).is_ok() {
+        log::set_max_level(LevelFilter::Trace);
+    }
+}
+
+fn record_event(record: &Record) {
+    let line = format!("{} {}", record.level(), record.args());
+    let pipeline = match line.split_once(' ').and_then(|(_, message)| message.split_once(": ")) {
+        Some((pipeline, _)) if !pipeline.contains(char::is_whitespace) => pipeline.to_owned(),
+        _ => String::new(),
+    };
+    let mut recorded = RECORDED.get_or_init(Default::default).lock().unwrap();
+    let events = recorded.entry(pipeline).or_default();
+    if events.len() == EVENTS {
+        events.pop_front();
+    }
+    events.push_back(Event { time: SystemTime::now(), line });
+}
+
+/// the recorded events of `pipeline` and of plumber itself, oldest first
+fn events(pipeline: &str) -> Vec<String> {
+    let Some(recorded) = RECORDED.get() else { return Vec::new() };
+    let recorded = recorded.lock().unwrap();
+    let mut events: Vec<&Event> = [pipeline, ""].iter()
+        .filter_map(|key| recorded.get(*key))
+        .flatten()
+        .collect();
+    events.sort_by_key(|event| event.time);
+    events.iter()
+        .map(|event| format!("{} {}\n", humantime::format_rfc3339_millis(event.time), event.line))
+        .collect()
+}
+
+/// writes what was recorded of `pipeline` to its `.flight`
+pub fn dump(pipeline: &ValidatedName) -> io::Result<()> {
+    // renamed into place, `plumber dump` reads it as soon as it changes
+    let written = Pipeline::metadata_file(pipeline, ".flight.tmp");
+    fs::write(&written, events(pipeline).concat())?;
+    fs::rename(written, Pipeline::metadata_file(pipeline, FLIGHT))
+}
+
+/// writes what was recorded of every pipeline supervised here, on SIGUSR1
+pub fn dump_all() {
+    let Some(recorded) = RECORDED.get() else { return };
+    let pipelines: Vec<String> = recorded.lock().unwrap().keys().filter(|p| !p.is_empty()).cloned().collect();
+    for pipeline in pipelines {
+        // records about other things that happen to look like `<word>: `
+        let Ok(pipeline) = ValidatedName::new(pipeline) else { continue };
+        if !Pipeline::metadata_file(&pipeline, "").is_dir() {
+            continue;
+        }
+        if let Err(e) = dump(&pipeline) {
+            log::warn!("{pipeline}: unable to write flight recorder events: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_by_pipeline() {
+        for i in 0..EVENTS + 5 {
+            record_event(&Record::builder()
+                .args(format_args!("recorder_test: event {i}"))
+                .level(log::Level::Debug)
+                .target("plumber::pipeline")
+                .build());
+        }
+        record_event(&Record::builder()
+            .args(format_args!("recorder_other: started"))
+            .level(log::Level::Info)
+            .target("plumber::pipeline")
+            .build());
+
+        let events = events("recorder_test");
+        assert!(events.len() >= EVENTS);
+        assert!(events.iter().any(|e| e.ends_with(&format!("DEBUG recorder_test: event {}\n", EVENTS + 4))));
+        assert!(!events.iter().any(|e| e.ends_with("DEBUG recorder_test: event 4\n")));
+        assert!(!events.iter().any(|e| e.contains("recorder_other")));
+    }
+}
@@ -0,0 +1,229 @@
+//! restart bookkeeping for pipelines run with `--restart`
+//!
+//! a pipeline restarted more than `--max-restarts` times within `--restart-window` is
+//! crash-looping. plumber stops restarting it, leaves a `.crash-looped` marker in its
+//! metadata dir and runs the `--on-crash-loop` command. the pipeline won't start again
+//! until `plumber reset <name>` removes the marker.
+//!
+//! restarts are delayed by an exponential backoff, from `--restart-delay` doubling up to
+//! `--max-restart-delay`, which starts over once a run outlasts the restart window. restart
+//! times and the backoff are kept in `.restarts`, so a plumber that is itself restarted
+//! carries on where the last one left off instead of respawning right away. `.restarts` also
+//! counts restarts, which every restart logs, until the pipeline exits without one or is reset.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, SystemTime};
+
+use serde_json::{json, Value};
+
+pub const MARKER: &str = ".crash-looped";
+const STATE: &str = ".restarts";
+/// format of `.restarts`, reported by `plumber version`
+pub const SCHEMA: u32 = 1;
+
+#[derive(Debug, Clone)]
+pub struct CrashLoop {
+    pub max_restarts: u32,
+    pub window: Duration,
+    /// run with `<shell> -c` once a pipeline is found crash-looping
+    pub on_crash_loop: Option<String>,
+}
+
+impl Default for CrashLoop {
+    fn default() -> Self {
+        CrashLoop { max_restarts: 5, window: Duration::from_secs(60), on_crash_loop: None }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff { initial: Duration::from_millis(100), max: Duration::from_secs(30) }
+    }
+}
+
+/// restarts within the crash-loop window and the backoff reached
+#[derive(Debug, Default, PartialEq)]
+pub struct Restarts {
+    recent: VecDeque<SystemTime>,
+    /// restarts since the last run that outlasted the restart window
+    streak: u32,
+    next_start: Option<SystemTime>,
+    /// restarts until the pipeline exits without one or is reset
+    count: u64,
+}
+
+fn millis(t: SystemTime) -> Value {
+    json!(t.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_millis() as u64)
+}
+
+fn from_millis(value: &Value) -> Option<SystemTime> {
+    Some(SystemTime::UNIX_EPOCH + Duration::from_millis(value.as_u64()?))
+}
+
+impl Restarts {
+    /// what the previous plumber running the pipeline left in `.restarts`
+    pub fn load(metadata_dir: &Path) -> Self {
+        let Some(state) = fs::read_to_string(metadata_dir.join(STATE)).ok().and_then(|s| serde_json::from_str::<Value>(&s).ok()) else {
+            return Restarts::default();
+        };
+        Restarts {
+            recent: match state.get("recent") {
+                Some(Value::Array(times)) => times.iter().filter_map(from_millis).collect(),
+                _ => VecDeque::new(),
+            },
+            streak: state["streak"].as_u64().unwrap_or(0) as u32,
+            next_start: state.get("next_start").and_then(from_millis),
+            count: state["count"].as_u64().unwrap_or(0),
+        }
+    }
+
+    pub fn save(&self, metadata_dir: &Path) -> io::Result<()> {
+        let recent: Vec<Value> = self.recent.iter().copied().map(millis).collect();
+        let mut state = json!({ "recent": recent, "streak": self.streak, "count": self.count });
+        if let Some(next_start) = self.next_start {
+            state["next_start"] = millis(next_start);
+        }
+        fs::write(metadata_dir.join(STATE), state.to_string())
+    }
+
+    /// forgets the history, once the pipeline was stopped or finished for good
+    pub fn clear(metadata_dir: &Path) {
+        let _ = fs::remove_file(metadata_dir.join(STATE));
+    }
+
+    /// how long is left of a backoff the previous plumber was waiting out
+    pub fn pending(&self, now: SystemTime) -> Option<Duration> {
+        self.next_start?.duration_since(now).ok()
+    }
+
+    /// records a restart after a run of `ran`, returning the delay before it
+    pub fn delay(&mut self, now: SystemTime, ran: Duration, limit: &CrashLoop, backoff: &Backoff) -> Duration {
+        self.streak = match ran > limit.window {
+            true => 0,
+            false => self.streak.saturating_add(1),
+        };
+        let delay = match self.streak {
+            0 => Duration::ZERO,
+            n => backoff.initial.saturating_mul(1 << (n - 1).min(31)).min(backoff.max),
+        };
+        self.next_start = Some(now + delay);
+        delay
+    }
+
+    /// restarts so far
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// records a restart at `now`, false if it's one too many for the window
+    pub fn record(&mut self, now: SystemTime, limit: &CrashLoop) -> bool {
+        self.count += 1;
+        while self.recent.front().is_some_and(|t| now.duration_since(*t).unwrap_or_default() > limit.window) {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(now);
+        self.recent.len() <= limit.max_restarts as usize
+    }
+}
+
+/// marks the pipeline crash-looped and fires the notification
+pub fn trip(name: &str, metadata_dir: &Path, limit: &CrashLoop, shell: &Path) {
+    let reason = format!(
+        "more than {} restarts within {}",
+        limit.max_restarts,
+        humantime::format_duration(limit.window),
+    );
+    log::error!("{name}: crash-looped with {reason}, not restarting until `plumber reset {name}`");
+    let marker = format!("{} {reason}\n", humantime::format_rfc3339_seconds(SystemTime::now()));
+    if let Err(e) = fs::write(metadata_dir.join(MARKER), marker) {
+        log::error!("{name}: unable to mark pipeline crash-looped: {e}");
+    }
+    crate::audit::record("crash-loop", name, &reason);
+
+    let Some(command) = &limit.on_crash_loop else { return };
+    let notified = Command::new(shell)
+        .arg("-c")
+        .arg(command)
+        .env("PLUMBER_PIPELINE", name)
+        .env("PLUMBER_EVENT", "crash-loop")
+        .env("PLUMBER_RESTARTS", limit.max_restarts.to_string())
+        .status();
+    match notified {
+        Ok(status) if status.success() => {},
+        Ok(status) => log::warn!("{name}: crash-loop notification {status}"),
+        Err(e) => log::warn!("{name}: unable to run crash-loop notification: {e}"),
+    }
+}
+
+/// why a pipeline is crash-looped, if it is
+pub fn crash_looped(metadata_dir: &Path) -> Option<String> {
+    fs::read_to_string(metadata_dir.join(MARKER)).ok().map(|m| m.trim().to_owned())
+}
+
+/// clears the crash-looped mark and the restart history, so the pipeline starts right away.
+/// false if it wasn't crash-looped
+pub fn reset(metadata_dir: &Path) -> io::Result<bool> {
+    Restarts::clear(metadata_dir);
+    match fs::remove_file(metadata_dir.join(MARKER)) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trips_on_too_many_restarts_in_window() {
+        let limit = CrashLoop { max_restarts: 2, window: Duration::from_secs(10), on_crash_loop: None };
+        let mut restarts = Restarts::default();
+        let at = |s| SystemTime::UNIX_EPOCH + Duration::from_secs(s);
+        assert!(restarts.record(at(0), &limit));
+        assert!(restarts.record(at(5), &limit));
+        // the first restart has left the window
+        assert!(restarts.record(at(11), &limit));
+        assert!(!restarts.record(at(12), &limit));
+    }
+
+    #[test]
+    fn backoff_doubles_until_a_stable_run() {
+        let limit = CrashLoop::default();
+        let backoff = Backoff { initial: Duration::from_secs(1), max: Duration::from_secs(3) };
+        let mut restarts = Restarts::default();
+        let now = SystemTime::UNIX_EPOCH;
+        let quick = Duration::from_secs(1);
+        let delays: Vec<u64> = (0..4).map(|_| restarts.delay(now, quick, &limit, &backoff).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 3, 3]);
+        assert_eq!(restarts.delay(now, Duration::from_secs(61), &limit, &backoff), Duration::ZERO);
+        assert_eq!(restarts.delay(now, quick, &limit, &backoff), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn state_survives_a_restarted_plumber() {
+        let dir = std::env::temp_dir().join(format!("plumber-restarts-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let mut restarts = Restarts::default();
+        restarts.record(now, &CrashLoop::default());
+        restarts.delay(now, Duration::ZERO, &CrashLoop::default(), &Backoff::default());
+        restarts.save(&dir).unwrap();
+
+        let loaded = Restarts::load(&dir);
+        assert_eq!(loaded, restarts);
+        assert_eq!(loaded.count(), 1);
+        assert_eq!(loaded.pending(now), Some(Duration::from_millis(100)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
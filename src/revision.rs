@@ -0,0 +1,111 @@
+//! plumber files kept in a git checkout
+//!
+//! a pipeline whose plumber file is in a git work tree records the commit the checkout was at
+//! as `revision` in the summary of every run. `plumber run <dir> --watch --git-pull 1m` pulls
+//! the checkout every minute, fast-forward only, and the watch reloads what the pull changed.
+//! `plumber rollback <dir> --to <commit>` pins the checkout to a commit by detaching its HEAD,
+//! which pulls leave alone, and `--to <branch>` follows the branch again.
+
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn git(dir: &Path, args: &[&str]) -> Result<Output, String> {
+    Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .map_err(|e| format!("unable to run git: {e}"))
+}
+
+/// what a failed git command said
+fn failure(output: &Output) -> String {
+    let said = String::from_utf8_lossy(&output.stderr);
+    match said.trim() {
+        "" => format!("git {}", output.status),
+        said => said.lines().last().unwrap_or(said).to_owned(),
+    }
+}
+
+/// the commit checked out in `dir`, none outside a git work tree
+pub fn head(dir: &Path) -> Option<String> {
+    let output = git(dir, &["rev-parse", "--verify", "--quiet", "HEAD"]).ok()?;
+    output.status.success().then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// whether the checkout in `dir` is pinned to a commit rather than following a branch
+pub fn pinned(dir: &Path) -> bool {
+    git(dir, &["symbolic-ref", "--quiet", "HEAD"]).is_ok_and(|output| !output.status.success())
+}
+
+/// pulls the branch `dir` follows, returning the commit it moved to, none if it didn't move
+/// or is pinned
+pub fn pull(dir: &Path) -> Result<Option<String>, String> {
+    if pinned(dir) {
+        return Ok(None);
+    }
+    let before = head(dir);
+    let output = git(dir, &["pull", "--ff-only", "--quiet"])?;
+    if !output.status.success() {
+        return Err(failure(&output));
+    }
+    Ok(head(dir).filter(|after| before.as_ref() != Some(after)))
+}
+
+/// checks out branch `to` to follow it, or pins the checkout to commit `to`, returning the
+/// commit it's at then
+pub fn rollback(dir: &Path, to: &str) -> Result<String, String> {
+    let branch = git(dir, &["show-ref", "--verify", "--quiet", &format!("refs/heads/{to}")])?.status.success();
+    let output = match branch {
+        true => git(dir, &["checkout", "--quiet", to])?,
+        false => git(dir, &["checkout", "--quiet", "--detach", to])?,
+    };
+    if !output.status.success() {
+        return Err(failure(&output));
+    }
+    head(dir).ok_or_else(|| format!("{} is not a git checkout", dir.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn pins_and_follows_again() {
+        let dir = std::env::temp_dir().join(format!("plumber-revision-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        assert_eq!(head(&dir), None);
+        let commit = |message: &str| {
+            fs::write(dir.join("etl.plumb"), message).unwrap();
+            let ok = Command::new("git")
+                .arg("-C").arg(&dir)
+                .args(["-c", "user.name=plumber", "-c", "user.email=plumber@localhost", "commit", "--quiet", "-a", "-m", message])
+                .status()
+                .unwrap();
+            assert!(ok.success());
+            head(&dir).unwrap()
+        };
+        if !git(&dir, &["init", "--quiet", "-b", "main"]).is_ok_and(|output| output.status.success()) {
+            // no git here
+            return;
+        }
+        fs::write(dir.join("etl.plumb"), "").unwrap();
+        assert!(git(&dir, &["add", "etl.plumb"]).unwrap().status.success());
+        let first = commit("cat");
+        let second = commit("cat | wc -l");
+        assert!(!pinned(&dir));
+
+        assert_eq!(rollback(&dir, &first).unwrap(), first);
+        assert!(pinned(&dir));
+        assert_eq!(fs::read_to_string(dir.join("etl.plumb")).unwrap(), "cat");
+        // a pinned checkout isn't pulled, though there's no remote to pull from either
+        assert_eq!(pull(&dir), Ok(None));
+
+        assert_eq!(rollback(&dir, "main").unwrap(), second);
+        assert!(!pinned(&dir));
+        assert!(rollback(&dir, "nowhere").is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,364 @@
+//! observe-only seccomp mode for stages
+//!
+//! with `--seccomp-observe network` every stage gets a seccomp filter that routes the
+//! watched syscalls to plumber via user notification (`SECCOMP_FILTER_FLAG_NEW_LISTENER`).
+//! plumber logs the call and lets it continue unchanged, so nothing is blocked, but the
+//! log shows which stages do more than expected, e.g. a "pure filter" that opens sockets.
+//! `--seccomp-observe grep=network,exec` only watches the stage logged as `grep`.
+//!
+//! the filter sets `no_new_privs`, so setuid programs don't gain privileges while observed.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+use crate::reactor::{self, Handler, Wait};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Category {
+    Network,
+    Exec,
+    Ptrace,
+}
+
+impl Category {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "network" => Ok(Category::Network),
+            "exec" => Ok(Category::Exec),
+            "ptrace" => Ok(Category::Ptrace),
+            other => Err(format!("unknown syscall category '{other}', expected network, exec or ptrace")),
+        }
+    }
+
+    fn syscalls(&self) -> &'static [(libc::c_long, &'static str)] {
+        match self {
+            Category::Network => &[
+                (libc::SYS_socket, "socket"),
+                (libc::SYS_connect, "connect"),
+                (libc::SYS_bind, "bind"),
+                (libc::SYS_listen, "listen"),
+                (libc::SYS_accept, "accept"),
+                (libc::SYS_accept4, "accept4"),
+            ],
+            Category::Exec => &[
+                (libc::SYS_execve, "execve"),
+                (libc::SYS_execveat, "execveat"),
+            ],
+            Category::Ptrace => &[
+                (libc::SYS_ptrace, "ptrace"),
+                (libc::SYS_process_vm_readv, "process_vm_readv"),
+                (libc::SYS_process_vm_writev, "process_vm_writev"),
+            ],
+        }
+    }
+}
+
+/// one `--seccomp-observe [<stage>=]<category>,...` flag
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObserveRule {
+    stage: Option<String>,
+    categories: Vec<Category>,
+}
+
+impl std::str::FromStr for ObserveRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (stage, categories) = match s.split_once('=') {
+            Some((stage, categories)) => (Some(stage.to_owned()), categories),
+            None => (None, s),
+        };
+        Ok(ObserveRule {
+            stage,
+            categories: categories.split(',').map(Category::parse).collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+/// watched syscalls for the stage with this log name
+pub fn watched(rules: &[ObserveRule], stage: &str) -> Vec<(libc::c_long, &'static str)> {
+    let mut syscalls = Vec::new();
+    for rule in rules.iter().filter(|r| r.stage.as_deref().is_none_or(|s| s == stage)) {
+        for category in &rule.categories {
+            for syscall in category.syscalls() {
+                if !syscalls.contains(syscall) {
+                    syscalls.push(*syscall);
+                }
+            }
+        }
+    }
+    syscalls
+}
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+const SECCOMP_FILTER_FLAG_NEW_LISTENER: libc::c_ulong = 1 << 3;
+const SECCOMP_RET_USER_NOTIF: u32 = 0x7fc0_0000;
+const SECCOMP_USER_NOTIF_FLAG_CONTINUE: u32 = 1;
+const SECCOMP_IOCTL_NOTIF_RECV: libc::c_ulong = 0xc050_2100;
+const SECCOMP_IOCTL_NOTIF_SEND: libc::c_ulong = 0xc018_2101;
+
+#[repr(C)]
+#[derive(Default)]
+struct SeccompData {
+    nr: i32,
+    arch: u32,
+    instruction_pointer: u64,
+    args: [u64; 6],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct SeccompNotif {
+    id: u64,
+    pid: u32,
+    flags: u32,
+    data: SeccompData,
+}
+
+#[repr(C)]
+struct SeccompNotifResp {
+    id: u64,
+    val: i64,
+    error: i32,
+    flags: u32,
+}
+
+/// bpf program sending `syscalls` to the listener and allowing everything else
+fn filter(syscalls: &[(libc::c_long, &str)]) -> Vec<libc::sock_filter> {
+    let stmt = |code: u32, k: u32| libc::sock_filter { code: code as u16, jt: 0, jf: 0, k };
+    let jump = |k: u32, jt: u8, jf: u8| libc::sock_filter {
+        code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16,
+        jt,
+        jf,
+        k,
+    };
+    let load = libc::BPF_LD | libc::BPF_W | libc::BPF_ABS;
+    let ret = libc::BPF_RET | libc::BPF_K;
+
+    // offsets of `nr` and `arch` in seccomp_data
+    let mut program = vec![
+        stmt(load, 4),
+        jump(AUDIT_ARCH, 1, 0),
+        stmt(ret, libc::SECCOMP_RET_ALLOW),
+        stmt(load, 0),
+    ];
+    for (i, (nr, _)) in syscalls.iter().enumerate() {
+        // on a match skip the remaining checks and the allow
+        program.push(jump(*nr as u32, (syscalls.len() - i) as u8, 0));
+    }
+    program.push(stmt(ret, libc::SECCOMP_RET_ALLOW));
+    program.push(stmt(ret, SECCOMP_RET_USER_NOTIF));
+    program
+}
+
+/// installs the filter in the child before exec and has the reactor log the watched
+/// syscalls for the stage. drop the returned descriptor once the stage was spawned, so the
+/// observer notices if it never sends its listener
+pub fn observe(command: &mut Command, pipeline: &str, stage: &str, syscalls: Vec<(libc::c_long, &'static str)>) -> io::Result<OwnedFd> {
+    let mut fds = [0; 2];
+    if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC, 0, fds.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (ours, theirs) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+    let program = filter(&syscalls);
+    let child_socket = theirs.as_raw_fd();
+    unsafe {
+        // only async-signal-safe calls in here, the program is built before the fork
+        command.pre_exec(move || {
+            let fprog = libc::sock_fprog {
+                len: program.len() as u16,
+                filter: program.as_ptr() as *mut libc::sock_filter,
+            };
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let listener = libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                SECCOMP_FILTER_FLAG_NEW_LISTENER,
+                &fprog as *const libc::sock_fprog,
+            );
+            if listener < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            send_fd(child_socket, listener as RawFd)?;
+            libc::close(listener as RawFd);
+            Ok(())
+        });
+    }
+
+    // nobody waits for the observer, it logs its summary once the stage is gone
+    let _ = reactor::register(Box::new(Observer {
+        pipeline: pipeline.to_owned(),
+        stage: stage.to_owned(),
+        socket: ours,
+        listener: None,
+        syscalls,
+        counts: BTreeMap::new(),
+        started: false,
+    }));
+    Ok(theirs)
+}
+
+/// waits for the stage's listener on `socket`, then answers its notifications
+struct Observer {
+    pipeline: String,
+    stage: String,
+    socket: OwnedFd,
+    listener: Option<OwnedFd>,
+    syscalls: Vec<(libc::c_long, &'static str)>,
+    counts: BTreeMap<&'static str, u64>,
+    started: bool,
+}
+
+impl Handler for Observer {
+    fn poll(&mut self) -> Wait {
+        let Some(listener) = &self.listener else {
+            match recv_fd(self.socket.as_raw_fd(), libc::MSG_DONTWAIT) {
+                Ok(Some(listener)) => self.listener = Some(listener),
+                Ok(None) => return Wait::Done,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Wait::Readable(self.socket.as_raw_fd()),
+                Err(e) => {
+                    log::error!("{}: unable to observe syscalls of {}: {e}", self.pipeline, self.stage);
+                    return Wait::Done;
+                },
+            }
+            return self.poll();
+        };
+
+        let listener = listener.as_raw_fd();
+        loop {
+            let mut poll = libc::pollfd { fd: listener, events: libc::POLLIN, revents: 0 };
+            if unsafe { libc::poll(&mut poll, 1, 0) } < 0 {
+                if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                break;
+            }
+            if poll.revents & libc::POLLIN == 0 {
+                if poll.revents == 0 {
+                    return Wait::Readable(listener);
+                }
+                // every process using the filter has exited
+                break;
+            }
+            self.respond(listener);
+        }
+
+        if !self.counts.is_empty() {
+            let summary: Vec<String> = self.counts.iter().map(|(name, n)| format!("{name} x{n}")).collect();
+            log::info!("{}: observed syscalls of {}: {}", self.pipeline, self.stage, summary.join(", "));
+        }
+        Wait::Done
+    }
+}
+
+impl Observer {
+    fn respond(&mut self, listener: RawFd) {
+        let mut notif = SeccompNotif::default();
+        if unsafe { libc::ioctl(listener, SECCOMP_IOCTL_NOTIF_RECV as _, &mut notif) } < 0 {
+            // the process may have died before we picked up the call
+            return;
+        }
+        let name = self.syscalls.iter()
+            .find(|(nr, _)| *nr == notif.data.nr as libc::c_long)
+            .map(|(_, name)| *name)
+            .unwrap_or("unknown");
+
+        // the exec starting the stage itself is expected
+        let initial_exec = !self.started && name == "execve";
+        self.started = true;
+        if !initial_exec {
+            let count = self.counts.entry(name).or_default();
+            if *count == 0 {
+                log::warn!("{}: {} (pid {}) made unexpected syscall {name}", self.pipeline, self.stage, notif.pid);
+            }
+            *count += 1;
+        }
+
+        let resp = SeccompNotifResp { id: notif.id, val: 0, error: 0, flags: SECCOMP_USER_NOTIF_FLAG_CONTINUE };
+        unsafe { libc::ioctl(listener, SECCOMP_IOCTL_NOTIF_SEND as _, &resp) };
+    }
+}
+
+/// async-signal-safe, used between fork and exec
+fn send_fd(socket: RawFd, fd: RawFd) -> io::Result<()> {
+    #[repr(C)]
+    struct Control {
+        header: libc::cmsghdr,
+        fd: RawFd,
+    }
+    let mut control: Control = unsafe { std::mem::zeroed() };
+    control.header.cmsg_len = unsafe { libc::CMSG_LEN(std::mem::size_of::<RawFd>() as u32) } as _;
+    control.header.cmsg_level = libc::SOL_SOCKET;
+    control.header.cmsg_type = libc::SCM_RIGHTS;
+    control.fd = fd;
+
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec { iov_base: byte.as_mut_ptr().cast(), iov_len: 1 };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = (&mut control as *mut Control).cast();
+    msg.msg_controllen = std::mem::size_of::<Control>() as _;
+    match unsafe { libc::sendmsg(socket, &msg, 0) } {
+        n if n < 0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// `None` when the other end was closed without sending a descriptor
+fn recv_fd(socket: RawFd, flags: libc::c_int) -> io::Result<Option<OwnedFd>> {
+    let mut control = [0u64; 4];
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec { iov_base: byte.as_mut_ptr().cast(), iov_len: 1 };
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = std::mem::size_of_val(&control) as _;
+
+    let n = unsafe { libc::recvmsg(socket, &mut msg, libc::MSG_CMSG_CLOEXEC | flags) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    unsafe {
+        let header = libc::CMSG_FIRSTHDR(&msg);
+        if n == 0 || header.is_null() || (*header).cmsg_type != libc::SCM_RIGHTS {
+            return Ok(None);
+        }
+        let fd = std::ptr::read_unaligned(libc::CMSG_DATA(header) as *const RawFd);
+        Ok(Some(OwnedFd::from_raw_fd(fd)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules_per_stage() {
+        let rules: Vec<ObserveRule> = vec!["network".parse().unwrap(), "grep=exec,network".parse().unwrap()];
+        let names = |stage| watched(&rules, stage).iter().map(|(_, n)| *n).collect::<Vec<_>>();
+        assert_eq!(names("sed"), ["socket", "connect", "bind", "listen", "accept", "accept4"]);
+        assert_eq!(names("grep").len(), 8);
+        assert!("grep=disk".parse::<ObserveRule>().is_err());
+    }
+
+    #[test]
+    fn filter_jumps_to_notify() {
+        let program = filter(&[(1, "a"), (2, "b")]);
+        // load arch, check, allow, load nr, two checks, allow, notify
+        assert_eq!(program.len(), 8);
+        assert_eq!((program[4].jt, program[5].jt), (2, 1));
+        assert_eq!(program[7].k, SECCOMP_RET_USER_NOTIF);
+    }
+}
@@ -0,0 +1,131 @@
+//! interpreter of inline scripts, `--shell [<stage>=]<interpreter>`
+//!
+//! `script:<source>` stages, `--on-eof` hooks and the `--on-crash-loop` command are run as
+//! `<interpreter> -c <source>`, which suits `bash`, `dash` and `python3` alike. a script stage's
+//! arguments follow `script` as its `$0`, so they're `$1` and on, or `sys.argv[2:]` in python.
+//! rules naming a stage apply to its eof hook, those of all script stages go by `script=`.
+//! `$PLUMBER_SHELL` sets the interpreter of every pipeline, below its own rules, and `/bin/sh`
+//! is used when nothing does.
+//!
+//! interpreters are resolved to absolute paths once, when the rules are read, so a changed
+//! `PATH` doesn't change what restarts and hooks run. the resolved rules are recorded in
+//! `.shells` in the pipeline's metadata dir.
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+const DEFAULT: &str = "/bin/sh";
+const SHELLS: &str = ".shells";
+
+/// one `--shell [STAGE=]INTERPRETER` rule, resolved
+#[derive(Debug, Clone, PartialEq)]
+pub struct Shell {
+    /// `None` applies to every stage and to the crash-loop command
+    pub stage: Option<String>,
+    pub interpreter: PathBuf,
+}
+
+impl std::str::FromStr for Shell {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (stage, interpreter) = match s.split_once('=') {
+            Some((stage, interpreter)) => (Some(stage.to_owned()), interpreter),
+            None => (None, s),
+        };
+        if interpreter.is_empty() {
+            return Err("empty interpreter".to_string());
+        }
+        let interpreter = resolve(interpreter).ok_or_else(|| format!("no interpreter '{interpreter}' found"))?;
+        Ok(Shell { stage, interpreter })
+    }
+}
+
+impl std::fmt::Display for Shell {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.stage {
+            Some(stage) => write!(f, "{stage}={}", self.interpreter.display()),
+            None => write!(f, "{}", self.interpreter.display()),
+        }
+    }
+}
+
+/// source of a `script:<source>` stage
+pub fn script(name: &str) -> Option<&str> {
+    name.strip_prefix("script:")
+}
+
+/// the executable `name` is run as, searching `PATH` if it has no `/`
+pub fn which(name: &str) -> Option<PathBuf> {
+    if name.contains('/') {
+        return Some(PathBuf::from(name));
+    }
+    std::env::var_os("PATH").and_then(|path| std::env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|candidate| fs::metadata(candidate).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)))
+}
+
+fn resolve(interpreter: &str) -> Option<PathBuf> {
+    let path = which(interpreter)?;
+    let path = match path.is_absolute() {
+        true => path,
+        false => std::env::current_dir().ok()?.join(path),
+    };
+    fs::metadata(&path).is_ok_and(|m| m.is_file()).then_some(path)
+}
+
+/// the `$PLUMBER_SHELL` rule, ahead of a pipeline's own
+pub fn global() -> Option<Shell> {
+    let interpreter = std::env::var("PLUMBER_SHELL").ok().filter(|s| !s.is_empty())?;
+    match resolve(&interpreter) {
+        Some(interpreter) => Some(Shell { stage: None, interpreter }),
+        None => {
+            log::warn!("ignoring $PLUMBER_SHELL, no interpreter '{interpreter}' found");
+            None
+        },
+    }
+}
+
+/// interpreter of `stage`, or of the crash-loop command for `None`; the last rule naming the
+/// stage wins, then the last for all stages
+pub fn shell<'a>(rules: &'a [Shell], stage: Option<&str>) -> &'a Path {
+    rules.iter()
+        .rev()
+        .find(|s| stage.is_some() && s.stage.as_deref() == stage)
+        .or_else(|| rules.iter().rev().find(|s| s.stage.is_none()))
+        .map_or(Path::new(DEFAULT), |s| &s.interpreter)
+}
+
+/// records the rules a pipeline runs with, the interpreter used by default first
+pub fn save(metadata_dir: &Path, rules: &[Shell]) -> io::Result<()> {
+    let default = shell(rules, None).display().to_string();
+    let rules: String = std::iter::once(default)
+        .chain(rules.iter().filter(|s| s.stage.is_some()).map(Shell::to_string))
+        .map(|rule| format!("{rule}\n"))
+        .collect();
+    fs::write(metadata_dir.join(SHELLS), rules)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_interpreters() {
+        let rules: Vec<Shell> = ["sh", "script=/bin/sh", "jq=sh"].iter().map(|s| s.parse().unwrap()).collect();
+        assert!(rules.iter().all(|s| s.interpreter.is_absolute() && s.interpreter.ends_with("sh")));
+        assert_eq!(rules[1].to_string(), "script=/bin/sh");
+        assert!("nope-not-an-interpreter".parse::<Shell>().is_err());
+        assert!("script=".parse::<Shell>().is_err());
+
+        let bash = Shell { stage: Some("script".to_string()), interpreter: PathBuf::from("/usr/bin/bash") };
+        let dash = Shell { stage: None, interpreter: PathBuf::from("/usr/bin/dash") };
+        assert_eq!(shell(&[bash.clone(), dash.clone()], Some("script")), Path::new("/usr/bin/bash"));
+        assert_eq!(shell(&[bash.clone(), dash], Some("wc")), Path::new("/usr/bin/dash"));
+        assert_eq!(shell(&[bash], None), Path::new("/bin/sh"));
+        assert_eq!(script("script:echo 'a|b'"), Some("echo 'a|b'"));
+        assert_eq!(script("scripts"), None);
+    }
+}
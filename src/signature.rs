@@ -0,0 +1,42 @@
+//! ssh signature verification through `ssh-keygen -Y`
+//!
+//! signatures are made with `ssh-keygen -Y sign -n <namespace> -f <key> <file>` and are
+//! trusted when made by a key in an ssh `allowed_signers` file. the namespace keeps a
+//! signature made for one purpose from being accepted for another.
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// returns the principal that signed `content` in `namespace`
+pub fn verify(namespace: &str, content: &[u8], signature: &Path, allowed_signers: &Path) -> Result<String, String> {
+    let output = Command::new("ssh-keygen")
+        .args(["-Y", "find-principals", "-s"])
+        .arg(signature)
+        .arg("-f")
+        .arg(allowed_signers)
+        .output()
+        .map_err(|e| format!("unable to run ssh-keygen: {e}"))?;
+    let principals = String::from_utf8_lossy(&output.stdout);
+    let Some(principal) = principals.lines().next().filter(|_| output.status.success()) else {
+        return Err(format!("no allowed signer made {}", signature.display()));
+    };
+
+    let mut child = Command::new("ssh-keygen")
+        .args(["-Y", "verify", "-n", namespace, "-I", principal, "-s"])
+        .arg(signature)
+        .arg("-f")
+        .arg(allowed_signers)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("unable to run ssh-keygen: {e}"))?;
+    // a write error shows up as a failed verification below
+    let _ = child.stdin.take().unwrap().write_all(content);
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+    match output.status.success() {
+        true => Ok(principal.to_owned()),
+        false => Err(String::from_utf8_lossy(&output.stderr).trim().to_owned()),
+    }
+}
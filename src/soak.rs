@@ -0,0 +1,262 @@
+//! `plumber soak <file> --duration 1h --input 'generate:ndjson rate=1000'`
+//!
+//! runs a pipeline definition against synthetic input for a while before it goes to
+//! production. the `--input` stage is put in front of the definition, links are instrumented
+//! and every `--interval` the cpu and resident memory of each stage and the throughput of each
+//! link are sampled, printed and appended to `soak.ndjson` in the metadata dir of the soak run,
+//! named `<name>-soak` so it can't collide with the pipeline running for real. a name too long
+//! to take the suffix is cut short first.
+//!
+//! once `--duration` is up the pipeline is stopped and the trends are judged: the run is
+//! unstable if the pipeline ended early or failed, if a stage's memory grew by more than
+//! `--max-rss-growth` percent between the first and last quarter of the run, or if a link
+//! moved less than half as much in the last quarter as in the first.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use crate::names;
+use crate::overhead;
+use crate::transport;
+
+/// one stage at one point in time
+#[derive(Debug, Clone, PartialEq)]
+pub struct StageSample {
+    pub stage: String,
+    /// `None` while the stage isn't running
+    pub cpu: Option<Duration>,
+    pub rss_kb: Option<u64>,
+}
+
+/// one link at one point in time
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkSample {
+    pub from: String,
+    pub to: String,
+    /// moved since the run started
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    /// since the soak started
+    pub at: Duration,
+    pub stages: Vec<StageSample>,
+    pub links: Vec<LinkSample>,
+}
+
+/// what the soak of pipeline `name` runs as, `<name>-soak` within the longest name allowed
+pub fn name(name: &str) -> String {
+    const SUFFIX: &str = "-soak";
+    let mut base = name.to_owned();
+    while base.len() > names::MAX_LEN - SUFFIX.len() {
+        base.pop();
+    }
+    format!("{base}{SUFFIX}")
+}
+
+/// samples the stages, given by name and pid, and the links of the pipeline in `metadata_dir`
+pub fn sample(at: Duration, stages: &[(String, Option<u32>)], metadata_dir: &Path) -> Sample {
+    let stages = stages.iter()
+        .map(|(stage, pid)| {
+            let stat = pid.and_then(|pid| fs::read_to_string(format!("/proc/{pid}/stat")).ok());
+            let status = pid.and_then(|pid| fs::read_to_string(format!("/proc/{pid}/status")).ok());
+            StageSample {
+                stage: stage.clone(),
+                cpu: stat.as_deref().and_then(overhead::cpu_time),
+                rss_kb: status.as_deref().and_then(|status| overhead::status_field(status, "VmRSS")),
+            }
+        })
+        .collect();
+    Sample { at, stages, links: links(metadata_dir) }
+}
+
+/// bytes moved through every instrumented link of the pipeline in `metadata_dir`
+pub fn links(metadata_dir: &Path) -> Vec<LinkSample> {
+    match fs::read_to_string(metadata_dir.join(".links")).ok().and_then(|raw| serde_json::from_str::<Value>(raw.trim()).ok()) {
+        Some(Value::Array(links)) => links.iter()
+            .filter_map(|link| {
+                let text = |key| match link.get(key) {
+                    Some(Value::String(s)) => Some(s.clone()),
+                    _ => None,
+                };
+                let bytes = link.get("bytes")?.as_u64()?;
+                Some(LinkSample { from: text("from")?, to: text("to")?, bytes })
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+impl Sample {
+    fn json(&self) -> Value {
+        let stages: Vec<Value> = self.stages.iter()
+            .map(|s| {
+                let mut stage = json!({ "stage": s.stage });
+                if let Some(cpu) = s.cpu {
+                    stage["cpu_ms"] = json!(cpu.as_millis() as u64);
+                }
+                if let Some(rss) = s.rss_kb {
+                    stage["rss_kb"] = json!(rss);
+                }
+                stage
+            })
+            .collect();
+        let links: Vec<Value> = self.links.iter()
+            .map(|l| json!({ "from": l.from, "to": l.to, "bytes": l.bytes }))
+            .collect();
+        json!({ "at_ms": self.at.as_millis() as u64, "stages": stages, "links": links })
+    }
+}
+
+/// the samples of a soak run so far
+pub struct Soak {
+    samples: Vec<Sample>,
+    /// percent a stage's memory may grow between the first and last quarter of the run
+    max_rss_growth: f64,
+}
+
+impl Soak {
+    pub fn new(max_rss_growth: f64) -> Self {
+        Soak { samples: Vec::new(), max_rss_growth }
+    }
+
+    /// keeps `sample`, appending it to `soak.ndjson`, and returns it as a line for people
+    pub fn record(&mut self, sample: Sample, metadata_dir: &Path) -> io::Result<String> {
+        let mut file = OpenOptions::new().create(true).append(true).open(metadata_dir.join("soak.ndjson"))?;
+        writeln!(file, "{}", sample.json())?;
+        let line = self.describe(&sample);
+        self.samples.push(sample);
+        Ok(line)
+    }
+
+    fn describe(&self, sample: &Sample) -> String {
+        let previous = self.samples.last();
+        let elapsed = sample.at.saturating_sub(previous.map_or(Duration::ZERO, |p| p.at)).as_secs_f64().max(0.001);
+        let mut parts: Vec<String> = sample.stages.iter()
+            .map(|stage| {
+                let before = previous.and_then(|p| p.stages.iter().find(|s| s.stage == stage.stage)).and_then(|s| s.cpu);
+                match (stage.cpu, stage.rss_kb) {
+                    (Some(cpu), Some(rss)) => format!(
+                        "{} {:.1}% cpu {}",
+                        stage.stage,
+                        cpu.saturating_sub(before.unwrap_or_default()).as_secs_f64() * 100.0 / elapsed,
+                        transport::format_bytes(rss as f64 * 1024.0),
+                    ),
+                    _ => format!("{} not running", stage.stage),
+                }
+            })
+            .collect();
+        parts.extend(sample.links.iter().map(|link| {
+            let before = previous.and_then(|p| p.links.iter().find(|l| l.from == link.from && l.to == link.to)).map_or(0, |l| l.bytes);
+            format!("{} -> {} {}/s", link.from, link.to, transport::format_bytes(link.bytes.saturating_sub(before) as f64 / elapsed))
+        }));
+        format!("{}s: {}", sample.at.as_secs(), parts.join(", "))
+    }
+
+    /// the first and last quarter of the samples, once there are enough to tell a trend
+    fn quarters(&self) -> Option<(&[Sample], &[Sample])> {
+        let quarter = self.samples.len() / 4;
+        (quarter > 0).then(|| (&self.samples[..quarter], &self.samples[self.samples.len() - quarter..]))
+    }
+
+    /// what looks unstable about the run, nothing if it looks stable
+    pub fn problems(&self) -> Vec<String> {
+        let Some((first, last)) = self.quarters() else { return Vec::new() };
+        let mut problems = Vec::new();
+        let average = |values: Vec<f64>| match values.is_empty() {
+            true => None,
+            false => Some(values.iter().sum::<f64>() / values.len() as f64),
+        };
+        let stages = self.samples[0].stages.iter().map(|s| &s.stage);
+        for stage in stages {
+            let rss = |samples: &[Sample]| average(samples.iter()
+                .flat_map(|sample| sample.stages.iter().filter(|s| &s.stage == stage).filter_map(|s| s.rss_kb))
+                .map(|rss| rss as f64)
+                .collect());
+            if let (Some(before), Some(after)) = (rss(first), rss(last)) {
+                let growth = (after - before) * 100.0 / before.max(1.0);
+                if growth > self.max_rss_growth {
+                    problems.push(format!(
+                        "memory of {stage} grew {growth:.0}% from {} to {}",
+                        transport::format_bytes(before * 1024.0),
+                        transport::format_bytes(after * 1024.0),
+                    ));
+                }
+            }
+        }
+        // bytes per second between consecutive samples
+        let rates = |samples: &[Sample], from: &str, to: &str| -> Option<f64> {
+            let at = |sample: &Sample| sample.links.iter().find(|l| l.from == from && l.to == to).map(|l| (sample.at, l.bytes));
+            let points: Vec<(Duration, u64)> = samples.iter().filter_map(at).collect();
+            let (start, end) = (points.first()?, points.last()?);
+            let elapsed = end.0.saturating_sub(start.0).as_secs_f64();
+            (elapsed > 0.0).then(|| end.1.saturating_sub(start.1) as f64 / elapsed)
+        };
+        for link in &self.samples[0].links {
+            if let (Some(before), Some(after)) = (rates(first, &link.from, &link.to), rates(last, &link.from, &link.to)) {
+                if after < before / 2.0 {
+                    problems.push(format!(
+                        "{} -> {} slowed from {}/s to {}/s",
+                        link.from,
+                        link.to,
+                        transport::format_bytes(before),
+                        transport::format_bytes(after),
+                    ));
+                }
+            }
+        }
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(secs: u64, rss_kb: u64, bytes: u64) -> Sample {
+        Sample {
+            at: Duration::from_secs(secs),
+            stages: vec![StageSample { stage: "jq".to_string(), cpu: Some(Duration::from_secs(secs / 2)), rss_kb: Some(rss_kb) }],
+            links: vec![LinkSample { from: "generate".to_string(), to: "jq".to_string(), bytes }],
+        }
+    }
+
+    #[test]
+    fn names_stay_valid() {
+        assert_eq!(name("etl"), "etl-soak");
+        let long = "a".repeat(names::MAX_LEN);
+        assert_eq!(name(&long), format!("{}-soak", &long[..names::MAX_LEN - 5]));
+        assert_eq!(names::check(&name(&long)), Ok(()));
+    }
+
+    #[test]
+    fn judges_trends() {
+        let dir = std::env::temp_dir().join(format!("plumber-soak-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut stable = Soak::new(50.0);
+        for i in 0..8 {
+            let line = stable.record(sample(i * 10, 1000 + i, i * 10_000), &dir).unwrap();
+            if i == 1 {
+                assert_eq!(line, "10s: jq 50.0% cpu 1001.0 KiB, generate -> jq 1000 B/s");
+            }
+        }
+        assert_eq!(stable.problems(), Vec::<String>::new());
+        assert_eq!(fs::read_to_string(dir.join("soak.ndjson")).unwrap().lines().count(), 8);
+
+        let mut leaking = Soak::new(50.0);
+        for i in 0..8 {
+            // throughput drops off after the first half
+            let bytes = if i < 4 { i * 10_000 } else { 30_000 + (i - 3) * 1000 };
+            leaking.record(sample(i * 10, 1000 * (i + 1), bytes), &dir).unwrap();
+        }
+        let problems = leaking.problems();
+        assert_eq!(problems.len(), 2, "{problems:?}");
+        assert!(problems[0].starts_with("memory of jq grew 400%"), "{}", problems[0]);
+        assert!(problems[1].starts_with("generate -> jq slowed"), "{}", problems[1]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
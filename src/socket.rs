@@ -0,0 +1,282 @@
+//! the socket a supervisor answers on, `<state dir>/<name>.sock`
+//!
+//! while plumber supervises a pipeline, in particular one run with `--detach`, it listens on a
+//! unix socket next to the pipeline's metadata dir, only accessible to its user. commands are
+//! lines, each answered with lines ending in `ok` or `error <reason>`:
+//! - `status` answers `state <state>`, `uptime <seconds>` while running and
+//!   `stage <index> <name> <pid>` for every stage, `-` for one that isn't running, and
+//!   `metric <name> <value>` for every counter and gauge the stages reported
+//! - `stop [drain|group]` stops the pipeline as `plumber stop --mode` does
+//! - `signal <signal> [<stage>]` sends `TERM`, `HUP`, `USR1` or any other signal to a stage,
+//!   by name or index, the first stage if none is given
+
+use std::fs;
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::audit::{self, Actor};
+use crate::metrics::Metrics;
+use crate::names::ValidatedName;
+use crate::pipeline::{Pipeline, PipelineError, StopMode};
+use crate::reactor::{self, Handler, Wait};
+use crate::status;
+
+/// the socket of pipeline `name`
+pub fn path(name: &ValidatedName) -> PathBuf {
+    Pipeline::state_dirs().metadata.join(format!("{name}.sock"))
+}
+
+/// listening for as long as it's kept
+pub struct Socket {
+    path: PathBuf,
+    closing: Arc<AtomicBool>,
+}
+
+impl Socket {
+    pub fn open(name: &ValidatedName) -> std::io::Result<Socket> {
+        let path = path(name);
+        // left behind by a supervisor that didn't get to clean up
+        let _ = fs::remove_file(&path);
+        let listener = bind(&path)?;
+        let closing = Arc::new(AtomicBool::new(false));
+        let (name, stopping) = (name.to_owned(), closing.clone());
+        reactor::listen(listener, move |stream| {
+            if stopping.load(Ordering::SeqCst) {
+                return false;
+            }
+            match stream {
+                Ok(stream) => serve(stream, &name),
+                Err(e) => {
+                    log::warn!("{name}: socket failed, no longer answering on it: {e}");
+                    return false;
+                },
+            }
+            true
+        })?;
+        Ok(Socket { path, closing })
+    }
+}
+
+/// listens on `path`, which only our user may ever connect to: the socket is bound in a dir only
+/// we may enter and moved into place once it's 0600, rather than changed after the fact
+fn bind(path: &Path) -> std::io::Result<UnixListener> {
+    let private = path.with_extension("sock.d");
+    let _ = fs::remove_dir_all(&private);
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = private.join("sock");
+    let listener = UnixListener::bind(&bound)
+        .and_then(|listener| fs::set_permissions(&bound, fs::Permissions::from_mode(0o600)).map(|_| listener))
+        .and_then(|listener| fs::rename(&bound, path).map(|_| listener));
+    let _ = fs::remove_dir_all(&private);
+    listener
+}
+
+/// the uid and pid of the process at the other end of `stream`
+fn peer(stream: &UnixStream) -> Option<Actor> {
+    let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let got = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED, (&mut cred as *mut libc::ucred).cast(), &mut len)
+    };
+    (got == 0).then(|| Actor::peer(cred.uid, cred.pid as u32))
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        self.closing.store(true, Ordering::SeqCst);
+        // wakes the listener
+        let _ = UnixStream::connect(&self.path);
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn serve(stream: UnixStream, name: &ValidatedName) {
+    let Some(peer) = peer(&stream) else { return };
+    reactor::register(Box::new(Connection {
+        stream,
+        peer,
+        name: name.to_owned(),
+        partial: Vec::new(),
+        answers: Vec::new(),
+        open: true,
+    }));
+}
+
+/// a connection to the socket, answered on the reactor
+struct Connection {
+    stream: UnixStream,
+    peer: Actor,
+    name: ValidatedName,
+    /// the start of a command not yet read in full
+    partial: Vec<u8>,
+    /// answers not yet written
+    answers: Vec<u8>,
+    /// the client may still send commands
+    open: bool,
+}
+
+impl Handler for Connection {
+    fn poll(&mut self) -> Wait {
+        loop {
+            if !self.answers.is_empty() {
+                match self.stream.write(&self.answers) {
+                    Ok(n) => {
+                        self.answers.drain(..n);
+                        continue;
+                    },
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Wait::Writable(self.stream.as_raw_fd()),
+                    Err(_) => return Wait::Done,
+                }
+            }
+            if !self.open {
+                return Wait::Done;
+            }
+            let (lines, open) = reactor::read_lines(&mut self.stream, &mut self.partial);
+            self.open = open;
+            if lines.is_empty() && open {
+                return Wait::Readable(self.stream.as_raw_fd());
+            }
+            for line in lines.iter().map(|line| line.trim()).filter(|line| !line.is_empty()) {
+                let answer = match answer(line, &self.name, &self.peer) {
+                    Ok(lines) => lines.into_iter().chain(["ok".to_string()]).collect::<Vec<_>>(),
+                    Err(e) => vec![format!("error {e}")],
+                };
+                self.answers.extend_from_slice(format!("{}\n", answer.join("\n")).as_bytes());
+            }
+        }
+    }
+}
+
+/// the stages of pipeline `name` by name, from the definition it runs
+fn stages(name: &ValidatedName) -> Vec<String> {
+    fs::read_to_string(Pipeline::metadata_file(name, ".pipeline"))
+        .ok()
+        .and_then(|raw| Pipeline::parse_raw_pipeline(&raw).ok())
+        .map(|commands| commands.iter().map(|cmd| cmd.log_name().to_owned()).collect())
+        .unwrap_or_default()
+}
+
+/// a signal by name, with or without `SIG`, or by number
+pub fn signal_number(signal: &str) -> Option<libc::c_int> {
+    let signal = signal.trim_start_matches("SIG");
+    if let Ok(number) = signal.parse() {
+        return Some(number);
+    }
+    let number = match signal.to_ascii_uppercase().as_str() {
+        "HUP" => libc::SIGHUP,
+        "INT" => libc::SIGINT,
+        "QUIT" => libc::SIGQUIT,
+        "KILL" => libc::SIGKILL,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        "TERM" => libc::SIGTERM,
+        "CONT" => libc::SIGCONT,
+        "STOP" => libc::SIGSTOP,
+        "WINCH" => libc::SIGWINCH,
+        _ => return None,
+    };
+    Some(number)
+}
+
+fn answer(line: &str, name: &ValidatedName, peer: &Actor) -> Result<Vec<String>, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words[..] {
+        ["status"] => {
+            let status = status::status(name.to_string(), &Pipeline::metadata_file(name, ""), SystemTime::now());
+            let mut lines = vec![format!("state {}", status.state)];
+            lines.extend(status.uptime.map(|uptime| format!("uptime {}", uptime.as_secs())));
+            let pids = Pipeline::stage_pids(name);
+            for (i, stage) in stages(name).iter().enumerate() {
+                let pid = pids.get(i).copied().flatten().map_or("-".to_string(), |pid| pid.to_string());
+                lines.push(format!("stage {i} {stage} {pid}"));
+            }
+            for (metric, _, value) in Metrics::load(&Pipeline::metadata_file(name, "")).values {
+                lines.push(format!("metric {metric} {value}"));
+            }
+            Ok(lines)
+        },
+        ["stop"] | ["stop", _] => {
+            let mode = match words.get(1) {
+                None | Some(&"drain") => StopMode::Drain,
+                Some(&"group") => StopMode::Group,
+                Some(mode) => return Err(format!("unknown stop mode '{mode}', expected drain or group")),
+            };
+            audit::record_by("stop", name, "", peer);
+            Pipeline::stop(name, mode).map(|_| Vec::new()).map_err(|e| e.to_string())
+        },
+        ["signal", signal] | ["signal", signal, _] => {
+            let number = signal_number(signal).ok_or_else(|| format!("unknown signal '{signal}'"))?;
+            let index = match words.get(2) {
+                None => 0,
+                Some(stage) => match stage.parse() {
+                    Ok(index) => index,
+                    Err(_) => stages(name).iter().position(|s| s == stage)
+                        .ok_or_else(|| format!("no stage '{stage}'"))?,
+                },
+            };
+            audit::record_by("signal", name, &format!("{signal} to stage {index}"), peer);
+            match Pipeline::signal_stage(name, index, number) {
+                Ok(()) => Ok(Vec::new()),
+                Err(PipelineError::NotRunning) => Err(format!("stage {index} is not running")),
+                Err(e) => Err(e.to_string()),
+            }
+        },
+        _ => Err(format!("unknown command '{line}', expected status, stop or signal")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+
+    #[test]
+    fn answers_commands() {
+        let name = &ValidatedName::new("asdf_plumber_test_socket").unwrap();
+        fs::create_dir_all(Pipeline::metadata_file(name, "")).unwrap();
+        fs::write(Pipeline::metadata_file(name, ".pipeline"), "cat | jq .").unwrap();
+        let socket = Socket::open(name).unwrap();
+        assert_eq!(fs::metadata(path(name)).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let stream = UnixStream::connect(path(name)).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut ask = |command: &str| {
+            (&stream).write_all(format!("{command}\n").as_bytes()).unwrap();
+            let mut lines = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end().to_string();
+                let done = line == "ok" || line.starts_with("error");
+                lines.push(line);
+                if done {
+                    return lines;
+                }
+            }
+        };
+        assert_eq!(ask("status"), ["state exited", "stage 0 cat -", "stage 1 jq -", "ok"]);
+        assert_eq!(ask("signal BOGUS"), ["error unknown signal 'BOGUS'"]);
+        assert_eq!(ask("signal HUP sort"), ["error no stage 'sort'"]);
+        assert_eq!(ask("signal SIGHUP jq"), ["error stage 1 is not running"]);
+        assert_eq!(ask("restart"), ["error unknown command 'restart', expected status, stop or signal"]);
+
+        drop(socket);
+        assert!(!path(name).exists());
+        fs::remove_dir_all(Pipeline::metadata_file(name, "")).unwrap();
+    }
+
+    #[test]
+    fn signals_by_name_or_number() {
+        assert_eq!(signal_number("TERM"), Some(libc::SIGTERM));
+        assert_eq!(signal_number("SIGusr1"), Some(libc::SIGUSR1));
+        assert_eq!(signal_number("9"), Some(9));
+        assert_eq!(signal_number("NOPE"), None);
+    }
+}
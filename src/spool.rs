@@ -0,0 +1,696 @@
+//! spooled links, `--spool [<stage>=]<size>`
+//!
+//! a stage that reads in bursts, or stalls now and then, holds up every stage before it once
+//! the pipe into it is full. with `--spool` plumber reads ahead of the stage instead: up to
+//! `size` of what the stage hasn't read yet is kept in memory, anything beyond that is spilled
+//! to an unlinked file in the run's `$PLUMBER_TMPDIR` and read back in order, so a slow stage
+//! can't make plumber itself run the host out of memory.
+//!
+//! `--max-spool` caps the disk a link may spill to. once it's reached, `--on-spool-full block`
+//! (the default) stops reading from upstream until the stage caught up, and `fail` ends the
+//! link: the stage sees EOF after what was spooled and upstream gets `EPIPE`.
+//!
+//! what's spilled is written in segments of 4M, each checksummed so a spill that was
+//! tampered with or corrupted ends the link instead of feeding the stage garbage.
+//! `--spool-compression [STAGE=]LEVEL` compresses segments with the `zstd` binary at that
+//! level, trading cpu for far less disk on verbose streams; level 0 leaves a link's spill as
+//! is.
+//!
+//! a spooled link is relayed on the reactor: spills are written and read back there, and
+//! segments are run through zstd as a child whose pipes the reactor waits on too. a segment
+//! read back is freed in the spill right away, so `--max-spool` caps what's on disk, not just
+//! what's waiting.
+
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::reactor::{self, Finished, Handler, Wait};
+use crate::sha256::Sha256;
+use crate::stages;
+use crate::transport::Link;
+
+/// most bytes read or written at once
+const CHUNK: usize = 64 << 10;
+/// bytes spilled at once, checksummed and compressed as one
+const SEGMENT: usize = 4 << 20;
+
+/// one `--spool [STAGE=]SIZE` flag, the stage being the one reading from the link
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spool {
+    /// `None` applies to every stage but the first
+    pub stage: Option<String>,
+    pub memory: u64,
+}
+
+impl std::str::FromStr for Spool {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (stage, size) = match s.split_once('=') {
+            Some((stage, size)) => (Some(stage.to_owned()), size),
+            None => (None, s),
+        };
+        let memory = stages::parse_size(size).map_err(|e| e.to_string())?;
+        if memory == 0 {
+            return Err("spool size must be above 0".to_string());
+        }
+        Ok(Spool { stage, memory })
+    }
+}
+
+/// memory of the link into `stage`; the last flag naming it wins, then the last for all stages
+pub fn memory(spools: &[Spool], stage: &str) -> Option<u64> {
+    spools.iter()
+        .rev()
+        .find(|s| s.stage.as_deref() == Some(stage))
+        .or_else(|| spools.iter().rev().find(|s| s.stage.is_none()))
+        .map(|s| s.memory)
+}
+
+/// one `--spool-compression [STAGE=]LEVEL` flag
+#[derive(Debug, Clone, PartialEq)]
+pub struct Compression {
+    /// `None` applies to every spooled link
+    pub stage: Option<String>,
+    /// zstd level, 0 for none
+    pub level: u32,
+}
+
+impl std::str::FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (stage, level) = match s.split_once('=') {
+            Some((stage, level)) => (Some(stage.to_owned()), level),
+            None => (None, s),
+        };
+        let level = level.parse().map_err(|_| format!("invalid zstd level '{level}'"))?;
+        if level > 19 {
+            return Err("zstd levels go up to 19".to_string());
+        }
+        Ok(Compression { stage, level })
+    }
+}
+
+/// zstd level of the spill of the link into `stage`, `None` if it isn't compressed
+pub fn compression(rules: &[Compression], stage: &str) -> Option<u32> {
+    rules.iter()
+        .rev()
+        .find(|c| c.stage.as_deref() == Some(stage))
+        .or_else(|| rules.iter().rev().find(|c| c.stage.is_none()))
+        .map(|c| c.level)
+        .filter(|level| *level > 0)
+}
+
+/// what a link does once its spill reached `--max-spool`
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum SpoolFull {
+    /// stop reading from upstream until the stage caught up
+    Block,
+    /// end the link
+    Fail,
+}
+
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub memory: u64,
+    /// `None` for unlimited
+    pub disk: Option<u64>,
+    pub full: SpoolFull,
+    /// zstd level of spilled segments
+    pub compression: Option<u32>,
+}
+
+/// a part of the spill, as written to the file
+struct Segment {
+    offset: u64,
+    stored: u64,
+    compressed: bool,
+    checksum: [u8; 32],
+}
+
+/// a segment on its way into the spill
+enum Sealing {
+    Compressing(Zstd),
+    /// as it's written, once there's room for it
+    Stored(Vec<u8>, bool),
+}
+
+/// relays `from` to `to` through a spool, until upstream closes its stdout or the stage its
+/// stdin. spills go to `dir`
+pub fn spool(from: OwnedFd, to: OwnedFd, link: Arc<Link>, limits: Limits, dir: &Path) -> Finished {
+    reactor::register(Box::new(Spooler::new(from, to, link, limits, dir)))
+}
+
+impl Spooler {
+    fn new(from: OwnedFd, to: OwnedFd, link: Arc<Link>, limits: Limits, dir: &Path) -> Spooler {
+        for fd in [&from, &to] {
+            unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) };
+        }
+        Spooler {
+            from: Some(File::from(from)),
+        to: File::from(to),
+        link,
+        limits,
+        dir: dir.to_owned(),
+        memory: VecDeque::new(),
+        in_memory: 0,
+        open: Vec::new(),
+        sealing: None,
+        spill: None,
+        segments: VecDeque::new(),
+        spilled: 0,
+        freed: 0,
+        writing: Vec::new(),
+        written: 0,
+            reading_back: None,
+            gone: false,
+        }
+    }
+}
+
+/// what was read from upstream and not yet written downstream
+struct Spooler {
+    /// dropped once upstream closed its end or the link failed, which passes it on upstream
+    from: Option<File>,
+    to: File,
+    link: Arc<Link>,
+    limits: Limits,
+    dir: PathBuf,
+    memory: VecDeque<Vec<u8>>,
+    in_memory: u64,
+    /// spilled but not yet a segment, handed on as is once the segments before it were
+    open: Vec<u8>,
+    /// reading from upstream waits for it
+    sealing: Option<Sealing>,
+    spill: Option<File>,
+    segments: VecDeque<Segment>,
+    /// end of the last segment in the spill
+    spilled: u64,
+    /// bytes of segments read back and punched out of the spill
+    freed: u64,
+    /// being written to the stage, from `written` on
+    writing: Vec<u8>,
+    written: usize,
+    /// a compressed segment read back for the stage
+    reading_back: Option<Zstd>,
+    /// the stage closed its stdin, or the link failed
+    gone: bool,
+}
+
+impl Handler for Spooler {
+    fn poll(&mut self) -> Wait {
+        // bounded, so a link that's always ready doesn't keep the reactor to itself
+        for _ in 0..64 {
+            let written = self.write();
+            let sealed = self.seal();
+            let read = self.read();
+            if !(written || sealed || read) {
+                break;
+            }
+        }
+        if self.gone || (self.from.is_none() && self.sealing.is_none() && !self.available()) {
+            return Wait::Done;
+        }
+        let mut waits = Vec::new();
+        if let (Some(from), None) = (&self.from, &self.sealing) {
+            waits.push(Wait::Readable(from.as_raw_fd()));
+        }
+        if !self.writing.is_empty() {
+            waits.push(Wait::Writable(self.to.as_raw_fd()));
+        }
+        if let Some(zstd) = &self.reading_back {
+            waits.extend(zstd.waits());
+        }
+        if let Some(Sealing::Compressing(zstd)) = &self.sealing {
+            waits.extend(zstd.waits());
+        }
+        Wait::Any(waits)
+    }
+}
+
+impl Spooler {
+    fn on_disk(&self) -> u64 {
+        self.spilled - self.freed
+    }
+
+    /// whether anything read is spilled, which whatever is read next has to wait behind
+    fn spilling(&self) -> bool {
+        !self.segments.is_empty() || !self.open.is_empty() || self.sealing.is_some()
+    }
+
+    /// whether there's something left to write
+    fn available(&self) -> bool {
+        !self.writing.is_empty()
+            || !self.memory.is_empty()
+            || !self.segments.is_empty()
+            || self.reading_back.is_some()
+            || !self.open.is_empty()
+    }
+
+    /// reads what upstream wrote, false if there was nothing to read
+    fn read(&mut self) -> bool {
+        let Some(from) = &mut self.from else { return false };
+        if self.sealing.is_some() {
+            return false;
+        }
+        let mut chunk = vec![0u8; CHUNK];
+        let n = match from.read(&mut chunk) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return true,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return false,
+            Err(e) => {
+                log::error!("{} -> {}: spool failed reading: {e}", self.link.from, self.link.to);
+                0
+            },
+        };
+        if n == 0 {
+            self.from = None;
+            return true;
+        }
+        chunk.truncate(n);
+        // in memory only while nothing is spilled, which would be passed over otherwise
+        if !self.spilling() && self.in_memory + n as u64 <= self.limits.memory {
+            self.in_memory += n as u64;
+            self.memory.push_back(chunk);
+            return true;
+        }
+        self.open.extend_from_slice(&chunk);
+        if self.open.len() >= segment_size(&self.limits) {
+            let raw = std::mem::take(&mut self.open);
+            self.sealing = Some(match self.limits.compression {
+                Some(level) => match Zstd::start(&[&format!("-{level}")], raw) {
+                    Ok(zstd) => Sealing::Compressing(zstd),
+                    Err((e, raw)) => {
+                        if e.kind() == io::ErrorKind::NotFound {
+                            static WARNED: AtomicBool = AtomicBool::new(false);
+                            if !WARNED.swap(true, Ordering::Relaxed) {
+                                log::warn!("no zstd binary to compress spools with, spilling them uncompressed");
+                            }
+                        } else {
+                            log::warn!("{} -> {}: unable to compress spool, spilling it uncompressed: {e}", self.link.from, self.link.to);
+                        }
+                        Sealing::Stored(raw, false)
+                    },
+                },
+                None => Sealing::Stored(raw, false),
+            });
+        }
+        true
+    }
+
+    /// moves the segment being sealed on, into the spill once there's room for it
+    fn seal(&mut self) -> bool {
+        let (stored, compressed) = match self.sealing.take() {
+            None => return false,
+            Some(Sealing::Stored(stored, compressed)) => (stored, compressed),
+            Some(Sealing::Compressing(mut zstd)) => match zstd.poll() {
+                Ok(Some(stored)) => (stored, true),
+                Ok(None) => {
+                    self.sealing = Some(Sealing::Compressing(zstd));
+                    return false;
+                },
+                Err(e) => {
+                    log::error!("{} -> {}: unable to compress spool: {e}", self.link.from, self.link.to);
+                    self.from = None;
+                    return true;
+                },
+            },
+        };
+        // a segment always fits an empty spill, or a cap below it would hold the link forever
+        if self.limits.disk.is_some_and(|disk| self.on_disk() > 0 && self.on_disk() + stored.len() as u64 > disk) {
+            if self.limits.full == SpoolFull::Fail {
+                log::error!("{} -> {}: spool is full, ending the link", self.link.from, self.link.to);
+                self.from = None;
+                return true;
+            }
+            self.sealing = Some(Sealing::Stored(stored, compressed));
+            return false;
+        }
+        if let Err(e) = self.store(&stored, compressed) {
+            log::error!("{} -> {}: unable to spill to {}: {e}", self.link.from, self.link.to, self.dir.display());
+            self.from = None;
+        }
+        true
+    }
+
+    /// writes `stored` to the end of the spill as a segment
+    fn store(&mut self, stored: &[u8], compressed: bool) -> io::Result<()> {
+        if self.spill.is_none() {
+            self.spill = Some(unlinked_file(&self.dir)?);
+            log::debug!("spilling to {}", self.dir.display());
+        }
+        let offset = self.spilled;
+        self.spill.as_ref().unwrap().write_all_at(stored, offset)?;
+        self.spilled += stored.len() as u64;
+        self.segments.push_back(Segment { offset, stored: stored.len() as u64, compressed, checksum: checksum(stored) });
+        Ok(())
+    }
+
+    /// writes what's next to the stage, false if it couldn't take any
+    fn write(&mut self) -> bool {
+        let mut wrote = self.next();
+        while self.written < self.writing.len() {
+            let n = match self.to.write(&self.writing[self.written..]) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return wrote,
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::BrokenPipe {
+                        log::error!("{} -> {}: spool failed writing: {e}", self.link.from, self.link.to);
+                    }
+                    self.gone = true;
+                    return true;
+                },
+            };
+            self.written += n;
+            wrote = true;
+            if !self.link.moved(n as u64) {
+                // the supervisor ends the stages
+                self.gone = true;
+                return true;
+            }
+        }
+        (self.writing, self.written) = (Vec::new(), 0);
+        self.next() || wrote
+    }
+
+    /// takes up the next chunk to write once the last one is written, false if there's none yet
+    fn next(&mut self) -> bool {
+        if !self.writing.is_empty() {
+            return false;
+        }
+        if let Some(chunk) = self.memory.pop_front() {
+            self.in_memory -= chunk.len() as u64;
+            self.writing = chunk;
+            return true;
+        }
+        if let Some(zstd) = &mut self.reading_back {
+            return match zstd.poll() {
+                Ok(None) => false,
+                Ok(Some(chunk)) => {
+                    (self.writing, self.reading_back) = (chunk, None);
+                    true
+                },
+                Err(e) => {
+                    log::error!("{} -> {}: unable to read back spool: {e}", self.link.from, self.link.to);
+                    self.gone = true;
+                    true
+                },
+            };
+        }
+        if let Some(segment) = self.segments.pop_front() {
+            let spill = self.spill.as_ref().unwrap();
+            let stored = match read_back(spill, &segment) {
+                Ok(stored) => stored,
+                Err(e) => {
+                    log::error!("{} -> {}: unable to read back spool: {e}", self.link.from, self.link.to);
+                    self.gone = true;
+                    return true;
+                },
+            };
+            self.free(&segment);
+            match segment.compressed {
+                false => self.writing = stored,
+                true => match Zstd::start(&["-d"], stored) {
+                    Ok(zstd) => self.reading_back = Some(zstd),
+                    Err((e, _)) => {
+                        log::error!("{} -> {}: unable to read back spool: {e}", self.link.from, self.link.to);
+                        self.gone = true;
+                    },
+                },
+            }
+            return true;
+        }
+        if !self.open.is_empty() && self.sealing.is_none() {
+            self.writing = std::mem::take(&mut self.open);
+            return true;
+        }
+        false
+    }
+
+    /// gives the disk of a segment read back to the filesystem
+    fn free(&mut self, segment: &Segment) {
+        let spill = self.spill.as_ref().unwrap();
+        if self.segments.is_empty() {
+            // emptied once it's all read back
+            let _ = spill.set_len(0);
+            (self.spilled, self.freed) = (0, 0);
+            return;
+        }
+        let punched = unsafe {
+            libc::fallocate(
+                spill.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                segment.offset as libc::off_t,
+                segment.stored as libc::off_t,
+            )
+        };
+        // filesystems that can't punch holes free the spill once it's emptied
+        if punched == 0 {
+            self.freed += segment.stored;
+        }
+    }
+}
+/// raw bytes of a segment, small enough that a few fit within `--max-spool`
+fn segment_size(limits: &Limits) -> usize {
+    limits.disk.map_or(SEGMENT, |disk| (disk / 4) as usize).clamp(CHUNK, SEGMENT)
+}
+
+fn checksum(data: &[u8]) -> [u8; 32] {
+    let mut sha = Sha256::new();
+    sha.update(data);
+    sha.finish()
+}
+
+/// the zstd binary run over a segment, fed and drained without blocking
+struct Zstd {
+    child: Child,
+    /// closed once all of `input` is fed
+    stdin: Option<File>,
+    stdout: File,
+    input: Vec<u8>,
+    fed: usize,
+    output: Vec<u8>,
+}
+
+impl Zstd {
+    /// runs zstd with `args` over `input`, handing `input` back if it couldn't be started
+    fn start(args: &[&str], input: Vec<u8>) -> Result<Zstd, (io::Error, Vec<u8>)> {
+        let spawned = Command::new("zstd")
+            .args(args)
+            .args(["-q", "-c"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(e) => return Err((e, input)),
+        };
+        let stdin = File::from(OwnedFd::from(child.stdin.take().unwrap()));
+        let stdout = File::from(OwnedFd::from(child.stdout.take().unwrap()));
+        for fd in [&stdin, &stdout] {
+            unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) };
+        }
+        Ok(Zstd { child, stdin: Some(stdin), stdout, input, fed: 0, output: Vec::new() })
+    }
+
+    /// feeds and drains zstd as far as it goes without blocking, what it wrote once it exited
+    fn poll(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut buf = vec![0u8; CHUNK];
+        loop {
+            let mut progressed = false;
+            if let Some(stdin) = &mut self.stdin {
+                match stdin.write(&self.input[self.fed..]) {
+                    Ok(n) => (self.fed, progressed) = (self.fed + n, true),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                    // zstd gave up, its exit status says why
+                    Err(_) => (self.fed, progressed) = (self.input.len(), true),
+                }
+                if self.fed == self.input.len() {
+                    // zstd sees eof
+                    (self.stdin, self.input) = (None, Vec::new());
+                }
+            }
+            match self.stdout.read(&mut buf) {
+                Ok(0) => {
+                    let status = self.child.wait()?;
+                    return match status.success() {
+                        true => Ok(Some(std::mem::take(&mut self.output))),
+                        false => Err(io::Error::other(format!("zstd {status}"))),
+                    };
+                },
+                Ok(n) => {
+                    self.output.extend_from_slice(&buf[..n]);
+                    progressed = true;
+                },
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                Err(e) => return Err(e),
+            }
+            if !progressed {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn waits(&self) -> Vec<Wait> {
+        let mut waits = vec![Wait::Readable(self.stdout.as_raw_fd())];
+        if let Some(stdin) = &self.stdin {
+            waits.push(Wait::Writable(stdin.as_raw_fd()));
+        }
+        waits
+    }
+}
+
+impl Drop for Zstd {
+    fn drop(&mut self) {
+        // reaped already unless the link ended while it ran
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// a file in `dir` that's gone as soon as it's closed
+fn unlinked_file(dir: &Path) -> io::Result<File> {
+    let dir = CString::new(dir.as_os_str().as_bytes()).map_err(|_| io::ErrorKind::InvalidInput)?;
+    let fd = unsafe { libc::open(dir.as_ptr(), libc::O_TMPFILE | libc::O_RDWR | libc::O_CLOEXEC, 0o600) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(File::from(unsafe { OwnedFd::from_raw_fd(fd) }))
+}
+
+/// a segment as it was stored in the spill, checked against its checksum
+fn read_back(spill: &File, segment: &Segment) -> io::Result<Vec<u8>> {
+    let mut stored = vec![0u8; segment.stored as usize];
+    spill.read_exact_at(&mut stored, segment.offset)?;
+    if checksum(&stored) != segment.checksum {
+        let error = format!("segment at {} of the spill is corrupt", segment.offset);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, error));
+    }
+    Ok(stored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use crate::transport;
+
+    fn spooled(data: &[u8], limits: Limits, read_slowly: bool) -> Vec<u8> {
+        let dir = std::env::temp_dir();
+        let (upstream_read, upstream_write) = transport::pipe().unwrap();
+        let (stage_read, stage_write) = transport::pipe().unwrap();
+        let link = Arc::new(Link::new("spool_test", "a", "b"));
+        let finished = spool(upstream_read, stage_write, link, limits, &dir);
+        let data = data.to_vec();
+        let writer = thread::spawn(move || {
+            let _ = File::from(upstream_write).write_all(&data);
+        });
+        if read_slowly {
+            // lets the reader get ahead and spill
+            thread::sleep(std::time::Duration::from_millis(200));
+        }
+        let mut out = Vec::new();
+        File::from(stage_read).read_to_end(&mut out).unwrap();
+        writer.join().unwrap();
+        finished.wait();
+        out
+    }
+
+    #[test]
+    fn spills_in_order() {
+        let data: Vec<u8> = (0..3_000_000u32).flat_map(|i| i.to_le_bytes()).collect();
+        let limits = Limits { memory: 100_000, disk: None, full: SpoolFull::Block, compression: None };
+        assert!(spooled(&data, limits, true) == data);
+        let limits = Limits { memory: 100_000, disk: Some(300_000), full: SpoolFull::Block, compression: None };
+        assert!(spooled(&data, limits, true) == data);
+    }
+
+    #[test]
+    fn frees_what_was_read_back() {
+        use std::os::unix::fs::MetadataExt;
+
+        let (upstream_read, upstream_write) = transport::pipe().unwrap();
+        let (stage_read, stage_write) = transport::pipe().unwrap();
+        unsafe { libc::fcntl(stage_read.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) };
+        unsafe { libc::fcntl(upstream_write.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) };
+        let link = Arc::new(Link::new("spool_test", "a", "b"));
+        let limits = Limits { memory: CHUNK as u64, disk: Some(400_000), full: SpoolFull::Block, compression: None };
+        let mut spooler = Spooler::new(upstream_read, stage_write, link, limits, &std::env::temp_dir());
+
+        let data: Vec<u8> = (0..1_000_000u32).flat_map(|i| i.to_le_bytes()).collect();
+        let (mut upstream, mut stage) = (Some(File::from(upstream_write)), File::from(stage_read));
+        let (mut fed, mut out, mut buf) = (0, Vec::new(), vec![0u8; 16 << 10]);
+        let mut spilled = 0;
+        while out.len() < data.len() {
+            if let Some(writer) = &mut upstream {
+                match writer.write(&data[fed..]) {
+                    Ok(n) => fed += n,
+                    Err(e) => assert_eq!(e.kind(), io::ErrorKind::WouldBlock),
+                }
+                if fed == data.len() {
+                    upstream = None;
+                }
+            }
+            spooler.poll();
+            // the stage reads slower than upstream writes
+            if let Ok(n) = stage.read(&mut buf) {
+                out.extend_from_slice(&buf[..n]);
+            }
+            spilled = spilled.max(spooler.spilled);
+            let allocated = spooler.spill.as_ref().map_or(0, |spill| spill.metadata().unwrap().blocks() * 512);
+            assert!(allocated <= 400_000 + (64 << 10), "{allocated} bytes of spill on disk");
+        }
+        assert!(out == data);
+        assert!(spilled > 400_000, "never spilled past the cap, {spilled}");
+    }
+
+    #[test]
+    fn compresses_and_checks_segments() {
+        let data: Vec<u8> = (0..2_000_000u32).flat_map(|i| format!("{}\n", i % 1000).into_bytes()).collect();
+        let limits = Limits { memory: 100_000, disk: None, full: SpoolFull::Block, compression: Some(3) };
+        assert!(spooled(&data, limits, true) == data);
+
+        let spill = unlinked_file(&std::env::temp_dir()).unwrap();
+        spill.write_all_at(b"spilled", 0).unwrap();
+        let mut segment = Segment { offset: 0, stored: 7, compressed: false, checksum: checksum(b"spilled") };
+        assert_eq!(read_back(&spill, &segment).unwrap(), b"spilled");
+        segment.checksum = checksum(b"tampered");
+        assert_eq!(read_back(&spill, &segment).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn fails_when_full() {
+        let data = vec![7u8; 4_000_000];
+        let limits = Limits { memory: 100_000, disk: Some(200_000), full: SpoolFull::Fail, compression: None };
+        let out = spooled(&data, limits, true);
+        assert!(out.len() < data.len() && out.iter().all(|&b| b == 7));
+    }
+
+    #[test]
+    fn parses_spools() {
+        let spools: Vec<Spool> = ["1M", "jq=64K"].iter().map(|s| s.parse().unwrap()).collect();
+        assert_eq!(memory(&spools, "jq"), Some(64 << 10));
+        assert_eq!(memory(&spools, "wc"), Some(1 << 20));
+        assert_eq!(memory(&[], "wc"), None);
+        assert!("jq=0".parse::<Spool>().is_err());
+
+        let rules: Vec<Compression> = ["3", "jq=0"].iter().map(|s| s.parse().unwrap()).collect();
+        assert_eq!(compression(&rules, "wc"), Some(3));
+        assert_eq!(compression(&rules, "jq"), None);
+        assert!("20".parse::<Compression>().is_err());
+    }
+}
@@ -0,0 +1,191 @@
+//! `csv-to-json:` and `json-to-csv:` stages
+//!
+//! both read rfc 4180 style csv: fields containing the delimiter, quotes or newlines are
+//! double quoted and quotes inside them are doubled. options go in the target or after it,
+//! e.g. `csv-to-json:delimiter=;` or `json-to-csv: columns=id,name`.
+//!
+//! csv-to-json options: `delimiter`, `header` (first row names the fields, otherwise they
+//! are `c1`, `c2`, ...) and `infer` (turn numbers, `true`/`false` and empty fields into
+//! json numbers, booleans and null instead of strings).
+//!
+//! json-to-csv options: `delimiter`, `header` (write a header row), `columns` (defaults to
+//! the keys of the first object) and `quote` (`needed` or `always`). nested values are
+//! written as json text.
+
+use std::io::{self, BufRead, Write};
+
+use serde_json::Value;
+use super::StageArgs;
+
+pub fn to_json(args: &StageArgs) -> io::Result<()> {
+    let args = args.options_in_target()?;
+    let delimiter = delimiter(&args)?;
+    let header = args.parse_or("header", true)?;
+    let infer = args.parse_or("infer", true)?;
+
+    let mut records = reader(io::stdin().lock(), delimiter).into_byte_records();
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    let result = (|| {
+        let mut names: Option<Vec<String>> = match header {
+            true => records.next().transpose()?.map(|record| fields(&record)),
+            false => None,
+        };
+
+        for record in records {
+            let record = fields(&record?);
+            if record.len() == 1 && record[0].is_empty() {
+                continue;
+            }
+            let names = names.get_or_insert_with(Vec::new);
+            while names.len() < record.len() {
+                names.push(format!("c{}", names.len() + 1));
+            }
+            let object = Value::Object(names.iter()
+                .zip(record)
+                .map(|(name, field)| (name.clone(), to_value(field, infer)))
+                .collect());
+            writeln!(out, "{object}")?;
+        }
+        out.flush()
+    })();
+
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        other => other,
+    }
+}
+
+pub fn from_json(args: &StageArgs) -> io::Result<()> {
+    let args = args.options_in_target()?;
+    let delimiter = delimiter(&args)?;
+    let header = args.parse_or("header", true)?;
+    let always_quote = match args.get_or("quote", "needed") {
+        "needed" => false,
+        "always" => true,
+        other => return Err(super::invalid(format!("unknown quote mode '{other}', expected needed or always"))),
+    };
+    // `columns` is itself comma separated, so it can only be given after the target
+    let mut columns: Option<Vec<String>> = args.get("columns")
+        .map(|c| c.split(',').map(str::to_owned).collect());
+
+    let mut out = writer(io::stdout().lock(), delimiter, always_quote);
+    let result = (|| {
+        for (number, line) in io::stdin().lock().lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let value: Value = serde_json::from_str(&line)
+                .map_err(|e| super::invalid(format!("line {}: {e}", number + 1)))?;
+            let Value::Object(fields) = &value else {
+                return Err(super::invalid(format!("line {}: expected a json object", number + 1)));
+            };
+
+            let columns = match &mut columns {
+                Some(columns) => columns,
+                None => {
+                    let columns = columns.insert(fields.keys().cloned().collect());
+                    if header {
+                        out.write_record(columns.iter())?;
+                    }
+                    columns
+                },
+            };
+
+            out.write_record(columns.iter().map(|c| match value.get(c) {
+                None | Some(Value::Null) => String::new(),
+                Some(Value::String(s)) => s.clone(),
+                Some(other) => other.to_string(),
+            }))?;
+        }
+        out.flush()
+    })();
+
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        other => other,
+    }
+}
+
+fn delimiter(args: &StageArgs) -> io::Result<u8> {
+    match args.get_or("delimiter", ",").as_bytes() {
+        [d] => Ok(*d),
+        b"\\t" | b"tab" => Ok(b'\t'),
+        _ => Err(super::invalid("delimiter must be a single byte".to_string())),
+    }
+}
+
+/// rows of `input` as they are, the header row included
+fn reader<R: io::Read>(input: R, delimiter: u8) -> csv::Reader<R> {
+    csv::ReaderBuilder::new().delimiter(delimiter).has_headers(false).flexible(true).from_reader(input)
+}
+
+fn writer<W: io::Write>(out: W, delimiter: u8, always_quote: bool) -> csv::Writer<W> {
+    let quote = match always_quote {
+        true => csv::QuoteStyle::Always,
+        false => csv::QuoteStyle::Necessary,
+    };
+    csv::WriterBuilder::new().delimiter(delimiter).quote_style(quote).flexible(true).from_writer(out)
+}
+
+fn fields(record: &csv::ByteRecord) -> Vec<String> {
+    record.iter().map(|field| String::from_utf8_lossy(field).into_owned()).collect()
+}
+
+fn to_value(field: String, infer: bool) -> Value {
+    if !infer {
+        return Value::String(field);
+    }
+    match field.as_str() {
+        "" => Value::Null,
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        // json numbers only, so "0x1f" or "1." stay strings. whitespace around one, newlines
+        // of a quoted field included, is dropped rather than written out with it
+        n => match serde_json::from_str(n) {
+            Ok(number @ Value::Number(_)) => number,
+            _ => Value::String(field),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(raw: &str) -> Vec<Vec<String>> {
+        reader(raw.as_bytes(), b',').into_byte_records().map(|record| fields(&record.unwrap())).collect()
+    }
+
+    #[test]
+    fn quoted_fields_and_newlines() {
+        let raw = "a,\"b,\"\"c\"\"\",\"multi\nline\"\r\n,x\n";
+        assert_eq!(records(raw), [vec!["a", "b,\"c\"", "multi\nline"], vec!["", "x"]]);
+    }
+
+    #[test]
+    fn inference() {
+        assert_eq!(to_value("42".to_string(), true), serde_json::json!(42));
+        assert_eq!(to_value("-1.5e3".to_string(), true).as_f64(), Some(-1500.0));
+        assert_eq!(to_value("1.".to_string(), true), Value::String("1.".to_string()));
+        assert_eq!(to_value("".to_string(), true), Value::Null);
+        assert_eq!(to_value("true".to_string(), false), Value::String("true".to_string()));
+    }
+
+    #[test]
+    fn padded_numbers_are_trimmed() {
+        let record: Vec<_> = records("\"12\n\", 7 ,\"\n\"\n").remove(0).into_iter().map(|field| to_value(field, true)).collect();
+        assert_eq!(record, [serde_json::json!(12), serde_json::json!(7), Value::String("\n".to_string())]);
+        let line = Value::Array(record).to_string();
+        assert!(!line.contains('\n'), "{line}");
+    }
+
+    #[test]
+    fn writer_quotes_when_needed() {
+        let mut out = writer(Vec::new(), b',', false);
+        out.write_record(["a", "b,c", "say \"hi\""]).unwrap();
+        let mut always = writer(out.into_inner().unwrap(), b';', true);
+        always.write_record(["a"]).unwrap();
+        assert_eq!(String::from_utf8(always.into_inner().unwrap()).unwrap(), "a,\"b,c\",\"say \"\"hi\"\"\"\n\"a\"\n");
+    }
+}
@@ -0,0 +1,210 @@
+//! `dedupe:window=<n>` stage
+//!
+//! drops records whose key was already seen among the last `window` distinct keys (`1M`
+//! is one million). memory stays bounded by the window:
+//!
+//! - `mode=exact` (default) keeps a 64 bit hash per key in a fifo set, about 40 bytes a key
+//! - `mode=bloom` rotates two bloom filters sized for the window, around 2 bytes a key at
+//!   the default `fp=0.001` false positive rate, at the cost of occasionally dropping a
+//!   record that was not a duplicate
+//!
+//! the key is the whole line unless `field=<n>` picks a 1-based field split on
+//! `delimiter` (whitespace by default).
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufWriter, Write};
+
+use super::{KeyExtractor, StageArgs};
+
+pub fn filter(args: &StageArgs) -> io::Result<()> {
+    let args = args.options_in_target()?;
+    let window = super::parse_count(args.get_or("window", "1M"))?;
+    if window == 0 {
+        return Err(super::invalid("dedupe window must be at least 1".to_string()));
+    }
+    let key = KeyExtractor::from_args(&args)?;
+    let mut seen: Box<dyn SeenSet> = match args.get_or("mode", "exact") {
+        "exact" => Box::new(FifoSet::new(window)),
+        "bloom" => Box::new(RotatingBloom::new(window, false_positives(&args)?)),
+        other => return Err(super::invalid(format!("unknown dedupe mode '{other}', expected exact or bloom"))),
+    };
+
+    let mut out = BufWriter::new(io::stdout().lock());
+    let mut dropped = 0u64;
+    for line in io::stdin().lock().split(b'\n') {
+        let line = line?;
+        if !seen.insert(hash(key.extract(&line))) {
+            dropped += 1;
+            continue;
+        }
+        let written = out.write_all(&line).and_then(|_| out.write_all(b"\n"));
+        match written {
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+            other => other?,
+        }
+    }
+    log::info!("dedupe: dropped {dropped} duplicate records");
+    match out.flush() {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        other => other,
+    }
+}
+
+fn hash(key: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+trait SeenSet {
+    /// returns false if the key was already present
+    fn insert(&mut self, key: u64) -> bool;
+}
+
+struct FifoSet {
+    set: HashSet<u64>,
+    order: VecDeque<u64>,
+    capacity: u64,
+}
+
+impl FifoSet {
+    fn new(capacity: u64) -> Self {
+        FifoSet {
+            set: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+}
+
+impl SeenSet for FifoSet {
+    fn insert(&mut self, key: u64) -> bool {
+        if !self.set.insert(key) {
+            return false;
+        }
+        self.order.push_back(key);
+        if self.order.len() as u64 > self.capacity {
+            let oldest = self.order.pop_front().unwrap();
+            self.set.remove(&oldest);
+        }
+        true
+    }
+}
+
+/// `fp`, a rate strictly between 0 and 1: 0 would take infinitely many bits
+fn false_positives(args: &StageArgs) -> io::Result<f64> {
+    let fp = args.parse_or("fp", 0.001)?;
+    match fp > 0.0 && fp < 1.0 {
+        true => Ok(fp),
+        false => Err(super::invalid(format!("dedupe fp must be between 0 and 1, got {fp}"))),
+    }
+}
+
+struct Bloom {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl Bloom {
+    fn new(entries: u64, fp: f64) -> Self {
+        let bits = (-(entries as f64) * fp.ln() / std::f64::consts::LN_2.powi(2)).ceil().max(64.0) as u64;
+        let hashes = ((bits as f64 / entries as f64) * std::f64::consts::LN_2).round().max(1.0) as u32;
+        Bloom {
+            bits: vec![0; bits.div_ceil(64) as usize],
+            hashes,
+        }
+    }
+
+    fn positions(&self, key: u64) -> impl Iterator<Item = u64> + '_ {
+        // double hashing, see Kirsch and Mitzenmacher
+        let (h1, h2) = (key & 0xffff_ffff, key >> 32);
+        let len = self.bits.len() as u64 * 64;
+        (0..self.hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % len)
+    }
+
+    fn contains(&self, key: u64) -> bool {
+        self.positions(key).all(|p| self.bits[(p / 64) as usize] & (1 << (p % 64)) != 0)
+    }
+
+    fn insert(&mut self, key: u64) {
+        let positions: Vec<u64> = self.positions(key).collect();
+        for p in positions {
+            self.bits[(p / 64) as usize] |= 1 << (p % 64);
+        }
+    }
+}
+
+/// two generations of bloom filters, the older one is dropped once the newer is full
+struct RotatingBloom {
+    current: Bloom,
+    previous: Option<Bloom>,
+    inserted: u64,
+    window: u64,
+    fp: f64,
+}
+
+impl RotatingBloom {
+    fn new(window: u64, fp: f64) -> Self {
+        RotatingBloom {
+            current: Bloom::new(window, fp),
+            previous: None,
+            inserted: 0,
+            window,
+            fp,
+        }
+    }
+}
+
+impl SeenSet for RotatingBloom {
+    fn insert(&mut self, key: u64) -> bool {
+        let seen = self.current.contains(key)
+            || self.previous.as_ref().is_some_and(|p| p.contains(key));
+        if seen {
+            return false;
+        }
+        if self.inserted == self.window {
+            let full = std::mem::replace(&mut self.current, Bloom::new(self.window, self.fp));
+            self.previous = Some(full);
+            self.inserted = 0;
+        }
+        self.current.insert(key);
+        self.inserted += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fifo_window_forgets_oldest() {
+        let mut set = FifoSet::new(2);
+        assert!(set.insert(1));
+        assert!(set.insert(2));
+        assert!(!set.insert(1));
+        assert!(set.insert(3));
+        // 1 fell out of the window
+        assert!(set.insert(1));
+        assert!(!set.insert(3));
+    }
+
+    #[test]
+    fn bloom_remembers_recent_keys() {
+        let mut bloom = RotatingBloom::new(1000, 0.001);
+        let fresh = (0..1000u64).filter(|&k| bloom.insert(hash(&k.to_be_bytes()))).count();
+        assert!(fresh >= 995);
+        assert!((0..1000u64).all(|k| !bloom.insert(hash(&k.to_be_bytes()))));
+    }
+
+    #[test]
+    fn false_positive_rates_are_between_0_and_1() {
+        let fp = |value: &str| false_positives(&StageArgs::parse("", &[format!("fp={value}")]).unwrap());
+        assert_eq!(fp("0.01").unwrap(), 0.01);
+        for value in ["0", "1", "1.5", "-0.1", "NaN", "inf"] {
+            assert!(fp(value).is_err(), "{value}");
+        }
+    }
+}
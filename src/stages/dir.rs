@@ -0,0 +1,161 @@
+//! `dir:<template>` sink stage
+//!
+//! writes stdin into files named by a utc date template, e.g.
+//! `dir:out/%Y/%m/%d/part-{seq}.ndjson`. a part is written to a hidden temp file next to
+//! its final name and renamed into place once it is complete, so anything watching the
+//! directory only ever sees whole files. `{seq}` counts up from the first unused number.
+//!
+//! options: `max-bytes` (default 64M) and `max-age` in seconds (default 3600) start a new
+//! part, as does the date template rolling over. lines are never split across parts.
+//! the path of every completed part is written to stdout.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::datetime::DateTime;
+use super::StageArgs;
+
+struct Part {
+    /// date template with only `{seq}` left unexpanded
+    period: String,
+    tmp_path: PathBuf,
+    final_path: PathBuf,
+    file: BufWriter<File>,
+    bytes: u64,
+    opened: Instant,
+}
+
+impl Part {
+    fn open(period: String) -> io::Result<Self> {
+        let final_path = (0..)
+            .map(|seq| PathBuf::from(period.replace("{seq}", &format!("{seq:05}"))))
+            .find(|p| !p.exists() && !tmp_path_for(p).exists())
+            .unwrap();
+        if let Some(parent) = final_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let tmp_path = tmp_path_for(&final_path);
+        Ok(Part {
+            file: BufWriter::new(File::create(&tmp_path)?),
+            period,
+            tmp_path,
+            final_path,
+            bytes: 0,
+            opened: Instant::now(),
+        })
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        self.file.write_all(line)?;
+        self.file.write_all(b"\n")?;
+        self.bytes += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn finish(self) -> io::Result<PathBuf> {
+        let file = self.file.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&self.tmp_path, &self.final_path)?;
+        Ok(self.final_path)
+    }
+}
+
+fn tmp_path_for(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}.tmp"))
+}
+
+pub fn write(args: &StageArgs) -> io::Result<()> {
+    let template = args.target.clone();
+    if !template.contains("{seq}") {
+        return Err(super::invalid(format!("dir template '{template}' needs a {{seq}} placeholder")));
+    }
+    let max_bytes = super::parse_size(args.get_or("max-bytes", "64M"))?;
+    let max_age = Duration::from_secs(args.parse_or("max-age", 3600)?);
+
+    // read on a separate thread so an idle input still lets parts age out
+    let (tx, rx) = mpsc::sync_channel::<io::Result<Vec<u8>>>(1024);
+    thread::spawn(move || {
+        for line in io::stdin().lock().split(b'\n') {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut part: Option<Part> = None;
+    loop {
+        let received = match &part {
+            Some(p) => rx.recv_timeout(max_age.saturating_sub(p.opened.elapsed())),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        let line = match received {
+            Ok(line) => line?,
+            Err(RecvTimeoutError::Timeout) => {
+                complete(part.take())?;
+                continue;
+            },
+            Err(RecvTimeoutError::Disconnected) => return complete(part.take()),
+        };
+
+        let period = DateTime::now().format(&template);
+        let rollover = part.as_ref()
+            .is_some_and(|p| p.period != period || p.bytes >= max_bytes);
+        if rollover {
+            complete(part.take())?;
+        }
+
+        let current = match part.as_mut() {
+            Some(p) => p,
+            None => part.insert(Part::open(period)?),
+        };
+        current.write_line(&line)?;
+    }
+}
+
+fn complete(part: Option<Part>) -> io::Result<()> {
+    let Some(part) = part else { return Ok(()) };
+    let path = part.finish()?;
+    log::info!("dir: completed {}", path.display());
+
+    let mut stdout = io::stdout().lock();
+    match writeln!(stdout, "{}", path.display()).and_then(|_| stdout.flush()) {
+        // nobody reading the list of parts is fine for a sink
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parts_are_renamed_into_place() {
+        let root = std::env::temp_dir().join(format!("plumber_dir_test_{}", std::process::id()));
+        let period = format!("{}/%Y/part-{{seq}}.log", root.display());
+        let period = DateTime::now().format(&period);
+
+        let mut first = Part::open(period.clone()).unwrap();
+        first.write_line(b"a").unwrap();
+        assert!(first.tmp_path.exists());
+        assert!(first.tmp_path.file_name().unwrap().to_string_lossy().starts_with('.'));
+
+        // the temp file already claims seq 0
+        let second = Part::open(period.clone()).unwrap();
+        assert!(second.final_path.to_string_lossy().ends_with("part-00001.log"));
+
+        let done = first.finish().unwrap();
+        assert!(done.to_string_lossy().ends_with("part-00000.log"));
+        assert_eq!(fs::read(&done).unwrap(), b"a\n");
+        second.finish().unwrap();
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
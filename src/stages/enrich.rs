@@ -0,0 +1,183 @@
+//! `enrich:` stage
+//!
+//! attaches metadata to every record so downstream consumers can tell where and when it
+//! was produced. options go in the target or after it, e.g. `enrich:format=json fields=ts,seq`.
+//!
+//! options: `fields` (any of `ts` rfc3339 utc timestamp, `host`, `pipeline` and `seq`, a
+//! line number starting at `seq-start`), `format` (`prefix` writes the values before the
+//! line joined by `separator`, a tab by default; `json` adds them as keys with
+//! `key-prefix` to json object lines, and wraps anything else as `{..., "line": ...}`).
+
+use std::io::{self, BufRead, BufWriter, Write};
+use std::time::SystemTime;
+
+use serde_json::Value;
+use super::StageArgs;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Timestamp,
+    Host,
+    Pipeline,
+    Seq,
+}
+
+impl Field {
+    fn parse(name: &str) -> io::Result<Self> {
+        match name {
+            "ts" => Ok(Field::Timestamp),
+            "host" => Ok(Field::Host),
+            "pipeline" => Ok(Field::Pipeline),
+            "seq" => Ok(Field::Seq),
+            other => Err(super::invalid(format!("unknown enrich field '{other}', expected ts, host, pipeline or seq"))),
+        }
+    }
+
+    fn key(&self) -> &'static str {
+        match self {
+            Field::Timestamp => "ts",
+            Field::Host => "host",
+            Field::Pipeline => "pipeline",
+            Field::Seq => "seq",
+        }
+    }
+}
+
+enum Format {
+    Prefix { separator: String },
+    Json { key_prefix: String },
+}
+
+struct Enricher {
+    fields: Vec<Field>,
+    format: Format,
+    host: String,
+    pipeline: String,
+    seq: u64,
+}
+
+impl Enricher {
+    fn values(&self, now: SystemTime) -> Vec<Value> {
+        self.fields.iter()
+            .map(|field| match field {
+                Field::Timestamp => Value::String(humantime::format_rfc3339_millis(now).to_string()),
+                Field::Host => Value::String(self.host.clone()),
+                Field::Pipeline => Value::String(self.pipeline.clone()),
+                Field::Seq => Value::from(self.seq),
+            })
+            .collect()
+    }
+
+    fn enrich(&mut self, line: &str, now: SystemTime) -> String {
+        let values = self.values(now);
+        self.seq += 1;
+
+        match &self.format {
+            Format::Prefix { separator } => {
+                let mut out = String::new();
+                for value in values {
+                    match value {
+                        Value::String(s) => out.push_str(&s),
+                        other => out.push_str(&other.to_string()),
+                    }
+                    out.push_str(separator);
+                }
+                out.push_str(line);
+                out
+            },
+            Format::Json { key_prefix } => {
+                let metadata = self.fields.iter()
+                    .map(|f| format!("{key_prefix}{}", f.key()))
+                    .zip(values);
+                match serde_json::from_str::<Value>(line) {
+                    Ok(Value::Object(mut fields)) => {
+                        fields.extend(metadata);
+                        Value::Object(fields).to_string()
+                    },
+                    _ => {
+                        let mut fields: serde_json::Map<String, Value> = metadata.collect();
+                        fields.insert("line".to_string(), Value::String(line.to_owned()));
+                        Value::Object(fields).to_string()
+                    },
+                }
+            },
+        }
+    }
+}
+
+pub fn enrich(args: &StageArgs) -> io::Result<()> {
+    let args = args.options_in_target()?;
+    // `fields` is comma separated, so in the target only a single field can be given
+    let fields = args.get_or("fields", "ts,host,pipeline,seq")
+        .split(',')
+        .map(Field::parse)
+        .collect::<io::Result<Vec<_>>>()?;
+    let format = match args.get_or("format", "prefix") {
+        "prefix" => Format::Prefix {
+            separator: args.get_or("separator", "\t").replace("\\t", "\t"),
+        },
+        "json" => Format::Json {
+            key_prefix: args.get_or("key-prefix", "_").to_owned(),
+        },
+        other => return Err(super::invalid(format!("unknown format '{other}', expected prefix or json"))),
+    };
+
+    let mut enricher = Enricher {
+        fields,
+        format,
+        host: super::hostname(),
+        pipeline: std::env::var("PLUMBER_PIPELINE").unwrap_or_default(),
+        seq: args.parse_or("seq-start", 1)?,
+    };
+
+    let mut out = BufWriter::new(io::stdout().lock());
+    let result = (|| {
+        for line in io::stdin().lock().lines() {
+            writeln!(out, "{}", enricher.enrich(&line?, SystemTime::now()))?;
+        }
+        out.flush()
+    })();
+
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn enricher(format: Format) -> Enricher {
+        Enricher {
+            fields: vec![Field::Timestamp, Field::Pipeline, Field::Seq],
+            format,
+            host: "box".to_string(),
+            pipeline: "etl".to_string(),
+            seq: 1,
+        }
+    }
+
+    #[test]
+    fn prefix_format() {
+        let now = UNIX_EPOCH + Duration::from_millis(1_500);
+        let mut e = enricher(Format::Prefix { separator: " ".to_string() });
+        assert_eq!(e.enrich("a", now), "1970-01-01T00:00:01.500Z etl 1 a");
+        assert_eq!(e.enrich("b", now), "1970-01-01T00:00:01.500Z etl 2 b");
+    }
+
+    #[test]
+    fn json_format() {
+        let now = UNIX_EPOCH;
+        let mut e = enricher(Format::Json { key_prefix: "_".to_string() });
+        assert_eq!(
+            e.enrich(r#"{"x":1}"#, now),
+            r#"{"x":1,"_ts":"1970-01-01T00:00:00.000Z","_pipeline":"etl","_seq":1}"#
+        );
+        assert_eq!(
+            e.enrich("plain", now),
+            r#"{"_ts":"1970-01-01T00:00:00.000Z","_pipeline":"etl","_seq":2,"line":"plain"}"#
+        );
+    }
+}
@@ -0,0 +1,252 @@
+//! `files:<glob>` source stage
+//!
+//! streams every file matching the glob to stdout, one after another. globs support `*`,
+//! `?`, `[abc]`/`[a-z]` and `**` for any number of directories.
+//!
+//! options: `order` (`name` for lexical, `mtime` for oldest first), `decompress` (`auto`
+//! pipes `.gz` and `.zst` files through `gzip -dc`/`zstd -dc`, `none` copies them as is),
+//! `allow-empty` (succeed when nothing matches) and `skip-consumed` (skip files already
+//! recorded by a previous run).
+//!
+//! each file is appended to `files.consumed` in the pipeline's metadata dir once it has
+//! been copied completely.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use super::StageArgs;
+
+const CONSUMED_FILE: &str = "files.consumed";
+
+pub fn read(args: &StageArgs) -> io::Result<()> {
+    let by_mtime = match args.get_or("order", "name") {
+        "name" => false,
+        "mtime" => true,
+        other => return Err(super::invalid(format!("unknown order '{other}', expected name or mtime"))),
+    };
+    let decompress = match args.get_or("decompress", "auto") {
+        "auto" => true,
+        "none" => false,
+        other => return Err(super::invalid(format!("unknown decompress mode '{other}', expected auto or none"))),
+    };
+    let allow_empty = args.parse_or("allow-empty", false)?;
+    let skip_consumed = args.parse_or("skip-consumed", false)?;
+
+    let consumed_path = std::env::var_os("PLUMBER_METADATA_DIR")
+        .map(|dir| Path::new(&dir).join(CONSUMED_FILE));
+    let already_consumed = match (&consumed_path, skip_consumed) {
+        (Some(path), true) => read_consumed(path)?,
+        _ => Vec::new(),
+    };
+
+    let mut files: Vec<PathBuf> = glob(&args.target)?
+        .into_iter()
+        .filter(|f| !already_consumed.contains(f))
+        .collect();
+    if by_mtime {
+        // sort_by_cached_key keeps lexical order between files with the same mtime
+        files.sort_by_cached_key(|f| fs::metadata(f).and_then(|m| m.modified()).ok());
+    }
+
+    if files.is_empty() && !allow_empty {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("no files match '{}'", args.target)));
+    }
+
+    let mut consumed = match &consumed_path {
+        Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+        None => None,
+    };
+
+    let mut stdout = io::stdout().lock();
+    for file in files {
+        log::info!("files: reading {}", file.display());
+        match copy_file(&file, decompress, &mut stdout) {
+            Ok(()) => {},
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+            Err(e) => return Err(io::Error::new(e.kind(), format!("{}: {e}", file.display()))),
+        }
+        stdout.flush()?;
+        if let Some(consumed) = consumed.as_mut() {
+            writeln!(consumed, "{}", file.display())?;
+        }
+    }
+    Ok(())
+}
+
+fn copy_file(path: &Path, decompress: bool, out: &mut impl Write) -> io::Result<()> {
+    let tool = match path.extension().and_then(|e| e.to_str()) {
+        Some("gz") if decompress => "gzip",
+        Some("zst") if decompress => "zstd",
+        _ => {
+            io::copy(&mut fs::File::open(path)?, out)?;
+            return Ok(());
+        },
+    };
+
+    let mut child = Command::new(tool)
+        .arg("-dc")
+        .arg(path)
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| io::Error::new(e.kind(), format!("unable to run {tool}: {e}")))?;
+    let copied = io::copy(child.stdout.as_mut().unwrap(), out);
+    if copied.is_err() {
+        let _ = child.kill();
+    }
+    let status = child.wait()?;
+    copied?;
+
+    match status.success() {
+        true => Ok(()),
+        false => Err(io::Error::other(format!("{tool} -dc exited with {status}"))),
+    }
+}
+
+fn read_consumed(path: &Path) -> io::Result<Vec<PathBuf>> {
+    match fs::File::open(path) {
+        Ok(file) => BufReader::new(file).lines()
+            .map(|line| line.map(PathBuf::from))
+            .collect(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// expands `pattern` into matching paths in lexical order
+pub fn glob(pattern: &str) -> io::Result<Vec<PathBuf>> {
+    let (root, rest) = match pattern.strip_prefix('/') {
+        Some(rest) => (PathBuf::from("/"), rest),
+        None => (PathBuf::new(), pattern),
+    };
+    let components: Vec<&str> = rest.split('/').filter(|c| !c.is_empty()).collect();
+
+    let mut matches = Vec::new();
+    expand(&root, &components, &mut matches)?;
+    matches.sort();
+    matches.dedup();
+    Ok(matches)
+}
+
+fn expand(dir: &Path, components: &[&str], matches: &mut Vec<PathBuf>) -> io::Result<()> {
+    let Some((&component, rest)) = components.split_first() else {
+        if dir.is_file() {
+            matches.push(dir.to_path_buf());
+        }
+        return Ok(());
+    };
+
+    if !has_wildcard(component) {
+        let next = dir.join(component);
+        if next.exists() {
+            expand(&next, rest, matches)?;
+        }
+        return Ok(());
+    }
+
+    let listing_dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let mut entries: Vec<(String, bool)> = match fs::read_dir(listing_dir) {
+        Ok(entries) => entries.filter_map(Result::ok)
+            // not following links, so `**` can't go round a symlink loop until the stack runs out
+            .map(|e| (e.file_name().to_string_lossy().into_owned(), e.file_type().is_ok_and(|t| t.is_dir())))
+            .filter(|(name, _)| !name.starts_with('.') || component.starts_with('.'))
+            .collect(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    entries.sort();
+
+    if component == "**" {
+        // zero directories ...
+        expand(dir, rest, matches)?;
+        // ... or one more, keeping `**` in place
+        for (name, is_dir) in entries {
+            if is_dir {
+                expand(&dir.join(name), components, matches)?;
+            }
+        }
+        return Ok(());
+    }
+
+    for (name, _) in entries {
+        if wildcard_match(component.as_bytes(), name.as_bytes()) {
+            expand(&dir.join(name), rest, matches)?;
+        }
+    }
+    Ok(())
+}
+
+fn has_wildcard(component: &str) -> bool {
+    component.contains(['*', '?', '['])
+}
+
+/// shell style matching of a single path component
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| wildcard_match(rest, &name[skip..])),
+        Some((b'?', rest)) => !name.is_empty() && wildcard_match(rest, &name[1..]),
+        Some((b'[', rest)) => {
+            let Some(end) = rest.iter().skip(1).position(|&b| b == b']').map(|p| p + 1) else {
+                return name.first() == Some(&b'[') && wildcard_match(rest, &name[1..]);
+            };
+            let Some((&c, name_rest)) = name.split_first() else { return false };
+            let (negate, set) = match rest[..end].split_first() {
+                Some((b'!' | b'^', set)) => (true, set),
+                _ => (false, &rest[..end]),
+            };
+            let mut found = false;
+            let mut i = 0;
+            while i < set.len() {
+                if i + 2 < set.len() && set[i + 1] == b'-' {
+                    found |= set[i] <= c && c <= set[i + 2];
+                    i += 3;
+                } else {
+                    found |= set[i] == c;
+                    i += 1;
+                }
+            }
+            found != negate && wildcard_match(&rest[end + 1..], name_rest)
+        },
+        Some((&p, rest)) => name.first() == Some(&p) && wildcard_match(rest, &name[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match(b"*.log", b"a.log"));
+        assert!(wildcard_match(b"*.log", b".log"));
+        assert!(!wildcard_match(b"*.log", b"a.log.gz"));
+        assert!(wildcard_match(b"part-??.gz", b"part-01.gz"));
+        assert!(wildcard_match(b"part-[0-9][!a].*", b"part-1b.zst"));
+        assert!(!wildcard_match(b"part-[0-9]", b"part-x"));
+        assert!(wildcard_match(b"a[b", b"a[b"));
+    }
+
+    #[test]
+    fn glob_recursive_in_lexical_order() {
+        let root = std::env::temp_dir().join(format!("plumber_files_test_{}", std::process::id()));
+        for file in ["b/2.log", "a/1.log", "a/deep/3.log", "a/skip.txt", "top.log"] {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, file).unwrap();
+        }
+
+        let pattern = format!("{}/**/*.log", root.display());
+        let found: Vec<String> = glob(&pattern).unwrap()
+            .iter()
+            .map(|p| p.strip_prefix(&root).unwrap().display().to_string())
+            .collect();
+        assert_eq!(found, ["a/1.log", "a/deep/3.log", "b/2.log", "top.log"]);
+
+        std::os::unix::fs::symlink(&root, root.join("a/loop")).unwrap();
+        assert_eq!(glob(&pattern).unwrap().len(), 4);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
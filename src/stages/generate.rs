@@ -0,0 +1,124 @@
+//! `generate:<format>` stage, synthetic input for soak tests and benchmarks
+//!
+//! writes numbered records as `lines`, `ndjson` or `csv` (with a header), at `rate` records
+//! per second (0, the default, as fast as the pipe takes them) until `count` records are
+//! written or forever. every record has a `seq`, one of `keys` keys, a value derived from
+//! `seq` and `size` bytes of payload, so the output is the same on every run.
+
+use std::io::{self, BufWriter, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::StageArgs;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Lines,
+    Ndjson,
+    Csv,
+}
+
+struct Generator {
+    format: Format,
+    keys: u64,
+    payload: String,
+}
+
+impl Generator {
+    fn from_args(args: &StageArgs) -> io::Result<Self> {
+        let format = match args.target.as_str() {
+            "" | "lines" => Format::Lines,
+            "ndjson" | "json" => Format::Ndjson,
+            "csv" => Format::Csv,
+            other => return Err(super::invalid(format!("generate writes lines, ndjson or csv, not '{other}'"))),
+        };
+        let keys = super::parse_count(args.get_or("keys", "100"))?.max(1);
+        let size = super::parse_size(args.get_or("size", "64"))?;
+        Ok(Generator { format, keys, payload: "x".repeat(size as usize) })
+    }
+
+    fn header(&self) -> Option<&'static str> {
+        (self.format == Format::Csv).then_some("seq,key,value,payload\n")
+    }
+
+    fn record(&self, seq: u64, out: &mut impl Write) -> io::Result<()> {
+        let key = seq % self.keys;
+        // spread over 0-99.99 without a rng, so every run writes the same
+        let value = seq.wrapping_mul(7919) % 10_000;
+        let (value, cents) = (value / 100, value % 100);
+        match self.format {
+            Format::Lines => writeln!(out, "{seq} key{key} {value}.{cents:02} {}", self.payload),
+            Format::Ndjson => writeln!(
+                out,
+                r#"{{"seq":{seq},"key":"key{key}","value":{value}.{cents:02},"payload":"{}"}}"#,
+                self.payload,
+            ),
+            Format::Csv => writeln!(out, "{seq},key{key},{value}.{cents:02},{}", self.payload),
+        }
+    }
+}
+
+pub fn generate(args: &StageArgs) -> io::Result<()> {
+    let generator = Generator::from_args(args)?;
+    let rate = super::parse_count(args.get_or("rate", "0"))?;
+    let count = super::parse_count(args.get_or("count", "0"))?;
+
+    let mut out = BufWriter::new(io::stdout().lock());
+    let result = (|| {
+        if let Some(header) = generator.header() {
+            out.write_all(header.as_bytes())?;
+        }
+        let started = Instant::now();
+        let mut seq = 0;
+        while count == 0 || seq < count {
+            let due = match rate {
+                0 => seq + 1024,
+                rate => (started.elapsed().as_secs_f64() * rate as f64) as u64,
+            };
+            let due = match count {
+                0 => due,
+                count => due.min(count),
+            };
+            if due <= seq {
+                out.flush()?;
+                thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+            for seq in seq..due {
+                generator.record(seq, &mut out)?;
+            }
+            seq = due;
+        }
+        out.flush()
+    })();
+
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generated(target: &str, options: &[&str], records: u64) -> String {
+        let options: Vec<String> = options.iter().map(|o| o.to_string()).collect();
+        let generator = Generator::from_args(&StageArgs::parse(target, &options).unwrap()).unwrap();
+        let mut out = generator.header().unwrap_or_default().as_bytes().to_vec();
+        for seq in 0..records {
+            generator.record(seq, &mut out).unwrap();
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn writes_formats() {
+        assert_eq!(generated("lines", &["size=3", "keys=2"], 3), "0 key0 0.00 xxx\n1 key1 79.19 xxx\n2 key0 58.38 xxx\n");
+        assert_eq!(generated("csv", &["size=0"], 1), "seq,key,value,payload\n0,key0,0.00,\n");
+        let json = generated("ndjson", &["size=1"], 2);
+        let second = serde_json::from_str::<serde_json::Value>(json.lines().nth(1).unwrap()).unwrap();
+        assert_eq!(second.get("key"), Some(&serde_json::Value::String("key1".to_string())));
+        assert!(Generator::from_args(&StageArgs::parse("xml", &[]).unwrap()).is_err());
+    }
+}
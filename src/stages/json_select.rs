@@ -0,0 +1,173 @@
+//! `json-select:<paths>` stage
+//!
+//! parses each input line as json and emits the selected fields, e.g.
+//! `json-select:.user.id,ts=.meta.timestamp`. a field is a jq style path, optionally
+//! renamed with `name=`; unnamed fields keep their path without the leading dot.
+//!
+//! options: `format` (`ndjson` objects of the selected fields, or `tsv` with strings
+//! written raw and tabs/newlines escaped), `header` (tsv only, writes the field names
+//! first) and `on-error` (`skip` lines that are not json, or `fail` the stage).
+//! missing fields are `null` in ndjson and empty in tsv.
+
+use std::io::{self, BufRead, BufWriter, Write};
+
+use serde_json::Value;
+use super::StageArgs;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Ndjson,
+    Tsv,
+}
+
+struct Field {
+    name: String,
+    /// a json pointer once parsed, see `pointer`
+    path: String,
+}
+
+/// the json pointer, `/a/b/0/c`, of a jq style path such as `.a.b[0].c`; `.` is the value
+/// itself
+fn pointer(path: &str) -> Option<String> {
+    let mut rest = path.strip_prefix('.')?;
+    let mut pointer = String::new();
+    while !rest.is_empty() {
+        pointer.push('/');
+        if let Some(after) = rest.strip_prefix('[') {
+            let end = after.find(']')?;
+            pointer.push_str(&after[..end].parse::<usize>().ok()?.to_string());
+            rest = &after[end + 1..];
+        } else {
+            rest = rest.strip_prefix('.').unwrap_or(rest);
+            let end = rest.find(['.', '[']).unwrap_or(rest.len());
+            if end == 0 {
+                return None;
+            }
+            pointer.push_str(&rest[..end].replace('~', "~0").replace('/', "~1"));
+            rest = &rest[end..];
+        }
+    }
+    Some(pointer)
+}
+
+fn parse_fields(spec: &str) -> io::Result<Vec<Field>> {
+    let fields: Vec<Field> = spec.split(',')
+        .filter(|f| !f.is_empty())
+        .map(|f| match f.split_once('=') {
+            Some((name, path)) => Field { name: name.to_owned(), path: path.to_owned() },
+            None => Field {
+                name: f.strip_prefix('.').unwrap_or(f).to_owned(),
+                path: f.to_owned(),
+            },
+        })
+        .collect();
+
+    if fields.is_empty() {
+        return Err(super::invalid("json-select needs at least one field, e.g. json-select:.a.b".to_string()));
+    }
+    fields.into_iter()
+        .map(|f| match pointer(&f.path) {
+            Some(path) => Ok(Field { name: f.name, path }),
+            None => Err(super::invalid(format!("invalid json path '{}', paths look like .a.b[0]", f.path))),
+        })
+        .collect()
+}
+
+pub fn select(args: &StageArgs) -> io::Result<()> {
+    let fields = parse_fields(&args.target)?;
+    let format = match args.get_or("format", "ndjson") {
+        "ndjson" => Format::Ndjson,
+        "tsv" => Format::Tsv,
+        other => return Err(super::invalid(format!("unknown format '{other}', expected ndjson or tsv"))),
+    };
+    let fail_on_error = match args.get_or("on-error", "skip") {
+        "skip" => false,
+        "fail" => true,
+        other => return Err(super::invalid(format!("unknown on-error '{other}', expected skip or fail"))),
+    };
+
+    let mut out = BufWriter::new(io::stdout().lock());
+    let result = (|| {
+        if format == Format::Tsv && args.parse_or("header", false)? {
+            let names: Vec<&str> = fields.iter().map(|f| f.name.as_str()).collect();
+            writeln!(out, "{}", names.join("\t"))?;
+        }
+
+        let mut skipped = 0u64;
+        for (number, line) in io::stdin().lock().lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let value: Value = match serde_json::from_str(&line) {
+                Ok(value) => value,
+                Err(e) if fail_on_error => return Err(super::invalid(format!("line {}: {e}", number + 1))),
+                Err(_) => {
+                    skipped += 1;
+                    continue;
+                },
+            };
+            writeln!(out, "{}", project(&value, &fields, format))?;
+        }
+        if skipped > 0 {
+            log::warn!("json-select: skipped {skipped} lines that were not valid json");
+        }
+        out.flush()
+    })();
+
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        other => other,
+    }
+}
+
+fn project(value: &Value, fields: &[Field], format: Format) -> String {
+    match format {
+        Format::Ndjson => Value::Object(fields.iter()
+            .map(|f| (f.name.clone(), value.pointer(&f.path).cloned().unwrap_or(Value::Null)))
+            .collect())
+            .to_string(),
+        Format::Tsv => fields.iter()
+            .map(|f| match value.pointer(&f.path) {
+                None | Some(Value::Null) => String::new(),
+                Some(Value::String(s)) => s.replace('\\', "\\\\")
+                    .replace('\t', "\\t")
+                    .replace('\n', "\\n")
+                    .replace('\r', "\\r"),
+                Some(other) => other.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("\t"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn projections() {
+        let fields = parse_fields(".user.id,ts=.meta.ts,.tags[1],.missing").unwrap();
+        let value: Value = serde_json::from_str(r#"{"user":{"id":7},"meta":{"ts":"a\tb"},"tags":["x","y"]}"#).unwrap();
+
+        assert_eq!(
+            project(&value, &fields, Format::Ndjson),
+            r#"{"user.id":7,"ts":"a\tb","tags[1]":"y","missing":null}"#
+        );
+        assert_eq!(project(&value, &fields, Format::Tsv), "7\ta\\tb\ty\t");
+    }
+
+    #[test]
+    fn jq_paths_as_pointers() {
+        assert_eq!(pointer(".a.b[0].c").as_deref(), Some("/a/b/0/c"));
+        assert_eq!(pointer(".").as_deref(), Some(""));
+        assert_eq!(pointer(".a/b~").as_deref(), Some("/a~1b~0"));
+    }
+
+    #[test]
+    fn rejects_bad_paths() {
+        assert!(parse_fields("").is_err());
+        assert!(parse_fields("a.b").is_err());
+        assert!(parse_fields("x=.a[").is_err());
+    }
+}
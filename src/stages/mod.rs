@@ -0,0 +1,369 @@
+//! built-in pseudo-stages
+//!
+//! a stage written as `<kind>:<target>` (e.g. `mqtt-sub:sensors/temp`) is not looked up on
+//! PATH. plumber re-executes itself with the hidden `stage` subcommand instead, so the stage
+//! still runs as an ordinary process in the pipeline and gets its own stderr log.
+//!
+//! any words following the stage are read as `key=value` options:
+//! ```text
+//! mqtt-sub:sensors/# host=broker.lan qos=1 | grep 'temp' | mqtt-pub:alerts host=broker.lan
+//! ```
+
+use std::fs;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+mod csv;
+mod dedupe;
+mod dir;
+mod enrich;
+mod files;
+mod generate;
+mod json_select;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+#[cfg(feature = "nats")]
+mod nats;
+#[cfg(feature = "redis")]
+mod redis;
+mod sample;
+#[cfg(feature = "smtp")]
+mod smtp;
+mod sort;
+
+/// every kind of built-in stage, including ones compiled out by feature flags
+const KINDS: &[&str] = &[
+    "mqtt-sub",
+    "mqtt-pub",
+    "redis-read",
+    "redis-write",
+    "nats-sub",
+    "nats-pub",
+    "smtp",
+    "files",
+    "dir",
+    "dedupe",
+    "json-select",
+    "csv-to-json",
+    "json-to-csv",
+    "enrich",
+    "sort",
+    "sample",
+    "generate",
+];
+
+/// returns the stage kind if `name` refers to a built-in stage
+pub fn builtin_kind(name: &str) -> Option<&str> {
+    let (kind, _) = name.split_once(':')?;
+    KINDS.contains(&kind).then_some(kind)
+}
+
+/// target and options of a built-in stage invocation
+#[derive(Debug, PartialEq)]
+pub struct StageArgs {
+    pub target: String,
+    options: Vec<(String, String)>,
+}
+
+impl StageArgs {
+    pub fn parse(target: &str, args: &[String]) -> io::Result<Self> {
+        let mut options = Vec::new();
+        for arg in args {
+            let Some((key, value)) = arg.split_once('=') else {
+                return Err(invalid(format!("expected key=value option, got '{arg}'")));
+            };
+            options.push((key.to_owned(), value.to_owned()));
+        }
+
+        Ok(StageArgs {
+            target: target.to_owned(),
+            options,
+        })
+    }
+
+    /// for stages configured entirely through options, such as `dedupe:window=1M,field=2`.
+    /// the comma separated options in the target come first, so trailing words override them
+    pub fn options_in_target(&self) -> io::Result<Self> {
+        let in_target: Vec<String> = self.target.split(',')
+            .filter(|o| !o.is_empty())
+            .map(str::to_owned)
+            .collect();
+        let mut args = StageArgs::parse("", &in_target)?;
+        args.options.extend(self.options.iter().cloned());
+        Ok(args)
+    }
+
+    /// last value given for `key`, so later options override earlier ones
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.options.iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn get_or<'a>(&'a self, key: &str, default: &'a str) -> &'a str {
+        self.get(key).unwrap_or(default)
+    }
+
+    /// a secret such as a password, given as `key` or, kept out of `ps` and `/proc/<pid>/cmdline`,
+    /// read from the file in `<key>-file`, without its trailing newline, or the variable in
+    /// `<key>-env`
+    pub fn secret(&self, key: &str) -> io::Result<Option<String>> {
+        if let Some(path) = self.get(&format!("{key}-file")) {
+            let secret = std::fs::read_to_string(path).map_err(|e| invalid(format!("{key}-file {path}: {e}")))?;
+            return Ok(Some(secret.strip_suffix('\n').unwrap_or(&secret).to_owned()));
+        }
+        if let Some(var) = self.get(&format!("{key}-env")) {
+            return std::env::var(var).map(Some).map_err(|_| invalid(format!("{key}-env: ${var} isn't set")));
+        }
+        Ok(self.get(key).map(str::to_owned))
+    }
+
+    pub fn parse_or<T: std::str::FromStr>(&self, key: &str, default: T) -> io::Result<T> {
+        match self.get(key) {
+            Some(value) => value.parse()
+                .map_err(|_| invalid(format!("invalid value for option '{key}': '{value}'"))),
+            None => Ok(default),
+        }
+    }
+}
+
+/// picks the part of a record that stages such as `dedupe` and `sort` compare, either the
+/// whole line or a 1-based `field` split on `delimiter` (whitespace by default)
+pub enum KeyExtractor {
+    Line,
+    Field { index: usize, delimiter: Option<u8> },
+}
+
+impl KeyExtractor {
+    pub fn from_args(args: &StageArgs) -> io::Result<Self> {
+        let Some(field) = args.get("field") else { return Ok(KeyExtractor::Line) };
+        let index: usize = field.parse()
+            .ok()
+            .filter(|&i| i > 0)
+            .ok_or_else(|| invalid(format!("field must be a number starting at 1, got '{field}'")))?;
+        let delimiter = match args.get("delimiter").map(str::as_bytes) {
+            None => None,
+            Some([d]) => Some(*d),
+            Some(_) => return Err(invalid("delimiter must be a single byte".to_string())),
+        };
+        Ok(KeyExtractor::Field { index: index - 1, delimiter })
+    }
+
+    /// missing fields yield an empty key
+    pub fn extract<'a>(&self, line: &'a [u8]) -> &'a [u8] {
+        match self {
+            KeyExtractor::Line => line,
+            KeyExtractor::Field { index, delimiter: Some(d) } => line.split(|b| b == d)
+                .nth(*index)
+                .unwrap_or_default(),
+            KeyExtractor::Field { index, delimiter: None } => line.split(|b| b.is_ascii_whitespace())
+                .filter(|f| !f.is_empty())
+                .nth(*index)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// entry point of the hidden `plumber stage` subcommand
+pub fn run(spec: &str, args: &[String]) -> io::Result<()> {
+    let Some((kind, target)) = spec.split_once(':') else {
+        return Err(invalid(format!("not a built-in stage: '{spec}'")));
+    };
+    let args = StageArgs::parse(target, args)?;
+
+    match kind {
+        "files" => files::read(&args),
+        "dir" => dir::write(&args),
+        "dedupe" => dedupe::filter(&args),
+        "json-select" => json_select::select(&args),
+        "csv-to-json" => csv::to_json(&args),
+        "json-to-csv" => csv::from_json(&args),
+        "enrich" => enrich::enrich(&args),
+        "sort" => sort::sort(&args),
+        "sample" => sample::sample(&args),
+        "generate" => generate::generate(&args),
+        #[cfg(feature = "mqtt")]
+        "mqtt-sub" => mqtt::subscribe(&args),
+        #[cfg(feature = "mqtt")]
+        "mqtt-pub" => mqtt::publish(&args),
+        #[cfg(not(feature = "mqtt"))]
+        "mqtt-sub" | "mqtt-pub" => Err(missing_feature(kind, "mqtt")),
+        #[cfg(feature = "redis")]
+        "redis-read" => redis::read(&args),
+        #[cfg(feature = "redis")]
+        "redis-write" => redis::write(&args),
+        #[cfg(not(feature = "redis"))]
+        "redis-read" | "redis-write" => Err(missing_feature(kind, "redis")),
+        #[cfg(feature = "nats")]
+        "nats-sub" => nats::subscribe(&args),
+        #[cfg(feature = "nats")]
+        "nats-pub" => nats::publish(&args),
+        #[cfg(not(feature = "nats"))]
+        "nats-sub" | "nats-pub" => Err(missing_feature(kind, "nats")),
+        #[cfg(feature = "smtp")]
+        "smtp" => smtp::send(&args),
+        #[cfg(not(feature = "smtp"))]
+        "smtp" => Err(missing_feature(kind, "smtp")),
+        _ => Err(invalid(format!("unknown built-in stage '{kind}'"))),
+    }
+}
+
+/// parses byte sizes such as `512`, `64K`, `1M` or `2G` (powers of 1024)
+pub fn parse_size(value: &str) -> io::Result<u64> {
+    let (digits, unit) = match value.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => value.split_at(i),
+        None => (value, ""),
+    };
+    let shift = match unit.to_ascii_uppercase().trim_end_matches(['B', 'I']) {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        _ => return Err(invalid(format!("invalid size '{value}'"))),
+    };
+    // a shift would drop the high bits, `17179869184G` being 0
+    digits.parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| invalid(format!("invalid size '{value}'")))
+}
+
+/// parses counts such as `1000`, `10K` or `1M` (powers of 1000)
+pub fn parse_count(value: &str) -> io::Result<u64> {
+    let (digits, multiplier) = match value.to_ascii_uppercase().chars().last() {
+        Some('K') => (&value[..value.len() - 1], 1_000),
+        Some('M') => (&value[..value.len() - 1], 1_000_000),
+        Some('G') => (&value[..value.len() - 1], 1_000_000_000),
+        _ => (value, 1),
+    };
+    digits.parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| invalid(format!("invalid count '{value}'")))
+}
+
+pub fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|h| h.trim().to_owned())
+        .unwrap_or_else(|_| "localhost".to_string())
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[allow(dead_code)]
+fn missing_feature(kind: &str, feature: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("stage '{kind}' requires plumber to be built with the '{feature}' feature"),
+    )
+}
+
+/// exponential reconnect delay shared by the network stages
+#[allow(dead_code)]
+pub struct Backoff {
+    current: Duration,
+    max: Duration,
+}
+
+#[allow(dead_code)]
+impl Backoff {
+    pub fn new() -> Self {
+        Backoff {
+            current: Duration::from_millis(500),
+            max: Duration::from_secs(30),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    pub fn wait(&mut self) {
+        thread::sleep(self.current);
+        self.current = (self.current * 2).min(self.max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_kind_requires_colon() {
+        assert_eq!(builtin_kind("mqtt-sub:a/b"), Some("mqtt-sub"));
+        assert_eq!(builtin_kind("mqtt-sub"), None);
+        assert_eq!(builtin_kind("grep"), None);
+        assert_eq!(builtin_kind("nope:a"), None);
+    }
+
+    #[test]
+    fn stage_args_last_option_wins() {
+        let args = StageArgs::parse("topic", &[
+            "host=a".to_string(),
+            "qos=1".to_string(),
+            "host=b".to_string(),
+        ]).unwrap();
+
+        assert_eq!(args.target, "topic");
+        assert_eq!(args.get("host"), Some("b"));
+        assert_eq!(args.parse_or("qos", 0u8).unwrap(), 1);
+        assert_eq!(args.parse_or("port", 1883u16).unwrap(), 1883);
+        assert!(StageArgs::parse("t", &["oops".to_string()]).is_err());
+    }
+
+    #[test]
+    fn secrets_from_files_and_the_environment() {
+        let file = std::env::temp_dir().join(format!("plumber-secret-test-{}", std::process::id()));
+        std::fs::write(&file, "hunter2\n").unwrap();
+        let from_file = StageArgs::parse("t", &[format!("password-file={}", file.display())]).unwrap();
+        assert_eq!(from_file.secret("password").unwrap().as_deref(), Some("hunter2"));
+        std::fs::remove_file(&file).unwrap();
+        assert!(from_file.secret("password").is_err());
+
+        let inline = StageArgs::parse("t", &["password=hunter2".to_string()]).unwrap();
+        assert_eq!(inline.secret("password").unwrap().as_deref(), Some("hunter2"));
+        assert_eq!(inline.secret("token").unwrap(), None);
+        let unset = StageArgs::parse("t", &["password-env=PLUMBER_SECRET_TEST_UNSET".to_string()]).unwrap();
+        assert!(unset.secret("password").is_err());
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("64K").unwrap(), 64 << 10);
+        assert_eq!(parse_size("1MiB").unwrap(), 1 << 20);
+        assert_eq!(parse_size("2g").unwrap(), 2 << 30);
+        assert!(parse_size("1T").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("17179869184G").is_err());
+        assert_eq!(parse_size("17179869183G").unwrap(), 17179869183 << 30);
+        assert_eq!(parse_count("1M").unwrap(), 1_000_000);
+        assert_eq!(parse_count("10k").unwrap(), 10_000);
+        assert!(parse_count("x").is_err());
+        assert!(parse_count("18446744073709552G").is_err());
+    }
+
+    #[test]
+    fn options_in_target_are_overridable() {
+        let args = StageArgs::parse("window=10,field=2", &["window=5".to_string()])
+            .unwrap()
+            .options_in_target()
+            .unwrap();
+        assert_eq!(args.get("window"), Some("5"));
+        assert_eq!(args.get("field"), Some("2"));
+    }
+
+    #[test]
+    fn field_keys() {
+        let ws = KeyExtractor::Field { index: 1, delimiter: None };
+        assert_eq!(ws.extract(b"  a   b c"), b"b");
+        assert_eq!(ws.extract(b"a"), b"");
+
+        let csv = KeyExtractor::Field { index: 2, delimiter: Some(b',') };
+        assert_eq!(csv.extract(b"a,,c"), b"c");
+    }
+}
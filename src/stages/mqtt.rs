@@ -0,0 +1,457 @@
+//! minimal mqtt 3.1.1 client behind the `mqtt-sub` and `mqtt-pub` stages
+//!
+//! options: `host`, `port`, `qos` (0 or 1), `client-id`, `username`, `password` (or
+//! `password-file`/`password-env`, see `StageArgs::secret`), `keepalive` (seconds), `retain`
+//! (pub only) and `with-topic` (sub only, prefixes each line with the topic and a tab).
+//!
+//! with qos 1 a received message is only acknowledged once it has been written to stdout,
+//! and a published line is resent after a reconnect until the broker acknowledges it.
+//! setting an explicit `client-id` also asks the broker to keep the session, so messages
+//! published while plumber is reconnecting are not lost.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use super::{Backoff, StageArgs};
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+const DISCONNECT: u8 = 0xe0;
+
+struct Config {
+    host: String,
+    port: u16,
+    client_id: String,
+    clean_session: bool,
+    username: Option<String>,
+    password: Option<String>,
+    keepalive: u16,
+    qos: u8,
+}
+
+impl Config {
+    fn from_args(args: &StageArgs, role: &str) -> io::Result<Self> {
+        let qos = args.parse_or("qos", 0u8)?;
+        if qos > 1 {
+            return Err(super::invalid(format!("unsupported qos {qos}, expected 0 or 1")));
+        }
+
+        let client_id = match args.get("client-id") {
+            Some(id) => id.to_owned(),
+            None => format!("plumber-{role}-{}", std::process::id()),
+        };
+
+        Ok(Config {
+            host: args.get_or("host", "localhost").to_owned(),
+            port: args.parse_or("port", 1883)?,
+            client_id,
+            clean_session: args.get("client-id").is_none(),
+            username: args.get("username").map(str::to_owned),
+            password: args.secret("password")?,
+            keepalive: args.parse_or("keepalive", 30)?,
+            qos,
+        })
+    }
+
+    fn addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+/// why a subscriber session ended
+enum Disconnect {
+    Broker(io::Error),
+    Output(io::Error),
+}
+
+pub fn subscribe(args: &StageArgs) -> io::Result<()> {
+    let cfg = Config::from_args(args, "sub")?;
+    let with_topic = args.parse_or("with-topic", false)?;
+    let mut stdout = io::stdout().lock();
+    let mut backoff = Backoff::new();
+
+    loop {
+        let err = match subscribe_session(&cfg, &args.target, with_topic, &mut stdout, &mut backoff) {
+            Disconnect::Output(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+            Disconnect::Output(e) => return Err(e),
+            Disconnect::Broker(e) => e,
+        };
+        log::warn!("mqtt-sub: connection to {} lost: {err}, reconnecting", cfg.addr());
+        backoff.wait();
+    }
+}
+
+fn subscribe_session(
+    cfg: &Config,
+    topic: &str,
+    with_topic: bool,
+    out: &mut impl Write,
+    backoff: &mut Backoff) -> Disconnect {
+    let mut conn = match Connection::open(cfg) {
+        Ok(conn) => conn,
+        Err(e) => return Disconnect::Broker(e),
+    };
+    if let Err(e) = conn.subscribe(topic, cfg.qos) {
+        return Disconnect::Broker(e);
+    }
+    log::info!("mqtt-sub: subscribed to '{topic}' on {}", cfg.addr());
+    backoff.reset();
+
+    loop {
+        // receiving qos 0 messages sends nothing, and reads may never time out while they keep
+        // coming, but the broker drops a client it hasn't heard from in 1.5 keepalives
+        if conn.ping_due() {
+            if let Err(e) = conn.ping() {
+                return Disconnect::Broker(e);
+            }
+        }
+        let (header, body) = match conn.read_packet() {
+            Ok(packet) => packet,
+            // the ping is sent above, once due
+            Err(e) if is_timeout(&e) => continue,
+            Err(e) => return Disconnect::Broker(e),
+        };
+        if header & 0xf0 != PUBLISH {
+            continue;
+        }
+
+        let message = match Message::decode(header, &body) {
+            Ok(message) => message,
+            Err(e) => return Disconnect::Broker(e),
+        };
+        if let Err(e) = message.write_line(out, with_topic) {
+            return Disconnect::Output(e);
+        }
+        if let Some(id) = message.packet_id {
+            if let Err(e) = conn.send(PUBACK, &id.to_be_bytes()) {
+                return Disconnect::Broker(e);
+            }
+        }
+    }
+}
+
+pub fn publish(args: &StageArgs) -> io::Result<()> {
+    let cfg = Config::from_args(args, "pub")?;
+    let retain = args.parse_or("retain", false)?;
+    let mut conn: Option<Connection> = None;
+    let mut backoff = Backoff::new();
+
+    for line in io::stdin().lock().split(b'\n') {
+        let payload = line?;
+        loop {
+            let mut current = match conn.take() {
+                Some(c) => c,
+                None => match Connection::open(&cfg) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        log::warn!("mqtt-pub: unable to connect to {}: {e}, retrying", cfg.addr());
+                        backoff.wait();
+                        continue;
+                    },
+                },
+            };
+
+            match current.keep_alive().and_then(|_| current.publish(&args.target, &payload, cfg.qos, retain)) {
+                Ok(()) => {
+                    backoff.reset();
+                    conn = Some(current);
+                    break;
+                },
+                Err(e) => {
+                    log::warn!("mqtt-pub: publish to {} failed: {e}, reconnecting", cfg.addr());
+                    backoff.wait();
+                },
+            }
+        }
+    }
+
+    if let Some(mut conn) = conn {
+        let _ = conn.send(DISCONNECT, &[]);
+    }
+    Ok(())
+}
+
+struct Connection {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    keepalive: Duration,
+    last_sent: Instant,
+    next_id: u16,
+}
+
+impl Connection {
+    fn open(cfg: &Config) -> io::Result<Self> {
+        let stream = TcpStream::connect((cfg.host.as_str(), cfg.port))?;
+        stream.set_nodelay(true)?;
+
+        // wake up in time to ping before the broker considers us gone
+        let keepalive = Duration::from_secs(cfg.keepalive.into());
+        let timeout = match cfg.keepalive {
+            0 => Duration::from_secs(30),
+            _ => keepalive / 2,
+        };
+        stream.set_read_timeout(Some(timeout))?;
+
+        let mut conn = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            stream,
+            keepalive,
+            last_sent: Instant::now(),
+            next_id: 0,
+        };
+        conn.send(CONNECT, &connect_body(cfg))?;
+
+        let (header, body) = conn.read_packet()?;
+        if header != CONNACK || body.len() != 2 {
+            return Err(protocol_error("expected CONNACK"));
+        }
+        match body[1] {
+            0 => Ok(conn),
+            code => Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("broker refused connection (return code {code})"),
+            )),
+        }
+    }
+
+    fn subscribe(&mut self, topic: &str, qos: u8) -> io::Result<()> {
+        let id = self.next_packet_id();
+        let mut body = id.to_be_bytes().to_vec();
+        put_str(&mut body, topic);
+        body.push(qos);
+        self.send(SUBSCRIBE, &body)?;
+
+        loop {
+            let (header, body) = self.read_packet()?;
+            if header != SUBACK || body.get(..2) != Some(&id.to_be_bytes()) {
+                continue;
+            }
+            return match body.get(2) {
+                Some(0x80) | None => Err(protocol_error("broker rejected subscription")),
+                Some(_) => Ok(()),
+            };
+        }
+    }
+
+    fn publish(&mut self, topic: &str, payload: &[u8], qos: u8, retain: bool) -> io::Result<()> {
+        let id = self.next_packet_id();
+        let mut body = Vec::with_capacity(topic.len() + payload.len() + 4);
+        put_str(&mut body, topic);
+        if qos > 0 {
+            body.extend_from_slice(&id.to_be_bytes());
+        }
+        body.extend_from_slice(payload);
+        self.send(PUBLISH | qos << 1 | retain as u8, &body)?;
+
+        if qos == 0 {
+            return Ok(());
+        }
+        loop {
+            let (header, body) = self.read_packet()?;
+            if header == PUBACK && body.get(..2) == Some(&id.to_be_bytes()) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// whether half the keepalive passed since anything was sent, leaving time for a ping
+    fn ping_due(&self) -> bool {
+        !self.keepalive.is_zero() && self.last_sent.elapsed() >= self.keepalive / 2
+    }
+
+    fn keep_alive(&mut self) -> io::Result<()> {
+        if !self.ping_due() {
+            return Ok(());
+        }
+        self.ping()?;
+        loop {
+            let (header, _) = self.read_packet()?;
+            if header == PINGRESP {
+                return Ok(());
+            }
+        }
+    }
+
+    fn ping(&mut self) -> io::Result<()> {
+        self.send(PINGREQ, &[])
+    }
+
+    fn next_packet_id(&mut self) -> u16 {
+        // packet id 0 is reserved
+        self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+        self.next_id
+    }
+
+    fn send(&mut self, header: u8, body: &[u8]) -> io::Result<()> {
+        let mut packet = Vec::with_capacity(body.len() + 5);
+        packet.push(header);
+        put_remaining_length(&mut packet, body.len());
+        packet.extend_from_slice(body);
+        self.stream.write_all(&packet)?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    /// a read timeout before the first byte is returned as is so callers can ping,
+    /// a timeout in the middle of a packet is treated as a broken connection
+    fn read_packet(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        let mut header = [0u8; 1];
+        self.reader.read_exact(&mut header)?;
+
+        let rest = read_remaining_length(&mut self.reader).and_then(|len| {
+            let mut body = vec![0; len];
+            self.reader.read_exact(&mut body)?;
+            Ok(body)
+        });
+
+        match rest {
+            Ok(body) => Ok((header[0], body)),
+            Err(e) if is_timeout(&e) => Err(protocol_error("timed out in the middle of a packet")),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+struct Message<'a> {
+    topic: &'a [u8],
+    packet_id: Option<u16>,
+    payload: &'a [u8],
+}
+
+impl<'a> Message<'a> {
+    fn decode(header: u8, body: &'a [u8]) -> io::Result<Self> {
+        let truncated = || protocol_error("truncated PUBLISH packet");
+        let topic_len = u16::from_be_bytes(body.get(..2).ok_or_else(truncated)?.try_into().unwrap());
+        let mut rest = body.get(2..).ok_or_else(truncated)?;
+        let topic = rest.get(..topic_len as usize).ok_or_else(truncated)?;
+        rest = &rest[topic.len()..];
+
+        let packet_id = match (header >> 1) & 0b11 {
+            0 => None,
+            _ => {
+                let id = rest.get(..2).ok_or_else(truncated)?;
+                rest = &rest[2..];
+                Some(u16::from_be_bytes(id.try_into().unwrap()))
+            },
+        };
+
+        Ok(Message { topic, packet_id, payload: rest })
+    }
+
+    fn write_line(&self, out: &mut impl Write, with_topic: bool) -> io::Result<()> {
+        if with_topic {
+            out.write_all(self.topic)?;
+            out.write_all(b"\t")?;
+        }
+        out.write_all(self.payload)?;
+        if !self.payload.ends_with(b"\n") {
+            out.write_all(b"\n")?;
+        }
+        out.flush()
+    }
+}
+
+fn connect_body(cfg: &Config) -> Vec<u8> {
+    let mut flags = 0u8;
+    if cfg.clean_session {
+        flags |= 0x02;
+    }
+    if cfg.username.is_some() {
+        flags |= 0x80;
+    }
+    if cfg.password.is_some() {
+        flags |= 0x40;
+    }
+
+    let mut body = Vec::new();
+    put_str(&mut body, "MQTT");
+    body.push(4); // protocol level 3.1.1
+    body.push(flags);
+    body.extend_from_slice(&cfg.keepalive.to_be_bytes());
+    put_str(&mut body, &cfg.client_id);
+    for field in [&cfg.username, &cfg.password].into_iter().flatten() {
+        put_str(&mut body, field);
+    }
+    body
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn put_remaining_length(buf: &mut Vec<u8>, mut len: usize) {
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        buf.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+}
+
+fn read_remaining_length(reader: &mut impl Read) -> io::Result<usize> {
+    let mut len = 0;
+    for shift in [0, 7, 14, 21] {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        len |= ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(len);
+        }
+    }
+    Err(protocol_error("malformed remaining length"))
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("mqtt protocol error: {msg}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remaining_length_round_trip() {
+        for len in [0, 127, 128, 16_383, 16_384, 268_435_455] {
+            let mut buf = Vec::new();
+            put_remaining_length(&mut buf, len);
+            assert_eq!(read_remaining_length(&mut buf.as_slice()).unwrap(), len);
+        }
+        assert!(read_remaining_length(&mut [0xff, 0xff, 0xff, 0xff].as_slice()).is_err());
+    }
+
+    #[test]
+    fn decode_qos1_publish() {
+        let mut body = Vec::new();
+        put_str(&mut body, "a/b");
+        body.extend_from_slice(&[0x00, 0x07]);
+        body.extend_from_slice(b"hello");
+
+        let message = Message::decode(PUBLISH | 1 << 1, &body).unwrap();
+        assert_eq!(message.topic, b"a/b");
+        assert_eq!(message.packet_id, Some(7));
+        assert_eq!(message.payload, b"hello");
+
+        let mut out = Vec::new();
+        message.write_line(&mut out, true).unwrap();
+        assert_eq!(out, b"a/b\thello\n");
+
+        assert!(Message::decode(PUBLISH, &[0x00, 0x09, b'a']).is_err());
+    }
+}
@@ -0,0 +1,341 @@
+//! nats core client behind the `nats-sub` and `nats-pub` stages
+//!
+//! options: `host`, `port`, `user`, `password`, `token`, `queue` (sub only, joins a queue
+//! group so several pipelines can share a subject) and `with-subject` (sub only, prefixes
+//! each line with the subject and a tab). `password` and `token` may be read from a file or
+//! the environment instead, `password-file=` or `token-env=`, see `StageArgs::secret`.
+//!
+//! nats core is at-most-once, so lines published while plumber is reconnecting may be
+//! dropped by the server; `nats-pub` does wait for the server to confirm everything it
+//! sent before exiting. between lines it reads whatever the server sent, answering its
+//! PINGs, and fails on the first `-ERR`, such as a publish the user may not make.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::os::fd::AsRawFd;
+
+use super::{Backoff, StageArgs};
+
+struct Config {
+    host: String,
+    port: u16,
+    user: Option<String>,
+    password: Option<String>,
+    token: Option<String>,
+}
+
+impl Config {
+    fn from_args(args: &StageArgs) -> io::Result<Self> {
+        Ok(Config {
+            host: args.get_or("host", "localhost").to_owned(),
+            port: args.parse_or("port", 4222)?,
+            user: args.get("user").map(str::to_owned),
+            password: args.secret("password")?,
+            token: args.secret("token")?,
+        })
+    }
+
+    fn addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    fn connect_line(&self) -> String {
+        let mut fields = vec![
+            r#""verbose":false"#.to_string(),
+            r#""pedantic":false"#.to_string(),
+            r#""lang":"rust""#.to_string(),
+            format!(r#""name":"plumber-{}""#, std::process::id()),
+        ];
+        for (key, value) in [("user", &self.user), ("pass", &self.password), ("auth_token", &self.token)] {
+            if let Some(value) = value {
+                fields.push(format!(r#""{key}":{}"#, serde_json::Value::from(value.as_str())));
+            }
+        }
+        format!("CONNECT {{{}}}\r\n", fields.join(","))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum ServerOp {
+    Msg { subject: String, payload: Vec<u8> },
+    Ping,
+    Pong,
+    Ok,
+    Info,
+}
+
+struct Connection {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Connection {
+    fn open(cfg: &Config) -> io::Result<Self> {
+        let stream = TcpStream::connect((cfg.host.as_str(), cfg.port))?;
+        stream.set_nodelay(true)?;
+        let mut conn = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            stream,
+        };
+
+        if conn.read_op()? != ServerOp::Info {
+            return Err(protocol_error("expected INFO"));
+        }
+        conn.stream.write_all(cfg.connect_line().as_bytes())?;
+        conn.flush()?;
+        Ok(conn)
+    }
+
+    /// writes `message` and reads what the server sent since, without waiting for more
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        self.stream.write_all(message)?;
+        while !self.reader.buffer().is_empty() || readable(&self.stream)? {
+            if self.read_op()? == ServerOp::Ping {
+                self.stream.write_all(b"PONG\r\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// round trips a PING, which also surfaces any -ERR for what was sent before it
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.write_all(b"PING\r\n")?;
+        loop {
+            match self.read_op()? {
+                ServerOp::Pong => return Ok(()),
+                ServerOp::Ping => self.stream.write_all(b"PONG\r\n")?,
+                _ => {},
+            }
+        }
+    }
+
+    fn read_op(&mut self) -> io::Result<ServerOp> {
+        read_op(&mut self.reader)
+    }
+}
+
+pub fn subscribe(args: &StageArgs) -> io::Result<()> {
+    let cfg = Config::from_args(args)?;
+    let with_subject = args.parse_or("with-subject", false)?;
+    let sub = match args.get("queue") {
+        Some(queue) => format!("SUB {} {queue} 1\r\n", args.target),
+        None => format!("SUB {} 1\r\n", args.target),
+    };
+    let mut stdout = io::stdout().lock();
+    let mut backoff = Backoff::new();
+
+    loop {
+        // no PING after SUB, messages may arrive before the PONG would
+        let mut conn = match Connection::open(&cfg).and_then(|mut c| {
+            c.stream.write_all(sub.as_bytes()).map(|_| c)
+        }) {
+            Ok(conn) => conn,
+            Err(e) if refused(&e) => return Err(e),
+            Err(e) => {
+                log::warn!("nats-sub: unable to subscribe on {}: {e}, retrying", cfg.addr());
+                backoff.wait();
+                continue;
+            },
+        };
+        log::info!("nats-sub: subscribed to '{}' on {}", args.target, cfg.addr());
+        backoff.reset();
+
+        let err = loop {
+            match conn.read_op() {
+                Ok(ServerOp::Msg { subject, payload }) => {
+                    let written = write_line(&mut stdout, &subject, &payload, with_subject);
+                    match written {
+                        Ok(()) => {},
+                        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+                        Err(e) => return Err(e),
+                    }
+                },
+                Ok(ServerOp::Ping) => if let Err(e) = conn.stream.write_all(b"PONG\r\n") {
+                    break e;
+                },
+                Ok(_) => {},
+                Err(e) => break e,
+            }
+        };
+        log::warn!("nats-sub: connection to {} lost: {err}, reconnecting", cfg.addr());
+        backoff.wait();
+    }
+}
+
+pub fn publish(args: &StageArgs) -> io::Result<()> {
+    let cfg = Config::from_args(args)?;
+    let mut conn: Option<Connection> = None;
+    let mut backoff = Backoff::new();
+
+    for line in io::stdin().lock().split(b'\n') {
+        let payload = line?;
+        let mut message = format!("PUB {} {}\r\n", args.target, payload.len()).into_bytes();
+        message.extend_from_slice(&payload);
+        message.extend_from_slice(b"\r\n");
+
+        loop {
+            let result = match conn.as_mut() {
+                Some(c) => c.send(&message),
+                None => Connection::open(&cfg).and_then(|c| conn.insert(c).send(&message)),
+            };
+            match result {
+                Ok(()) => {
+                    backoff.reset();
+                    break;
+                },
+                Err(e) if refused(&e) => return Err(e),
+                Err(e) => {
+                    log::warn!("nats-pub: publish to {} failed: {e}, reconnecting", cfg.addr());
+                    conn = None;
+                    backoff.wait();
+                },
+            }
+        }
+    }
+
+    match conn {
+        Some(mut conn) => conn.flush(),
+        None => Ok(()),
+    }
+}
+
+fn write_line(out: &mut impl Write, subject: &str, payload: &[u8], with_subject: bool) -> io::Result<()> {
+    if with_subject {
+        out.write_all(subject.as_bytes())?;
+        out.write_all(b"\t")?;
+    }
+    out.write_all(payload)?;
+    if !payload.ends_with(b"\n") {
+        out.write_all(b"\n")?;
+    }
+    out.flush()
+}
+
+fn read_op(reader: &mut impl BufRead) -> io::Result<ServerOp> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "server closed the connection"));
+    }
+    let line = line.trim_end();
+    let (op, rest) = line.split_once(' ').unwrap_or((line, ""));
+
+    match op.to_ascii_uppercase().as_str() {
+        "MSG" => {
+            // MSG <subject> <sid> [reply-to] <#bytes>
+            let fields: Vec<&str> = rest.split_whitespace().collect();
+            let (Some(subject), Some(len)) = (fields.first(), fields.last()) else {
+                return Err(protocol_error("malformed MSG"));
+            };
+            let len: usize = len.parse().map_err(|_| protocol_error("malformed MSG size"))?;
+            let mut payload = vec![0; len + 2];
+            reader.read_exact(&mut payload)?;
+            payload.truncate(len);
+            Ok(ServerOp::Msg { subject: subject.to_string(), payload })
+        },
+        "PING" => Ok(ServerOp::Ping),
+        "PONG" => Ok(ServerOp::Pong),
+        "+OK" => Ok(ServerOp::Ok),
+        "INFO" => Ok(ServerOp::Info),
+        "-ERR" => Err(io::Error::other(Refused(rest.trim_matches('\'').to_string()))),
+        _ => Err(protocol_error(&format!("unexpected operation '{op}'"))),
+    }
+}
+
+/// an `-ERR` from the server
+#[derive(Debug)]
+struct Refused(String);
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "nats error: {}", self.0)
+    }
+}
+
+impl Error for Refused {}
+
+fn refused(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|e| e.is::<Refused>())
+}
+
+/// whether there's something to read on `stream`, which is closing it as well
+fn readable(stream: &TcpStream) -> io::Result<bool> {
+    let mut poll = libc::pollfd { fd: stream.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+    match unsafe { libc::poll(&mut poll, 1, 0) } {
+        n if n < 0 => Err(io::Error::last_os_error()),
+        n => Ok(n > 0),
+    }
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("nats protocol error: {msg}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_server_ops() {
+        let mut raw: &[u8] = b"INFO {\"server_id\":\"x\"}\r\nMSG a.b 1 _INBOX.r 5\r\nhello\r\nPING\r\n-ERR 'Authorization Violation'\r\n";
+        assert_eq!(read_op(&mut raw).unwrap(), ServerOp::Info);
+        assert_eq!(read_op(&mut raw).unwrap(), ServerOp::Msg {
+            subject: "a.b".to_string(),
+            payload: b"hello".to_vec(),
+        });
+        assert_eq!(read_op(&mut raw).unwrap(), ServerOp::Ping);
+        let err = read_op(&mut raw).unwrap_err();
+        assert!(refused(&err) && err.to_string() == "nats error: Authorization Violation", "{err}");
+        assert_eq!(read_op(&mut raw).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn send_answers_pings_and_fails_on_errors() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            stream.write_all(b"INFO {}\r\n").unwrap();
+            for _ in 0..2 {
+                reader.read_line(&mut line).unwrap();
+            }
+            stream.write_all(b"PONG\r\nPING\r\n").unwrap();
+            // the client answers once it has published
+            while line != "PONG\r\n" {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+            }
+            stream.write_all(b"-ERR 'Permissions Violation for Publish to a'\r\n").unwrap();
+            reader.read_line(&mut line).unwrap();
+        });
+        let cfg = Config { host: "127.0.0.1".to_string(), port, user: None, password: None, token: None };
+        let mut conn = Connection::open(&cfg).unwrap();
+        let err = loop {
+            match conn.send(b"PUB a 1\r\nx\r\n") {
+                Ok(()) => std::thread::sleep(std::time::Duration::from_millis(10)),
+                Err(e) => break e,
+            }
+        };
+        assert!(refused(&err) && err.to_string().contains("Permissions Violation"), "{err}");
+        drop(conn);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn connect_line_quotes_credentials() {
+        let cfg = Config {
+            host: "localhost".to_string(),
+            port: 4222,
+            user: Some("me".to_string()),
+            password: Some("a\"b".to_string()),
+            token: None,
+        };
+        let line = cfg.connect_line();
+        assert!(line.starts_with("CONNECT {"));
+        assert!(line.contains(r#""user":"me","pass":"a\"b""#));
+        assert!(line.ends_with("}\r\n"));
+    }
+}
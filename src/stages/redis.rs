@@ -0,0 +1,373 @@
+//! redis client behind the `redis-read` and `redis-write` stages
+//!
+//! options: `host`, `port`, `password` (or `password-file`/`password-env`), `db`, `type`
+//! (`list` or `stream`), `field` (stream entry field holding the line, defaults to `line`),
+//! `maxlen` (approximate stream cap for `redis-write`), and `group`/`consumer` for reading a
+//! stream through a consumer group.
+//!
+//! lists are written with LPUSH and read with BRPOP, so they behave as a fifo queue.
+//! reading a stream through a consumer group acknowledges each entry only after it has been
+//! written to stdout; a plain stream read starts at new entries (`from=$`) unless told otherwise.
+
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::TcpStream;
+
+use super::{Backoff, StageArgs};
+
+/// seconds a blocking read waits before polling again
+const BLOCK_SECS: u64 = 5;
+
+#[derive(Clone, Copy, PartialEq)]
+enum KeyType {
+    List,
+    Stream,
+}
+
+struct Config {
+    host: String,
+    port: u16,
+    password: Option<String>,
+    db: u32,
+    key_type: KeyType,
+    field: String,
+}
+
+impl Config {
+    fn from_args(args: &StageArgs) -> io::Result<Self> {
+        let key_type = match args.get_or("type", "list") {
+            "list" => KeyType::List,
+            "stream" => KeyType::Stream,
+            other => return Err(super::invalid(format!("unknown redis type '{other}', expected list or stream"))),
+        };
+
+        Ok(Config {
+            host: args.get_or("host", "localhost").to_owned(),
+            port: args.parse_or("port", 6379)?,
+            password: args.secret("password")?,
+            db: args.parse_or("db", 0)?,
+            key_type,
+            field: args.get_or("field", "line").to_owned(),
+        })
+    }
+
+    fn addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Simple(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+impl Reply {
+    fn into_bulk(self) -> Option<Vec<u8>> {
+        match self {
+            Reply::Bulk(bytes) => bytes,
+            Reply::Simple(s) => Some(s.into_bytes()),
+            _ => None,
+        }
+    }
+
+    fn into_array(self) -> Vec<Reply> {
+        match self {
+            Reply::Array(Some(items)) => items,
+            _ => Vec::new(),
+        }
+    }
+}
+
+struct Connection {
+    writer: BufWriter<TcpStream>,
+    reader: BufReader<TcpStream>,
+}
+
+impl Connection {
+    fn open(cfg: &Config) -> io::Result<Self> {
+        let stream = TcpStream::connect((cfg.host.as_str(), cfg.port))?;
+        stream.set_nodelay(true)?;
+        let mut conn = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        };
+
+        if let Some(password) = &cfg.password {
+            conn.call(&[b"AUTH", password.as_bytes()])?;
+        }
+        if cfg.db != 0 {
+            conn.call(&[b"SELECT", cfg.db.to_string().as_bytes()])?;
+        }
+        Ok(conn)
+    }
+
+    fn call(&mut self, args: &[&[u8]]) -> io::Result<Reply> {
+        self.writer.write_all(&encode_command(args))?;
+        self.writer.flush()?;
+        read_reply(&mut self.reader)
+    }
+}
+
+pub fn write(args: &StageArgs) -> io::Result<()> {
+    let cfg = Config::from_args(args)?;
+    let key = args.target.as_bytes();
+    let maxlen = args.get("maxlen").map(str::to_owned);
+    let mut conn: Option<Connection> = None;
+    let mut backoff = Backoff::new();
+
+    for line in io::stdin().lock().split(b'\n') {
+        let line = line?;
+        let command: Vec<&[u8]> = match (cfg.key_type, &maxlen) {
+            (KeyType::List, _) => vec![b"LPUSH", key, &line],
+            (KeyType::Stream, Some(maxlen)) => vec![b"XADD", key, b"MAXLEN", b"~", maxlen.as_bytes(), b"*", cfg.field.as_bytes(), &line],
+            (KeyType::Stream, None) => vec![b"XADD", key, b"*", cfg.field.as_bytes(), &line],
+        };
+
+        loop {
+            let result = match conn.as_mut() {
+                Some(c) => c.call(&command),
+                None => Connection::open(&cfg).and_then(|c| conn.insert(c).call(&command)),
+            };
+            match result {
+                Ok(_) => {
+                    backoff.reset();
+                    break;
+                },
+                // the server answered, retrying the same command will not help
+                Err(e) if e.kind() == io::ErrorKind::Other => return Err(e),
+                Err(e) => {
+                    log::warn!("redis-write: {} unavailable: {e}, reconnecting", cfg.addr());
+                    conn = None;
+                    backoff.wait();
+                },
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn read(args: &StageArgs) -> io::Result<()> {
+    let cfg = Config::from_args(args)?;
+    let mut reader = match (cfg.key_type, args.get("group")) {
+        (KeyType::List, _) => StreamReader::List,
+        (KeyType::Stream, Some(group)) => StreamReader::Group {
+            group: group.to_owned(),
+            consumer: match args.get("consumer") {
+                Some(consumer) => consumer.to_owned(),
+                None => format!("plumber-{}", std::process::id()),
+            },
+        },
+        (KeyType::Stream, None) => StreamReader::Plain {
+            last_id: args.get_or("from", "$").to_owned(),
+        },
+    };
+
+    let key = args.target.as_bytes();
+    let mut stdout = io::stdout().lock();
+    let mut backoff = Backoff::new();
+    let mut conn: Option<Connection> = None;
+
+    loop {
+        let c = match conn.as_mut() {
+            Some(c) => c,
+            None => match Connection::open(&cfg).and_then(|mut c| reader.prepare(&mut c, key).map(|_| c)) {
+                Ok(c) => {
+                    backoff.reset();
+                    conn.insert(c)
+                },
+                Err(e) => {
+                    log::warn!("redis-read: {} unavailable: {e}, retrying", cfg.addr());
+                    backoff.wait();
+                    continue;
+                },
+            },
+        };
+
+        let result = reader.next_batch(c, key, &cfg.field, &mut |line| {
+            stdout.write_all(line)?;
+            stdout.write_all(b"\n")
+        });
+        match result.and_then(|_| stdout.flush()) {
+            Ok(()) => {},
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::Other => return Err(e),
+            Err(e) => {
+                log::warn!("redis-read: lost connection to {}: {e}, reconnecting", cfg.addr());
+                conn = None;
+                backoff.wait();
+            },
+        }
+    }
+}
+
+enum StreamReader {
+    List,
+    Plain { last_id: String },
+    Group { group: String, consumer: String },
+}
+
+impl StreamReader {
+    fn prepare(&self, conn: &mut Connection, key: &[u8]) -> io::Result<()> {
+        let StreamReader::Group { group, .. } = self else { return Ok(()) };
+        match conn.call(&[b"XGROUP", b"CREATE", key, group.as_bytes(), b"$", b"MKSTREAM"]) {
+            Err(e) if e.to_string().contains("BUSYGROUP") => Ok(()),
+            other => other.map(|_| ()),
+        }
+    }
+
+    fn next_batch(
+        &mut self,
+        conn: &mut Connection,
+        key: &[u8],
+        field: &str,
+        emit: &mut dyn FnMut(&[u8]) -> io::Result<()>) -> io::Result<()> {
+        let block = (BLOCK_SECS * 1000).to_string();
+        match self {
+            StreamReader::List => {
+                let reply = conn.call(&[b"BRPOP", key, BLOCK_SECS.to_string().as_bytes()])?;
+                // [key, value] or nil on timeout
+                if let Some(value) = reply.into_array().pop().and_then(Reply::into_bulk) {
+                    emit(&value)?;
+                }
+                Ok(())
+            },
+            StreamReader::Plain { last_id } => {
+                let reply = conn.call(&[b"XREAD", b"BLOCK", block.as_bytes(), b"COUNT", b"100",
+                                        b"STREAMS", key, last_id.as_bytes()])?;
+                for (id, value) in stream_entries(reply, field) {
+                    if let Some(value) = value {
+                        emit(&value)?;
+                    }
+                    *last_id = id;
+                }
+                Ok(())
+            },
+            StreamReader::Group { group, consumer } => {
+                let reply = conn.call(&[b"XREADGROUP", b"GROUP", group.as_bytes(), consumer.as_bytes(),
+                                        b"BLOCK", block.as_bytes(), b"COUNT", b"100", b"STREAMS", key, b">"])?;
+                for (id, value) in stream_entries(reply, field) {
+                    if let Some(value) = value {
+                        emit(&value)?;
+                    }
+                    conn.call(&[b"XACK", key, group.as_bytes(), id.as_bytes()])?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+/// flattens an XREAD reply into `(entry id, value of field)` pairs
+fn stream_entries(reply: Reply, field: &str) -> Vec<(String, Option<Vec<u8>>)> {
+    let mut entries = Vec::new();
+    // [[key, [[id, [field, value, ...]], ...]], ...]
+    for stream in reply.into_array() {
+        let Some(stream_entries) = stream.into_array().pop() else { continue };
+        for entry in stream_entries.into_array() {
+            let mut entry = entry.into_array().into_iter();
+            let Some(id) = entry.next().and_then(Reply::into_bulk) else { continue };
+            let fields: Vec<Vec<u8>> = entry.next()
+                .map(Reply::into_array)
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(Reply::into_bulk)
+                .collect();
+            let value = fields.chunks(2)
+                .find(|pair| pair[0] == field.as_bytes())
+                .and_then(|pair| pair.get(1).cloned());
+            if value.is_none() {
+                log::warn!("redis-read: entry {} has no field '{field}'", String::from_utf8_lossy(&id));
+            }
+            entries.push((String::from_utf8_lossy(&id).into_owned(), value));
+        }
+    }
+    entries
+}
+
+fn encode_command(args: &[&[u8]]) -> Vec<u8> {
+    let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg);
+        buf.extend_from_slice(b"\r\n");
+    }
+    buf
+}
+
+/// server side errors come back as `ErrorKind::Other`, anything else is a transport problem
+fn read_reply(reader: &mut impl BufRead) -> io::Result<Reply> {
+    let mut line = Vec::new();
+    reader.read_until(b'\n', &mut line)?;
+    if !line.ends_with(b"\r\n") {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed mid reply"));
+    }
+    line.truncate(line.len() - 2);
+    let (kind, rest) = line.split_first()
+        .ok_or_else(|| protocol_error("empty reply"))?;
+    let rest = String::from_utf8_lossy(rest).into_owned();
+    let len = || rest.parse::<i64>().map_err(|_| protocol_error("invalid length"));
+
+    match kind {
+        b'+' => Ok(Reply::Simple(rest)),
+        b'-' => Err(io::Error::other(format!("redis error: {rest}"))),
+        b':' => Ok(Reply::Integer(len()?)),
+        b'$' => match len()? {
+            n if n < 0 => Ok(Reply::Bulk(None)),
+            n => {
+                let mut data = vec![0; n as usize + 2];
+                reader.read_exact(&mut data)?;
+                data.truncate(n as usize);
+                Ok(Reply::Bulk(Some(data)))
+            },
+        },
+        b'*' => match len()? {
+            n if n < 0 => Ok(Reply::Array(None)),
+            n => (0..n).map(|_| read_reply(reader))
+                .collect::<io::Result<_>>()
+                .map(|items| Reply::Array(Some(items))),
+        },
+        _ => Err(protocol_error("unknown reply type")),
+    }
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("redis protocol error: {msg}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_lpush() {
+        assert_eq!(
+            encode_command(&[b"LPUSH", b"q", b"a b"]),
+            b"*3\r\n$5\r\nLPUSH\r\n$1\r\nq\r\n$3\r\na b\r\n"
+        );
+    }
+
+    #[test]
+    fn parse_xread_reply() {
+        let raw = b"*1\r\n*2\r\n$1\r\ns\r\n*2\r\n\
+                    *2\r\n$3\r\n1-0\r\n*2\r\n$4\r\nline\r\n$5\r\nhello\r\n\
+                    *2\r\n$3\r\n2-0\r\n*2\r\n$5\r\nother\r\n$1\r\nx\r\n";
+        let reply = read_reply(&mut raw.as_slice()).unwrap();
+
+        assert_eq!(stream_entries(reply, "line"), vec![
+            ("1-0".to_string(), Some(b"hello".to_vec())),
+            ("2-0".to_string(), None),
+        ]);
+    }
+
+    #[test]
+    fn parse_scalar_replies() {
+        assert_eq!(read_reply(&mut b":42\r\n".as_slice()).unwrap(), Reply::Integer(42));
+        assert_eq!(read_reply(&mut b"$-1\r\n".as_slice()).unwrap(), Reply::Bulk(None));
+        assert_eq!(read_reply(&mut b"*-1\r\n".as_slice()).unwrap(), Reply::Array(None));
+        let err = read_reply(&mut b"-WRONGTYPE nope\r\n".as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert!(read_reply(&mut b"$5\r\nab".as_slice()).is_err());
+    }
+}
@@ -0,0 +1,191 @@
+//! `sample:<rate>` and `sample:first=<n>` stages
+//!
+//! `sample:0.01` keeps each record with probability 0.01, `sample:first=1000` keeps the
+//! first 1000 records and then exits like `head`. options go in the target or after it.
+//!
+//! with `field` (and `delimiter`, see `dedupe`) the sample is taken per key: a rate keeps
+//! or drops every record of a key together, decided by a hash of the key, and `first`
+//! keeps the first n records of every key. `seed` makes rate sampling reproducible; keyed
+//! sampling with the same seed picks the same keys across runs and hosts.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufWriter, Write};
+use std::time::SystemTime;
+
+use super::{KeyExtractor, StageArgs};
+
+enum Sampler {
+    Rate { rate: f64, rng: XorShift },
+    KeyRate { rate: f64, seed: u64 },
+    First { remaining: u64 },
+    KeyFirst { limit: u64, counts: HashMap<Vec<u8>, u64> },
+}
+
+impl Sampler {
+    fn keep(&mut self, key: &[u8]) -> bool {
+        match self {
+            Sampler::Rate { rate, rng } => rng.next_f64() < *rate,
+            Sampler::KeyRate { rate, seed } => {
+                let mut hasher = DefaultHasher::new();
+                seed.hash(&mut hasher);
+                key.hash(&mut hasher);
+                unit(hasher.finish()) < *rate
+            },
+            Sampler::First { remaining } => {
+                let keep = *remaining > 0;
+                *remaining = remaining.saturating_sub(1);
+                keep
+            },
+            Sampler::KeyFirst { limit, counts } => {
+                let count = counts.entry(key.to_vec()).or_default();
+                *count += 1;
+                *count <= *limit
+            },
+        }
+    }
+
+    /// true once no further record can be kept
+    fn done(&self) -> bool {
+        matches!(self, Sampler::First { remaining: 0 })
+    }
+}
+
+/// xorshift64*, plenty for sampling and avoids a dependency
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        // the state must never be zero
+        XorShift((seed ^ 0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        unit(self.0.wrapping_mul(0x2545_f491_4f6c_dd1d))
+    }
+}
+
+/// maps a random u64 onto [0, 1)
+fn unit(value: u64) -> f64 {
+    (value >> 11) as f64 / (1u64 << 53) as f64
+}
+
+fn sampler(args: &StageArgs) -> io::Result<(Sampler, KeyExtractor)> {
+    // a bare number in the target is the rate
+    let args = match args.target.parse::<f64>() {
+        Ok(_) => StageArgs {
+            target: format!("rate={}", args.target),
+            options: args.options.clone(),
+        }
+        .options_in_target()?,
+        Err(_) => args.options_in_target()?,
+    };
+    let key = KeyExtractor::from_args(&args)?;
+    let keyed = !matches!(key, KeyExtractor::Line);
+
+    let sampler = match (args.get("rate"), args.get("first")) {
+        (Some(_), None) => {
+            let rate: f64 = args.parse_or("rate", 0.0)?;
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(super::invalid(format!("sample rate must be between 0 and 1, got {rate}")));
+            }
+            let seed = match args.get("seed") {
+                Some(_) => args.parse_or("seed", 0)?,
+                None if keyed => 0,
+                None => SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map(|d| d.as_nanos() as u64)
+                    .unwrap_or_default() ^ std::process::id() as u64,
+            };
+            match keyed {
+                true => Sampler::KeyRate { rate, seed },
+                false => Sampler::Rate { rate, rng: XorShift::new(seed) },
+            }
+        },
+        (None, Some(_)) => {
+            let limit = super::parse_count(args.get_or("first", "0"))?;
+            match keyed {
+                true => Sampler::KeyFirst { limit, counts: HashMap::new() },
+                false => Sampler::First { remaining: limit },
+            }
+        },
+        _ => return Err(super::invalid("sample needs either a rate, e.g. sample:0.01, or first=<n>".to_string())),
+    };
+    Ok((sampler, key))
+}
+
+pub fn sample(args: &StageArgs) -> io::Result<()> {
+    let (mut sampler, key) = sampler(args)?;
+
+    let mut out = BufWriter::new(io::stdout().lock());
+    let result = (|| {
+        for line in io::stdin().lock().split(b'\n') {
+            if sampler.done() {
+                break;
+            }
+            let line = line?;
+            if sampler.keep(key.extract(&line)) {
+                out.write_all(&line)?;
+                out.write_all(b"\n")?;
+            }
+        }
+        out.flush()
+    })();
+
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sampler_for(target: &str, args: &[&str]) -> Sampler {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        sampler(&StageArgs::parse(target, &args).unwrap()).unwrap().0
+    }
+
+    #[test]
+    fn rate_is_roughly_respected() {
+        let mut sampler = sampler_for("0.1", &["seed=7"]);
+        let kept = (0..100_000).filter(|_| sampler.keep(b"")).count();
+        assert!((9_000..11_000).contains(&kept), "kept {kept}");
+    }
+
+    #[test]
+    fn keyed_rate_keeps_keys_together() {
+        let mut a = sampler_for("0.5", &["field=1"]);
+        let mut b = sampler_for("rate=0.5,field=1", &[]);
+        for key in 0..1000u32 {
+            let key = key.to_string();
+            let first = a.keep(key.as_bytes());
+            assert_eq!(first, a.keep(key.as_bytes()));
+            assert_eq!(first, b.keep(key.as_bytes()));
+        }
+    }
+
+    #[test]
+    fn first_per_key() {
+        let mut head = sampler_for("first=2", &[]);
+        assert!(head.keep(b"") && head.keep(b"") && !head.keep(b""));
+        assert!(head.done());
+
+        let mut keyed = sampler_for("first=1", &["field=1"]);
+        assert!(keyed.keep(b"a") && keyed.keep(b"b") && !keyed.keep(b"a"));
+        assert!(!keyed.done());
+    }
+
+    #[test]
+    fn rejects_bad_options() {
+        let args = |target: &str| sampler(&StageArgs::parse(target, &[]).unwrap()).map(|_| ());
+        assert!(args("1.5").is_err());
+        assert!(args("").is_err());
+        assert!(args("rate=0.1,first=10").is_err());
+    }
+}
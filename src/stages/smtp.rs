@@ -0,0 +1,284 @@
+//! smtp client behind the `smtp` sink stage
+//!
+//! reads the pipeline's output until eof and mails it to the comma separated recipients in
+//! the target, e.g. `smtp:ops@example.com,oncall@example.com from=plumber@example.com`.
+//!
+//! options: `host`, `port`, `from`, `subject`, `username`, `password` (AUTH PLAIN, or
+//! `password-file`/`password-env`), `helo`, `mode` (`body` sends the output inline, `attach`
+//! sends a short summary with the output as `attachment-name`) and `retries`.
+//!
+//! transient failures (connection errors and 4xx replies) are retried with backoff, 5xx
+//! replies fail the stage immediately. there is no TLS support, point `host` at a local
+//! relay when the upstream server requires it.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use super::{Backoff, StageArgs};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Body,
+    Attach,
+}
+
+struct Config {
+    host: String,
+    port: u16,
+    from: String,
+    recipients: Vec<String>,
+    subject: String,
+    credentials: Option<(String, String)>,
+    helo: String,
+    mode: Mode,
+    attachment_name: String,
+    pipeline: String,
+}
+
+impl Config {
+    fn from_args(args: &StageArgs) -> io::Result<Self> {
+        let recipients: Vec<String> = args.target.split(',')
+            .map(str::trim)
+            .filter(|r| !r.is_empty())
+            .map(str::to_owned)
+            .collect();
+        if recipients.is_empty() {
+            return Err(super::invalid("smtp stage needs at least one recipient, e.g. smtp:ops@example.com".to_string()));
+        }
+
+        let mode = match args.get_or("mode", "body") {
+            "body" => Mode::Body,
+            "attach" => Mode::Attach,
+            other => return Err(super::invalid(format!("unknown smtp mode '{other}', expected body or attach"))),
+        };
+
+        let pipeline = std::env::var("PLUMBER_PIPELINE").unwrap_or_else(|_| "pipeline".to_string());
+        let credentials = match (args.get("username"), args.secret("password")?) {
+            (Some(user), Some(pass)) => Some((user.to_owned(), pass)),
+            (None, None) => None,
+            _ => return Err(super::invalid("smtp username and password must be given together".to_string())),
+        };
+
+        Ok(Config {
+            host: args.get_or("host", "localhost").to_owned(),
+            port: args.parse_or("port", 25)?,
+            from: args.get_or("from", "plumber@localhost").to_owned(),
+            recipients,
+            subject: args.get("subject")
+                .map(str::to_owned)
+                .unwrap_or_else(|| format!("plumber: output of {pipeline}")),
+            credentials,
+            helo: args.get("helo")
+                .map(str::to_owned)
+                .unwrap_or_else(super::hostname),
+            mode,
+            attachment_name: args.get("attachment-name")
+                .map(str::to_owned)
+                .unwrap_or_else(|| format!("{pipeline}.txt")),
+            pipeline,
+        })
+    }
+}
+
+/// failure of a single delivery attempt
+enum SendError {
+    Transient(io::Error),
+    Permanent(io::Error),
+}
+
+impl From<io::Error> for SendError {
+    fn from(value: io::Error) -> Self {
+        SendError::Transient(value)
+    }
+}
+
+pub fn send(args: &StageArgs) -> io::Result<()> {
+    let cfg = Config::from_args(args)?;
+    let retries: u32 = args.parse_or("retries", 5)?;
+
+    let mut output = Vec::new();
+    io::stdin().lock().read_to_end(&mut output)?;
+    let message = build_message(&cfg, &output);
+
+    let mut backoff = Backoff::new();
+    for attempt in 0..=retries {
+        match deliver(&cfg, &message) {
+            Ok(()) => {
+                log::info!("smtp: mailed {} bytes to {}", output.len(), cfg.recipients.join(", "));
+                return Ok(());
+            },
+            Err(SendError::Permanent(e)) => return Err(e),
+            Err(SendError::Transient(e)) if attempt == retries => return Err(e),
+            Err(SendError::Transient(e)) => {
+                log::warn!("smtp: delivery via {}:{} failed: {e}, retrying", cfg.host, cfg.port);
+                backoff.wait();
+            },
+        }
+    }
+    unreachable!()
+}
+
+fn deliver(cfg: &Config, message: &[u8]) -> Result<(), SendError> {
+    let stream = TcpStream::connect((cfg.host.as_str(), cfg.port))?;
+    stream.set_read_timeout(Some(Duration::from_secs(60)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    expect(&mut reader, 220)?;
+    command(&mut writer, &mut reader, &format!("EHLO {}", cfg.helo), 250)?;
+    if let Some((user, pass)) = &cfg.credentials {
+        let token = base64(format!("\0{user}\0{pass}").as_bytes());
+        command(&mut writer, &mut reader, &format!("AUTH PLAIN {token}"), 235)?;
+    }
+    command(&mut writer, &mut reader, &format!("MAIL FROM:<{}>", cfg.from), 250)?;
+    for rcpt in &cfg.recipients {
+        command(&mut writer, &mut reader, &format!("RCPT TO:<{rcpt}>"), 250)?;
+    }
+    command(&mut writer, &mut reader, "DATA", 354)?;
+    writer.write_all(message)?;
+    writer.write_all(b".\r\n")?;
+    expect(&mut reader, 250)?;
+    let _ = command(&mut writer, &mut reader, "QUIT", 221);
+    Ok(())
+}
+
+fn command(writer: &mut impl Write, reader: &mut impl BufRead, line: &str, code: u16) -> Result<(), SendError> {
+    writer.write_all(line.as_bytes())?;
+    writer.write_all(b"\r\n")?;
+    writer.flush()?;
+    expect(reader, code)
+}
+
+/// reads a possibly multi-line reply and checks its status code
+fn expect(reader: &mut impl BufRead, code: u16) -> Result<(), SendError> {
+    let mut reply = String::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "server closed the connection").into());
+        }
+        reply.push_str(line.trim_end());
+        // "250-..." continues, "250 ..." ends the reply
+        if line.as_bytes().get(3) != Some(&b'-') {
+            break;
+        }
+        reply.push(' ');
+    }
+
+    let got: u16 = reply.get(..3)
+        .and_then(|c| c.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("malformed smtp reply '{reply}'")))?;
+    // 251 "user not local, will forward" is as good as 250
+    if got == code || (code == 250 && got == 251) {
+        return Ok(());
+    }
+
+    let err = io::Error::other(format!("smtp server replied '{reply}'"));
+    match got {
+        400..=499 => Err(SendError::Transient(err)),
+        _ => Err(SendError::Permanent(err)),
+    }
+}
+
+fn build_message(cfg: &Config, output: &[u8]) -> Vec<u8> {
+    let mut msg = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nMIME-Version: 1.0\r\n",
+        cfg.from,
+        cfg.recipients.join(", "),
+        cfg.subject,
+    ).into_bytes();
+
+    match cfg.mode {
+        Mode::Body => {
+            msg.extend_from_slice(b"Content-Type: text/plain; charset=utf-8\r\n\r\n");
+            dot_stuff(&mut msg, output);
+        },
+        Mode::Attach => {
+            let boundary = format!("plumber-{}", std::process::id());
+            let lines = output.iter().filter(|&&b| b == b'\n').count();
+            msg.extend_from_slice(format!(
+                "Content-Type: multipart/mixed; boundary=\"{boundary}\"\r\n\r\n\
+                 --{boundary}\r\n\
+                 Content-Type: text/plain; charset=utf-8\r\n\r\n\
+                 pipeline '{}' produced {lines} lines ({} bytes), attached as {}.\r\n\r\n\
+                 --{boundary}\r\n\
+                 Content-Type: application/octet-stream\r\n\
+                 Content-Transfer-Encoding: base64\r\n\
+                 Content-Disposition: attachment; filename=\"{}\"\r\n\r\n",
+                cfg.pipeline, output.len(), cfg.attachment_name, cfg.attachment_name,
+            ).as_bytes());
+            for chunk in base64(output).as_bytes().chunks(76) {
+                msg.extend_from_slice(chunk);
+                msg.extend_from_slice(b"\r\n");
+            }
+            msg.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+        },
+    }
+    msg
+}
+
+/// copies `body` with CRLF line endings, doubling leading dots so no line ends DATA early
+fn dot_stuff(msg: &mut Vec<u8>, body: &[u8]) {
+    if body.is_empty() {
+        return;
+    }
+    let body = body.strip_suffix(b"\n").unwrap_or(body);
+    for line in body.split(|&b| b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.starts_with(b".") {
+            msg.push(b'.');
+        }
+        msg.extend_from_slice(line);
+        msg.extend_from_slice(b"\r\n");
+    }
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"\0me\0pw"), "AG1lAHB3");
+    }
+
+    #[test]
+    fn dot_stuffing_uses_crlf() {
+        let mut msg = Vec::new();
+        dot_stuff(&mut msg, b"a\n.\n..b\r\nc\n");
+        assert_eq!(msg, b"a\r\n..\r\n...b\r\nc\r\n");
+    }
+
+    #[test]
+    fn multiline_replies() {
+        let mut ok: &[u8] = b"250-mail.example.com\r\n250-PIPELINING\r\n250 HELP\r\n";
+        assert!(expect(&mut ok, 250).is_ok());
+
+        let mut busy: &[u8] = b"451 try again later\r\n";
+        assert!(matches!(expect(&mut busy, 250), Err(SendError::Transient(_))));
+
+        let mut rejected: &[u8] = b"550 no such user\r\n";
+        assert!(matches!(expect(&mut rejected, 250), Err(SendError::Permanent(_))));
+    }
+}
@@ -0,0 +1,239 @@
+//! `sort:` stage
+//!
+//! sorts lines by plain byte comparison, so the order does not depend on the locale of the
+//! host like GNU sort does. options go in the target or after it, e.g. `sort:field=2,numeric=true`.
+//!
+//! options: `field` and `delimiter` pick the key (the whole line by default), `numeric`
+//! compares keys as numbers (keys that are not numbers sort first), `reverse`, `unique`
+//! keeps the first of consecutive records with equal keys, `buffer` (`64M` by default) is
+//! how much is sorted in memory before a sorted run is spilled to `tmpdir`. the runs are
+//! merged at the end of input. records with equal keys keep their input order.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use super::{KeyExtractor, StageArgs};
+
+struct Comparator {
+    key: KeyExtractor,
+    numeric: bool,
+    reverse: bool,
+}
+
+impl Comparator {
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        let (a, b) = (self.key.extract(a), self.key.extract(b));
+        let ordering = match self.numeric {
+            true => number(a).total_cmp(&number(b)),
+            false => a.cmp(b),
+        };
+        match self.reverse {
+            true => ordering.reverse(),
+            false => ordering,
+        }
+    }
+
+    fn same_key(&self, a: &[u8], b: &[u8]) -> bool {
+        self.compare(a, b) == Ordering::Equal
+    }
+}
+
+fn number(key: &[u8]) -> f64 {
+    std::str::from_utf8(key)
+        .ok()
+        .and_then(|k| k.trim().parse().ok())
+        .filter(|n: &f64| !n.is_nan())
+        .unwrap_or(f64::NEG_INFINITY)
+}
+
+/// spilled runs, removed again when the stage ends
+struct Runs {
+    dir: PathBuf,
+    paths: Vec<PathBuf>,
+}
+
+impl Runs {
+    fn spill(&mut self, lines: &[Vec<u8>]) -> io::Result<()> {
+        let path = self.dir.join(format!("plumber-sort-{}-{}", std::process::id(), self.paths.len()));
+        self.paths.push(path.clone());
+        let mut out = BufWriter::new(File::create(&path)?);
+        for line in lines {
+            out.write_all(line)?;
+            out.write_all(b"\n")?;
+        }
+        out.flush()
+    }
+}
+
+impl Drop for Runs {
+    fn drop(&mut self) {
+        for path in &self.paths {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+pub fn sort(args: &StageArgs) -> io::Result<()> {
+    let args = args.options_in_target()?;
+    let comparator = Comparator {
+        key: KeyExtractor::from_args(&args)?,
+        numeric: args.parse_or("numeric", false)?,
+        reverse: args.parse_or("reverse", false)?,
+    };
+    let unique = args.parse_or("unique", false)?;
+    let buffer = super::parse_size(args.get_or("buffer", "64M"))?;
+    let tmpdir = match args.get("tmpdir") {
+        Some(dir) => PathBuf::from(dir),
+        None => std::env::temp_dir(),
+    };
+
+    let mut runs = Runs { dir: tmpdir, paths: Vec::new() };
+    let mut lines = Vec::new();
+    let mut buffered = 0u64;
+    for line in io::stdin().lock().split(b'\n') {
+        let line = line?;
+        // count some overhead per line so many short lines still respect the buffer
+        buffered += line.len() as u64 + 32;
+        lines.push(line);
+        if buffered >= buffer {
+            lines.sort_by(|a, b| comparator.compare(a, b));
+            runs.spill(&lines)?;
+            lines.clear();
+            buffered = 0;
+        }
+    }
+    lines.sort_by(|a, b| comparator.compare(a, b));
+    if !runs.paths.is_empty() {
+        log::info!("sort: merging {} runs spilled to {}", runs.paths.len() + 1, runs.dir.display());
+    }
+
+    let mut out = BufWriter::new(io::stdout().lock());
+    let result = (|| {
+        let mut previous: Option<Vec<u8>> = None;
+        let mut emit = |line: Vec<u8>| -> io::Result<()> {
+            if unique && previous.as_ref().is_some_and(|p| comparator.same_key(p, &line)) {
+                return Ok(());
+            }
+            out.write_all(&line)?;
+            out.write_all(b"\n")?;
+            previous = Some(line);
+            Ok(())
+        };
+
+        match runs.paths.is_empty() {
+            true => lines.into_iter().try_for_each(&mut emit)?,
+            false => merge(&runs.paths, lines, &comparator, &mut emit)?,
+        }
+        out.flush()
+    })();
+
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        other => other,
+    }
+}
+
+/// head of one sorted run during the merge; ties go to the earlier run to keep the sort stable
+struct Head<'a> {
+    line: Vec<u8>,
+    run: usize,
+    comparator: &'a Comparator,
+}
+
+impl Ord for Head<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.comparator.compare(&self.line, &other.line).then(self.run.cmp(&other.run))
+    }
+}
+
+impl PartialOrd for Head<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Head<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Head<'_> {}
+
+/// k-way merge of the spilled runs and the last, in-memory one
+fn merge(
+    paths: &[PathBuf],
+    last: Vec<Vec<u8>>,
+    comparator: &Comparator,
+    emit: &mut impl FnMut(Vec<u8>) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut sources: Vec<Box<dyn Iterator<Item = io::Result<Vec<u8>>>>> = paths.iter()
+        .map(|p| open_run(p))
+        .collect::<io::Result<_>>()?;
+    sources.push(Box::new(last.into_iter().map(Ok)));
+
+    let mut heap = BinaryHeap::new();
+    for (run, source) in sources.iter_mut().enumerate() {
+        if let Some(line) = source.next() {
+            heap.push(Reverse(Head { line: line?, run, comparator }));
+        }
+    }
+    while let Some(Reverse(head)) = heap.pop() {
+        if let Some(line) = sources[head.run].next() {
+            heap.push(Reverse(Head { line: line?, run: head.run, comparator }));
+        }
+        emit(head.line)?;
+    }
+    Ok(())
+}
+
+fn open_run(path: &Path) -> io::Result<Box<dyn Iterator<Item = io::Result<Vec<u8>>>>> {
+    Ok(Box::new(BufReader::new(File::open(path)?).split(b'\n')))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn comparator(numeric: bool, reverse: bool) -> Comparator {
+        Comparator { key: KeyExtractor::Field { index: 0, delimiter: Some(b',') }, numeric, reverse }
+    }
+
+    #[test]
+    fn key_ordering() {
+        let bytes = comparator(false, false);
+        assert_eq!(bytes.compare(b"B,1", b"a,0"), Ordering::Less);
+        assert_eq!(bytes.compare(b"10,x", b"9,x"), Ordering::Less);
+
+        let numeric = comparator(true, false);
+        assert_eq!(numeric.compare(b"10,x", b"9,x"), Ordering::Greater);
+        assert_eq!(numeric.compare(b"-1.5", b"abc"), Ordering::Greater);
+        assert_eq!(comparator(true, true).compare(b"10", b"9"), Ordering::Less);
+    }
+
+    #[test]
+    fn merge_is_stable() {
+        let dir = std::env::temp_dir().join(format!("plumber-sort-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let comparator = comparator(false, false);
+
+        let mut runs = Runs { dir: dir.clone(), paths: Vec::new() };
+        runs.spill(&[b"a,1".to_vec(), b"c,1".to_vec()]).unwrap();
+        runs.spill(&[b"a,2".to_vec(), b"b,2".to_vec()]).unwrap();
+        let last = vec![b"a,3".to_vec(), b"d,3".to_vec()];
+
+        let mut merged = Vec::new();
+        merge(&runs.paths, last, &comparator, &mut |line| {
+            merged.push(String::from_utf8(line).unwrap());
+            Ok(())
+        }).unwrap();
+        assert_eq!(merged, ["a,1", "a,2", "a,3", "b,2", "c,1", "d,3"]);
+
+        drop(runs);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir(&dir).unwrap();
+    }
+}
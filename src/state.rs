@@ -0,0 +1,133 @@
+//! `state.json` in a pipeline's metadata dir, all there is to know about its current or last run
+//!
+//! written when a run starts, with the pid of every stage, again whenever a stage exits or is
+//! respawned, and when the run ends, with how every stage exited and the exit code of the
+//! pipeline, so monitoring doesn't have to piece it together from `.pid`, `.stages` and
+//! `.summary`:
+//!
+//! ```text
+//! {"schema":1,"name":"etl","pipeline":"tail -F app.log | jq .","state":"running","started":"2026-10-16T10:15:02.337Z","stages":[{"stage":"tail","pid":41},{"stage":"jq","pid":42}]}
+//! ```
+//!
+//! an ended run is `"state":"exited"` and has `ended`, `exit_code` and `failed`, and an
+//! `exit_code` or `signal` for every stage. the file is replaced by a rename, so it's never
+//! read half written.
+
+use std::fs;
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::time::SystemTime;
+
+use serde_json::{json, Value};
+use crate::summary::{PipelineExitStatus, RunSummary};
+
+pub const FILE: &str = "state.json";
+/// of `state.json`, bumped along with changes an older reader would trip over
+pub const SCHEMA: u32 = 1;
+
+fn time(t: SystemTime) -> Value {
+    Value::String(humantime::format_rfc3339_millis(t).to_string())
+}
+
+fn header(name: &str, pipeline: &str, state: &str, started: SystemTime) -> Value {
+    json!({
+        "schema": SCHEMA,
+        "name": name,
+        "pipeline": pipeline.trim(),
+        "state": state,
+        "started": time(started),
+    })
+}
+
+/// the run that started at `started` with `stages`, each with its pid if it was spawned
+pub fn running(name: &str, pipeline: &str, started: SystemTime, stages: &[(&str, Option<u32>)]) -> Value {
+    let mut state = header(name, pipeline, "running", started);
+    let stages = stages.iter().map(|(stage, pid)| {
+        let mut stage = json!({ "stage": stage });
+        if let Some(pid) = pid {
+            stage["pid"] = json!(pid);
+        }
+        stage
+    });
+    state["stages"] = Value::Array(stages.collect());
+    state
+}
+
+/// the run `summary` is of, which exited with `status`
+pub fn ended(name: &str, pipeline: &str, summary: &RunSummary, status: &PipelineExitStatus) -> Value {
+    let mut state = header(name, pipeline, "exited", summary.started);
+    state["ended"] = time(summary.ended);
+    state["exit_code"] = json!(status.code());
+    state["failed"] = json!(summary.failed());
+    // superseded by the run it was respawned as
+    let stages = summary.stages.iter().filter(|run| !run.respawned).map(|run| {
+        let mut stage = json!({ "stage": run.stage });
+        if let Some(pid) = run.pid {
+            stage["pid"] = json!(pid);
+        }
+        match run.status.map(|status| (status.code(), status.signal())) {
+            Some((Some(code), _)) => stage["exit_code"] = json!(code),
+            Some((_, Some(signal))) => stage["signal"] = json!(signal),
+            _ => {},
+        }
+        stage
+    });
+    state["stages"] = Value::Array(stages.collect());
+    state
+}
+
+pub fn write(metadata_dir: &Path, state: &Value) -> io::Result<()> {
+    let partial = metadata_dir.join(format!(".{FILE}.partial"));
+    fs::write(&partial, state.to_string())?;
+    fs::rename(partial, metadata_dir.join(FILE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::ExitStatus;
+    use std::time::Duration;
+
+    use crate::summary::StageRun;
+
+    #[test]
+    fn running_then_exited() {
+        let started = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let state = running("etl", "tail -F app.log | jq .\n", started, &[("tail", Some(41)), ("jq", None)]);
+        assert_eq!(state.to_string(), r#"{"schema":1,"name":"etl","pipeline":"tail -F app.log | jq .","state":"running","started":"2023-11-14T22:13:20.000Z","stages":[{"stage":"tail","pid":41},{"stage":"jq"}]}"#);
+
+        let run = |stage: &str, pid, raw| StageRun {
+            stage: stage.to_string(),
+            pid: Some(pid),
+            status: Some(ExitStatus::from_raw(raw)),
+            usage: None,
+            core: None,
+            respawned: false,
+            warned: false,
+            fallback: None,
+        };
+        let summary = RunSummary {
+            started,
+            ended: started + Duration::from_secs(2),
+            stages: vec![run("tail", 41, 15), run("jq", 42, 2 << 8)],
+            outputs: Vec::new(),
+            revision: None,
+            over_budget: false,
+        };
+        let state = ended("etl", "tail -F app.log | jq .", &summary, &summary.exit_status(false));
+        assert_eq!(state.pointer("/state"), Some(&Value::String("exited".to_string())));
+        assert_eq!(state.pointer("/ended"), Some(&Value::String("2023-11-14T22:13:22.000Z".to_string())));
+        assert_eq!(state.pointer("/exit_code"), Some(&json!(2)));
+        assert_eq!(state.pointer("/failed"), Some(&Value::Bool(true)));
+        assert_eq!(state.pointer("/stages/0/signal"), Some(&json!(15)));
+        assert_eq!(state.pointer("/stages/1/pid"), Some(&json!(42)));
+        assert_eq!(state.pointer("/stages/1/exit_code"), Some(&json!(2)));
+
+        let dir = std::env::temp_dir().join(format!("plumber-state-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        write(&dir, &state).unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&fs::read_to_string(dir.join(FILE)).unwrap()).unwrap(), state);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+//! `plumber status`, what plumber is running on this machine
+//!
+//! every pipeline with a metadata dir is listed with its state:
+//! - running while the plumber supervising it, or its first stage, is alive
+//! - stale when it recorded pids but none of them is alive, as after plumber was killed
+//! - exited when it finished and cleaned up after itself
+//!
+//! a recorded pid only counts while the process with it started before the pid was recorded,
+//! so a pid the kernel handed to some other process after plumber was SIGKILLed doesn't keep
+//! the pipeline running.
+//!
+//! the uptime of a running pipeline counts from when its supervisor started it.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::pipeline::Pipeline;
+
+/// files a running pipeline keeps its pids in
+const PID_FILES: [&str; 2] = [".supervisor", ".pid"];
+/// how much later than its pid was recorded a process may seem to have started, `/proc/stat`
+/// giving the boot time to the second
+const START_SLACK: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    Running,
+    Exited,
+    Stale,
+}
+
+impl std::fmt::Display for State {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            State::Running => "running",
+            State::Exited => "exited",
+            State::Stale => "stale",
+        })
+    }
+}
+
+/// one line of `plumber status`
+#[derive(Debug, PartialEq)]
+pub struct Status {
+    pub name: String,
+    pub state: State,
+    /// only of a running pipeline
+    pub uptime: Option<Duration>,
+    /// `None` if its definition is missing or malformed
+    pub stages: Option<usize>,
+}
+
+/// a pid plumber wrote to a file, read back: positive, and above 1 for a process group. a
+/// `0` or `-1` would have `kill` signal plumber's own process group, or every process it may
+pub fn parse_pid(text: &str, group: bool) -> Option<libc::pid_t> {
+    let min = if group { 2 } else { 1 };
+    text.trim().parse().ok().filter(|pid| *pid >= min)
+}
+
+/// whether a process with `pid` exists, even one we may not signal
+pub fn alive(pid: libc::pid_t) -> bool {
+    (unsafe { libc::kill(pid, 0) }) == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// when the process with `pid` started, if `/proc` tells
+fn started(pid: libc::pid_t) -> Option<SystemTime> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // after the command, which may have spaces and parens of its own
+    let ticks: u64 = stat.rsplit_once(')')?.1.split_whitespace().nth(19)?.parse().ok()?;
+    let boot: u64 = fs::read_to_string("/proc/stat").ok()?.lines().find_map(|line| line.strip_prefix("btime "))?.trim().parse().ok()?;
+    let hz = match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+        hz if hz > 0 => hz as u64,
+        _ => return None,
+    };
+    Some(UNIX_EPOCH + Duration::from_secs(boot) + Duration::from_millis(ticks * 1000 / hz))
+}
+
+/// whether the process recorded in `file` is alive and is the one that was recorded there
+pub fn recorded_alive(metadata_dir: &Path, file: &str) -> bool {
+    let path = metadata_dir.join(file);
+    let Some(pid) = fs::read_to_string(&path).ok().and_then(|pid| parse_pid(&pid, false)) else { return false };
+    if !alive(pid) {
+        return false;
+    }
+    let recorded = fs::metadata(&path).and_then(|m| m.modified()).ok();
+    match (started(pid), recorded) {
+        (Some(started), Some(recorded)) => started <= recorded + START_SLACK,
+        _ => true,
+    }
+}
+
+/// whether the plumber supervising the pipeline, or its first stage, is alive
+pub fn running(metadata_dir: &Path) -> bool {
+    PID_FILES.iter().any(|file| recorded_alive(metadata_dir, file))
+}
+
+/// the status of the pipeline kept in `metadata_dir`
+pub fn status(name: String, metadata_dir: &Path, now: SystemTime) -> Status {
+    let recorded = PID_FILES.iter().any(|file| metadata_dir.join(file).exists());
+    let state = match (running(metadata_dir), recorded) {
+        (true, _) => State::Running,
+        (false, true) => State::Stale,
+        (false, false) => State::Exited,
+    };
+    // `.supervisor` is written when the pipeline starts, and again by an upgraded plumber
+    let started = PID_FILES.iter().find_map(|file| fs::metadata(metadata_dir.join(file)).ok()?.modified().ok());
+    let uptime = match state {
+        State::Running => started.map(|started| now.duration_since(started).unwrap_or_default()),
+        _ => None,
+    };
+    let stages = fs::read_to_string(metadata_dir.join(".pipeline"))
+        .ok()
+        .and_then(|raw| Pipeline::stage_command_lines(&raw).ok())
+        .map(|stages| stages.len());
+    Status { name, state, uptime, stages }
+}
+
+/// every pipeline with a dir under `metadata_root`, by name
+pub fn statuses(metadata_root: &Path, now: SystemTime) -> io::Result<Vec<Status>> {
+    let mut statuses = Vec::new();
+    let entries = match fs::read_dir(metadata_root) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(statuses),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let Ok(name) = entry.file_name().into_string() else { continue };
+        statuses.push(status(name, &entry.path(), now));
+    }
+    statuses.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(statuses)
+}
+
+/// an uptime to the second, e.g. `2h 5m 3s`
+pub fn format_uptime(uptime: Duration) -> String {
+    humantime::format_duration(Duration::from_secs(uptime.as_secs())).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_running_exited_and_stale_apart() {
+        let root = std::env::temp_dir().join(format!("plumber-status-test-{}", std::process::id()));
+        for (name, files) in [
+            ("running", vec![(".supervisor", std::process::id().to_string()), (".pipeline", "cat | jq . | wc -l".to_string())]),
+            ("stale", vec![(".pid", i32::MAX.to_string()), (".pipeline", "cat".to_string())]),
+            ("exited", vec![(".summary", "{}".to_string())]),
+        ] {
+            fs::create_dir_all(root.join(name)).unwrap();
+            for (file, content) in files {
+                fs::write(root.join(name).join(file), content).unwrap();
+            }
+        }
+        let later = SystemTime::now() + Duration::from_secs(65);
+        let statuses = statuses(&root, later).unwrap();
+        let states: Vec<(&str, State, Option<usize>)> = statuses.iter().map(|s| (s.name.as_str(), s.state, s.stages)).collect();
+        assert_eq!(states, [("exited", State::Exited, None), ("running", State::Running, Some(3)), ("stale", State::Stale, Some(1))]);
+        assert!(statuses[1].uptime.is_some_and(|uptime| uptime >= Duration::from_secs(64)));
+        assert_eq!(statuses[2].uptime, None);
+        assert_eq!(format_uptime(Duration::from_millis(3_905_700)), "1h 5m 5s");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn pids_that_would_signal_more_than_one_process() {
+        assert_eq!(parse_pid(" 42\n", false), Some(42));
+        assert_eq!(parse_pid("1", false), Some(1));
+        assert_eq!(parse_pid("1", true), None);
+        assert_eq!(parse_pid("0", false), None);
+        assert_eq!(parse_pid("-1", false), None);
+        // a u32 that would wrap to -1 as a pid_t
+        assert_eq!(parse_pid("4294967295", false), None);
+        assert_eq!(parse_pid("", false), None);
+    }
+
+    #[test]
+    fn reused_pids_are_stale() {
+        let dir = std::env::temp_dir().join(format!("plumber-status-reused-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let supervisor = dir.join(".supervisor");
+        fs::write(&supervisor, std::process::id().to_string()).unwrap();
+        assert!(running(&dir));
+        // recorded long before this process started, so it was another one with the same pid
+        let long_ago = started(std::process::id() as libc::pid_t).unwrap() - Duration::from_secs(3600);
+        fs::File::options().write(true).open(&supervisor).unwrap().set_modified(long_ago).unwrap();
+        assert!(!running(&dir));
+        assert_eq!(status("reused".to_string(), &dir, SystemTime::now()).state, State::Stale);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,263 @@
+//! what a run of a pipeline did, kept in `.summary` in its metadata dir once it ends
+//!
+//! for every stage: its pid, how it exited, the cpu time, peak memory and io it took and
+//! where its core dump was collected to, so an expensive or crashing stage can be found after
+//! the fact. each run replaces the summary of the one before.
+
+use std::fs;
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::time::SystemTime;
+
+use crate::budget;
+use serde_json::{json, Value};
+use crate::usage::Usage;
+
+const SUMMARY: &str = ".summary";
+/// of the summary's json, bumped when a field changes or goes away
+pub const SCHEMA: u32 = 1;
+
+/// one stage of a run
+pub struct StageRun {
+    pub stage: String,
+    /// `None` for a stage that exited while plumber was being upgraded
+    pub pid: Option<u32>,
+    pub status: Option<ExitStatus>,
+    pub usage: Option<Usage>,
+    pub core: Option<PathBuf>,
+    /// respawned in place after it exited, see `upstream`
+    pub respawned: bool,
+    /// exited with a code `--on-exit` makes a warning of, see `exits`
+    pub warned: bool,
+    /// the fallback that ran in place of the stage's command, `None` if it was the command
+    pub fallback: Option<String>,
+}
+
+impl StageRun {
+    pub fn succeeded(&self) -> bool {
+        // an exit status lost in an upgrade doesn't count as a failure, nor does one that was
+        // already dealt with by respawning the stage or only a warning
+        self.respawned || self.warned || self.status.is_none_or(|status| status.success())
+    }
+
+    fn to_json(&self) -> Value {
+        let mut run = json!({ "stage": self.stage });
+        if let Some(pid) = self.pid {
+            run["pid"] = json!(pid);
+        }
+        match self.status.map(|status| (status.code(), status.signal())) {
+            Some((Some(code), _)) => run["exit_code"] = json!(code),
+            Some((_, Some(signal))) => run["signal"] = json!(signal),
+            _ => {},
+        }
+        if let Some(usage) = &self.usage {
+            run["user_ms"] = json!(usage.user.as_millis() as u64);
+            run["system_ms"] = json!(usage.system.as_millis() as u64);
+            run["max_rss_kb"] = json!(usage.max_rss);
+            run["read_bytes"] = json!(usage.read);
+            run["written_bytes"] = json!(usage.written);
+            run["storage_read_bytes"] = json!(usage.storage_read);
+            run["storage_written_bytes"] = json!(usage.storage_written);
+        }
+        if let Some(core) = &self.core {
+            run["core"] = json!(core.display().to_string());
+        }
+        if self.respawned {
+            run["respawned"] = json!(true);
+        }
+        if self.warned {
+            run["warned"] = json!(true);
+        }
+        if let Some(fallback) = &self.fallback {
+            run["fallback"] = json!(fallback);
+        }
+        run
+    }
+}
+
+pub struct RunSummary {
+    pub started: SystemTime,
+    pub ended: SystemTime,
+    pub stages: Vec<StageRun>,
+    /// `key=value`s the stages handed on, see `outputs`
+    pub outputs: Vec<(String, String)>,
+    /// commit of the git checkout the plumber file is in, see `revision`
+    pub revision: Option<String>,
+    /// ended for moving more than `--max-run-bytes`, see `budget`
+    pub over_budget: bool,
+}
+
+impl RunSummary {
+    pub fn failed(&self) -> bool {
+        self.over_budget || !self.stages.iter().all(StageRun::succeeded)
+    }
+
+    /// how the pipeline exited, from the last run of every stage
+    pub fn exit_status(&self, pipefail: bool) -> PipelineExitStatus {
+        let stages = self.stages.iter()
+            // superseded by the run it was respawned as
+            .filter(|run| !run.respawned)
+            .map(|run| (run.stage.clone(), run.status.map(|status| if run.warned { 0 } else { exit_code(status) })))
+            .collect();
+        PipelineExitStatus { stages, pipefail, over_budget: self.over_budget }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let time = |t| humantime::format_rfc3339_millis(t).to_string();
+        let mut summary = json!({
+            "started": time(self.started),
+            "ended": time(self.ended),
+            "failed": self.failed(),
+            "stages": self.stages.iter().map(StageRun::to_json).collect::<Vec<_>>(),
+        });
+        if !self.outputs.is_empty() {
+            let outputs = self.outputs.iter().map(|(key, value)| (key.clone(), Value::String(value.clone()))).collect();
+            summary["outputs"] = Value::Object(outputs);
+        }
+        if let Some(revision) = &self.revision {
+            summary["revision"] = json!(revision);
+        }
+        if self.over_budget {
+            summary["over_budget"] = json!(true);
+        }
+        summary
+    }
+
+    pub fn write(&self, metadata_dir: &Path) -> io::Result<()> {
+        fs::write(metadata_dir.join(SUMMARY), self.to_json().to_string())
+    }
+}
+
+/// what a shell reports for `status`, 128 and the signal for a stage killed by one
+pub fn exit_code(status: ExitStatus) -> i32 {
+    status.code().or_else(|| status.signal().map(|signal| 128 + signal)).unwrap_or(1)
+}
+
+/// how a pipeline exited, as a shell would report it, `--pipefail` having it fail when any
+/// stage did
+#[derive(Debug, Clone, PartialEq)]
+pub struct PipelineExitStatus {
+    /// every stage and its exit code, `None` if it was lost in an upgrade
+    pub stages: Vec<(String, Option<i32>)>,
+    pub pipefail: bool,
+    /// ended for moving more than `--max-run-bytes`, which makes it exit 3
+    pub over_budget: bool,
+}
+
+impl PipelineExitStatus {
+    /// that of the last stage, or with `pipefail` of the last stage that failed. 1 for a
+    /// pipeline that didn't run
+    pub fn code(&self) -> i32 {
+        if self.over_budget {
+            return budget::EXIT_CODE;
+        }
+        if self.stages.is_empty() {
+            return 1;
+        }
+        let mut codes = self.stages.iter().map(|(_, code)| code.unwrap_or(0));
+        match self.pipefail {
+            true => codes.rfind(|code| *code != 0).unwrap_or(0),
+            false => codes.next_back().unwrap_or(0),
+        }
+    }
+
+    pub fn success(&self) -> bool {
+        self.code() == 0
+    }
+}
+
+impl std::fmt::Display for PipelineExitStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let stages: Vec<String> = self.stages.iter()
+            .map(|(stage, code)| format!("{stage} {}", code.map_or("?".to_string(), |code| code.to_string())))
+            .collect();
+        match self.over_budget {
+            true => write!(f, "exit code {} (over budget; {})", self.code(), stages.join(", ")),
+            false => write!(f, "exit code {} ({})", self.code(), stages.join(", ")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn summary_json() {
+        let started = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let summary = RunSummary {
+            started,
+            ended: started + Duration::from_millis(1500),
+            stages: vec![
+                StageRun {
+                    stage: "jq".to_string(),
+                    pid: Some(42),
+                    status: Some(ExitStatus::from_raw(11 | 0x80)),
+                    usage: Some(Usage { user: Duration::from_millis(1200), max_rss: 2048, ..Usage::default() }),
+                    core: Some(PathBuf::from("/tmp/plumber/lib/etl/cores/jq.42.core")),
+                    respawned: false,
+                    warned: false,
+                    fallback: Some("gojq .".to_string()),
+                },
+                StageRun { stage: "cat".to_string(), pid: None, status: None, usage: None, core: None, respawned: false, warned: false, fallback: None },
+            ],
+            outputs: vec![("output_file".to_string(), "/data/a.ndjson".to_string())],
+            revision: Some("5d1e0c9".to_string()),
+            over_budget: false,
+        };
+        assert!(summary.failed());
+        let json = summary.to_json();
+        assert_eq!(json.pointer("/ended"), Some(&Value::String("2023-11-14T22:13:21.500Z".to_string())));
+        assert_eq!(json.pointer("/stages/0/signal"), Some(&Value::from(11)));
+        assert_eq!(json.pointer("/stages/0/user_ms"), Some(&Value::from(1200)));
+        assert!(json.pointer("/stages/0/core").is_some());
+        assert_eq!(json.pointer("/stages/0/fallback"), Some(&Value::String("gojq .".to_string())));
+        assert!(json.pointer("/stages/1/pid").is_none());
+        assert_eq!(json.pointer("/outputs/output_file"), Some(&Value::String("/data/a.ndjson".to_string())));
+        assert_eq!(json.pointer("/revision"), Some(&Value::String("5d1e0c9".to_string())));
+        assert!(serde_json::from_str::<Value>(&json.to_string()).is_ok());
+    }
+
+    #[test]
+    fn exit_status_like_a_shell() {
+        let run = |stage: &str, code: i32, respawned| StageRun {
+            stage: stage.to_string(),
+            pid: None,
+            status: Some(ExitStatus::from_raw(code << 8)),
+            usage: None,
+            core: None,
+            respawned,
+            warned: false,
+            fallback: None,
+        };
+        let started = SystemTime::UNIX_EPOCH;
+        let summary = RunSummary {
+            started,
+            ended: started,
+            stages: vec![run("tail", 3, true), run("tail", 0, false), run("jq", 2, false), run("wc", 0, false)],
+            outputs: Vec::new(),
+            revision: None,
+            over_budget: false,
+        };
+        let status = summary.exit_status(false);
+        assert_eq!(status.code(), 0);
+        assert_eq!(status.to_string(), "exit code 0 (tail 0, jq 2, wc 0)");
+        assert_eq!(summary.exit_status(true).code(), 2);
+
+        let killed = StageRun { status: Some(ExitStatus::from_raw(15)), ..run("wc", 0, false) };
+        let summary = RunSummary { started, ended: started, stages: vec![killed], outputs: Vec::new(), revision: None, over_budget: false };
+        assert_eq!(summary.exit_status(false).code(), 143);
+        let warned = StageRun { warned: true, ..run("grep", 1, false) };
+        let summary = RunSummary { started, ended: started, stages: vec![warned], outputs: Vec::new(), revision: None, over_budget: false };
+        assert!(!summary.failed());
+        assert_eq!(summary.exit_status(true).code(), 0);
+        let never_ran = PipelineExitStatus { stages: Vec::new(), pipefail: false, over_budget: false };
+        assert!(!never_ran.success());
+        let over_budget = RunSummary { over_budget: true, ..summary };
+        assert!(over_budget.failed());
+        assert_eq!(over_budget.exit_status(false).to_string(), "exit code 3 (over budget; grep 0)");
+    }
+}
@@ -0,0 +1,147 @@
+//! named placeholders that make one pipeline file a template, filled in with `--set`
+//!
+//! ```text
+//! tar -cf - {{input}} | zstd -T{{threads}} | aws s3 cp - s3://backups/{{name}}.tar.zst
+//! ```
+//! `plumber run backup.plumb --set input=/data --set threads=4 --set name=db` runs it with
+//! every `{{input}}` replaced by `/data` and so on, in the words of a pipeline as the parser
+//! left them, so a value with spaces stays one word. a placeholder without a value is an
+//! error listing all of them. `{{runs.<name>.last.<key>}}` has dots and is left for `outputs`.
+
+use std::sync::OnceLock;
+
+use crate::parser::{ParseError, Problem};
+use crate::pipeline::PipelineCommand;
+
+/// one `--set NAME=VALUE` flag
+#[derive(Debug, Clone, PartialEq)]
+pub struct Set {
+    pub name: String,
+    pub value: String,
+}
+
+impl std::str::FromStr for Set {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s.split_once('=').ok_or_else(|| format!("expected NAME=VALUE, got '{s}'"))?;
+        if !is_name(name) {
+            return Err(format!("'{name}' is not a placeholder name, expected letters, digits, `_` and `-`"));
+        }
+        Ok(Set { name: name.to_owned(), value: value.to_owned() })
+    }
+}
+
+/// the values of `--set`, the last one for a name winning
+static VALUES: OnceLock<Vec<Set>> = OnceLock::new();
+
+/// sets the values placeholders are filled in with, before any pipeline is parsed
+pub fn set(values: Vec<Set>) {
+    let _ = VALUES.set(values);
+}
+
+fn is_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// the placeholders in `s`, each with where its `{{` starts and the `}}` ends
+fn placeholders(s: &str) -> Vec<(usize, usize, &str)> {
+    let mut found = Vec::new();
+    let mut from = 0;
+    while let Some(start) = s[from..].find("{{").map(|start| from + start) {
+        let Some(end) = s[start..].find("}}").map(|end| start + end + 2) else { break };
+        let name = s[start + 2..end - 2].trim();
+        match is_name(name) {
+            true => {
+                found.push((start, end, name));
+                from = end;
+            },
+            false => from = start + 2,
+        }
+    }
+    found
+}
+
+/// `word` with its placeholders filled in from `values`, adding those without a value to
+/// `unresolved`
+fn fill_word(word: &str, values: &[Set], unresolved: &mut Vec<String>) -> String {
+    let mut filled = String::new();
+    let mut rest = 0;
+    for (start, end, name) in placeholders(word) {
+        filled.push_str(&word[rest..start]);
+        match values.iter().rev().find(|set| set.name == name) {
+            Some(set) => filled.push_str(&set.value),
+            None => {
+                filled.push_str(&word[start..end]);
+                if !unresolved.iter().any(|n| n == name) {
+                    unresolved.push(name.to_owned());
+                }
+            },
+        }
+        rest = end;
+    }
+    filled.push_str(&word[rest..]);
+    filled
+}
+
+fn fill_command(command: &mut PipelineCommand, fill: &mut impl FnMut(&mut String)) {
+    fill(&mut command.name);
+    command.args.iter_mut().for_each(&mut *fill);
+    command.env.iter_mut().for_each(|(_, value)| fill(value));
+    let redirects = [&mut command.stdin, &mut command.stdout, &mut command.stderr].into_iter().flatten().map(|redirect| &mut redirect.path);
+    for path in [&mut command.cwd, &mut command.stderr_log].into_iter().flatten().chain(redirects) {
+        if let Some(s) = path.to_str() {
+            let mut s = s.to_owned();
+            fill(&mut s);
+            *path = s.into();
+        }
+    }
+    for fallback in &mut command.fallbacks {
+        fill_command(fallback, fill);
+    }
+}
+
+/// fills in the placeholders of the stages parsed from `input` with the values of `--set`
+pub fn fill(input: &str, commands: &mut [PipelineCommand]) -> Result<(), ParseError> {
+    fill_with(input, commands, VALUES.get().map_or(&[], Vec::as_slice))
+}
+
+fn fill_with(input: &str, commands: &mut [PipelineCommand], values: &[Set]) -> Result<(), ParseError> {
+    let mut unresolved = Vec::new();
+    let mut fill = |word: &mut String| *word = fill_word(word, values, &mut unresolved);
+    for command in commands.iter_mut() {
+        fill_command(command, &mut fill);
+    }
+    if unresolved.is_empty() {
+        return Ok(());
+    }
+    // pointing at the first of them in the definition
+    let at = placeholders(input).iter().find(|(_, _, name)| unresolved.iter().any(|n| n == name)).map_or(0, |(start, _, _)| *start);
+    Err(ParseError::new(input, Problem::Unfilled(unresolved), at))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fills_in_placeholders() {
+        let values: Vec<Set> = vec!["input=/my data".parse().unwrap(), "threads=2".parse().unwrap(), "threads=4".parse().unwrap()];
+        let mut commands = vec![
+            PipelineCommand::new("tar".to_string(), vec!["-cf".to_string(), "-".to_string(), "{{input}}".to_string()]),
+            PipelineCommand::new("zstd".to_string(), vec!["-T{{ threads }}".to_string(), "{{runs.a.last.b}}".to_string()]),
+        ];
+        fill_with("", &mut commands, &values).unwrap();
+        assert_eq!(commands[0].args[2], "/my data");
+        assert_eq!(commands[1].args, ["-T4", "{{runs.a.last.b}}"]);
+
+        let input = "cat {{input}} | head -n {{lines}} | tee {{out}} {{lines}}";
+        let mut commands: Vec<_> = crate::parser::parse(input).unwrap().into_iter()
+            .map(|stage| PipelineCommand::new(stage.words[0].value.clone(), stage.words[1..].iter().map(|word| word.value.clone()).collect()))
+            .collect();
+        let e = fill_with(input, &mut commands, &values).unwrap_err();
+        assert_eq!(e.problem, Problem::Unfilled(vec!["lines".to_string(), "out".to_string()]));
+        assert_eq!(e.at, input.find("{{lines}}").unwrap());
+        assert!("in put=x".parse::<Set>().is_err() && "input".parse::<Set>().is_err());
+    }
+}
@@ -0,0 +1,522 @@
+//! instrumented transport between stages
+//!
+//! normally a stage's stdout is the next stage's stdin. with `--instrument` plumber puts a
+//! relay thread on every link instead, which counts what passes through so throughput can
+//! be reported. the relay moves data with `splice(2)` from pipe to pipe, so it never
+//! copies it through userspace, and grows both pipes while the producer keeps them full.
+//! when splice isn't possible it falls back to batched reads and vectored writes.
+//!
+//! relays run as handlers on the shared `reactor` rather than a thread each. counters are
+//! written to `.links` in the pipeline's metadata dir every second, and a sample of the data
+//! passing through goes to the flight `recorder` as often.
+//!
+//! a `budget` has every link relayed as well, the relays counting what they pass on against
+//! it and stopping once the run is over it.
+//!
+//! independent of instrumentation, `--pipe-size` raises the capacity of the pipes feeding
+//! stages above the 64 KiB default, which helps with bursty producers.
+
+use std::fs::{self, File};
+use std::io::{self, IoSlice, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::os::unix::fs::FileTypeExt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::budget::Budget;
+use serde_json::{json, Value};
+use crate::reactor::{self, Finished, Handler, Wait};
+use crate::stages;
+
+/// largest read buffer of the copying relay, and most bytes it batches into one write
+const MAX_BUFFER: usize = 4 << 20;
+const MIN_BUFFER: usize = 64 << 10;
+/// bytes of the data passing through a link sampled for the flight recorder
+const SAMPLE: usize = 64;
+const SAMPLE_EVERY: Duration = Duration::from_secs(1);
+
+/// counters of the link between two stages
+pub struct Link {
+    pipeline: String,
+    pub from: String,
+    pub to: String,
+    bytes: AtomicU64,
+    /// what relaying costs: syscalls moving data, time spent in them, and bytes that went
+    /// through plumber's memory rather than being spliced
+    calls: AtomicU64,
+    busy_ns: AtomicU64,
+    copied: AtomicU64,
+    /// of the run, see `budget`
+    budget: Option<Arc<Budget>>,
+}
+
+impl Link {
+    pub fn new(pipeline: &str, from: &str, to: &str) -> Self {
+        Link {
+            pipeline: pipeline.to_owned(),
+            from: from.to_owned(),
+            to: to.to_owned(),
+            bytes: AtomicU64::new(0),
+            calls: AtomicU64::new(0),
+            busy_ns: AtomicU64::new(0),
+            copied: AtomicU64::new(0),
+            budget: None,
+        }
+    }
+
+    /// counting what passes through against `budget`
+    pub fn within(self, budget: Option<Arc<Budget>>) -> Self {
+        Link { budget, ..self }
+    }
+
+    /// counts `bytes` passed on, false once the run is over its budget
+    fn passed(&self, bytes: u64) -> bool {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.budget.as_ref().is_none_or(|budget| budget.spend(bytes))
+    }
+
+    fn busy(&self, since: Instant) {
+        self.busy_ns.fetch_add(since.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn record_sample(&self, data: &[u8]) {
+        log::trace!("{}: {} -> {}: {}", self.pipeline, self.from, self.to, data[..data.len().min(SAMPLE)].escape_ascii());
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// counts bytes passed on by something else relaying the link, see `spool`, which copies
+    /// them. false once the run is over its budget
+    pub fn moved(&self, bytes: u64) -> bool {
+        self.copied.fetch_add(bytes, Ordering::Relaxed);
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.passed(bytes)
+    }
+}
+
+/// one `--pipe-size [<stage>=]<size>` flag, the stage being the one reading from the pipe
+#[derive(Debug, Clone, PartialEq)]
+pub struct PipeSize {
+    stage: Option<String>,
+    bytes: u64,
+}
+
+impl std::str::FromStr for PipeSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (stage, size) = match s.split_once('=') {
+            Some((stage, size)) => (Some(stage.to_owned()), size),
+            None => (None, s),
+        };
+        let bytes = stages::parse_size(size).map_err(|e| e.to_string())?;
+        Ok(PipeSize { stage, bytes })
+    }
+}
+
+/// requested capacity of the pipe feeding `stage`; a size for the stage wins over one for all
+pub fn pipe_size(sizes: &[PipeSize], stage: &str) -> Option<u64> {
+    sizes.iter()
+        .rev()
+        .find(|s| s.stage.as_deref() == Some(stage))
+        .or_else(|| sizes.iter().rev().find(|s| s.stage.is_none()))
+        .map(|s| s.bytes)
+}
+
+fn max_pipe_size() -> u64 {
+    fs::read_to_string("/proc/sys/fs/pipe-max-size")
+        .ok()
+        .and_then(|m| m.trim().parse::<u64>().ok())
+        .unwrap_or(1 << 20)
+}
+
+/// sets the capacity of the pipe behind `fd`, limited to `/proc/sys/fs/pipe-max-size`
+/// for unprivileged users. returns the capacity the kernel actually used
+pub fn set_pipe_size(fd: &impl AsRawFd, bytes: u64) -> io::Result<u64> {
+    let max = max_pipe_size();
+    let mut wanted = bytes;
+    if bytes > max && unsafe { libc::geteuid() } != 0 {
+        log::warn!("pipe size {bytes} is above /proc/sys/fs/pipe-max-size, using {max}");
+        wanted = max;
+    }
+    match unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETPIPE_SZ, wanted as libc::c_int) } {
+        n if n < 0 => Err(io::Error::last_os_error()),
+        n => Ok(n as u64),
+    }
+}
+
+fn get_pipe_size(fd: &impl AsRawFd) -> io::Result<usize> {
+    match unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETPIPE_SZ) } {
+        n if n < 0 => Err(io::Error::last_os_error()),
+        n => Ok(n as usize),
+    }
+}
+
+/// pipe with both ends close-on-exec, so only the stage it is handed to inherits an end
+pub fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+/// moves everything from `from` to `to` until the upstream stage closes its stdout or the
+/// downstream stage closes its stdin; dropping the ends passes either on to the other side.
+/// with `grow` the pipes are enlarged while the upstream stage keeps them full
+pub fn relay(from: OwnedFd, to: OwnedFd, link: Arc<Link>, grow: bool) -> Finished {
+    let (from, to) = (File::from(from), File::from(to));
+    if !is_pipe(&from) || !is_pipe(&to) {
+        // epoll can't wait on regular files, so copy on a thread of its own
+        log::debug!("{} -> {}: splice not supported, copying instead", link.from, link.to);
+        let (done, finished) = Finished::channel();
+        thread::spawn(move || {
+            let _done = done;
+            let (mut from, mut to) = (from, to);
+            match copy_all(&mut from, &mut to, &link) {
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {},
+                Err(e) => log::error!("{} -> {}: relay failed: {e}", link.from, link.to),
+                Ok(()) => {},
+            }
+        });
+        return finished;
+    }
+
+    for fd in [&from, &to] {
+        unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) };
+    }
+    let capacity = get_pipe_size(&to).unwrap_or(MIN_BUFFER);
+    reactor::register(Box::new(Splice {
+        from,
+        to,
+        link,
+        grow,
+        capacity,
+        max: max_pipe_size() as usize,
+        scratch: None,
+        sampled: None,
+    }))
+}
+
+fn is_pipe(file: &File) -> bool {
+    file.metadata().is_ok_and(|m| m.file_type().is_fifo())
+}
+
+/// moves data between two non-blocking pipes on the reactor
+struct Splice {
+    from: File,
+    to: File,
+    link: Arc<Link>,
+    grow: bool,
+    /// bytes moved per call; a full pipe per call means fewer wakeups for everyone
+    capacity: usize,
+    max: usize,
+    /// pipe data is teed into for samples, made for the first one
+    scratch: Option<(File, File)>,
+    sampled: Option<Instant>,
+}
+
+impl Handler for Splice {
+    fn poll(&mut self) -> Wait {
+        let started = Instant::now();
+        let wait = self.splice();
+        self.link.busy(started);
+        wait
+    }
+}
+
+impl Splice {
+    /// moves as much as it can without blocking
+    fn splice(&mut self) -> Wait {
+        loop {
+            self.sample();
+            let n = unsafe {
+                libc::splice(
+                    self.from.as_raw_fd(),
+                    std::ptr::null_mut(),
+                    self.to.as_raw_fd(),
+                    std::ptr::null_mut(),
+                    self.capacity,
+                    libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+                )
+            };
+            self.link.calls.fetch_add(1, Ordering::Relaxed);
+            if n == 0 {
+                return Wait::Done;
+            }
+            if n > 0 {
+                // over budget, the supervisor ends the stages
+                if !self.link.passed(n as u64) {
+                    return Wait::Done;
+                }
+                if self.grow && n as usize >= self.capacity && self.capacity < self.max {
+                    self.grow_pipes();
+                }
+                continue;
+            }
+
+            let e = io::Error::last_os_error();
+            match e.kind() {
+                io::ErrorKind::Interrupted => continue,
+                // either side can be the one that would block
+                io::ErrorKind::WouldBlock => return match readable(&self.from) {
+                    0 => Wait::Readable(self.from.as_raw_fd()),
+                    _ => Wait::Writable(self.to.as_raw_fd()),
+                },
+                io::ErrorKind::BrokenPipe => return Wait::Done,
+                _ => {
+                    log::error!("{} -> {}: relay failed: {e}", self.link.from, self.link.to);
+                    return Wait::Done;
+                },
+            }
+        }
+    }
+
+    /// tees the start of what's waiting in `from` to the recorder without taking it out
+    fn sample(&mut self) {
+        if self.sampled.is_some_and(|at| at.elapsed() < SAMPLE_EVERY) {
+            return;
+        }
+        if self.scratch.is_none() {
+            let Ok((read, write)) = pipe() else { return };
+            for fd in [&read, &write] {
+                unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) };
+            }
+            self.scratch = Some((File::from(read), File::from(write)));
+        }
+        let Some((read, write)) = &mut self.scratch else { return };
+        let n = unsafe { libc::tee(self.from.as_raw_fd(), write.as_raw_fd(), SAMPLE, libc::SPLICE_F_NONBLOCK) };
+        if n <= 0 {
+            return;
+        }
+        let mut sample = [0u8; SAMPLE];
+        let Ok(n) = read.read(&mut sample[..n as usize]) else { return };
+        self.sampled = Some(Instant::now());
+        self.link.record_sample(&sample[..n]);
+    }
+
+    fn grow_pipes(&mut self) {
+        let wanted = (self.capacity * 2).min(self.max) as u64;
+        if let (Ok(_), Ok(grown)) = (set_pipe_size(&self.from, wanted), set_pipe_size(&self.to, wanted)) {
+            log::debug!("{} -> {}: grew pipes to {grown} bytes", self.link.from, self.link.to);
+            self.capacity = grown as usize;
+        }
+    }
+}
+
+/// bytes that can be read from `fd` without blocking
+fn readable(fd: &impl AsRawFd) -> usize {
+    let mut available: libc::c_int = 0;
+    match unsafe { libc::ioctl(fd.as_raw_fd(), libc::FIONREAD, &mut available) } {
+        0 => available.max(0) as usize,
+        _ => 0,
+    }
+}
+
+/// doubles the read buffer whenever a read filled it, up to `MAX_BUFFER`
+fn next_buffer_size(current: usize, read: usize) -> usize {
+    match read == current {
+        true => (current * 2).min(MAX_BUFFER),
+        false => current,
+    }
+}
+
+/// fallback when splice can't be used: reads everything that is already available into a
+/// batch of buffers and hands it downstream with a single vectored write
+fn copy_all(from: &mut File, to: &mut File, link: &Link) -> io::Result<()> {
+    let mut buffer_size = MIN_BUFFER;
+    let mut batch: Vec<Vec<u8>> = Vec::new();
+    let mut sampled: Option<Instant> = None;
+    loop {
+        let mut batched = 0;
+        loop {
+            let mut buf = vec![0u8; buffer_size];
+            let n = match from.read(&mut buf) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if n == 0 {
+                break;
+            }
+            buffer_size = next_buffer_size(buffer_size, n);
+            buf.truncate(n);
+            batch.push(buf);
+            batched += n;
+            if batched >= MAX_BUFFER || readable(from) == 0 {
+                break;
+            }
+        }
+        if batch.is_empty() {
+            return Ok(());
+        }
+        if sampled.is_none_or(|at| at.elapsed() >= SAMPLE_EVERY) {
+            sampled = Some(Instant::now());
+            link.record_sample(&batch[0]);
+        }
+
+        // reads block until upstream writes, only the writes count as busy
+        let started = Instant::now();
+        let mut slices: Vec<IoSlice> = batch.iter().map(|b| IoSlice::new(b)).collect();
+        let mut slices = &mut slices[..];
+        while !slices.is_empty() {
+            link.calls.fetch_add(1, Ordering::Relaxed);
+            match to.write_vectored(slices) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => IoSlice::advance_slices(&mut slices, n),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+        link.busy(started);
+        link.copied.fetch_add(batched as u64, Ordering::Relaxed);
+        if !link.passed(batched as u64) {
+            return Ok(());
+        }
+        batch.clear();
+    }
+}
+
+/// tracks throughput between two snapshots of the counters
+pub struct Stats {
+    links: Vec<Arc<Link>>,
+    last: Vec<u64>,
+    last_at: Instant,
+}
+
+impl Stats {
+    pub fn new(links: Vec<Arc<Link>>) -> Self {
+        let last = vec![0; links.len()];
+        Stats { links, last, last_at: Instant::now() }
+    }
+
+    /// writes the counters and the throughput since the previous write
+    pub fn write(&mut self, metadata_dir: &Path) -> io::Result<()> {
+        let elapsed = self.last_at.elapsed().as_secs_f64().max(0.001);
+        self.last_at = Instant::now();
+        let mut entries = Vec::new();
+        for (link, last) in self.links.iter().zip(&mut self.last) {
+            let bytes = link.bytes();
+            let rate = (bytes - *last) as f64 / elapsed;
+            *last = bytes;
+            entries.push(json!({
+                "from": link.from,
+                "to": link.to,
+                "bytes": bytes,
+                "bytes_per_sec": rate.round() as u64,
+                "calls": link.calls.load(Ordering::Relaxed),
+                "busy_us": link.busy_ns.load(Ordering::Relaxed) / 1000,
+                "copied_bytes": link.copied.load(Ordering::Relaxed),
+            }));
+        }
+        let tmp = metadata_dir.join(".links.tmp");
+        fs::write(&tmp, format!("{}\n", Value::Array(entries)))?;
+        fs::rename(tmp, metadata_dir.join(".links"))
+    }
+}
+
+/// annotation per link from a `.links` file, e.g. `1.5 MiB/s, 20.0 MiB total`
+pub fn read_annotations(metadata_dir: &Path) -> Vec<Option<String>> {
+    let Ok(raw) = fs::read_to_string(metadata_dir.join(".links")) else { return Vec::new() };
+    let Ok(Value::Array(entries)) = serde_json::from_str::<Value>(raw.trim()) else { return Vec::new() };
+    let number = |entry: &Value, key: &str| entry.get(key).and_then(Value::as_f64);
+    entries.iter()
+        .map(|entry| {
+            let bytes = number(entry, "bytes")?;
+            let rate = number(entry, "bytes_per_sec")?;
+            Some(format!("{}/s, {} total", format_bytes(rate), format_bytes(bytes)))
+        })
+        .collect()
+}
+
+pub fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{value:.0} {}", UNITS[unit]),
+        _ => format!("{value:.1} {}", UNITS[unit]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relays_between_pipes() {
+        let (upstream_read, upstream_write) = pipe().unwrap();
+        let (downstream_read, downstream_write) = pipe().unwrap();
+        let link = Arc::new(Link::new("p", "a", "b"));
+        let finished = relay(upstream_read, downstream_write, link.clone(), true);
+
+        let data = vec![7u8; 300_000];
+        let writer = {
+            let data = data.clone();
+            thread::spawn(move || File::from(upstream_write).write_all(&data).unwrap())
+        };
+        let mut received = Vec::new();
+        File::from(downstream_read).read_to_end(&mut received).unwrap();
+        writer.join().unwrap();
+        finished.wait();
+
+        assert_eq!(received, data);
+        assert_eq!(link.bytes(), 300_000);
+    }
+
+    #[test]
+    fn copies_when_splice_is_unsupported() {
+        let dir = std::env::temp_dir().join(format!("plumber-transport-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("in"), b"hello").unwrap();
+        let from = OwnedFd::from(File::open(dir.join("in")).unwrap());
+        let to = OwnedFd::from(File::create(dir.join("out")).unwrap());
+        let link = Arc::new(Link::new("p", "a", "b"));
+        relay(from, to, link.clone(), false).wait();
+        assert_eq!(fs::read(dir.join("out")).unwrap(), b"hello");
+        assert_eq!(link.bytes(), 5);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn buffers_grow_while_reads_fill_them() {
+        assert_eq!(next_buffer_size(MIN_BUFFER, MIN_BUFFER), MIN_BUFFER * 2);
+        assert_eq!(next_buffer_size(MIN_BUFFER, 10), MIN_BUFFER);
+        assert_eq!(next_buffer_size(MAX_BUFFER, MAX_BUFFER), MAX_BUFFER);
+    }
+
+    #[test]
+    fn pipe_sizes() {
+        let sizes: Vec<PipeSize> = vec!["1M".parse().unwrap(), "grep=256K".parse().unwrap()];
+        assert_eq!(pipe_size(&sizes, "grep"), Some(256 << 10));
+        assert_eq!(pipe_size(&sizes, "wc"), Some(1 << 20));
+        assert_eq!(pipe_size(&[], "wc"), None);
+        assert!("grep=lots".parse::<PipeSize>().is_err());
+
+        let (read, _write) = pipe().unwrap();
+        assert_eq!(set_pipe_size(&read, 256 << 10).unwrap(), 256 << 10);
+    }
+
+    #[test]
+    fn stats_round_trip() {
+        let dir = std::env::temp_dir().join(format!("plumber-stats-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let link = Arc::new(Link::new("p", "cat", "grep"));
+        link.bytes.store(3 << 20, Ordering::Relaxed);
+        Stats::new(vec![link]).write(&dir).unwrap();
+        let annotations = read_annotations(&dir);
+        assert_eq!(annotations.len(), 1);
+        assert!(annotations[0].as_ref().unwrap().ends_with("/s, 3.0 MiB total"));
+        fs::remove_dir_all(dir).unwrap();
+        assert_eq!(format_bytes(512.0), "512 B");
+    }
+}
@@ -0,0 +1,102 @@
+//! `plumber self-update`
+//!
+//! downloads the release binary for this platform, `plumber-<arch>-<os>`, and its ssh
+//! signature `plumber-<arch>-<os>.sig` with curl, checks the signature against an
+//! `allowed_signers` file, and renames the binary over the running one. releases are signed
+//! with `ssh-keygen -Y sign -n plumber-release -f <key> plumber-x86_64-linux`.
+//!
+//! running pipelines keep the old binary until `plumber upgrade-daemon` hands them over.
+
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::{sha256, signature, upgrade};
+
+pub const DEFAULT_URL: &str = "https://github.com/mike-saparov/plumber/releases/latest/download";
+const NAMESPACE: &str = "plumber-release";
+
+#[derive(Debug)]
+pub enum UpdateError {
+    Download(String),
+    BadSignature(String),
+    Broken(String),
+    Install(std::io::Error),
+}
+
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpdateError::Download(reason) => write!(f, "download failed: {reason}"),
+            UpdateError::BadSignature(reason) => write!(f, "release signature rejected: {reason}"),
+            UpdateError::Broken(reason) => write!(f, "downloaded binary doesn't run: {reason}"),
+            UpdateError::Install(e) => write!(f, "unable to replace binary: {e}"),
+        }
+    }
+}
+
+pub enum Updated {
+    AlreadyCurrent,
+    /// signer of the installed release
+    Installed { signer: String, version: String },
+}
+
+/// name of the release asset for this platform
+fn asset() -> String {
+    format!("plumber-{}-{}", std::env::consts::ARCH, std::env::consts::OS)
+}
+
+/// removed on drop, so a failed update leaves nothing behind
+struct Download(PathBuf);
+
+impl Drop for Download {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+fn download(url: &str, to: &Path) -> Result<Download, UpdateError> {
+    let download = Download(to.to_owned());
+    let output = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location", "--proto", "=https,file", "--output"])
+        .arg(to)
+        .arg(url)
+        .output()
+        .map_err(|e| UpdateError::Download(format!("unable to run curl: {e}")))?;
+    match output.status.success() {
+        true => Ok(download),
+        false => Err(UpdateError::Download(format!("{url}: {}", String::from_utf8_lossy(&output.stderr).trim()))),
+    }
+}
+
+/// replaces the running binary with the latest signed release under `base_url`
+pub fn self_update(base_url: &str, allowed_signers: &Path) -> Result<Updated, UpdateError> {
+    let exe = upgrade::current_exe();
+    // next to the binary, so the rename is atomic
+    let dir = exe.parent().unwrap_or(Path::new("/"));
+    let url = format!("{}/{}", base_url.trim_end_matches('/'), asset());
+    let binary = download(&url, &dir.join(format!(".plumber-update-{}", std::process::id())))?;
+    let signature = download(&format!("{url}.sig"), &dir.join(format!(".plumber-update-{}.sig", std::process::id())))?;
+
+    let content = fs::read(&binary.0).map_err(|e| UpdateError::Download(e.to_string()))?;
+    let signer = signature::verify(NAMESPACE, &content, &signature.0, allowed_signers)
+        .map_err(UpdateError::BadSignature)?;
+    if fs::read(&exe).is_ok_and(|current| sha256::hex_digest(&current) == sha256::hex_digest(&content)) {
+        return Ok(Updated::AlreadyCurrent);
+    }
+
+    fs::set_permissions(&binary.0, fs::Permissions::from_mode(0o755)).map_err(UpdateError::Install)?;
+    let output = Command::new(&binary.0)
+        .arg("--version")
+        .output()
+        .map_err(|e| UpdateError::Broken(e.to_string()))?;
+    if !output.status.success() {
+        return Err(UpdateError::Broken(String::from_utf8_lossy(&output.stderr).trim().to_owned()));
+    }
+    let version = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+
+    fs::rename(&binary.0, &exe).map_err(UpdateError::Install)?;
+    Ok(Updated::Installed { signer, version })
+}
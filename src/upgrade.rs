@@ -0,0 +1,233 @@
+//! in-place upgrades of a running plumber
+//!
+//! `plumber upgrade-daemon <name>` sends SIGUSR2 to the plumber supervising a pipeline. it
+//! re-executes its binary, by then the upgraded one, with the arguments it was started with.
+//! the pid stays the same, so the stages remain its children and keep running; the new binary
+//! adopts them from a handoff written to an inherited memfd named by `$PLUMBER_HANDOFF`,
+//! holding a pidfd for every stage of every supervised pipeline.
+//!
+//! relays, syscall observers, log captures and pipe ends held for `--on-upstream-exit` live in
+//! plumber's memory and can't be handed over, so an upgrade is refused while a pipeline using
+//! them is supervised.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::thread;
+
+use serde_json::Value;
+use crate::recorder;
+use crate::usage::{self, Usage};
+
+const HANDOFF_ENV: &str = "PLUMBER_HANDOFF";
+/// format of the handoff, both binaries of an upgrade need to agree on it
+pub const SCHEMA: u32 = 1;
+
+/// a pipeline's stages as far as an upgrade is concerned
+pub struct Supervised {
+    /// pid of every stage, `None` once it has been waited for
+    pub stages: Vec<Option<u32>>,
+    pub transferable: bool,
+}
+
+static SUPERVISED: Mutex<BTreeMap<String, Supervised>> = Mutex::new(BTreeMap::new());
+static HANDED_OFF: OnceLock<Mutex<HashMap<String, Vec<Option<Adopted>>>>> = OnceLock::new();
+
+/// pipelines being supervised; hold it while changing stages, so an upgrade can't cut in
+pub fn supervised() -> MutexGuard<'static, BTreeMap<String, Supervised>> {
+    SUPERVISED.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// a stage started by the plumber this one replaced
+pub struct Adopted {
+    pub pid: u32,
+    pidfd: OwnedFd,
+}
+
+impl Adopted {
+    /// waits for the stage to exit
+    pub fn wait(&self) -> io::Result<(ExitStatus, Usage)> {
+        usage::reap_pidfd(&self.pidfd)
+    }
+
+    /// another fd of its pidfd, readable once it exited
+    pub fn pidfd(&self) -> io::Result<OwnedFd> {
+        self.pidfd.try_clone()
+    }
+}
+
+/// stages handed over for `pipeline`, once; `None` for a stage that exited during the upgrade
+pub fn adopt(pipeline: &str) -> Option<Vec<Option<Adopted>>> {
+    HANDED_OFF.get()?.lock().unwrap().remove(pipeline)
+}
+
+/// whether `pipeline` has stages waiting to be adopted
+pub fn handed_off(pipeline: &str) -> bool {
+    HANDED_OFF.get().is_some_and(|h| h.lock().unwrap().contains_key(pipeline))
+}
+
+/// picks up a handoff from the plumber this one replaced and starts listening for upgrades.
+/// called first thing, before any other thread exists
+pub fn init() {
+    if let Some(fd) = std::env::var(HANDOFF_ENV).ok().and_then(|fd| fd.parse::<RawFd>().ok()) {
+        std::env::remove_var(HANDOFF_ENV);
+        let mut handoff = String::new();
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        let read = file.seek(SeekFrom::Start(0)).and_then(|_| file.read_to_string(&mut handoff));
+        match read.map_err(|e| e.to_string()).and_then(|_| serde_json::from_str::<Value>(&handoff).map_err(|e| e.to_string())) {
+            Ok(handoff) => {
+                let _ = HANDED_OFF.set(Mutex::new(parse_handoff(&handoff)));
+            },
+            Err(e) => log::error!("unable to read upgrade handoff: {e}"),
+        }
+    }
+
+    // blocked in every thread, so only the upgrade thread sees them. it also takes the
+    // SIGUSR1 of `plumber dump`. stages start with an empty signal mask
+    unsafe {
+        let mut signals: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGUSR1);
+        libc::sigaddset(&mut signals, libc::SIGUSR2);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut());
+        thread::spawn(move || loop {
+            let mut signal = 0;
+            if libc::sigwait(&signals, &mut signal) != 0 {
+                continue;
+            }
+            match signal {
+                libc::SIGUSR1 => recorder::dump_all(),
+                libc::SIGUSR2 => upgrade(),
+                _ => {},
+            }
+        });
+    }
+}
+
+fn parse_handoff(handoff: &Value) -> HashMap<String, Vec<Option<Adopted>>> {
+    let Value::Object(pipelines) = handoff else { return HashMap::new() };
+    let number = |stage: &Value, key| stage.get(key).and_then(Value::as_i64);
+    pipelines.iter()
+        .map(|(name, stages)| {
+            let Value::Array(stages) = stages else { return (name.clone(), Vec::new()) };
+            let stages = stages.iter()
+                .map(|stage| {
+                    let (pid, pidfd) = (number(stage, "pid")?, number(stage, "pidfd")?);
+                    let pidfd = unsafe { OwnedFd::from_raw_fd(pidfd as RawFd) };
+                    // not passed on to stages spawned from now on
+                    unsafe { libc::fcntl(pidfd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) };
+                    Some(Adopted { pid: pid as u32, pidfd })
+                })
+                .collect();
+            (name.clone(), stages)
+        })
+        .collect()
+}
+
+/// re-executes plumber, handing over the supervised stages. only returns if that failed
+fn upgrade() {
+    let supervised = supervised();
+    if let Some((name, _)) = supervised.iter().find(|(_, s)| !s.transferable) {
+        log::error!("{name}: instrumented, observed, log filtered or upstream exit handling pipelines can't be handed over, not upgrading");
+        return;
+    }
+
+    let mut pidfds = Vec::new();
+    let mut pipelines = serde_json::Map::new();
+    for (name, pipeline) in supervised.iter() {
+        let stages = pipeline.stages.iter()
+            .map(|pid| {
+                // a stage that already exited has nothing to hand over
+                let Some(pidfd) = pid.and_then(|pid| inheritable_pidfd(pid).ok()) else { return Value::Null };
+                let stage = serde_json::json!({ "pid": pid, "pidfd": pidfd.as_raw_fd() });
+                pidfds.push(pidfd);
+                stage
+            })
+            .collect();
+        pipelines.insert(name.clone(), Value::Array(stages));
+    }
+
+    let handoff = match write_handoff(&Value::Object(pipelines).to_string()) {
+        Ok(handoff) => handoff,
+        Err(e) => {
+            log::error!("unable to write upgrade handoff: {e}");
+            return;
+        },
+    };
+    let exe = current_exe();
+    log::info!("upgrading in place to {}, handing over {} pipelines", exe.display(), supervised.len());
+
+    let mut args = std::env::args_os();
+    let e = Command::new(&exe)
+        .arg0(args.next().unwrap_or_else(|| exe.clone().into()))
+        .args(args)
+        .env(HANDOFF_ENV, handoff.as_raw_fd().to_string())
+        .exec();
+    log::error!("unable to upgrade to {}: {e}", exe.display());
+}
+
+fn inheritable_pidfd(pid: u32) -> io::Result<OwnedFd> {
+    let fd = usage::pidfd(pid)?;
+    // pidfds are always close-on-exec, the new binary needs them
+    unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, 0) };
+    Ok(fd)
+}
+
+fn write_handoff(handoff: &str) -> io::Result<OwnedFd> {
+    // without MFD_CLOEXEC, so it survives the exec
+    let fd = unsafe { libc::memfd_create(c"plumber-handoff".as_ptr(), 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(handoff.as_bytes())?;
+    Ok(file.into())
+}
+
+/// the binary now installed where plumber was started from
+pub fn current_exe() -> PathBuf {
+    let exe = std::env::current_exe().unwrap_or_default();
+    // the link still points at the replaced binary, which has been unlinked
+    match exe.to_str().and_then(|e| e.strip_suffix(" (deleted)")) {
+        Some(path) => PathBuf::from(path),
+        None => exe,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adopts_handed_off_stages() {
+        let mut child = Command::new("sh").arg("-c").arg("exit 3").spawn().unwrap();
+        let pidfd = inheritable_pidfd(child.id()).unwrap();
+        let handoff = format!("{{\"etl\": [{{\"pid\": {}, \"pidfd\": {}}}, null]}}", child.id(), pidfd.as_raw_fd());
+        std::mem::forget(pidfd);
+
+        let mut pipelines = parse_handoff(&serde_json::from_str::<Value>(&handoff).unwrap());
+        let stages = pipelines.remove("etl").unwrap();
+        assert_eq!(stages.len(), 2);
+        assert!(stages[1].is_none());
+        let adopted = stages[0].as_ref().unwrap();
+        assert_eq!(adopted.pid, child.id());
+        assert_eq!(adopted.wait().unwrap().0.code(), Some(3));
+        // reaped through the pidfd
+        assert!(child.try_wait().is_err());
+    }
+
+    #[test]
+    fn handoff_survives_exec() {
+        let handoff = write_handoff("{}").unwrap();
+        let output = Command::new("cat")
+            .arg(format!("/proc/self/fd/{}", handoff.as_raw_fd()))
+            .output()
+            .unwrap();
+        assert_eq!(output.stdout, b"{}");
+    }
+}
@@ -0,0 +1,123 @@
+//! what a stage's stdin does when the stage upstream of it exits, `--on-upstream-exit`
+//!
+//! - `close`, the default, lets the stage read to the end of what was written and see EOF,
+//!   as in a shell pipeline
+//! - `keep-open` keeps the stdin open while the upstream stage is respawned in place by the
+//!   `--restart` policy, so a long-lived consumer carries on reading from the new producer.
+//!   plumber also holds the upstream stage's own stdin, so the stage before it blocks instead
+//!   of failing with EPIPE meanwhile. an upstream stage that isn't respawned closes it
+//! - `sentinel:<line>` writes `<line>` after whatever the upstream stage wrote, then closes
+//!   the stdin, for consumers that need an end-of-stream record rather than EOF
+//!
+//! `--on-eof [<stage>=]<command>` runs `command` with `sh -c`, or the stage's `--shell`, once
+//! the stage's input is complete: the stage before it exited for good and its sentinel, if any,
+//! was written. it sees
+//! `PLUMBER_PIPELINE`, `PLUMBER_EVENT=eof`, `PLUMBER_STAGE` and `PLUMBER_UPSTREAM`, and a run
+//! of the pipeline isn't over until it has finished.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OnUpstreamExit {
+    Close,
+    KeepOpen,
+    Sentinel(String),
+}
+
+/// one `--on-upstream-exit [STAGE=]POLICY` flag
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpstreamExit {
+    /// `None` applies to every stage
+    pub stage: Option<String>,
+    pub policy: OnUpstreamExit,
+}
+
+impl std::str::FromStr for UpstreamExit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // a sentinel line may contain `=`, only a prefix before the policy names a stage
+        let (stage, policy) = match s.split_once('=') {
+            Some((stage, policy)) if !stage.contains(':') => (Some(stage.to_owned()), policy),
+            _ => (None, s),
+        };
+        let policy = match policy.split_once(':') {
+            Some(("sentinel", line)) => OnUpstreamExit::Sentinel(line.to_owned()),
+            None if policy == "close" => OnUpstreamExit::Close,
+            None if policy == "keep-open" => OnUpstreamExit::KeepOpen,
+            _ => return Err(format!("unknown policy '{policy}', expected close, keep-open or sentinel:<line>")),
+        };
+        Ok(UpstreamExit { stage, policy })
+    }
+}
+
+/// one `--on-eof [STAGE=]COMMAND` flag
+#[derive(Debug, Clone, PartialEq)]
+pub struct EofHook {
+    /// `None` applies to every stage
+    pub stage: Option<String>,
+    pub command: String,
+}
+
+impl std::str::FromStr for EofHook {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // a command may contain `=` too, a stage name is a single word
+        let (stage, command) = match s.split_once('=') {
+            Some((stage, command)) if !stage.is_empty() && stage.chars().all(|c| c.is_alphanumeric() || "-_.".contains(c)) => {
+                (Some(stage.to_owned()), command)
+            },
+            _ => (None, s),
+        };
+        if command.trim().is_empty() {
+            return Err("empty command".to_string());
+        }
+        Ok(EofHook { stage, command: command.to_owned() })
+    }
+}
+
+/// hook run when the input of `stage` is complete, as with `policy`
+pub fn eof_hook<'a>(hooks: &'a [EofHook], stage: &str) -> Option<&'a str> {
+    hooks.iter()
+        .rev()
+        .find(|h| h.stage.as_deref() == Some(stage))
+        .or_else(|| hooks.iter().rev().find(|h| h.stage.is_none()))
+        .map(|h| h.command.as_str())
+}
+
+/// policy for the stdin of `stage`; the last flag naming it wins, then the last for all stages
+pub fn policy<'a>(rules: &'a [UpstreamExit], stage: &str) -> &'a OnUpstreamExit {
+    rules.iter()
+        .rev()
+        .find(|r| r.stage.as_deref() == Some(stage))
+        .or_else(|| rules.iter().rev().find(|r| r.stage.is_none()))
+        .map_or(&OnUpstreamExit::Close, |r| &r.policy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_policies() {
+        let rules: Vec<UpstreamExit> = ["keep-open", "jq=sentinel:{\"eof\": true}", "wc=close"].iter()
+            .map(|r| r.parse().unwrap())
+            .collect();
+        assert_eq!(policy(&rules, "jq"), &OnUpstreamExit::Sentinel("{\"eof\": true}".to_string()));
+        assert_eq!(policy(&rules, "wc"), &OnUpstreamExit::Close);
+        assert_eq!(policy(&rules, "grep"), &OnUpstreamExit::KeepOpen);
+        assert_eq!(policy(&[], "grep"), &OnUpstreamExit::Close);
+        assert_eq!("sentinel:a=b".parse::<UpstreamExit>().unwrap().policy, OnUpstreamExit::Sentinel("a=b".to_string()));
+        assert!("jq=reopen".parse::<UpstreamExit>().is_err());
+    }
+
+    #[test]
+    fn parses_eof_hooks() {
+        let hooks: Vec<EofHook> = ["touch /run/done", "loader=curl -d state=done http://batch/"].iter()
+            .map(|h| h.parse().unwrap())
+            .collect();
+        assert_eq!(eof_hook(&hooks, "loader"), Some("curl -d state=done http://batch/"));
+        assert_eq!(eof_hook(&hooks, "wc"), Some("touch /run/done"));
+        assert_eq!(eof_hook(&[], "wc"), None);
+        assert!("wc=".parse::<EofHook>().is_err());
+    }
+}
@@ -0,0 +1,123 @@
+//! resources a stage used, gathered as it's reaped
+//!
+//! cpu time and peak memory come from the rusage `waitid` fills in, io counters from
+//! `/proc/<pid>/io`, read while the stage is a zombie and its accounting is still there.
+
+use std::fs;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::time::Duration;
+
+/// missing from the libc crate
+const P_PIDFD: libc::idtype_t = 3;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Usage {
+    pub user: Duration,
+    pub system: Duration,
+    /// peak resident set size, in kilobytes. the kernel counts what the stage took over from
+    /// plumber before exec in, so it's never less than plumber's own
+    pub max_rss: u64,
+    /// bytes passed through read and write syscalls, whatever they were reading or writing
+    pub read: u64,
+    pub written: u64,
+    /// bytes actually fetched from and sent to storage
+    pub storage_read: u64,
+    pub storage_written: u64,
+}
+
+/// a pidfd of `pid`, readable once it exited and close-on-exec
+pub fn pidfd(pid: u32) -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+/// waits for the child `pid` to exit and reaps it
+pub fn reap(pid: u32) -> io::Result<(ExitStatus, Usage)> {
+    reap_id(libc::P_PID, pid as libc::id_t)
+}
+
+/// waits for the process behind `pidfd` to exit and reaps it
+pub fn reap_pidfd(pidfd: &OwnedFd) -> io::Result<(ExitStatus, Usage)> {
+    reap_id(P_PIDFD, pidfd.as_raw_fd() as libc::id_t)
+}
+
+fn reap_id(idtype: libc::idtype_t, id: libc::id_t) -> io::Result<(ExitStatus, Usage)> {
+    // left a zombie first, for its io counters
+    let exited = waitid(idtype, id, libc::WEXITED | libc::WNOWAIT, std::ptr::null_mut())?;
+    let mut usage = io_counters(unsafe { exited.si_pid() } as u32).unwrap_or_default();
+
+    let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+    let info = waitid(idtype, id, libc::WEXITED, &mut rusage)?;
+    let time = |t: libc::timeval| Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+    usage.user = time(rusage.ru_utime);
+    usage.system = time(rusage.ru_stime);
+    usage.max_rss = rusage.ru_maxrss as u64;
+    Ok((exit_status(&info), usage))
+}
+
+/// waitid(2) as the syscall, which unlike the libc wrapper fills in rusage
+fn waitid(idtype: libc::idtype_t, id: libc::id_t, flags: libc::c_int, rusage: *mut libc::rusage) -> io::Result<libc::siginfo_t> {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    while unsafe { libc::syscall(libc::SYS_waitid, idtype, id, &mut info, flags, rusage) } < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+    Ok(info)
+}
+
+/// in the encoding of wait(2), which ExitStatus decodes
+fn exit_status(info: &libc::siginfo_t) -> ExitStatus {
+    let status = unsafe { info.si_status() };
+    ExitStatus::from_raw(match info.si_code {
+        libc::CLD_EXITED => (status & 0xff) << 8,
+        libc::CLD_DUMPED => status | 0x80,
+        _ => status,
+    })
+}
+
+fn io_counters(pid: u32) -> io::Result<Usage> {
+    let mut usage = Usage::default();
+    for line in fs::read_to_string(format!("/proc/{pid}/io"))?.lines() {
+        let Some((key, value)) = line.split_once(": ") else { continue };
+        let counter = match key {
+            "rchar" => &mut usage.read,
+            "wchar" => &mut usage.written,
+            "read_bytes" => &mut usage.storage_read,
+            "write_bytes" => &mut usage.storage_written,
+            _ => continue,
+        };
+        *counter = value.trim().parse().unwrap_or(0);
+    }
+    Ok(usage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::{Command, Stdio};
+
+    #[test]
+    fn reaps_with_usage() {
+        let pid = Command::new("sh").arg("-c").arg("exit 3").spawn().unwrap().id();
+        assert_eq!(reap(pid).unwrap().0.code(), Some(3));
+
+        let pid = Command::new("head")
+            .args(["-c", "1000000", "/dev/zero"])
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap()
+            .id();
+        let (status, usage) = reap(pid).unwrap();
+        assert!(status.success());
+        assert!(usage.read >= 1_000_000 && usage.written >= 1_000_000);
+        assert!(usage.max_rss > 0);
+    }
+}
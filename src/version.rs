@@ -0,0 +1,95 @@
+//! what this build of plumber is and can do, for automation to check before relying on it
+//!
+//! `plumber version` prints the version, which optional built-in stages were compiled in, the
+//! format versions of the state plumber keeps and where it keeps it. `--json` prints the same
+//! as one object:
+//!
+//! ```text
+//! {"version":"0.3.1","features":{"mqtt":true,...},"schemas":{"summary":1,...},"dirs":{"metadata":"/tmp/plumber/lib",...}}
+//! ```
+//!
+//! a schema version only goes up when a format changes in a way an older reader would trip
+//! over. `run` and `exec` log the same as a banner when they start.
+
+use serde_json::Value;
+use crate::pipeline::Pipeline;
+use crate::{archive, audit, restarts, state, summary, upgrade};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// cargo features and whether this build has them
+pub const FEATURES: &[(&str, bool)] = &[
+    ("mqtt", cfg!(feature = "mqtt")),
+    ("nats", cfg!(feature = "nats")),
+    ("redis", cfg!(feature = "redis")),
+    ("smtp", cfg!(feature = "smtp")),
+];
+
+/// formats of what plumber writes and reads back
+pub const SCHEMAS: &[(&str, u32)] = &[
+    ("summary", summary::SCHEMA),
+    ("restarts", restarts::SCHEMA),
+    ("run", archive::SCHEMA),
+    ("audit", audit::SCHEMA),
+    ("handoff", upgrade::SCHEMA),
+    ("state", state::SCHEMA),
+];
+
+fn dirs() -> Vec<(&'static str, String)> {
+    let dirs = Pipeline::state_dirs();
+    vec![
+        ("metadata", dirs.metadata.display().to_string()),
+        ("logs", dirs.logs.display().to_string()),
+        ("tmp", dirs.tmp.display().to_string()),
+        ("audit", audit::path().display().to_string()),
+    ]
+}
+
+pub fn json() -> Value {
+    let object = |fields: Vec<(&str, Value)>| Value::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect());
+    object(vec![
+        ("version", Value::String(VERSION.to_string())),
+        ("features", object(FEATURES.iter().map(|&(name, on)| (name, Value::Bool(on))).collect())),
+        ("schemas", object(SCHEMAS.iter().map(|&(name, v)| (name, Value::from(v))).collect())),
+        ("dirs", object(dirs().into_iter().map(|(name, dir)| (name, Value::String(dir))).collect())),
+    ])
+}
+
+/// compiled in features, space separated
+fn features() -> String {
+    let features: Vec<&str> = FEATURES.iter().filter(|(_, on)| *on).map(|(name, _)| *name).collect();
+    match features.is_empty() {
+        true => "none".to_string(),
+        false => features.join(" "),
+    }
+}
+
+pub fn text() -> String {
+    let schemas: Vec<String> = SCHEMAS.iter().map(|(name, v)| format!("{name} {v}")).collect();
+    let mut out = format!("plumber {VERSION}\nfeatures: {}\nschemas: {}\n", features(), schemas.join(", "));
+    for (name, dir) in dirs() {
+        out.push_str(&format!("{name}: {dir}\n"));
+    }
+    out
+}
+
+/// logged when plumber starts supervising
+pub fn banner() {
+    log::info!("plumber {VERSION}, features: {}", features());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_capabilities() {
+        let report = serde_json::from_str::<Value>(&json().to_string()).unwrap();
+        assert_eq!(report.pointer("/version"), Some(&Value::String(VERSION.to_string())));
+        assert_eq!(report.pointer("/features/mqtt"), Some(&Value::Bool(cfg!(feature = "mqtt"))));
+        assert_eq!(report.pointer("/schemas/summary"), Some(&Value::from(1)));
+        let metadata = Pipeline::state_dirs().metadata.display().to_string();
+        assert_eq!(report.pointer("/dirs/metadata"), Some(&Value::String(metadata)));
+        assert!(text().starts_with(&format!("plumber {VERSION}\nfeatures: ")));
+    }
+}
@@ -0,0 +1,157 @@
+//! systemd watchdog, petted only while plumber is supervising its pipelines
+//!
+//! under a unit with `WatchdogSec=`, systemd passes `$WATCHDOG_USEC` and restarts the service
+//! unless `WATCHDOG=1` arrives on `$NOTIFY_SOCKET` at least that often. plumber sends it at half
+//! the interval, as long as the supervision loop of every pipeline checked in within the
+//! interval and no `--critical` pipeline crash-looped. a wedged plumber, or one that can't keep
+//! a pipeline it's there for running, is then restarted by systemd, as `Restart=` says.
+//!
+//! stages don't see `$WATCHDOG_USEC`, the watchdog is plumber's.
+
+use std::collections::HashMap;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct Supervised {
+    critical: bool,
+    checked_in: Instant,
+    /// why a critical pipeline is no longer supervised
+    failed: Option<String>,
+}
+
+static SUPERVISED: OnceLock<Mutex<HashMap<String, Supervised>>> = OnceLock::new();
+
+/// how often systemd expects to hear from plumber, `None` without a watchdog
+pub fn interval() -> Option<Duration> {
+    static INTERVAL: OnceLock<Option<Duration>> = OnceLock::new();
+    *INTERVAL.get_or_init(|| {
+        // meant for another process if it names one
+        if std::env::var("WATCHDOG_PID").is_ok_and(|pid| pid != std::process::id().to_string()) {
+            return None;
+        }
+        std::env::var_os("NOTIFY_SOCKET")?;
+        let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+        (usec > 0).then(|| Duration::from_micros(usec))
+    })
+}
+
+/// how often a supervision loop checks in, `None` without a watchdog
+pub fn check_in_every() -> Option<Duration> {
+    interval().map(|interval| interval / 4)
+}
+
+/// starts supervising `pipeline`
+pub fn supervise(pipeline: &str, critical: bool) {
+    SUPERVISED.get_or_init(Default::default).lock().unwrap()
+        .insert(pipeline.to_owned(), Supervised { critical, checked_in: Instant::now(), failed: None });
+}
+
+/// the supervision loop of `pipeline` is still going
+pub fn check_in(pipeline: &str) {
+    let Some(supervised) = SUPERVISED.get() else { return };
+    if let Some(supervised) = supervised.lock().unwrap().get_mut(pipeline) {
+        supervised.checked_in = Instant::now();
+    }
+}
+
+/// `pipeline` is no longer supervised, `failed` says why if that's a failure
+pub fn release(pipeline: &str, failed: Option<String>) {
+    let Some(supervised) = SUPERVISED.get() else { return };
+    let mut supervised = supervised.lock().unwrap();
+    match (failed, supervised.get_mut(pipeline)) {
+        (Some(reason), Some(pipeline)) if pipeline.critical => pipeline.failed = Some(reason),
+        _ => {
+            supervised.remove(pipeline);
+        },
+    }
+}
+
+/// why the watchdog shouldn't be petted, if it shouldn't
+fn unhealthy(supervised: &HashMap<String, Supervised>, now: Instant, interval: Duration) -> Option<String> {
+    let mut names: Vec<&String> = supervised.keys().collect();
+    names.sort();
+    names.into_iter().find_map(|name| {
+        let pipeline = &supervised[name];
+        match &pipeline.failed {
+            Some(reason) => Some(format!("critical pipeline {name} {reason}")),
+            None if now.saturating_duration_since(pipeline.checked_in) > interval => {
+                Some(format!("supervision of {name} is stuck"))
+            },
+            None => None,
+        }
+    })
+}
+
+/// pets the watchdog while everything's healthy, if plumber runs under one
+pub fn start() {
+    let Some(interval) = interval() else { return };
+    let Some(socket) = std::env::var("NOTIFY_SOCKET").ok() else { return };
+    log::info!("petting the systemd watchdog every {}", humantime::format_duration(interval / 2));
+    thread::spawn(move || {
+        let mut was_healthy = true;
+        loop {
+            let problem = SUPERVISED.get()
+                .and_then(|supervised| unhealthy(&supervised.lock().unwrap(), Instant::now(), interval));
+            match problem {
+                Some(problem) => {
+                    if was_healthy {
+                        log::error!("not petting the systemd watchdog, {problem}");
+                    }
+                    was_healthy = false;
+                },
+                None => {
+                    if let Err(e) = notify(&socket, "WATCHDOG=1") {
+                        log::warn!("unable to pet the systemd watchdog: {e}");
+                    }
+                    was_healthy = true;
+                },
+            }
+            thread::sleep(interval / 2);
+        }
+    });
+}
+
+/// sends `state` to systemd's `socket`, which may be in the abstract namespace
+fn notify(socket: &str, state: &str) -> io::Result<()> {
+    let sender = UnixDatagram::unbound()?;
+    let addr = match socket.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(socket)?,
+    };
+    sender.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pets_only_when_healthy() {
+        let now = Instant::now();
+        let interval = Duration::from_secs(10);
+        let mut supervised = HashMap::new();
+        supervised.insert("etl".to_string(), Supervised { critical: false, checked_in: now, failed: None });
+        assert_eq!(unhealthy(&supervised, now + Duration::from_secs(5), interval), None);
+        assert_eq!(unhealthy(&supervised, now + Duration::from_secs(11), interval).unwrap(), "supervision of etl is stuck");
+
+        let failed = Some("crash-looped".to_string());
+        supervised.insert("api".to_string(), Supervised { critical: true, checked_in: now, failed });
+        assert_eq!(unhealthy(&supervised, now, interval).unwrap(), "critical pipeline api crash-looped");
+    }
+
+    #[test]
+    fn notifies_systemd() {
+        let path = std::env::temp_dir().join(format!("plumber-watchdog-test-{}", std::process::id()));
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        notify(path.to_str().unwrap(), "WATCHDOG=1").unwrap();
+        let mut buf = [0; 16];
+        let n = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"WATCHDOG=1");
+        std::fs::remove_file(path).unwrap();
+    }
+}
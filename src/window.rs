@@ -0,0 +1,155 @@
+//! the times of day a pipeline may run
+//!
+//! `--window 22:00-06:00` lets runs start only within the window, in local time, wrapping past
+//! midnight when it ends before it starts. with several windows any of them will do, and a
+//! `--blackout 02:00-02:30` is a time no run starts in, whatever the windows say. a pipeline
+//! started or restarting outside its windows waits for the next one to open. what a run does
+//! once its window closes is up to `--outside-window`: `finish` lets it run to its end, `stop`
+//! drains it as `plumber stop` does.
+//!
+//! a structured definition may set `windows`, `blackouts` and `outside_window` for its
+//! pipeline, which apply unless the flags say otherwise.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAY: u32 = 24 * 60;
+
+/// `HH:MM-HH:MM`, by minute of the day
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Window {
+    from: u32,
+    to: u32,
+}
+
+impl std::str::FromStr for Window {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let minute = |time: &str| -> Option<u32> {
+            let (hour, minute) = time.trim().split_once(':')?;
+            let (hour, minute): (u32, u32) = (hour.parse().ok()?, minute.parse().ok()?);
+            (hour < 24 && minute < 60).then_some(hour * 60 + minute)
+        };
+        let invalid = || format!("invalid window '{s}', expected HH:MM-HH:MM such as 22:00-06:00");
+        let (from, to) = s.split_once('-').ok_or_else(invalid)?;
+        let (from, to) = (minute(from).ok_or_else(invalid)?, minute(to).ok_or_else(invalid)?);
+        if from == to {
+            return Err(format!("window '{s}' is empty"));
+        }
+        Ok(Window { from, to })
+    }
+}
+
+impl Window {
+    fn contains(&self, minute: u32) -> bool {
+        match self.from < self.to {
+            true => (self.from..self.to).contains(&minute),
+            false => minute >= self.from || minute < self.to,
+        }
+    }
+}
+
+/// what a run does once its window closes
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum Outside {
+    /// run to its end, no run starting until the window opens again
+    #[default]
+    Finish,
+    /// drain it as `plumber stop` does
+    Stop,
+}
+
+/// `--window`, `--blackout` and `--outside-window`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Schedule {
+    pub windows: Vec<Window>,
+    pub blackouts: Vec<Window>,
+    pub outside: Option<Outside>,
+}
+
+impl Schedule {
+    pub fn is_empty(&self) -> bool {
+        self.windows.is_empty() && self.blackouts.is_empty()
+    }
+
+    fn allows(&self, minute: u32) -> bool {
+        (self.windows.is_empty() || self.windows.iter().any(|window| window.contains(minute)))
+            && !self.blackouts.iter().any(|blackout| blackout.contains(minute))
+    }
+
+    /// how long after `now` runs may start, zero if they may now, none if they never may
+    pub fn opens_in(&self, now: SystemTime) -> Option<Duration> {
+        self.until(now, true)
+    }
+
+    /// how long after `now` a run going on is drained, none if it isn't
+    pub fn closes_in(&self, now: SystemTime) -> Option<Duration> {
+        match self.outside.unwrap_or_default() {
+            Outside::Finish => None,
+            Outside::Stop => self.until(now, false),
+        }
+    }
+
+    /// how long until runs are `allowed` or no longer, looking a day ahead
+    fn until(&self, now: SystemTime, allowed: bool) -> Option<Duration> {
+        if self.is_empty() {
+            return allowed.then_some(Duration::ZERO);
+        }
+        let (minute, second) = local(now);
+        (0..=DAY).find(|later| self.allows((minute + later) % DAY) == allowed).map(|later| match later {
+            0 => Duration::ZERO,
+            later => Duration::from_secs(later as u64 * 60 - second as u64),
+        })
+    }
+}
+
+/// minute of the day and second of the minute `time` is, in local time
+fn local(time: SystemTime) -> (u32, u32) {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs()) as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+        let utc = secs.rem_euclid(86_400) as u32;
+        return (utc / 60, utc % 60);
+    }
+    (tm.tm_hour as u32 * 60 + tm.tm_min as u32, tm.tm_sec as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_wrap_past_midnight() {
+        let night: Window = "22:00-06:00".parse().unwrap();
+        assert!(night.contains(23 * 60) && night.contains(0) && night.contains(5 * 60 + 59));
+        assert!(!night.contains(6 * 60) && !night.contains(12 * 60));
+        let lunch: Window = "12:00-13:30".parse().unwrap();
+        assert!(lunch.contains(12 * 60) && !lunch.contains(13 * 60 + 30));
+        assert!("24:00-01:00".parse::<Window>().is_err());
+        assert!("22:00".parse::<Window>().is_err());
+        assert_eq!("06:00-06:00".parse::<Window>(), Err("window '06:00-06:00' is empty".to_string()));
+    }
+
+    #[test]
+    fn blackouts_win_over_windows() {
+        let schedule = Schedule {
+            windows: vec!["22:00-06:00".parse().unwrap()],
+            blackouts: vec!["02:00-02:30".parse().unwrap()],
+            outside: Some(Outside::Stop),
+        };
+        assert!(schedule.allows(1) && !schedule.allows(2 * 60 + 10) && schedule.allows(2 * 60 + 30));
+        assert!(!schedule.allows(12 * 60));
+
+        let always = Schedule::default();
+        assert_eq!(always.opens_in(SystemTime::now()), Some(Duration::ZERO));
+        assert_eq!(always.closes_in(SystemTime::now()), None);
+        let never = Schedule { blackouts: vec!["00:00-12:00".parse().unwrap(), "12:00-00:00".parse().unwrap()], ..Schedule::default() };
+        assert_eq!(never.opens_in(SystemTime::now()), None);
+
+        // whatever the local time, a run may start within a day and is drained within one
+        let now = SystemTime::now();
+        let opens = schedule.opens_in(now).unwrap();
+        assert!(opens < Duration::from_secs(86_400));
+        assert!(schedule.closes_in(now + opens).unwrap() > Duration::ZERO);
+    }
+}
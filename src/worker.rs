@@ -0,0 +1,181 @@
+//! stages kept running across runs, for programs that take long to start, such as jvm tools
+//!
+//! `--worker <stage>` spawns the stage once, the first time a run needs it, and keeps it running
+//! with `$PLUMBER_WORKER` set for as long as the pipeline is supervised. in its place every run
+//! spawns a small shuttle, `plumber shuttle`, which hands the worker what the stage reads and
+//! passes on what it answers, so the run sees a stage like any other. the worker reads and
+//! writes frames on stdin and stdout:
+//! - `<length>\n` and as many bytes are data
+//! - `0\n` from plumber ends the input of a run
+//! - `0 <exit code>\n` from the worker ends its output for the run, and is what the stage
+//!   exits with
+//!
+//! a worker reads a run's input up to its end before ending the output. a worker that exited
+//! is spawned again for the next run, and one whose run was cut short, its shuttle killed or
+//! the frames garbled, is ended and spawned afresh, so no run reads what was meant for another.
+//! its stderr goes to the stage's log.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::status;
+
+/// set for the worker, to speak frames on stdin and stdout
+pub const ENV: &str = "PLUMBER_WORKER";
+/// the pid of the worker, for the shuttle to end it when the frames got garbled
+const PID_ENV: &str = "PLUMBER_WORKER_PID";
+/// where the shuttle finds the worker's stdin and stdout
+const INPUT_FD: RawFd = 3;
+const OUTPUT_FD: RawFd = 4;
+/// of data read at once, and so of a frame from plumber
+const FRAME: usize = 64 * 1024;
+
+/// a stage kept running across runs
+pub struct Worker {
+    child: Child,
+    /// the write end of its stdin and the read end of its stdout
+    input: OwnedFd,
+    output: OwnedFd,
+}
+
+impl Worker {
+    pub fn new(child: Child, input: OwnedFd, output: OwnedFd) -> Self {
+        Worker { child, input, output }
+    }
+
+    pub fn alive(&mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(None))
+    }
+
+    /// has `command`, the shuttle, see the worker's stdin and stdout where it expects them
+    pub fn attach(&self, command: &mut Command) -> io::Result<()> {
+        let (input, output) = (self.input.try_clone()?, self.output.try_clone()?);
+        command.env(PID_ENV, self.child.id().to_string());
+        unsafe {
+            command.pre_exec(move || {
+                // moved out of the way first, so neither is dup'd over the other
+                let above = OUTPUT_FD + 1;
+                let input = libc::fcntl(input.as_raw_fd(), libc::F_DUPFD_CLOEXEC, above);
+                let output = libc::fcntl(output.as_raw_fd(), libc::F_DUPFD_CLOEXEC, above);
+                if input < 0 || output < 0 || libc::dup2(input, INPUT_FD) < 0 || libc::dup2(output, OUTPUT_FD) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        // it leads its own process group
+        let group = -(self.child.id() as libc::pid_t);
+        unsafe { libc::kill(group, libc::SIGTERM) };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while matches!(self.child.try_wait(), Ok(None)) {
+            if Instant::now() >= deadline {
+                unsafe { libc::kill(group, libc::SIGKILL) };
+                let _ = self.child.wait();
+                return;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+/// writes everything read from `from` to the worker as frames, ending with `0\n`
+fn feed(mut from: impl Read, mut to: impl Write) -> io::Result<()> {
+    let mut buf = vec![0u8; FRAME];
+    loop {
+        let n = match from.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if n == 0 {
+            to.write_all(b"0\n")?;
+            return to.flush();
+        }
+        to.write_all(format!("{n}\n").as_bytes())?;
+        to.write_all(&buf[..n])?;
+    }
+}
+
+/// passes the data of the worker's frames on to `to` until it ends its output, returning the
+/// exit code it ended with
+fn unframe(mut from: impl BufRead, mut to: impl Write) -> io::Result<i32> {
+    let garbled = |header: &str| io::Error::new(io::ErrorKind::InvalidData, format!("garbled frame header '{}'", header.escape_debug()));
+    let mut header = String::new();
+    // once nobody reads what the stage writes, the rest of the run's output is only taken off the worker
+    let mut closed = false;
+    loop {
+        header.clear();
+        if from.read_line(&mut header)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "worker exited mid-run"));
+        }
+        let mut words = header.split_whitespace();
+        let length: usize = words.next().and_then(|n| n.parse().ok()).ok_or_else(|| garbled(&header))?;
+        if length == 0 {
+            let code = words.next().map_or(Ok(0), str::parse).map_err(|_| garbled(&header))?;
+            if !closed {
+                to.flush()?;
+            }
+            return Ok(code);
+        }
+        let mut data = vec![0u8; length];
+        from.read_exact(&mut data)?;
+        if !closed {
+            match to.write_all(&data) {
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => closed = true,
+                written => written?,
+            }
+        }
+    }
+}
+
+/// `plumber shuttle`, a run's stand-in for a worker, returning what it exits with
+pub fn shuttle() -> i32 {
+    let (input, output) = unsafe { (File::from_raw_fd(INPUT_FD), File::from_raw_fd(OUTPUT_FD)) };
+    let feeding = thread::spawn(move || feed(io::stdin().lock(), input));
+    let code = unframe(BufReader::new(output), io::stdout().lock());
+    let fed = feeding.join().unwrap_or_else(|_| Err(io::Error::other("feeding the worker panicked")));
+    match (code, fed) {
+        (Ok(code), Ok(())) => code,
+        (Err(e), _) | (_, Err(e)) => {
+            log::error!("worker: {e}, ending it");
+            // what's left of the run would be read by the next one
+            if let Some(pid) = std::env::var(PID_ENV).ok().and_then(|pid| status::parse_pid(&pid, true)) {
+                unsafe { libc::kill(-pid, libc::SIGTERM) };
+            }
+            1
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_both_ways() {
+        let mut framed = Vec::new();
+        feed(&b"ab\ncd\n"[..], &mut framed).unwrap();
+        assert_eq!(framed, b"6\nab\ncd\n0\n");
+        let mut empty = Vec::new();
+        feed(&b""[..], &mut empty).unwrap();
+        assert_eq!(empty, b"0\n");
+
+        let mut out = Vec::new();
+        assert_eq!(unframe(&b"3\nab\n2\ncd0 2\n"[..], &mut out).unwrap(), 2);
+        assert_eq!(out, b"ab\ncd");
+        assert_eq!(unframe(&b"0\n"[..], &mut Vec::new()).unwrap(), 0);
+        assert_eq!(unframe(&b"3\nab"[..], &mut Vec::new()).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(unframe(&b"lots\n"[..], &mut Vec::new()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,126 @@
+//! spawn wrappers for debugging and profiling stages, `--wrapper [<stage>=]<command>`
+//!
+//! the command is put in front of the stage's own, e.g.
+//! `--wrapper 'jq=strace -f -o {log_dir}/strace.out'` spawns `strace -f -o ... /usr/bin/jq ...`.
+//! `{log_dir}`, `{stage}` and `{tmpdir}` are expanded in it. a rule with no command, `jq=`,
+//! leaves the stage unwrapped.
+//!
+//! `plumber wrap <name> [<rule>...]` sets the rules of a running pipeline without touching
+//! its definition, kept in `.wrappers` in the pipeline's metadata dir and taking precedence
+//! over the flags. they're read whenever a stage is spawned, so they apply from its next
+//! restart; `plumber wrap <name>` without rules drops them again.
+//!
+//! a pipeline run with `--allowlist` is never wrapped, as the wrapper would run what nobody
+//! approved: `--wrapper` is refused, and rules set with `plumber wrap` are ignored, which is
+//! logged and recorded in the audit log.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const WRAPPERS: &str = ".wrappers";
+
+/// one `--wrapper [STAGE=]COMMAND` rule
+#[derive(Debug, Clone, PartialEq)]
+pub struct Wrapper {
+    /// `None` applies to every stage
+    pub stage: Option<String>,
+    /// empty for a stage left unwrapped
+    pub command: Vec<String>,
+    rule: String,
+}
+
+impl std::str::FromStr for Wrapper {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // a command may contain `=` too, a stage name is a single word
+        let (stage, command) = match s.split_once('=') {
+            Some((stage, command)) if !stage.is_empty() && stage.chars().all(|c| c.is_alphanumeric() || "-_.".contains(c)) => {
+                (Some(stage.to_owned()), command)
+            },
+            _ => (None, s),
+        };
+        let command = shlex::split(command).ok_or_else(|| format!("unbalanced quotes in '{command}'"))?;
+        if stage.is_none() && command.is_empty() {
+            return Err("empty command".to_string());
+        }
+        Ok(Wrapper { stage, command, rule: s.to_owned() })
+    }
+}
+
+impl std::fmt::Display for Wrapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.rule)
+    }
+}
+
+/// wrapper command of `stage`; the last rule naming it wins, then the last for all stages
+pub fn wrapper<'a>(rules: &'a [Wrapper], stage: &str) -> Option<&'a [String]> {
+    rules.iter()
+        .rev()
+        .find(|w| w.stage.as_deref() == Some(stage))
+        .or_else(|| rules.iter().rev().find(|w| w.stage.is_none()))
+        .map(|w| w.command.as_slice())
+        .filter(|command| !command.is_empty())
+}
+
+/// `{log_dir}`, `{stage}` and `{tmpdir}` of a word of a wrapper command
+pub fn expand(word: &str, log_dir: &Path, stage: &str, tmpdir: Option<&Path>) -> String {
+    word.replace("{log_dir}", &log_dir.to_string_lossy())
+        .replace("{stage}", stage)
+        .replace("{tmpdir}", &tmpdir.map_or(String::new(), |dir| dir.to_string_lossy().into_owned()))
+}
+
+/// rules set with `plumber wrap`, unreadable ones are skipped
+pub fn load(metadata_dir: &Path) -> Vec<Wrapper> {
+    let Ok(rules) = fs::read_to_string(metadata_dir.join(WRAPPERS)) else { return Vec::new() };
+    rules.lines()
+        .filter_map(|rule| rule.parse()
+            .map_err(|e| log::warn!("{}: ignoring wrapper '{rule}': {e}", metadata_dir.display()))
+            .ok())
+        .collect()
+}
+
+/// replaces the rules set with `plumber wrap`, none removes them
+pub fn save(metadata_dir: &Path, rules: &[Wrapper]) -> io::Result<()> {
+    if rules.is_empty() {
+        return match fs::remove_file(metadata_dir.join(WRAPPERS)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+    }
+    let rules: String = rules.iter().map(|w| format!("{w}\n")).collect();
+    fs::write(metadata_dir.join(WRAPPERS), rules)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_wrappers() {
+        let rules: Vec<Wrapper> = ["valgrind -q", "jq=strace -f -o '{log_dir}/strace out'", "wc="].iter()
+            .map(|w| w.parse().unwrap())
+            .collect();
+        assert_eq!(wrapper(&rules, "jq").unwrap(), ["strace", "-f", "-o", "{log_dir}/strace out"]);
+        assert_eq!(wrapper(&rules, "grep").unwrap(), ["valgrind", "-q"]);
+        assert_eq!(wrapper(&rules, "wc"), None);
+        assert_eq!(wrapper(&[], "wc"), None);
+        assert!("".parse::<Wrapper>().is_err());
+        assert!("jq=strace -o 'x".parse::<Wrapper>().is_err());
+        assert_eq!(expand("{log_dir}/{stage}.perf", Path::new("/tmp/plumber/log/etl"), "jq", None), "/tmp/plumber/log/etl/jq.perf");
+    }
+
+    #[test]
+    fn saves_rules() {
+        let dir = std::env::temp_dir().join(format!("plumber-wrapper-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rules: Vec<Wrapper> = vec!["jq=perf record -o {tmpdir}/perf.data --".parse().unwrap()];
+        save(&dir, &rules).unwrap();
+        assert_eq!(load(&dir), rules);
+        save(&dir, &[]).unwrap();
+        assert!(load(&dir).is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
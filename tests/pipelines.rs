@@ -0,0 +1,314 @@
+//! pipelines run end to end by the plumber binary, through `plumber-test`
+
+use plumber_test::Scratch;
+
+fn scratch() -> Scratch {
+    Scratch::with_binary(env!("CARGO_BIN_EXE_plumber")).unwrap()
+}
+
+#[test]
+fn runs_to_completion() {
+    let scratch = scratch();
+    let run = scratch.run_with("words", "tr ' ' '\\n' | sort | uniq -c | wc -l", &[], b"b a b c").unwrap();
+    run.assert_success().assert_stage_exit("sort", 0).assert_stdout("3\n");
+    assert_eq!(run.stages.len(), 4);
+    assert!(scratch.metadata_dir("words").join(".summary").exists());
+    let state = std::fs::read_to_string(scratch.metadata_dir("words").join("state.json")).unwrap();
+    assert!(state.contains(r#""state":"exited""#) && state.contains(r#""exit_code":0"#), "{state}");
+}
+
+#[test]
+fn reports_failing_stages() {
+    let scratch = scratch();
+    let run = scratch.run_with("failing", "sh -c 'echo oops >&2; exit 3' | cat", &["--pipefail"], b"").unwrap();
+    run.assert_failure().assert_stage_exit("sh", 3).assert_stage_exit("cat", 0).assert_stderr_contains("sh", "oops");
+    assert_eq!(run.status.code(), Some(3));
+}
+
+#[test]
+fn keeps_pipes_inside_quotes() {
+    let scratch = scratch();
+    let run = scratch.run_with("quoted", "grep 'a|b' | awk -F'|' '{ print $2 }' | wc -l", &[], b"a|b\nc|d\na|b\n").unwrap();
+    run.assert_success().assert_stdout("2\n");
+    assert_eq!(run.stages.len(), 3);
+}
+
+#[test]
+fn merges_stderr_into_the_pipe() {
+    let scratch = scratch();
+    let run = scratch.run("merged", "sh -c 'echo progress >&2; echo done' |& sort | cat").unwrap();
+    run.assert_success().assert_stdout("done\nprogress\n");
+    assert!(!run.stage("sh").stderr.contains("progress"));
+}
+
+#[test]
+fn redirects_stages_to_files() {
+    let scratch = scratch();
+    let (input, output, errors) = (scratch.root().join("in.txt"), scratch.root().join("out.txt"), scratch.root().join("errors"));
+    std::fs::write(&input, "b\na\nb\n").unwrap();
+    std::fs::write(&output, "counted\n").unwrap();
+    let pipeline = format!("sort < {} | sh -c 'uniq -c; echo oops >&2' 2> {} >> {}", input.display(), errors.display(), output.display());
+    let run = scratch.run("redirected", &pipeline).unwrap();
+    run.assert_success().assert_stdout("");
+    let counted: Vec<Vec<String>> = std::fs::read_to_string(&output).unwrap().lines().map(|line| line.split_whitespace().map(str::to_owned).collect()).collect();
+    assert_eq!(counted, [vec!["counted"], vec!["1", "a"], vec!["2", "b"]]);
+    assert_eq!(std::fs::read_to_string(&errors).unwrap(), "oops\n");
+
+    let run = scratch.run("redirected", "sort < /no/such/file | cat").unwrap();
+    run.assert_failure();
+    assert!(run.plumber_stderr.contains("sort: unable to spawn"), "{}", run.plumber_stderr);
+}
+
+#[test]
+fn holds_stages_at_barriers() {
+    let scratch = scratch();
+    let done = scratch.root().join("extracted");
+    let pipeline = format!("sh -c 'sleep 0.2; echo b; echo a; touch {0}' ||| sh -c 'test -e {0} && sort' | cat", done.display());
+    let run = scratch.run("phased", &pipeline).unwrap();
+    run.assert_success().assert_stdout("a\nb\n");
+    assert_eq!(run.stages.len(), 3);
+
+    let run = scratch.run("phased", "sh -c 'echo partial; exit 3' ||| cat").unwrap();
+    run.assert_failure().assert_stage_exit("sh", 3).assert_stdout("");
+    assert_eq!(run.stages.len(), 1);
+}
+
+#[test]
+fn falls_back_to_alternative_commands() {
+    let scratch = scratch();
+    let run = scratch.run("fetching", "asdf-no-such-fetcher x || sh -c 'echo partial; exit 2' || echo fetched | cat").unwrap();
+    run.assert_success().assert_stdout("partial\nfetched\n");
+    let summary = std::fs::read_to_string(scratch.metadata_dir("fetching").join(".summary")).unwrap();
+    assert!(summary.contains(r#""fallback":"echo fetched""#), "{summary}");
+
+    let run = scratch.run_with("fetching", "false || false | cat", &["--pipefail"], b"").unwrap();
+    run.assert_failure();
+}
+
+#[test]
+fn chains_pipelines() {
+    let scratch = scratch();
+    let dump = scratch.root().join("dump.txt");
+    let pipeline = format!("printf 'b\\na\\n' | sort > {0} && wc -l < {0}; echo done", dump.display());
+    let run = scratch.run("nightly", &pipeline).unwrap();
+    run.assert_success().assert_stdout("2\ndone\n");
+    assert_eq!(run.stages.len(), 4);
+
+    let run = scratch.run("nightly", "sh -c 'exit 4' | cat && echo uploaded && echo twice; echo done").unwrap();
+    run.assert_success().assert_stdout("done\n");
+    let run = scratch.run_with("nightly", "true && false | cat", &["--pipefail"], b"").unwrap();
+    run.assert_failure();
+}
+
+#[test]
+fn chains_outputs_of_earlier_runs() {
+    let scratch = scratch();
+    scratch.run("extract", "sh -c 'echo rows=3 >> \"$PLUMBER_OUTPUT\"' | cat").unwrap().assert_success();
+    let run = scratch.run("load", "echo {{runs.extract.last.rows}} rows {{runs.extract.last.failed}} | cat").unwrap();
+    run.assert_success().assert_stdout("3 rows false\n");
+}
+
+#[test]
+fn keeps_metadata_and_logs_where_told() {
+    let scratch = scratch();
+    let (state, logs) = (scratch.root().join("state"), scratch.root().join("logs"));
+    let options = ["--state-dir", state.to_str().unwrap(), "--log-dir", logs.to_str().unwrap()];
+    let run = scratch.run_with("moved", "sh -c 'echo oops >&2' | cat", &options, b"").unwrap();
+    run.assert_success();
+    assert!(state.join("moved").join(".summary").exists());
+    assert_eq!(std::fs::read_to_string(logs.join("moved").join("sh.stderr.log")).unwrap(), "oops\n");
+    assert!(!scratch.metadata_dir("moved").exists());
+}
+
+#[test]
+fn keeps_namespaces_apart() {
+    let scratch = scratch();
+    let team = scratch.root().join("namespaces").join("team-a");
+    std::fs::create_dir_all(&team).unwrap();
+    std::fs::write(scratch.root().join("namespaces").join("team-a.quota.json"), r#"{"max_stages":2}"#).unwrap();
+
+    let run = scratch.run_with("etl", "echo a | cat", &["--namespace", "team-a"], b"").unwrap();
+    run.assert_success().assert_stdout("a\n");
+    assert!(team.join("lib").join("etl").join(".summary").exists());
+    assert!(!scratch.metadata_dir("etl").exists());
+
+    let run = scratch.run_with("etl", "echo a | cat | cat", &["--namespace", "team-a"], b"").unwrap();
+    run.assert_failure();
+    assert!(run.plumber_stderr.contains("over the quota of namespace team-a: 3 stages"), "{}", run.plumber_stderr);
+}
+
+#[test]
+fn sends_stdout_where_told() {
+    let scratch = scratch();
+    let run = scratch.run_with("quiet", "echo a | cat", &["--stdout", "log"], b"").unwrap();
+    run.assert_success().assert_stdout("");
+    assert_eq!(std::fs::read_to_string(scratch.logging_dir("quiet").join("cat.stdout.log")).unwrap(), "a\n");
+
+    let out = scratch.root().join("out.txt");
+    let append = format!("append:{}", out.display());
+    for _ in 0..2 {
+        scratch.run_with("quiet", "echo b | cat", &["--stdout", &append], b"").unwrap().assert_success().assert_stdout("");
+    }
+    assert_eq!(std::fs::read_to_string(out).unwrap(), "b\nb\n");
+
+    scratch.run_with("quiet", "echo c | cat", &["--stdout", "discard"], b"").unwrap().assert_success().assert_stdout("");
+}
+
+#[test]
+fn keeps_logs_to_their_size() {
+    let scratch = scratch();
+    let run = scratch.run_with("flooding", "sh -c 'seq 1000 >&2; seq 1000' | cat", &["--stdout", "log", "--max-log-size", "10"], b"").unwrap();
+    run.assert_success();
+    let marked = "1\n2\n3\n4\n5\n[plumber] log reached --max-log-size of 10 bytes\n[plumber] 3883 bytes dropped\n";
+    for log in ["sh.stderr.log", "cat.stdout.log"] {
+        assert_eq!(std::fs::read_to_string(scratch.logging_dir("flooding").join(log)).unwrap(), marked, "{log}");
+    }
+
+    let run = scratch.run_with("flooding", "yes | cat", &["--stdout", "log", "--max-log-size", "cat=1K", "--on-log-full", "fail"], b"").unwrap();
+    run.assert_failure();
+    assert_eq!(run.stage("cat").signal, Some(15));
+}
+
+#[test]
+fn ends_runs_over_their_budget() {
+    let scratch = scratch();
+    let run = scratch.run_with("unbounded", "yes | cat", &["--stdout", "discard", "--max-run-bytes", "1M"], b"").unwrap();
+    run.assert_failure();
+    assert_eq!(run.status.code(), Some(3));
+    let summary = std::fs::read_to_string(scratch.metadata_dir("unbounded").join(".summary")).unwrap();
+    assert!(summary.contains(r#""over_budget":true"#), "{summary}");
+
+    let run = scratch.run_with("bounded", "seq 100 | wc -l", &["--max-run-bytes", "1K"], b"").unwrap();
+    run.assert_success().assert_stdout("100\n");
+}
+
+#[test]
+fn keeps_workers_across_runs() {
+    let scratch = scratch();
+    // answers every run with its count and pid, failing the first so it's restarted
+    let worker = r#"sh -c 'runs=0; while read n; do if [ "$n" = 0 ]; then runs=$((runs+1)); out="run $runs $$
+"; printf "%s\n%s" ${#out} "$out"; printf "0 %s\n" $((runs < 2)); else dd bs=1 count="$n" 2>/dev/null >/dev/null; fi; done'"#;
+    let options = ["--worker", "sh", "--restart", "on-failure", "--restart-delay", "10ms"];
+    let run = scratch.run_with("working", &format!("echo a | {worker} | cat"), &options, b"").unwrap();
+    run.assert_success();
+    let stdout = String::from_utf8_lossy(&run.stdout);
+    let lines: Vec<Vec<&str>> = stdout.lines().map(|line| line.split(' ').collect()).collect();
+    assert_eq!(lines.len(), 2, "{stdout}");
+    assert_eq!((lines[0][1], lines[1][1]), ("1", "2"));
+    // the same process both times
+    assert_eq!(lines[0][2], lines[1][2]);
+}
+
+#[test]
+fn acts_on_exit_codes() {
+    let scratch = scratch();
+    let paged = scratch.root().join("paged");
+    let page = format!("sh=3:page:echo $PLUMBER_STAGE $PLUMBER_EXIT_CODE > {}", paged.display());
+    let run = scratch.run_with("grepping", "sh -c 'exit 3' | grep nothing", &["--pipefail", "--on-exit", "grep=1:warn", "--on-exit", &page], b"").unwrap();
+    run.assert_failure().assert_stage_exit("sh", 3);
+    assert_eq!(std::fs::read_to_string(paged).unwrap(), "sh 3\n");
+
+    let run = scratch.run_with("grepping", "echo a | grep nothing", &["--on-exit", "grep=1:warn"], b"").unwrap();
+    run.assert_success();
+}
+
+#[test]
+fn keeps_what_stages_report() {
+    let scratch = scratch();
+    let report = r#"sh -c 'echo plumber-metric:records+=2 >&$PLUMBER_METRICS_FD; echo plumber-metric:lag=7 >&$PLUMBER_METRICS_FD'"#;
+    for _ in 0..2 {
+        scratch.run_with("reporting", &format!("{report} | cat"), &["--fd", "sh=3>/dev/null", "--report-metrics", "sh"], b"").unwrap().assert_success();
+    }
+    let metrics = std::fs::read_to_string(scratch.metadata_dir("reporting").join(".metrics")).unwrap();
+    assert_eq!(metrics, r#"{"counters":{"records":4},"gauges":{"lag":7}}"#);
+}
+
+#[test]
+fn runs_structured_definitions() {
+    let scratch = scratch();
+    let definition = r#"
+timeout = "300ms"
+
+[[stage]]
+cmd = "sh"
+args = ["-c", "echo $GREETING from $(pwd); exec sleep 5"]
+env = { GREETING = "hello" }
+cwd = "/"
+
+[[stage]]
+cmd = "cat"
+stderr_log = "sink.log"
+"#;
+    let run = scratch.run_with("structured", definition, &[], b"").unwrap();
+    run.assert_stdout("hello from /\n").assert_stage_exit("cat", 0);
+    assert_eq!(run.stage("sh").signal, Some(15));
+    assert!(scratch.logging_dir("structured").join("sink.log").exists());
+}
+
+#[test]
+fn expands_environment_variables() {
+    let scratch = scratch();
+    let home = std::env::var("HOME").unwrap();
+    let run = scratch.run_with("expanding", r#"echo "$HOME" ${PLUMBER_TEST_UNSET:-fallback} '$HOME'"#, &[], b"").unwrap();
+    run.assert_success().assert_stdout(&format!("{home} fallback $HOME\n"));
+    let run = scratch.run_with("expanding", "echo $HOME", &["--no-expand"], b"").unwrap();
+    run.assert_success().assert_stdout("$HOME\n");
+}
+
+#[test]
+fn installs_and_runs_packages() {
+    let scratch = scratch();
+    let plumber = |args: &[&str]| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_plumber")).args(args).env("PLUMBER_ROOT", scratch.root()).output().unwrap()
+    };
+    let package = scratch.root().join("greeter");
+    for version in ["1.2.0", "1.10.0"] {
+        std::fs::create_dir_all(package.join("pipelines")).unwrap();
+        std::fs::write(package.join("package.toml"), format!("name = \"greeter\"\nversion = \"{version}\"\nenv = {{ GREETING = \"hello\" }}\n")).unwrap();
+        std::fs::write(package.join("pipelines").join("greet.plumb"), format!("echo $GREETING {version} | cat")).unwrap();
+        assert!(plumber(&["install", package.to_str().unwrap()]).status.success());
+    }
+    assert!(!plumber(&["install", package.to_str().unwrap()]).status.success());
+
+    let run = plumber(&["run", "greeter"]);
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "hello 1.10.0\n");
+    let run = plumber(&["run", "greeter@1.2.0"]);
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "hello 1.2.0\n");
+    let listed = String::from_utf8_lossy(&plumber(&["packages"]).stdout).into_owned();
+    assert!(listed.lines().any(|line| line.split_whitespace().eq(["greeter", "1.10.0", "1.2.0"])), "{listed}");
+}
+
+#[test]
+fn fills_in_templates() {
+    let scratch = scratch();
+    let template = "echo {{greeting}} {{name}} | tr a-z A-Z";
+    let run = scratch.run_with("templated", template, &["--set", "greeting=hello", "--set", "name=big world"], b"").unwrap();
+    run.assert_success().assert_stdout("HELLO BIG WORLD\n");
+    let run = scratch.run_with("templated", template, &["--set", "greeting=hello"], b"").unwrap();
+    run.assert_failure();
+    assert!(run.plumber_stderr.contains("no value for {{name}}"), "{}", run.plumber_stderr);
+}
+
+#[test]
+fn refuses_names_outside_its_state() {
+    let scratch = scratch();
+    let victim = scratch.root().join("victim");
+    std::fs::create_dir_all(&victim).unwrap();
+    for args in [&["reset", "../../victim"][..], &["observe", "logs", "../victim"]] {
+        let refused = std::process::Command::new(env!("CARGO_BIN_EXE_plumber")).args(args).env("PLUMBER_ROOT", scratch.root()).output().unwrap();
+        assert_eq!(refused.status.code(), Some(2));
+        assert!(String::from_utf8_lossy(&refused.stderr).contains("can't name a pipeline"));
+    }
+    assert!(victim.exists());
+}
+
+#[test]
+fn fails_fast_on_unmet_preconditions() {
+    let scratch = scratch();
+    let run = scratch.run_with("unmet", "echo hi", &["--require", "path:/", "--require", "path:/no/such/dir", "--on-unmet", "fail"], b"").unwrap();
+    run.assert_failure().assert_stdout("");
+    assert!(run.plumber_stderr.contains("precondition path:/no/such/dir not met"), "{}", run.plumber_stderr);
+
+    let run = scratch.run_with("met", "echo hi", &["--require", "path:/", "--on-unmet", "fail"], b"").unwrap();
+    run.assert_success().assert_stdout("hi\n");
+}